@@ -0,0 +1,298 @@
+use std::fmt;
+use std::rc::Rc;
+
+use crate::span::Span;
+
+/// A declared rlua type, as written after `local`/`global` or in front of a
+/// function, parameter or table field.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TypeName {
+    Int,
+    Long,
+    Float,
+    Double,
+    String,
+    Table,
+    Bool,
+    Char,
+    Void,
+    /// `vec`, or `vec<T>` when its elements are typed
+    Vec(Option<Box<TypeName>>),
+}
+
+impl fmt::Display for TypeName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TypeName::Int => write!(f, "int"),
+            TypeName::Long => write!(f, "long"),
+            TypeName::Float => write!(f, "float"),
+            TypeName::Double => write!(f, "double"),
+            TypeName::String => write!(f, "string"),
+            TypeName::Table => write!(f, "table"),
+            TypeName::Bool => write!(f, "bool"),
+            TypeName::Char => write!(f, "char"),
+            TypeName::Void => write!(f, "void"),
+            TypeName::Vec(None) => write!(f, "vec"),
+            TypeName::Vec(Some(elem)) => write!(f, "vec<{}>", elem),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scope {
+    Local,
+    Global,
+}
+
+/// Attribute of a local, `local x <const> = 1`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Attrib {
+    /// Cannot be assigned after its declaration
+    Const,
+    /// A constant whose value's `__close` metamethod runs when it goes out
+    /// of scope
+    Close,
+}
+
+impl fmt::Display for Attrib {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Attrib::Const => write!(f, "const"),
+            Attrib::Close => write!(f, "close"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Name {
+    pub name: String,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Block {
+    pub stmts: Vec<Stmt>,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Stmt {
+    pub kind: StmtKind,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[allow(clippy::large_enum_variant)]
+pub enum StmtKind {
+    /// `int a = 10`, `local x, y = f()`, `global bool k`
+    Local {
+        scope: Scope,
+        ty: Option<TypeName>,
+        names: Vec<Name>,
+        /// Attribute of each name, `local f <close> = ...`
+        attribs: Vec<Option<Attrib>>,
+        values: Vec<Expr>,
+    },
+    /// `int function f() end`, `global function g() end`, `function t.a:b() end`
+    Function {
+        scope: Scope,
+        name: FunctionName,
+        func: Rc<Function>,
+    },
+    Assign {
+        targets: Vec<Expr>,
+        values: Vec<Expr>,
+    },
+    /// A call evaluated for its side effects
+    Call(Expr),
+    /// A bare anonymous or fastcall function, e.g. `void () end`
+    Expr(Expr),
+    Do(Block),
+    While {
+        cond: Expr,
+        body: Block,
+    },
+    Repeat {
+        body: Block,
+        cond: Expr,
+    },
+    If {
+        branches: Vec<(Expr, Block)>,
+        else_block: Option<Block>,
+    },
+    NumericFor {
+        var: Name,
+        start: Expr,
+        limit: Expr,
+        step: Option<Expr>,
+        body: Block,
+    },
+    GenericFor {
+        names: Vec<Name>,
+        exprs: Vec<Expr>,
+        body: Block,
+    },
+    Return(Vec<Expr>),
+    Break,
+    Goto(Name),
+    Label(Name),
+}
+
+/// Target of a function declaration: `name{.field}[:method]`
+#[derive(Debug, Clone, PartialEq)]
+pub struct FunctionName {
+    pub path: Vec<Name>,
+    pub method: Option<Name>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Param {
+    pub name: Name,
+    pub ty: Option<TypeName>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Function {
+    pub ret: Option<TypeName>,
+    pub params: Vec<Param>,
+    pub is_vararg: bool,
+    /// Written without the `function` keyword: `void () end`
+    pub fastcall: bool,
+    pub body: Block,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expr {
+    pub kind: ExprKind,
+    pub span: Span,
+}
+
+impl Expr {
+    /// Calls and `...` produce a variable number of values.
+    pub fn is_multi(&self) -> bool {
+        matches!(
+            self.kind,
+            ExprKind::Call { .. } | ExprKind::MethodCall { .. } | ExprKind::Vararg
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExprKind {
+    Nil,
+    True,
+    False,
+    Integer(i64),
+    Float(f64),
+    /// Byte string, as produced by the lexer
+    String(Vec<u8>),
+    Char(char),
+    Vararg,
+    Function(Rc<Function>),
+    Table(Vec<TableField>),
+    /// `[1, 2, 3]`
+    Vec(Vec<Expr>),
+    Name(String),
+    /// `a[b]`, and `a.b` with a string key
+    Index {
+        object: Box<Expr>,
+        key: Box<Expr>,
+    },
+    Call {
+        func: Box<Expr>,
+        args: Vec<Expr>,
+    },
+    MethodCall {
+        object: Box<Expr>,
+        method: Name,
+        args: Vec<Expr>,
+    },
+    /// Parenthesized expression, truncates multiple results to one
+    Paren(Box<Expr>),
+    /// `(int)expr`
+    Cast {
+        ty: TypeName,
+        expr: Box<Expr>,
+    },
+    Unary {
+        op: UnOp,
+        expr: Box<Expr>,
+    },
+    Binary {
+        op: BinOp,
+        lhs: Box<Expr>,
+        rhs: Box<Expr>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnOp {
+    Neg,
+    Not,
+    Len,
+    BitNot,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    FloorDiv,
+    Mod,
+    Pow,
+    BitAnd,
+    BitOr,
+    BitXor,
+    Shl,
+    Shr,
+    Concat,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    And,
+    Or,
+}
+
+/// Binding power of unary operators, only `^` binds tighter.
+pub const UNARY_PRIORITY: u8 = 12;
+
+impl BinOp {
+    /// Left and right binding power, following the Lua 5.4 reference manual.
+    /// A right power lower than the left one makes the operator right
+    /// associative.
+    pub fn precedence(self) -> (u8, u8) {
+        match self {
+            BinOp::Or => (1, 1),
+            BinOp::And => (2, 2),
+            BinOp::Eq | BinOp::Ne | BinOp::Lt | BinOp::Le | BinOp::Gt | BinOp::Ge => (3, 3),
+            BinOp::BitOr => (4, 4),
+            BinOp::BitXor => (5, 5),
+            BinOp::BitAnd => (6, 6),
+            BinOp::Shl | BinOp::Shr => (7, 7),
+            BinOp::Concat => (9, 8),
+            BinOp::Add | BinOp::Sub => (10, 10),
+            BinOp::Mul | BinOp::Div | BinOp::FloorDiv | BinOp::Mod => (11, 11),
+            BinOp::Pow => (14, 13),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TableField {
+    /// `expr`
+    Positional(Expr),
+    /// `name = expr` or typed `int name = expr`
+    Named {
+        name: Name,
+        ty: Option<TypeName>,
+        value: Expr,
+    },
+    /// `[key] = expr`
+    Keyed { key: Expr, value: Expr },
+}
@@ -0,0 +1,1087 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::ast::*;
+use crate::span::{Position, Span};
+
+/// Static type of an expression as far as the checker can tell. Anything
+/// it cannot see through, like table reads or untyped locals, is `Any`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Type {
+    Int,
+    Long,
+    Float,
+    Double,
+    String,
+    /// Types of the fields declared as `T name = value` in the constructor,
+    /// empty for a table without a schema
+    Table(Vec<(String, Type)>),
+    Bool,
+    Char,
+    /// Element type, `Any` for an untyped `vec`
+    Vec(Box<Type>),
+    Nil,
+    /// The absence of a value, only ever produced by calling a `void` function
+    Void,
+    Function(Box<Signature>),
+    Any,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Signature {
+    pub params: Vec<Type>,
+    pub is_vararg: bool,
+    pub ret: Type,
+}
+
+impl Type {
+    pub fn from_name(name: &TypeName) -> Type {
+        match name {
+            TypeName::Int => Type::Int,
+            TypeName::Long => Type::Long,
+            TypeName::Float => Type::Float,
+            TypeName::Double => Type::Double,
+            TypeName::String => Type::String,
+            TypeName::Table => Type::Table(Vec::new()),
+            TypeName::Bool => Type::Bool,
+            TypeName::Char => Type::Char,
+            TypeName::Void => Type::Void,
+            TypeName::Vec(elem) => {
+                Type::Vec(Box::new(elem.as_deref().map_or(Type::Any, Type::from_name)))
+            }
+        }
+    }
+
+    fn is_numeric(&self) -> bool {
+        matches!(self, Type::Int | Type::Long | Type::Float | Type::Double)
+    }
+
+    /// Whether a value of type `found` may be stored where `self` is declared.
+    /// Integers widen to larger integers and to floating point, `float` and
+    /// `double` convert freely, a `char` is also a `string`, and `nil` fits
+    /// any non-numeric type.
+    pub fn accepts(&self, found: &Type) -> bool {
+        match (self, found) {
+            (Type::Any, _) | (_, Type::Any) => true,
+            (Type::Void, _) | (_, Type::Void) => false,
+            // Numbers always hold a number, other types may be nil
+            (expected, Type::Nil) => !expected.is_numeric(),
+            (Type::Long, Type::Int) => true,
+            (Type::Float | Type::Double, Type::Int | Type::Long | Type::Float | Type::Double) => {
+                true
+            }
+            // A char is a one-character string
+            (Type::String, Type::Char) => true,
+            (Type::Function(expected), Type::Function(found)) => expected.ret.accepts(&found.ret),
+            (Type::Vec(expected), Type::Vec(found)) => expected.accepts(found),
+            // Schemas are open, any table may be stored in any other
+            (Type::Table(_), Type::Table(_)) => true,
+            (expected, found) => expected == found,
+        }
+    }
+
+    /// Whether `(self)value` can succeed for a value of type `found`. On top
+    /// of what `accepts` allows, numbers, strings and chars convert into each
+    /// other, bools format as strings, and vecs convert their elements.
+    fn can_cast(&self, found: &Type) -> bool {
+        match (self, found) {
+            (to, from) if to.is_numeric() => {
+                from.is_numeric() || matches!(from, Type::String | Type::Char | Type::Any)
+            }
+            (Type::Char, from) if from.is_numeric() || *from == Type::String => true,
+            (Type::String, from) if from.is_numeric() || *from == Type::Bool => true,
+            (Type::Vec(_), Type::Vec(_)) => true,
+            (to, from) => to.accepts(from),
+        }
+    }
+
+    /// The type a variable declared as `self` takes when initialised with a
+    /// value of type `found`: a plain `table` keeps the value's schema.
+    fn refine(&self, found: &Type) -> Type {
+        match (self, found) {
+            (Type::Table(fields), Type::Table(_)) if fields.is_empty() => found.clone(),
+            _ => self.clone(),
+        }
+    }
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Type::Int => write!(f, "int"),
+            Type::Long => write!(f, "long"),
+            Type::Float => write!(f, "float"),
+            Type::Double => write!(f, "double"),
+            Type::String => write!(f, "string"),
+            Type::Table(_) => write!(f, "table"),
+            Type::Bool => write!(f, "bool"),
+            Type::Char => write!(f, "char"),
+            Type::Vec(elem) if **elem == Type::Any => write!(f, "vec"),
+            Type::Vec(elem) => write!(f, "vec<{}>", elem),
+            Type::Nil => write!(f, "nil"),
+            Type::Void => write!(f, "void"),
+            Type::Function(sig) => write!(f, "{} function", sig.ret),
+            Type::Any => write!(f, "any"),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum TypeErrorKind {
+    Mismatch { expected: Type, found: Type },
+    ReturnFromVoid,
+    MissingReturnValue(Type),
+    VoidVariable,
+    InvalidOperand { op: &'static str, found: Type },
+    ArgumentCount { expected: usize, found: usize },
+    InvalidCast { from: Type, to: Type },
+    AssignToConst(String),
+    BreakOutsideLoop,
+    UndefinedLabel(String),
+    DuplicateLabel(String),
+    JumpIntoScope { label: String, local: String },
+}
+
+impl fmt::Display for TypeErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TypeErrorKind::Mismatch { expected, found } => {
+                write!(f, "expected {}, found {}", expected, found)
+            }
+            TypeErrorKind::ReturnFromVoid => {
+                write!(f, "cannot return a value from a void function")
+            }
+            TypeErrorKind::MissingReturnValue(ty) => {
+                write!(f, "missing return value, function returns {}", ty)
+            }
+            TypeErrorKind::VoidVariable => write!(f, "a void declaration must hold a function"),
+            TypeErrorKind::InvalidOperand { op, found } => {
+                write!(f, "cannot apply '{}' to {}", op, found)
+            }
+            TypeErrorKind::ArgumentCount { expected, found } => {
+                write!(f, "expected {} arguments, found {}", expected, found)
+            }
+            TypeErrorKind::InvalidCast { from, to } => {
+                write!(f, "cannot cast {} to {}", from, to)
+            }
+            TypeErrorKind::AssignToConst(name) => {
+                write!(f, "attempt to assign to const variable '{}'", name)
+            }
+            TypeErrorKind::BreakOutsideLoop => write!(f, "break outside a loop"),
+            TypeErrorKind::UndefinedLabel(label) => {
+                write!(f, "no visible label '{}' for goto", label)
+            }
+            TypeErrorKind::DuplicateLabel(label) => {
+                write!(f, "label '{}' already defined", label)
+            }
+            TypeErrorKind::JumpIntoScope { label, local } => {
+                write!(
+                    f,
+                    "goto {} jumps into the scope of local '{}'",
+                    label, local
+                )
+            }
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct TypeError {
+    pub kind: TypeErrorKind,
+    pub span: Span,
+}
+
+impl fmt::Display for TypeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.span, self.kind)
+    }
+}
+
+impl std::error::Error for TypeError {}
+
+/// A local in scope.
+#[derive(Debug)]
+struct Local {
+    ty: Type,
+    /// Declared `<const>` or `<close>`, so it cannot be assigned
+    constant: bool,
+}
+
+impl Local {
+    fn new(ty: Type) -> Local {
+        Local {
+            ty,
+            constant: false,
+        }
+    }
+}
+
+/// Walks the AST once, tracking declared types of locals and globals, and
+/// collects every type error instead of stopping at the first.
+#[derive(Debug, Default)]
+pub struct Checker {
+    scopes: Vec<HashMap<String, Local>>,
+    globals: HashMap<String, Type>,
+    /// Declared return type of each enclosing function, `None` if untyped
+    returns: Vec<Option<Type>>,
+    errors: Vec<TypeError>,
+}
+
+impl Checker {
+    pub fn new() -> Self {
+        Checker::default()
+    }
+
+    pub fn check(mut self, chunk: &Block) -> Result<(), Vec<TypeError>> {
+        self.check_jumps(&chunk.stmts, &mut Vec::new(), false);
+        self.declare_globals(&chunk.stmts);
+        self.check_block(chunk);
+        if self.errors.is_empty() {
+            Ok(())
+        } else {
+            Err(self.errors)
+        }
+    }
+
+    /// Declares every typed global up front, wherever it appears, so stores
+    /// into it from functions defined before the declaration are checked.
+    fn declare_globals(&mut self, stmts: &[Stmt]) {
+        for stmt in stmts {
+            match &stmt.kind {
+                StmtKind::Local {
+                    scope,
+                    ty,
+                    names,
+                    values,
+                    ..
+                } => {
+                    let declared = ty.as_ref().map(Type::from_name);
+                    if let (Scope::Global, Some(declared)) = (scope, declared) {
+                        for (i, name) in names.iter().enumerate() {
+                            // Typed function values keep their signature
+                            let value = values.get(i);
+                            if declared == Type::Void
+                                || value.is_some_and(|v| matches!(v.kind, ExprKind::Function(_)))
+                            {
+                                continue;
+                            }
+                            self.globals
+                                .entry(name.name.clone())
+                                .or_insert_with(|| declared.clone());
+                        }
+                    }
+                    for value in values {
+                        self.declare_globals_in(value);
+                    }
+                }
+                StmtKind::Function { func, .. } => self.declare_globals(&func.body.stmts),
+                StmtKind::Assign { targets, values } => {
+                    for expr in targets.iter().chain(values) {
+                        self.declare_globals_in(expr);
+                    }
+                }
+                StmtKind::Call(expr) | StmtKind::Expr(expr) => self.declare_globals_in(expr),
+                StmtKind::Do(body) => self.declare_globals(&body.stmts),
+                StmtKind::While { cond, body } | StmtKind::Repeat { body, cond } => {
+                    self.declare_globals_in(cond);
+                    self.declare_globals(&body.stmts);
+                }
+                StmtKind::If {
+                    branches,
+                    else_block,
+                } => {
+                    for (cond, body) in branches {
+                        self.declare_globals_in(cond);
+                        self.declare_globals(&body.stmts);
+                    }
+                    if let Some(body) = else_block {
+                        self.declare_globals(&body.stmts);
+                    }
+                }
+                StmtKind::NumericFor {
+                    start,
+                    limit,
+                    step,
+                    body,
+                    ..
+                } => {
+                    for expr in [Some(start), Some(limit), step.as_ref()]
+                        .into_iter()
+                        .flatten()
+                    {
+                        self.declare_globals_in(expr);
+                    }
+                    self.declare_globals(&body.stmts);
+                }
+                StmtKind::GenericFor { exprs, body, .. } => {
+                    for expr in exprs {
+                        self.declare_globals_in(expr);
+                    }
+                    self.declare_globals(&body.stmts);
+                }
+                StmtKind::Return(values) => {
+                    for value in values {
+                        self.declare_globals_in(value);
+                    }
+                }
+                StmtKind::Break | StmtKind::Goto(_) | StmtKind::Label(_) => {}
+            }
+        }
+    }
+
+    /// Declares the typed globals in the function bodies inside `expr`.
+    fn declare_globals_in(&mut self, expr: &Expr) {
+        match &expr.kind {
+            ExprKind::Function(func) => self.declare_globals(&func.body.stmts),
+            ExprKind::Table(fields) => {
+                for field in fields {
+                    match field {
+                        TableField::Positional(value) | TableField::Named { value, .. } => {
+                            self.declare_globals_in(value)
+                        }
+                        TableField::Keyed { key, value } => {
+                            self.declare_globals_in(key);
+                            self.declare_globals_in(value);
+                        }
+                    }
+                }
+            }
+            ExprKind::Vec(items) => {
+                for item in items {
+                    self.declare_globals_in(item);
+                }
+            }
+            ExprKind::Index { object, key } => {
+                self.declare_globals_in(object);
+                self.declare_globals_in(key);
+            }
+            ExprKind::Call { func: object, args } | ExprKind::MethodCall { object, args, .. } => {
+                self.declare_globals_in(object);
+                for arg in args {
+                    self.declare_globals_in(arg);
+                }
+            }
+            ExprKind::Paren(expr) | ExprKind::Cast { expr, .. } | ExprKind::Unary { expr, .. } => {
+                self.declare_globals_in(expr)
+            }
+            ExprKind::Binary { lhs, rhs, .. } => {
+                self.declare_globals_in(lhs);
+                self.declare_globals_in(rhs);
+            }
+            _ => {}
+        }
+    }
+
+    fn error(&mut self, kind: TypeErrorKind, span: Span) {
+        self.errors.push(TypeError { kind, span });
+    }
+
+    fn expect(&mut self, expected: &Type, found: &Type, span: Span) {
+        if !expected.accepts(found) {
+            self.error(
+                TypeErrorKind::Mismatch {
+                    expected: expected.clone(),
+                    found: found.clone(),
+                },
+                span,
+            );
+        }
+    }
+
+    fn declare(&mut self, scope: Scope, name: &str, ty: Type) {
+        match (scope, self.scopes.last_mut()) {
+            (Scope::Local, Some(locals)) => {
+                locals.insert(name.to_string(), Local::new(ty));
+            }
+            _ => {
+                self.globals.insert(name.to_string(), ty);
+            }
+        }
+    }
+
+    fn lookup(&self, name: &str) -> Type {
+        self.scopes
+            .iter()
+            .rev()
+            .find_map(|scope| scope.get(name).map(|local| &local.ty))
+            .or_else(|| self.globals.get(name))
+            .cloned()
+            .unwrap_or(Type::Any)
+    }
+
+    fn is_constant(&self, name: &str) -> bool {
+        self.scopes
+            .iter()
+            .rev()
+            .find_map(|scope| scope.get(name))
+            .is_some_and(|local| local.constant)
+    }
+
+    fn check_block(&mut self, block: &Block) {
+        self.scopes.push(HashMap::new());
+        for stmt in &block.stmts {
+            self.check_stmt(stmt);
+        }
+        self.scopes.pop();
+    }
+
+    fn check_stmt(&mut self, stmt: &Stmt) {
+        match &stmt.kind {
+            StmtKind::Local {
+                scope,
+                ty,
+                names,
+                attribs,
+                values,
+            } => {
+                self.check_declaration(*scope, ty.as_ref(), names, values);
+                if let Some(locals) = self.scopes.last_mut() {
+                    for (name, attrib) in names.iter().zip(attribs) {
+                        if let (Some(_), Some(local)) = (attrib, locals.get_mut(&name.name)) {
+                            local.constant = true;
+                        }
+                    }
+                }
+            }
+            StmtKind::Function { scope, name, func } => {
+                let sig = signature(func, func.ret.as_ref());
+                if name.path.len() == 1 && name.method.is_none() {
+                    // Declared before the body is checked so it may recurse
+                    let ty = Type::Function(Box::new(sig.clone()));
+                    self.declare(*scope, &name.path[0].name, ty);
+                }
+                self.check_function_body(func, &sig);
+            }
+            StmtKind::Assign { targets, values } => {
+                for (i, target) in targets.iter().enumerate() {
+                    if let ExprKind::Name(name) = &target.kind {
+                        if self.is_constant(name) {
+                            self.error(TypeErrorKind::AssignToConst(name.clone()), target.span);
+                        }
+                    }
+                    let expected = self.check_expr(target);
+                    let Some(value) = values.get(i) else {
+                        continue;
+                    };
+                    let found = self.check_expr(value);
+                    self.expect(&expected, &found, value.span);
+                }
+                for value in values.iter().skip(targets.len()) {
+                    self.check_expr(value);
+                }
+            }
+            StmtKind::Call(expr) | StmtKind::Expr(expr) => {
+                self.check_expr(expr);
+            }
+            StmtKind::Do(body) => self.check_block(body),
+            StmtKind::While { cond, body } => {
+                self.check_expr(cond);
+                self.check_block(body);
+            }
+            StmtKind::Repeat { body, cond } => {
+                // The condition sees the body's locals
+                self.scopes.push(HashMap::new());
+                for stmt in &body.stmts {
+                    self.check_stmt(stmt);
+                }
+                self.check_expr(cond);
+                self.scopes.pop();
+            }
+            StmtKind::If {
+                branches,
+                else_block,
+            } => {
+                for (cond, body) in branches {
+                    self.check_expr(cond);
+                    self.check_block(body);
+                }
+                if let Some(body) = else_block {
+                    self.check_block(body);
+                }
+            }
+            StmtKind::NumericFor {
+                var,
+                start,
+                limit,
+                step,
+                body,
+            } => {
+                let mut ty = Type::Int;
+                for expr in [Some(start), Some(limit), step.as_ref()]
+                    .into_iter()
+                    .flatten()
+                {
+                    let found = self.check_expr(expr);
+                    if !found.is_numeric() && found != Type::Any {
+                        self.error(
+                            TypeErrorKind::InvalidOperand {
+                                op: "for",
+                                found: found.clone(),
+                            },
+                            expr.span,
+                        );
+                    }
+                    ty = arith_result(&ty, &found);
+                }
+                self.scopes
+                    .push(HashMap::from([(var.name.clone(), Local::new(ty))]));
+                self.check_block(body);
+                self.scopes.pop();
+            }
+            StmtKind::GenericFor { names, exprs, body } => {
+                for expr in exprs {
+                    self.check_expr(expr);
+                }
+                let vars = names
+                    .iter()
+                    .map(|n| (n.name.clone(), Local::new(Type::Any)))
+                    .collect();
+                self.scopes.push(vars);
+                self.check_block(body);
+                self.scopes.pop();
+            }
+            StmtKind::Return(values) => self.check_return(values, stmt.span),
+            StmtKind::Break | StmtKind::Goto(_) | StmtKind::Label(_) => {}
+        }
+    }
+
+    fn check_declaration(
+        &mut self,
+        scope: Scope,
+        ty: Option<&TypeName>,
+        names: &[Name],
+        values: &[Expr],
+    ) {
+        let declared = ty.map(Type::from_name);
+        let mut types = Vec::with_capacity(names.len());
+        for (i, name) in names.iter().enumerate() {
+            let value = values.get(i);
+            let ty = match (&declared, value) {
+                (None, Some(value)) => {
+                    self.check_expr(value);
+                    Type::Any
+                }
+                (None, None) => Type::Any,
+                // `T name = function() end` declares a function returning T
+                (Some(declared), Some(value)) if matches!(value.kind, ExprKind::Function(_)) => {
+                    let ExprKind::Function(func) = &value.kind else {
+                        unreachable!()
+                    };
+                    let ret = func.ret.as_ref().map(Type::from_name);
+                    if let Some(ret) = ret.filter(|ret| ret != declared) {
+                        self.expect(declared, &ret, value.span);
+                    }
+                    let sig = self.check_function(func, ty);
+                    Type::Function(Box::new(sig))
+                }
+                (Some(Type::Void), value) => {
+                    let found = value.map(|v| self.check_expr(v));
+                    match found {
+                        Some(Type::Function(sig)) if sig.ret == Type::Void => Type::Function(sig),
+                        _ => {
+                            self.error(
+                                TypeErrorKind::VoidVariable,
+                                value.map_or(name.span, |v| v.span),
+                            );
+                            Type::Any
+                        }
+                    }
+                }
+                (Some(declared), Some(value)) => {
+                    let found = self.check_expr(value);
+                    self.expect(declared, &found, value.span);
+                    declared.refine(&found)
+                }
+                (Some(declared), None) => declared.clone(),
+            };
+            types.push(ty);
+        }
+        for value in values.iter().skip(names.len()) {
+            self.check_expr(value);
+        }
+        for (name, ty) in names.iter().zip(types) {
+            self.declare(scope, &name.name, ty);
+        }
+    }
+
+    fn check_return(&mut self, values: &[Expr], span: Span) {
+        let found: Vec<Type> = values.iter().map(|v| self.check_expr(v)).collect();
+        let Some(Some(expected)) = self.returns.last().cloned() else {
+            return;
+        };
+        match (&expected, values.first()) {
+            (Type::Void, Some(value)) => self.error(TypeErrorKind::ReturnFromVoid, value.span),
+            (Type::Void, None) => {}
+            (expected, None) => {
+                self.error(TypeErrorKind::MissingReturnValue(expected.clone()), span)
+            }
+            (expected, Some(value)) => self.expect(expected, &found[0], value.span),
+        }
+    }
+
+    /// Checks the labels, gotos and breaks of a block, and of the blocks
+    /// nested in it up to the next function, as Lua does before running
+    /// anything. `enclosing` holds the statements of the outer blocks and
+    /// the index of the one being checked in each. A label is visible in
+    /// its block and the nested ones and may not repeat a visible label, a
+    /// goto may not jump forward into the scope of a local unless its label
+    /// ends the block, and a break must be in a loop.
+    fn check_jumps<'a>(
+        &mut self,
+        stmts: &'a [Stmt],
+        enclosing: &mut Vec<(&'a [Stmt], usize)>,
+        in_loop: bool,
+    ) {
+        for (i, stmt) in stmts.iter().enumerate() {
+            match &stmt.kind {
+                StmtKind::Label(label) => {
+                    let mut visible = enclosing
+                        .iter()
+                        .flat_map(|(stmts, at)| &stmts[..*at])
+                        .chain(&stmts[..i]);
+                    if visible
+                        .any(|s| matches!(&s.kind, StmtKind::Label(l) if l.name == label.name))
+                    {
+                        self.error(
+                            TypeErrorKind::DuplicateLabel(label.name.clone()),
+                            label.span,
+                        );
+                    }
+                }
+                StmtKind::Goto(label) => {
+                    let mut blocks = enclosing.iter().copied().chain([(stmts, i)]).rev();
+                    let target = blocks.find_map(|(stmts, at)| {
+                        let to = stmts.iter().position(
+                            |s| matches!(&s.kind, StmtKind::Label(l) if l.name == label.name),
+                        )?;
+                        Some((stmts, at, to))
+                    });
+                    let Some((stmts, at, to)) = target else {
+                        self.error(
+                            TypeErrorKind::UndefinedLabel(label.name.clone()),
+                            label.span,
+                        );
+                        continue;
+                    };
+                    let at_end = stmts[to + 1..]
+                        .iter()
+                        .all(|s| matches!(s.kind, StmtKind::Label(_)));
+                    // Only a forward jump skips declarations
+                    let skipped = stmts.get(at + 1..to).unwrap_or_default();
+                    let local = skipped.iter().find_map(declared_local);
+                    if let Some(local) = local.filter(|_| !at_end) {
+                        self.error(
+                            TypeErrorKind::JumpIntoScope {
+                                label: label.name.clone(),
+                                local: local.to_string(),
+                            },
+                            label.span,
+                        );
+                    }
+                }
+                StmtKind::Break if !in_loop => {
+                    self.error(TypeErrorKind::BreakOutsideLoop, stmt.span);
+                }
+                _ => {}
+            }
+            enclosing.push((stmts, i));
+            match &stmt.kind {
+                StmtKind::Do(body) => self.check_jumps(&body.stmts, enclosing, in_loop),
+                StmtKind::While { body, .. }
+                | StmtKind::Repeat { body, .. }
+                | StmtKind::NumericFor { body, .. }
+                | StmtKind::GenericFor { body, .. } => {
+                    self.check_jumps(&body.stmts, enclosing, true)
+                }
+                StmtKind::If {
+                    branches,
+                    else_block,
+                } => {
+                    for body in branches.iter().map(|(_, body)| body).chain(else_block) {
+                        self.check_jumps(&body.stmts, enclosing, in_loop);
+                    }
+                }
+                _ => {}
+            }
+            enclosing.pop();
+        }
+    }
+
+    fn check_function(&mut self, func: &Function, ret: Option<&TypeName>) -> Signature {
+        let sig = signature(func, ret);
+        self.check_function_body(func, &sig);
+        sig
+    }
+
+    fn check_function_body(&mut self, func: &Function, sig: &Signature) {
+        let locals = func
+            .params
+            .iter()
+            .zip(&sig.params)
+            .map(|(p, ty)| (p.name.name.clone(), Local::new(ty.clone())))
+            .collect();
+        self.scopes.push(locals);
+        self.check_jumps(&func.body.stmts, &mut Vec::new(), false);
+        self.returns
+            .push(Some(sig.ret.clone()).filter(|ret| *ret != Type::Any));
+        self.check_block(&func.body);
+        if !matches!(sig.ret, Type::Void | Type::Any) && self.falls_through(&func.body.stmts) {
+            self.error(
+                TypeErrorKind::MissingReturnValue(sig.ret.clone()),
+                end_span(func),
+            );
+        }
+        self.returns.pop();
+        self.scopes.pop();
+    }
+
+    /// Whether running `stmts` may reach their end. Loops other than
+    /// `while true` and `repeat ... until false` may run zero times or be
+    /// left with `break`, and a call to the builtin `error` never returns.
+    fn falls_through(&self, stmts: &[Stmt]) -> bool {
+        let Some(last) = stmts.last() else {
+            return true;
+        };
+        match &last.kind {
+            StmtKind::Return(_) | StmtKind::Goto(_) => false,
+            StmtKind::Do(body) => self.falls_through(&body.stmts),
+            StmtKind::If {
+                branches,
+                else_block: Some(else_block),
+            } => {
+                branches
+                    .iter()
+                    .any(|(_, body)| self.falls_through(&body.stmts))
+                    || self.falls_through(&else_block.stmts)
+            }
+            StmtKind::While { cond, body } if cond.kind == ExprKind::True => has_break(&body.stmts),
+            StmtKind::Repeat { body, cond } if cond.kind == ExprKind::False => {
+                has_break(&body.stmts)
+            }
+            StmtKind::Call(call) => match &call.kind {
+                ExprKind::Call { func, .. } => !matches!(
+                    &func.kind,
+                    ExprKind::Name(name) if name == "error" && self.lookup(name) == Type::Any
+                ),
+                _ => true,
+            },
+            _ => true,
+        }
+    }
+
+    fn check_expr(&mut self, expr: &Expr) -> Type {
+        match &expr.kind {
+            ExprKind::Nil => Type::Nil,
+            ExprKind::True | ExprKind::False => Type::Bool,
+            ExprKind::Integer(n) => integer_type(*n),
+            ExprKind::Float(_) => Type::Double,
+            ExprKind::String(_) => Type::String,
+            ExprKind::Char(_) => Type::Char,
+            ExprKind::Vararg => Type::Any,
+            ExprKind::Function(func) => {
+                Type::Function(Box::new(self.check_function(func, func.ret.as_ref())))
+            }
+            ExprKind::Table(fields) => {
+                let mut schema = Vec::new();
+                for field in fields {
+                    match field {
+                        TableField::Positional(value) => {
+                            self.check_expr(value);
+                        }
+                        TableField::Named { name, ty, value } => {
+                            let found = self.check_expr(value);
+                            if let Some(ty) = ty {
+                                let declared = Type::from_name(ty);
+                                self.expect(&declared, &found, value.span);
+                                schema.retain(|(field, _)| *field != name.name);
+                                schema.push((name.name.clone(), declared.refine(&found)));
+                            }
+                        }
+                        TableField::Keyed { key, value } => {
+                            self.check_expr(key);
+                            self.check_expr(value);
+                        }
+                    }
+                }
+                Type::Table(schema)
+            }
+            ExprKind::Vec(items) => {
+                // Elements are typed when they all agree
+                let mut elem = None;
+                for item in items {
+                    let found = self.check_expr(item);
+                    match &elem {
+                        None => elem = Some(found),
+                        Some(ty) if *ty != found => elem = Some(Type::Any),
+                        Some(_) => {}
+                    }
+                }
+                Type::Vec(Box::new(elem.unwrap_or(Type::Any)))
+            }
+            ExprKind::Name(name) => self.lookup(name),
+            ExprKind::Index { object, key } => {
+                let object = self.check_expr(object);
+                let found = self.check_expr(key);
+                match (object, &key.kind) {
+                    (Type::Vec(elem), _) if found.is_numeric() => *elem,
+                    (Type::Table(schema), ExprKind::String(field)) => schema
+                        .into_iter()
+                        .find(|(name, _)| name.as_bytes() == field.as_slice())
+                        .map_or(Type::Any, |(_, ty)| ty),
+                    _ => Type::Any,
+                }
+            }
+            ExprKind::Call { func, args } => {
+                let callee = self.check_expr(func);
+                let found: Vec<Type> = args.iter().map(|a| self.check_expr(a)).collect();
+                let Type::Function(sig) = callee else {
+                    return Type::Any;
+                };
+                // Missing arguments are nil, which only numbers cannot hold,
+                // and a trailing call or `...` may supply any number of them
+                let open_ended = args.last().is_some_and(Expr::is_multi);
+                let missing_required = sig
+                    .params
+                    .iter()
+                    .skip(found.len())
+                    .any(|param| !param.accepts(&Type::Nil));
+                if found.len() > sig.params.len() && !sig.is_vararg
+                    || missing_required && !open_ended
+                {
+                    self.error(
+                        TypeErrorKind::ArgumentCount {
+                            expected: sig.params.len(),
+                            found: found.len(),
+                        },
+                        expr.span,
+                    );
+                }
+                for ((param, found), arg) in sig.params.iter().zip(&found).zip(args) {
+                    self.expect(param, found, arg.span);
+                }
+                sig.ret
+            }
+            ExprKind::MethodCall { object, args, .. } => {
+                self.check_expr(object);
+                for arg in args {
+                    self.check_expr(arg);
+                }
+                Type::Any
+            }
+            ExprKind::Paren(inner) => self.check_expr(inner),
+            ExprKind::Cast { ty, expr: operand } => {
+                let found = self.check_expr(operand);
+                let ty = Type::from_name(ty);
+                if !ty.can_cast(&found) {
+                    self.error(
+                        TypeErrorKind::InvalidCast {
+                            from: found,
+                            to: ty.clone(),
+                        },
+                        expr.span,
+                    );
+                }
+                ty
+            }
+            ExprKind::Unary { op, expr: operand } => {
+                // `-2147483648` is an `int` although `2147483648` is not
+                if let (UnOp::Neg, ExprKind::Integer(n)) = (op, &operand.kind) {
+                    return integer_type(n.wrapping_neg());
+                }
+                let found = self.check_expr(operand);
+                match op {
+                    UnOp::Not => Type::Bool,
+                    UnOp::Neg => {
+                        self.expect_operand("-", &found, operand.span);
+                        if found.is_numeric() {
+                            found
+                        } else {
+                            Type::Any
+                        }
+                    }
+                    UnOp::BitNot => {
+                        self.expect_operand("~", &found, operand.span);
+                        bitwise_result(&found, &found)
+                    }
+                    UnOp::Len => {
+                        if matches!(
+                            found,
+                            Type::Bool | Type::Nil | Type::Void | Type::Function(_)
+                        ) || found.is_numeric()
+                        {
+                            self.error(
+                                TypeErrorKind::InvalidOperand { op: "#", found },
+                                operand.span,
+                            );
+                            return Type::Int;
+                        }
+                        // `__len` may return anything
+                        match found {
+                            Type::String | Type::Char | Type::Vec(_) => Type::Int,
+                            _ => Type::Any,
+                        }
+                    }
+                }
+            }
+            ExprKind::Binary { op, lhs, rhs } => {
+                let left = self.check_expr(lhs);
+                let right = self.check_expr(rhs);
+                self.binary_type(*op, (&left, lhs.span), (&right, rhs.span))
+            }
+        }
+    }
+
+    fn binary_type(&mut self, op: BinOp, lhs: (&Type, Span), rhs: (&Type, Span)) -> Type {
+        let symbol = match op {
+            BinOp::Add => "+",
+            BinOp::Sub => "-",
+            BinOp::Mul => "*",
+            BinOp::Div => "/",
+            BinOp::FloorDiv => "//",
+            BinOp::Mod => "%",
+            BinOp::Pow => "^",
+            BinOp::BitAnd => "&",
+            BinOp::BitOr => "|",
+            BinOp::BitXor => "~",
+            BinOp::Shl => "<<",
+            BinOp::Shr => ">>",
+            BinOp::Concat => "..",
+            BinOp::Lt => "<",
+            BinOp::Le => "<=",
+            BinOp::Gt => ">",
+            BinOp::Ge => ">=",
+            BinOp::Eq | BinOp::Ne => return Type::Bool,
+            BinOp::And | BinOp::Or => {
+                return if lhs.0 == rhs.0 {
+                    lhs.0.clone()
+                } else {
+                    Type::Any
+                };
+            }
+        };
+        for (ty, span) in [lhs, rhs] {
+            self.expect_operand(symbol, ty, span);
+        }
+        match op {
+            // Other operands go through `__concat`, which may return anything
+            BinOp::Concat => match (lhs.0, rhs.0) {
+                (l, r)
+                    if [l, r]
+                        .iter()
+                        .all(|t| t.is_numeric() || matches!(t, Type::String | Type::Char)) =>
+                {
+                    Type::String
+                }
+                _ => Type::Any,
+            },
+            BinOp::Lt | BinOp::Le | BinOp::Gt | BinOp::Ge => Type::Bool,
+            BinOp::Div | BinOp::Pow => match (lhs.0, rhs.0) {
+                (Type::Any, _) | (_, Type::Any) => Type::Any,
+                _ => Type::Double,
+            },
+            BinOp::BitAnd | BinOp::BitOr | BinOp::BitXor | BinOp::Shl | BinOp::Shr => {
+                bitwise_result(lhs.0, rhs.0)
+            }
+            _ => arith_result(lhs.0, rhs.0),
+        }
+    }
+
+    /// Operands Lua itself would reject at runtime, without a metatable.
+    fn expect_operand(&mut self, op: &'static str, found: &Type, span: Span) {
+        if matches!(
+            found,
+            Type::Bool | Type::Nil | Type::Void | Type::Function(_)
+        ) {
+            self.error(
+                TypeErrorKind::InvalidOperand {
+                    op,
+                    found: found.clone(),
+                },
+                span,
+            );
+        }
+    }
+}
+
+fn signature(func: &Function, ret: Option<&TypeName>) -> Signature {
+    Signature {
+        params: func
+            .params
+            .iter()
+            .map(|p| p.ty.as_ref().map_or(Type::Any, Type::from_name))
+            .collect(),
+        is_vararg: func.is_vararg,
+        ret: ret.map_or(Type::Any, Type::from_name),
+    }
+}
+
+/// Whether `stmts` hold a `break` leaving the loop they are the body of.
+fn has_break(stmts: &[Stmt]) -> bool {
+    stmts.iter().any(|stmt| match &stmt.kind {
+        StmtKind::Break => true,
+        StmtKind::Do(body) => has_break(&body.stmts),
+        StmtKind::If {
+            branches,
+            else_block,
+        } => branches
+            .iter()
+            .map(|(_, body)| body)
+            .chain(else_block)
+            .any(|body| has_break(&body.stmts)),
+        _ => false,
+    })
+}
+
+/// Span of the `end` closing a function.
+fn end_span(func: &Function) -> Span {
+    let end = func.span.end;
+    let start = Position::new(end.offset - 3, end.line, end.column - 3);
+    Span::new(func.span.file_id, start, end)
+}
+
+/// The first local a statement declares, if any.
+fn declared_local(stmt: &Stmt) -> Option<&str> {
+    match &stmt.kind {
+        StmtKind::Local {
+            scope: Scope::Local,
+            names,
+            ..
+        } => names.first().map(|n| n.name.as_str()),
+        StmtKind::Function {
+            scope: Scope::Local,
+            name,
+            ..
+        } if name.path.len() == 1 && name.method.is_none() => Some(&name.path[0].name),
+        _ => None,
+    }
+}
+
+/// Type of an integer literal: `int` when it fits in 32 bits.
+fn integer_type(n: i64) -> Type {
+    if i32::try_from(n).is_ok() {
+        Type::Int
+    } else {
+        Type::Long
+    }
+}
+
+/// Result type of `+ - * // %` on two operands: the wider of the two numbers.
+fn arith_result(lhs: &Type, rhs: &Type) -> Type {
+    match (lhs, rhs) {
+        (Type::Int, Type::Int) => Type::Int,
+        (Type::Int | Type::Long, Type::Int | Type::Long) => Type::Long,
+        (Type::Float, Type::Float) => Type::Float,
+        (l, r) if l.is_numeric() && r.is_numeric() => Type::Double,
+        _ => Type::Any,
+    }
+}
+
+/// Result type of the bitwise operators, always an integer: `int` when both
+/// operands are, `long` otherwise.
+fn bitwise_result(lhs: &Type, rhs: &Type) -> Type {
+    match (lhs, rhs) {
+        (Type::Int, Type::Int) => Type::Int,
+        (l, r) if l.is_numeric() && r.is_numeric() => Type::Long,
+        _ => Type::Any,
+    }
+}
@@ -0,0 +1,1641 @@
+use std::fmt;
+use std::rc::Rc;
+
+use crate::ast::*;
+use crate::span::Span;
+use crate::value::Value;
+
+pub type Reg = u8;
+
+/// Count operand meaning "every value up to the stack top" for calls,
+/// returns, `...` and table constructors.
+pub const MULTI: u8 = u8::MAX;
+
+/// Registers one function may use, leaving room below `MULTI`.
+const MAX_REGISTERS: usize = 250;
+
+/// Positional constructor items stored by one `SetList`.
+const FIELDS_PER_FLUSH: usize = 50;
+
+/// One VM instruction. `a`, `b` and `c` are registers of the running
+/// function, `k` indexes its constants and jump targets are absolute.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Instr {
+    /// R[a] = R[b]
+    Move {
+        a: Reg,
+        b: Reg,
+    },
+    /// R[a] = K[k]
+    LoadK {
+        a: Reg,
+        k: u32,
+    },
+    LoadBool {
+        a: Reg,
+        value: bool,
+    },
+    /// R[a], ..., R[a+n-1] = nil
+    LoadNil {
+        a: Reg,
+        n: u8,
+    },
+    GetUpval {
+        a: Reg,
+        up: u8,
+    },
+    SetUpval {
+        up: u8,
+        b: Reg,
+    },
+    /// R[a] = globals[K[k]]
+    GetGlobal {
+        a: Reg,
+        k: u32,
+    },
+    SetGlobal {
+        k: u32,
+        b: Reg,
+    },
+    /// R[a] = R[b][R[c]]
+    GetIndex {
+        a: Reg,
+        b: Reg,
+        c: Reg,
+    },
+    /// R[a] = R[b][K[k]]
+    GetField {
+        a: Reg,
+        b: Reg,
+        k: u32,
+    },
+    /// R[a][R[b]] = R[c]
+    SetIndex {
+        a: Reg,
+        b: Reg,
+        c: Reg,
+    },
+    /// R[a][K[k]] = R[c]
+    SetField {
+        a: Reg,
+        k: u32,
+        c: Reg,
+    },
+    NewTable {
+        a: Reg,
+    },
+    /// Declares field K[k] of the table R[a] as a `types[ty]`
+    TypeField {
+        a: Reg,
+        k: u32,
+        ty: u16,
+    },
+    NewVec {
+        a: Reg,
+    },
+    /// R[a][start+i] = R[a+1+i] for the `n` registers above the table, or
+    /// appends them when R[a] is a vec
+    SetList {
+        a: Reg,
+        n: u8,
+        start: u32,
+    },
+    /// R[a+1] = R[b]; R[a] = R[b][K[k]]
+    Method {
+        a: Reg,
+        b: Reg,
+        k: u32,
+    },
+    Unary {
+        op: UnOp,
+        a: Reg,
+        b: Reg,
+    },
+    /// R[a] = R[b] op R[c], any operator but `and`/`or`
+    Binary {
+        op: BinOp,
+        a: Reg,
+        b: Reg,
+        c: Reg,
+    },
+    /// Arithmetic and comparisons on registers the compiler proved to hold
+    /// integers, with no type dispatch at runtime
+    AddInt {
+        a: Reg,
+        b: Reg,
+        c: Reg,
+    },
+    SubInt {
+        a: Reg,
+        b: Reg,
+        c: Reg,
+    },
+    MulInt {
+        a: Reg,
+        b: Reg,
+        c: Reg,
+    },
+    LtInt {
+        a: Reg,
+        b: Reg,
+        c: Reg,
+    },
+    LeInt {
+        a: Reg,
+        b: Reg,
+        c: Reg,
+    },
+    /// Same for registers proved to hold floats
+    AddFloat {
+        a: Reg,
+        b: Reg,
+        c: Reg,
+    },
+    SubFloat {
+        a: Reg,
+        b: Reg,
+        c: Reg,
+    },
+    MulFloat {
+        a: Reg,
+        b: Reg,
+        c: Reg,
+    },
+    DivFloat {
+        a: Reg,
+        b: Reg,
+        c: Reg,
+    },
+    LtFloat {
+        a: Reg,
+        b: Reg,
+        c: Reg,
+    },
+    LeFloat {
+        a: Reg,
+        b: Reg,
+        c: Reg,
+    },
+    Jump {
+        target: u32,
+    },
+    JumpIfFalse {
+        a: Reg,
+        target: u32,
+    },
+    JumpIfTrue {
+        a: Reg,
+        target: u32,
+    },
+    /// Calls R[a] with `nargs` arguments from R[a+1], storing `nresults`
+    /// results from R[a]
+    Call {
+        a: Reg,
+        nargs: u8,
+        nresults: u8,
+    },
+    Return {
+        a: Reg,
+        n: u8,
+    },
+    /// R[a] = closure over `protos[proto]`
+    Closure {
+        a: Reg,
+        proto: u32,
+    },
+    /// R[a], ..., R[a+n-1] = ...
+    Vararg {
+        a: Reg,
+        n: u8,
+    },
+    /// Numeric `for` over R[a] (start), R[a+1] (limit) and R[a+2] (step),
+    /// with the visible variable in R[a+3]. Skips to `exit` if the loop
+    /// does not run at all.
+    ForPrep {
+        a: Reg,
+        exit: u32,
+    },
+    ForLoop {
+        a: Reg,
+        body: u32,
+    },
+    /// R[a+3], ..., R[a+2+n] = R[a](R[a+1], R[a+2])
+    TForCall {
+        a: Reg,
+        n: u8,
+    },
+    /// If R[a+3] is not nil, R[a+2] = R[a+3] and jump to `body`
+    TForLoop {
+        a: Reg,
+        body: u32,
+    },
+    /// Closes upvalues pointing to R[a] and above, then calls `__close` on
+    /// the to-be-closed variables there
+    Close {
+        a: Reg,
+    },
+    /// Marks R[a], the `<close>` variable named `constants[k]`, to be closed
+    /// when its block ends
+    Tbc {
+        a: Reg,
+        k: u32,
+    },
+    /// R[a] = R[a] converted to `types[ty]`
+    Conform {
+        a: Reg,
+        ty: u16,
+    },
+    /// R[a] = (types[ty])R[a]
+    Cast {
+        a: Reg,
+        ty: u16,
+    },
+}
+
+/// Where a closure finds one of its upvalues when it is created.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct UpvalDesc {
+    /// A register of the enclosing function, or else one of its upvalues
+    pub in_parent_local: bool,
+    pub index: u8,
+}
+
+/// A compiled function.
+#[derive(Debug)]
+pub struct Proto {
+    pub code: Vec<Instr>,
+    /// Source location of each instruction, reported by runtime errors
+    pub spans: Vec<Span>,
+    pub constants: Vec<Value>,
+    pub protos: Vec<Rc<Proto>>,
+    pub upvalues: Vec<UpvalDesc>,
+    /// Declared types referenced by `Conform`, `Cast` and `params`
+    pub types: Vec<TypeName>,
+    /// Declared type of each parameter and where the parameter is named
+    pub params: Vec<(Option<u16>, Span)>,
+    pub is_vararg: bool,
+    pub ret: Option<TypeName>,
+    pub max_stack: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CompileErrorKind {
+    TooManyRegisters,
+    TooManyUpvalues,
+    BreakOutsideLoop,
+    UndefinedLabel(String),
+    JumpIntoScope { label: String, local: String },
+}
+
+impl fmt::Display for CompileErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CompileErrorKind::TooManyRegisters => write!(f, "function needs too many registers"),
+            CompileErrorKind::TooManyUpvalues => write!(f, "function has too many upvalues"),
+            CompileErrorKind::BreakOutsideLoop => write!(f, "break outside a loop"),
+            CompileErrorKind::UndefinedLabel(label) => {
+                write!(f, "no visible label '{}' for goto", label)
+            }
+            CompileErrorKind::JumpIntoScope { label, local } => {
+                write!(
+                    f,
+                    "goto {} jumps into the scope of local '{}'",
+                    label, local
+                )
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompileError {
+    pub kind: CompileErrorKind,
+    pub span: Span,
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.span, self.kind)
+    }
+}
+
+impl std::error::Error for CompileError {}
+
+type CompileResult<T> = Result<T, CompileError>;
+
+/// What the compiler knows about the representation of a value.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum NumKind {
+    Int,
+    Float,
+}
+
+fn num_kind(ty: Option<&TypeName>) -> Option<NumKind> {
+    match ty? {
+        TypeName::Int | TypeName::Long => Some(NumKind::Int),
+        TypeName::Float | TypeName::Double => Some(NumKind::Float),
+        _ => None,
+    }
+}
+
+fn is_function(expr: Option<&Expr>) -> bool {
+    matches!(expr, Some(e) if matches!(e.kind, ExprKind::Function(_)))
+}
+
+enum Var {
+    Local(Reg, Option<TypeName>),
+    Upval(u8, Option<TypeName>),
+    Global(u32),
+}
+
+struct Local {
+    name: String,
+    reg: Reg,
+    /// Declared type, `Conform` keeps the register true to it
+    ty: Option<TypeName>,
+    /// Referenced by a closure, so leaving its block closes an upvalue
+    captured: bool,
+}
+
+struct Label {
+    name: String,
+    pc: usize,
+    nactive: usize,
+}
+
+struct BlockScope {
+    /// Active locals when the block was entered
+    nactive: usize,
+    is_loop: bool,
+    breaks: Vec<usize>,
+    labels: Vec<Label>,
+}
+
+/// A forward `goto`, compiled as a `Close` and a `Jump` patched once its
+/// label shows up.
+struct PendingGoto {
+    name: String,
+    close_pc: usize,
+    jump_pc: usize,
+    nactive: usize,
+    depth: usize,
+    span: Span,
+}
+
+struct FuncState {
+    proto: Proto,
+    actives: Vec<Local>,
+    blocks: Vec<BlockScope>,
+    free_reg: usize,
+    /// Name and declared type of each upvalue, parallel to `proto.upvalues`
+    upvalue_names: Vec<(String, Option<TypeName>)>,
+    gotos: Vec<PendingGoto>,
+}
+
+impl FuncState {
+    fn new(is_vararg: bool, ret: Option<TypeName>) -> Self {
+        FuncState {
+            proto: Proto {
+                code: Vec::new(),
+                spans: Vec::new(),
+                constants: Vec::new(),
+                protos: Vec::new(),
+                upvalues: Vec::new(),
+                types: Vec::new(),
+                params: Vec::new(),
+                is_vararg,
+                ret,
+                max_stack: 0,
+            },
+            actives: Vec::new(),
+            blocks: Vec::new(),
+            free_reg: 0,
+            upvalue_names: Vec::new(),
+            gotos: Vec::new(),
+        }
+    }
+
+    /// First register past the first `nactive` locals.
+    fn reg_level(&self, nactive: usize) -> usize {
+        match nactive {
+            0 => 0,
+            n => self.actives[n - 1].reg as usize + 1,
+        }
+    }
+}
+
+/// Lowers a checked AST to bytecode for the VM, one `Proto` per function.
+pub struct Compiler {
+    funcs: Vec<FuncState>,
+}
+
+impl Default for Compiler {
+    fn default() -> Self {
+        Compiler::new()
+    }
+}
+
+impl Compiler {
+    pub fn new() -> Self {
+        Compiler { funcs: Vec::new() }
+    }
+
+    /// Compiles a chunk into the prototype of its main function.
+    pub fn compile(mut self, chunk: &Block) -> CompileResult<Rc<Proto>> {
+        self.funcs.push(FuncState::new(true, None));
+        self.function_body(&chunk.stmts, chunk.span)?;
+        let fs = self.funcs.pop().unwrap();
+        Ok(Rc::new(fs.proto))
+    }
+
+    fn fs(&mut self) -> &mut FuncState {
+        self.funcs.last_mut().unwrap()
+    }
+
+    fn pc(&self) -> usize {
+        self.funcs.last().unwrap().proto.code.len()
+    }
+
+    fn emit(&mut self, instr: Instr, span: Span) -> usize {
+        let proto = &mut self.fs().proto;
+        proto.code.push(instr);
+        proto.spans.push(span);
+        proto.code.len() - 1
+    }
+
+    /// Points the jump at `pc` to `target`.
+    fn patch(&mut self, pc: usize, target: usize) {
+        let target = target as u32;
+        match &mut self.fs().proto.code[pc] {
+            Instr::Jump { target: t }
+            | Instr::JumpIfFalse { target: t, .. }
+            | Instr::JumpIfTrue { target: t, .. }
+            | Instr::ForPrep { exit: t, .. } => *t = target,
+            instr => unreachable!("not a jump: {:?}", instr),
+        }
+    }
+
+    fn patch_here(&mut self, pc: usize) {
+        let here = self.pc();
+        self.patch(pc, here);
+    }
+
+    fn constant(&mut self, value: Value) -> u32 {
+        let constants = &mut self.fs().proto.constants;
+        let same = |k: &Value| {
+            std::mem::discriminant(k) == std::mem::discriminant(&value) && k.raw_equals(&value)
+        };
+        match constants.iter().position(same) {
+            Some(i) => i as u32,
+            None => {
+                constants.push(value);
+                constants.len() as u32 - 1
+            }
+        }
+    }
+
+    fn string_constant(&mut self, s: impl AsRef<[u8]>) -> u32 {
+        self.constant(Value::bytes(s.as_ref()))
+    }
+
+    fn type_index(&mut self, ty: &TypeName) -> u16 {
+        let types = &mut self.fs().proto.types;
+        match types.iter().position(|t| t == ty) {
+            Some(i) => i as u16,
+            None => {
+                types.push(ty.clone());
+                types.len() as u16 - 1
+            }
+        }
+    }
+
+    fn conform(&mut self, reg: Reg, ty: &TypeName, span: Span) {
+        let ty = self.type_index(ty);
+        self.emit(Instr::Conform { a: reg, ty }, span);
+    }
+
+    /// Reserves the next `n` registers, returning the first.
+    fn reserve(&mut self, n: usize, span: Span) -> CompileResult<Reg> {
+        let fs = self.fs();
+        let first = fs.free_reg;
+        fs.free_reg += n;
+        if fs.free_reg > MAX_REGISTERS {
+            return Err(CompileError {
+                kind: CompileErrorKind::TooManyRegisters,
+                span,
+            });
+        }
+        fs.proto.max_stack = fs.proto.max_stack.max(fs.free_reg);
+        Ok(first as Reg)
+    }
+
+    fn alloc(&mut self, span: Span) -> CompileResult<Reg> {
+        self.reserve(1, span)
+    }
+
+    fn free_to(&mut self, reg: usize) {
+        self.fs().free_reg = reg;
+    }
+
+    fn free_reg(&self) -> usize {
+        self.funcs.last().unwrap().free_reg
+    }
+
+    /// Makes a local visible from here on, stored in `reg`.
+    fn activate(&mut self, name: &str, reg: Reg, ty: Option<TypeName>) {
+        self.fs().actives.push(Local {
+            name: name.to_string(),
+            reg,
+            ty,
+            captured: false,
+        });
+    }
+
+    fn resolve(&mut self, name: &str, span: Span) -> CompileResult<Var> {
+        let level = self.funcs.len() - 1;
+        match self.resolve_at(level, name, span)? {
+            Some(var) => Ok(var),
+            None => Ok(Var::Global(self.string_constant(name))),
+        }
+    }
+
+    /// Looks `name` up in the function at `level`, turning locals of
+    /// enclosing functions into upvalues along the way.
+    fn resolve_at(&mut self, level: usize, name: &str, span: Span) -> CompileResult<Option<Var>> {
+        let fs = &self.funcs[level];
+        if let Some(local) = fs.actives.iter().rev().find(|l| l.name == name) {
+            return Ok(Some(Var::Local(local.reg, local.ty.clone())));
+        }
+        if let Some(i) = fs.upvalue_names.iter().position(|(n, _)| n == name) {
+            return Ok(Some(Var::Upval(i as u8, fs.upvalue_names[i].1.clone())));
+        }
+        if level == 0 {
+            return Ok(None);
+        }
+        let (desc, ty) = match self.resolve_at(level - 1, name, span)? {
+            None => return Ok(None),
+            Some(Var::Local(reg, ty)) => {
+                let parent = &mut self.funcs[level - 1];
+                let local = parent
+                    .actives
+                    .iter_mut()
+                    .rev()
+                    .find(|l| l.name == name)
+                    .unwrap();
+                local.captured = true;
+                (
+                    UpvalDesc {
+                        in_parent_local: true,
+                        index: reg,
+                    },
+                    ty,
+                )
+            }
+            Some(Var::Upval(index, ty)) => (
+                UpvalDesc {
+                    in_parent_local: false,
+                    index,
+                },
+                ty,
+            ),
+            Some(Var::Global(_)) => unreachable!("globals are not resolved per function"),
+        };
+        let fs = &mut self.funcs[level];
+        if fs.upvalue_names.len() > u8::MAX as usize {
+            return Err(CompileError {
+                kind: CompileErrorKind::TooManyUpvalues,
+                span,
+            });
+        }
+        fs.proto.upvalues.push(desc);
+        fs.upvalue_names.push((name.to_string(), ty.clone()));
+        Ok(Some(Var::Upval(fs.upvalue_names.len() as u8 - 1, ty)))
+    }
+
+    /// Declared type of a visible variable, without capturing it.
+    fn var_type(&self, name: &str) -> Option<&TypeName> {
+        self.funcs
+            .iter()
+            .rev()
+            .find_map(|fs| fs.actives.iter().rev().find(|l| l.name == name))
+            .and_then(|l| l.ty.as_ref())
+    }
+
+    /// Whether the value of `expr` is statically known to be stored the way
+    /// `ty` requires, so converting it can be skipped. `int` and `float` are
+    /// narrower than the values arithmetic produces, so only values that
+    /// were already narrowed qualify.
+    fn is_conformed(&self, expr: &Expr, ty: &TypeName) -> bool {
+        if self.kind_of(expr).is_none() || self.kind_of(expr) != num_kind(Some(ty)) {
+            return false;
+        }
+        match (&expr.kind, ty) {
+            (_, TypeName::Long | TypeName::Double) => true,
+            (ExprKind::Integer(i), TypeName::Int) => i32::try_from(*i).is_ok(),
+            (ExprKind::Name(name), _) => self.var_type(name) == Some(ty),
+            (ExprKind::Cast { ty: cast, .. }, _) => cast == ty,
+            (ExprKind::Paren(inner), _) => self.is_conformed(inner, ty),
+            _ => false,
+        }
+    }
+
+    /// Representation an expression is statically known to produce.
+    fn kind_of(&self, expr: &Expr) -> Option<NumKind> {
+        match &expr.kind {
+            ExprKind::Integer(_) => Some(NumKind::Int),
+            ExprKind::Float(_) => Some(NumKind::Float),
+            ExprKind::Name(name) => num_kind(self.var_type(name)),
+            ExprKind::Paren(inner) => self.kind_of(inner),
+            ExprKind::Cast { ty, .. } => num_kind(Some(ty)),
+            ExprKind::Unary {
+                op: UnOp::Neg,
+                expr,
+            } => self.kind_of(expr),
+            // Bitwise operators on numbers either produce an integer or raise
+            // an error, other operands may have metamethods
+            ExprKind::Unary {
+                op: UnOp::BitNot,
+                expr,
+            } => self.kind_of(expr).map(|_| NumKind::Int),
+            ExprKind::Binary {
+                op: BinOp::BitAnd | BinOp::BitOr | BinOp::BitXor | BinOp::Shl | BinOp::Shr,
+                lhs,
+                rhs,
+            } => self
+                .kind_of(lhs)
+                .and(self.kind_of(rhs))
+                .map(|_| NumKind::Int),
+            ExprKind::Binary { op, lhs, rhs } => {
+                let kinds = (self.kind_of(lhs)?, self.kind_of(rhs)?);
+                match (op, kinds) {
+                    (
+                        BinOp::Add | BinOp::Sub | BinOp::Mul | BinOp::FloorDiv,
+                        (NumKind::Int, NumKind::Int),
+                    ) => Some(NumKind::Int),
+                    (
+                        BinOp::Add | BinOp::Sub | BinOp::Mul | BinOp::Div | BinOp::FloorDiv,
+                        (NumKind::Float, NumKind::Float),
+                    ) => Some(NumKind::Float),
+                    _ => None,
+                }
+            }
+            _ => None,
+        }
+    }
+
+    fn enter_block(&mut self, is_loop: bool) {
+        let fs = self.fs();
+        let nactive = fs.actives.len();
+        fs.blocks.push(BlockScope {
+            nactive,
+            is_loop,
+            breaks: Vec::new(),
+            labels: Vec::new(),
+        });
+    }
+
+    /// Ends the innermost block, returning its pending `break` jumps. With
+    /// `close`, upvalues of the block's locals are closed, which in a loop
+    /// body gives every iteration fresh variables.
+    fn leave_block(&mut self, close: bool, span: Span) -> Vec<usize> {
+        let fs = self.fs();
+        let block = fs.blocks.pop().unwrap();
+        let level = fs.reg_level(block.nactive);
+        let captured = fs.actives[block.nactive..].iter().any(|l| l.captured);
+        fs.actives.truncate(block.nactive);
+        fs.free_reg = level;
+        let depth = fs.blocks.len() + 1;
+        for goto in fs.gotos.iter_mut().filter(|g| g.depth == depth) {
+            goto.depth -= 1;
+            goto.nactive = goto.nactive.min(block.nactive);
+        }
+        if close && captured {
+            self.emit(Instr::Close { a: level as Reg }, span);
+        }
+        block.breaks
+    }
+
+    fn block(&mut self, block: &Block) -> CompileResult<()> {
+        self.enter_block(false);
+        self.stmts(&block.stmts)?;
+        self.leave_block(true, block.span);
+        Ok(())
+    }
+
+    fn stmts(&mut self, stmts: &[Stmt]) -> CompileResult<()> {
+        for (i, stmt) in stmts.iter().enumerate() {
+            if let StmtKind::Label(name) = &stmt.kind {
+                // A label ending its block is outside the scope of the
+                // block's locals, so `goto continue` may jump over them
+                let at_end = stmts[i + 1..]
+                    .iter()
+                    .all(|s| matches!(s.kind, StmtKind::Label(_)));
+                self.label(name, at_end)?;
+            } else {
+                self.stmt(stmt)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn function_body(&mut self, stmts: &[Stmt], span: Span) -> CompileResult<()> {
+        self.enter_block(false);
+        self.stmts(stmts)?;
+        self.leave_block(false, span);
+        self.emit(Instr::Return { a: 0, n: 0 }, span);
+        if let Some(goto) = self.fs().gotos.first() {
+            return Err(CompileError {
+                kind: CompileErrorKind::UndefinedLabel(goto.name.clone()),
+                span: goto.span,
+            });
+        }
+        Ok(())
+    }
+
+    /// Compiles a nested function, returning its index in `protos`.
+    fn function(
+        &mut self,
+        func: &Function,
+        ret: Option<TypeName>,
+        is_method: bool,
+    ) -> CompileResult<u32> {
+        self.funcs.push(FuncState::new(func.is_vararg, ret));
+        if is_method {
+            let reg = self.alloc(func.span)?;
+            self.activate("self", reg, None);
+            self.fs().proto.params.push((None, func.span));
+        }
+        for param in &func.params {
+            let reg = self.alloc(param.name.span)?;
+            self.activate(&param.name.name, reg, param.ty.clone());
+            let ty = param.ty.as_ref().map(|ty| self.type_index(ty));
+            self.fs().proto.params.push((ty, param.name.span));
+        }
+        self.function_body(&func.body.stmts, func.body.span)?;
+        let fs = self.funcs.pop().unwrap();
+        let protos = &mut self.fs().proto.protos;
+        protos.push(Rc::new(fs.proto));
+        Ok(protos.len() as u32 - 1)
+    }
+
+    fn closure(
+        &mut self,
+        func: &Function,
+        ret: Option<TypeName>,
+        is_method: bool,
+        dst: Reg,
+        span: Span,
+    ) -> CompileResult<()> {
+        let proto = self.function(func, ret, is_method)?;
+        self.emit(Instr::Closure { a: dst, proto }, span);
+        Ok(())
+    }
+
+    fn label(&mut self, name: &Name, at_end: bool) -> CompileResult<()> {
+        let pc = self.pc();
+        let fs = self.fs();
+        let nactive = if at_end {
+            fs.blocks.last().unwrap().nactive
+        } else {
+            fs.actives.len()
+        };
+        fs.blocks.last_mut().unwrap().labels.push(Label {
+            name: name.name.clone(),
+            pc,
+            nactive,
+        });
+        let depth = fs.blocks.len();
+        let (resolved, pending): (Vec<PendingGoto>, Vec<PendingGoto>) =
+            std::mem::take(&mut fs.gotos)
+                .into_iter()
+                .partition(|g| g.depth == depth && g.name == name.name);
+        fs.gotos = pending;
+        for goto in resolved {
+            if goto.nactive < nactive {
+                let local = self.fs().actives[goto.nactive].name.clone();
+                return Err(CompileError {
+                    kind: CompileErrorKind::JumpIntoScope {
+                        label: name.name.clone(),
+                        local,
+                    },
+                    span: goto.span,
+                });
+            }
+            let level = self.fs().reg_level(nactive) as Reg;
+            self.fs().proto.code[goto.close_pc] = Instr::Close { a: level };
+            self.patch(goto.jump_pc, pc);
+        }
+        Ok(())
+    }
+
+    fn goto(&mut self, name: &Name) -> CompileResult<()> {
+        let fs = self.fs();
+        let label = fs
+            .blocks
+            .iter()
+            .rev()
+            .flat_map(|b| b.labels.iter())
+            .find(|l| l.name == name.name)
+            .map(|l| (l.pc, l.nactive));
+        match label {
+            Some((pc, nactive)) => {
+                let level = fs.reg_level(nactive) as Reg;
+                self.emit(Instr::Close { a: level }, name.span);
+                self.emit(Instr::Jump { target: pc as u32 }, name.span);
+            }
+            None => {
+                let close_pc = self.emit(Instr::Close { a: 0 }, name.span);
+                let jump_pc = self.emit(Instr::Jump { target: 0 }, name.span);
+                let fs = self.fs();
+                let goto = PendingGoto {
+                    name: name.name.clone(),
+                    close_pc,
+                    jump_pc,
+                    nactive: fs.actives.len(),
+                    depth: fs.blocks.len(),
+                    span: name.span,
+                };
+                fs.gotos.push(goto);
+            }
+        }
+        Ok(())
+    }
+
+    fn stmt(&mut self, stmt: &Stmt) -> CompileResult<()> {
+        match &stmt.kind {
+            StmtKind::Local {
+                scope,
+                ty,
+                names,
+                attribs,
+                values,
+            } => self.declaration(*scope, ty.as_ref(), names, attribs, values)?,
+            StmtKind::Function { scope, name, func } => {
+                self.function_decl(*scope, name, func, stmt.span)?
+            }
+            StmtKind::Assign { targets, values } => self.assign(targets, values)?,
+            StmtKind::Call(expr) => {
+                let base = self.alloc(expr.span)?;
+                self.call(expr, base, 0)?;
+                self.free_to(base as usize);
+            }
+            StmtKind::Expr(expr) => {
+                // A fastcall function written as a statement runs right away
+                if let ExprKind::Function(func) = &expr.kind {
+                    if func.fastcall {
+                        let base = self.alloc(expr.span)?;
+                        self.closure(func, func.ret.clone(), false, base, expr.span)?;
+                        let call = Instr::Call {
+                            a: base,
+                            nargs: 0,
+                            nresults: 0,
+                        };
+                        self.emit(call, expr.span);
+                        self.free_to(base as usize);
+                    }
+                }
+            }
+            StmtKind::Do(body) => self.block(body)?,
+            StmtKind::While { cond, body } => {
+                let start = self.pc();
+                let exit = self.cond_jump(cond)?;
+                self.enter_block(true);
+                self.stmts(&body.stmts)?;
+                let breaks = self.leave_block(true, body.span);
+                self.emit(
+                    Instr::Jump {
+                        target: start as u32,
+                    },
+                    stmt.span,
+                );
+                self.patch_here(exit);
+                for pc in breaks {
+                    self.patch_here(pc);
+                }
+            }
+            StmtKind::Repeat { body, cond } => {
+                let start = self.pc();
+                self.enter_block(true);
+                self.stmts(&body.stmts)?;
+                // The condition sees the body's locals, so they are closed
+                // only once it has been evaluated
+                let saved = self.free_reg();
+                let reg = self.expr_any(cond)?;
+                let fs = self.fs();
+                let block = fs.blocks.last().unwrap();
+                if fs.actives[block.nactive..].iter().any(|l| l.captured) {
+                    let level = fs.reg_level(block.nactive) as Reg;
+                    self.emit(Instr::Close { a: level }, cond.span);
+                }
+                self.emit(
+                    Instr::JumpIfFalse {
+                        a: reg,
+                        target: start as u32,
+                    },
+                    cond.span,
+                );
+                self.free_to(saved);
+                for pc in self.leave_block(false, body.span) {
+                    self.patch_here(pc);
+                }
+            }
+            StmtKind::If {
+                branches,
+                else_block,
+            } => {
+                let mut ends = Vec::new();
+                for (i, (cond, body)) in branches.iter().enumerate() {
+                    let next = self.cond_jump(cond)?;
+                    self.block(body)?;
+                    if i + 1 < branches.len() || else_block.is_some() {
+                        ends.push(self.emit(Instr::Jump { target: 0 }, body.span));
+                    }
+                    self.patch_here(next);
+                }
+                if let Some(body) = else_block {
+                    self.block(body)?;
+                }
+                for pc in ends {
+                    self.patch_here(pc);
+                }
+            }
+            StmtKind::NumericFor {
+                var,
+                start,
+                limit,
+                step,
+                body,
+            } => self.numeric_for(var, start, limit, step.as_ref(), body)?,
+            StmtKind::GenericFor { names, exprs, body } => {
+                self.enter_block(false);
+                let base = self.free_reg() as Reg;
+                self.expr_list(exprs, Some(3), None)?;
+                for (i, hidden) in ["(for generator)", "(for state)", "(for control)"]
+                    .into_iter()
+                    .enumerate()
+                {
+                    self.activate(hidden, base + i as Reg, None);
+                }
+                let enter = self.emit(Instr::Jump { target: 0 }, stmt.span);
+                let body_pc = self.pc() as u32;
+                self.enter_block(true);
+                for name in names {
+                    let reg = self.alloc(name.span)?;
+                    self.activate(&name.name, reg, None);
+                }
+                self.stmts(&body.stmts)?;
+                let breaks = self.leave_block(true, body.span);
+                self.patch_here(enter);
+                let n = names.len() as u8;
+                self.emit(Instr::TForCall { a: base, n }, stmt.span);
+                self.emit(
+                    Instr::TForLoop {
+                        a: base,
+                        body: body_pc,
+                    },
+                    stmt.span,
+                );
+                for pc in breaks {
+                    self.patch_here(pc);
+                }
+                self.leave_block(false, stmt.span);
+            }
+            StmtKind::Return(values) => {
+                let saved = self.free_reg();
+                let (base, n) = self.expr_list(values, None, None)?;
+                self.emit(Instr::Return { a: base, n }, stmt.span);
+                self.free_to(saved);
+            }
+            StmtKind::Break => {
+                let fs = self.fs();
+                let Some(i) = fs.blocks.iter().rposition(|b| b.is_loop) else {
+                    return Err(CompileError {
+                        kind: CompileErrorKind::BreakOutsideLoop,
+                        span: stmt.span,
+                    });
+                };
+                let level = fs.reg_level(fs.blocks[i].nactive) as Reg;
+                self.emit(Instr::Close { a: level }, stmt.span);
+                let pc = self.emit(Instr::Jump { target: 0 }, stmt.span);
+                self.fs().blocks[i].breaks.push(pc);
+            }
+            StmtKind::Goto(name) => self.goto(name)?,
+            StmtKind::Label(name) => self.label(name, false)?,
+        }
+        Ok(())
+    }
+
+    fn declaration(
+        &mut self,
+        scope: Scope,
+        ty: Option<&TypeName>,
+        names: &[Name],
+        attribs: &[Option<Attrib>],
+        values: &[Expr],
+    ) -> CompileResult<()> {
+        let base = self.free_reg() as Reg;
+        let ends_multi = values.last().is_some_and(Expr::is_multi);
+        self.expr_list(values, Some(names.len()), ty)?;
+        let mut locals = Vec::with_capacity(names.len());
+        for (i, name) in names.iter().enumerate() {
+            let reg = base + i as Reg;
+            let value = values.get(i);
+            // The declared type of a function literal is its return type
+            let ty = ty.filter(|_| !is_function(value));
+            if let Some(ty) = ty {
+                match value {
+                    None if !ends_multi => self.default_value(ty, reg, name.span),
+                    Some(value) if self.is_conformed(value, ty) => {}
+                    _ => self.conform(reg, ty, value.map_or(name.span, |v| v.span)),
+                }
+            }
+            match scope {
+                Scope::Local => locals.push((name, reg, ty.cloned())),
+                Scope::Global => {
+                    let k = self.string_constant(&name.name);
+                    self.emit(Instr::SetGlobal { k, b: reg }, name.span);
+                }
+            }
+        }
+        match scope {
+            Scope::Local => {
+                for ((name, reg, ty), attrib) in locals.into_iter().zip(attribs) {
+                    self.activate(&name.name, reg, ty);
+                    if let Some(Attrib::Close) = attrib {
+                        // Treated as captured so every way out of the block
+                        // emits the `Close` that calls `__close`
+                        self.fs().actives.last_mut().unwrap().captured = true;
+                        let k = self.string_constant(&name.name);
+                        self.emit(Instr::Tbc { a: reg, k }, name.span);
+                    }
+                }
+            }
+            Scope::Global => self.free_to(base as usize),
+        }
+        Ok(())
+    }
+
+    /// Zero for numbers, matching `value::default_value`.
+    fn default_value(&mut self, ty: &TypeName, reg: Reg, span: Span) {
+        let zero = match num_kind(Some(ty)) {
+            Some(NumKind::Int) => Value::Int(0),
+            Some(NumKind::Float) => Value::Float(0.0),
+            None => return,
+        };
+        let k = self.constant(zero);
+        self.emit(Instr::LoadK { a: reg, k }, span);
+    }
+
+    fn function_decl(
+        &mut self,
+        scope: Scope,
+        name: &FunctionName,
+        func: &Rc<Function>,
+        span: Span,
+    ) -> CompileResult<()> {
+        let first = &name.path[0];
+        let saved = self.free_reg();
+        if name.path.len() == 1 && name.method.is_none() {
+            let reg = self.alloc(first.span)?;
+            match scope {
+                Scope::Local => {
+                    // Declared before the closure is made so it can recurse
+                    self.activate(&first.name, reg, None);
+                    self.closure(func, func.ret.clone(), false, reg, span)?;
+                }
+                Scope::Global => {
+                    self.closure(func, func.ret.clone(), false, reg, span)?;
+                    let k = self.string_constant(&first.name);
+                    self.emit(Instr::SetGlobal { k, b: reg }, span);
+                    self.free_to(saved);
+                }
+            }
+            return Ok(());
+        }
+        let mut object = self.name_to_reg(&first.name, first.span)?;
+        let (last, path) = match &name.method {
+            Some(method) => (method, &name.path[1..]),
+            None => name.path[1..].split_last().unwrap(),
+        };
+        for field in path {
+            let reg = self.alloc(field.span)?;
+            let k = self.string_constant(&field.name);
+            self.emit(
+                Instr::GetField {
+                    a: reg,
+                    b: object,
+                    k,
+                },
+                field.span,
+            );
+            object = reg;
+        }
+        let reg = self.alloc(span)?;
+        self.closure(func, func.ret.clone(), name.method.is_some(), reg, span)?;
+        let k = self.string_constant(&last.name);
+        self.emit(
+            Instr::SetField {
+                a: object,
+                k,
+                c: reg,
+            },
+            last.span,
+        );
+        self.free_to(saved);
+        Ok(())
+    }
+
+    fn assign(&mut self, targets: &[Expr], values: &[Expr]) -> CompileResult<()> {
+        enum Place {
+            Var(Var),
+            Field(Reg, u32),
+            Index(Reg, Reg),
+        }
+        let saved = self.free_reg();
+        let mut places = Vec::with_capacity(targets.len());
+        for target in targets {
+            places.push(match &target.kind {
+                ExprKind::Name(name) => Place::Var(self.resolve(name, target.span)?),
+                ExprKind::Index { object, key } => {
+                    let object = self.expr_any(object)?;
+                    match &key.kind {
+                        ExprKind::String(s) => Place::Field(object, self.string_constant(s)),
+                        _ => Place::Index(object, self.expr_any(key)?),
+                    }
+                }
+                _ => unreachable!("parser only produces names and indexes as targets"),
+            });
+        }
+        let (base, _) = self.expr_list(values, Some(targets.len()), None)?;
+        for (i, (place, target)) in places.into_iter().zip(targets).enumerate() {
+            let value = base + i as Reg;
+            let span = target.span;
+            let conform = |this: &mut Self, ty: &Option<TypeName>| {
+                if let Some(ty) = ty {
+                    if !values.get(i).is_some_and(|v| this.is_conformed(v, ty)) {
+                        this.conform(value, ty, span);
+                    }
+                }
+            };
+            let instr = match place {
+                Place::Var(Var::Local(reg, ty)) => {
+                    conform(self, &ty);
+                    Instr::Move { a: reg, b: value }
+                }
+                Place::Var(Var::Upval(up, ty)) => {
+                    conform(self, &ty);
+                    Instr::SetUpval { up, b: value }
+                }
+                Place::Var(Var::Global(k)) => Instr::SetGlobal { k, b: value },
+                Place::Field(object, k) => Instr::SetField {
+                    a: object,
+                    k,
+                    c: value,
+                },
+                Place::Index(object, key) => Instr::SetIndex {
+                    a: object,
+                    b: key,
+                    c: value,
+                },
+            };
+            self.emit(instr, span);
+        }
+        self.free_to(saved);
+        Ok(())
+    }
+
+    fn numeric_for(
+        &mut self,
+        var: &Name,
+        start: &Expr,
+        limit: &Expr,
+        step: Option<&Expr>,
+        body: &Block,
+    ) -> CompileResult<()> {
+        self.enter_block(false);
+        let base = self.free_reg() as Reg;
+        let reg = self.alloc(start.span)?;
+        self.expr(start, reg)?;
+        let reg = self.alloc(limit.span)?;
+        self.expr(limit, reg)?;
+        let reg = self.alloc(var.span)?;
+        match step {
+            Some(step) => self.expr(step, reg)?,
+            None => {
+                let k = self.constant(Value::Int(1));
+                self.emit(Instr::LoadK { a: reg, k }, var.span);
+            }
+        }
+        for (i, hidden) in ["(for start)", "(for limit)", "(for step)"]
+            .into_iter()
+            .enumerate()
+        {
+            self.activate(hidden, base + i as Reg, None);
+        }
+        let prep = self.emit(Instr::ForPrep { a: base, exit: 0 }, var.span);
+        self.enter_block(true);
+        let reg = self.alloc(var.span)?;
+        self.activate(&var.name, reg, None);
+        self.stmts(&body.stmts)?;
+        let breaks = self.leave_block(true, body.span);
+        self.emit(
+            Instr::ForLoop {
+                a: base,
+                body: prep as u32 + 1,
+            },
+            var.span,
+        );
+        self.patch_here(prep);
+        for pc in breaks {
+            self.patch_here(pc);
+        }
+        self.leave_block(false, body.span);
+        Ok(())
+    }
+
+    /// Evaluates a condition and emits a jump taken when it is false,
+    /// returning the jump to patch.
+    fn cond_jump(&mut self, cond: &Expr) -> CompileResult<usize> {
+        let saved = self.free_reg();
+        let a = self.expr_any(cond)?;
+        let pc = self.emit(Instr::JumpIfFalse { a, target: 0 }, cond.span);
+        self.free_to(saved);
+        Ok(pc)
+    }
+
+    /// Evaluates `exprs` into consecutive new registers. With `want`, exactly
+    /// that many registers are filled; otherwise a final call or `...`
+    /// leaves all its values up to the stack top and the count is `MULTI`.
+    /// `ty` is the type a declaration gives its function literals.
+    fn expr_list(
+        &mut self,
+        exprs: &[Expr],
+        want: Option<usize>,
+        ty: Option<&TypeName>,
+    ) -> CompileResult<(Reg, u8)> {
+        let base = self.free_reg();
+        for (i, expr) in exprs.iter().enumerate() {
+            if i + 1 == exprs.len() && expr.is_multi() {
+                let n = match want {
+                    Some(want) => want.saturating_sub(i) as u8,
+                    None => MULTI,
+                };
+                let reg = self.alloc(expr.span)?;
+                self.multi(expr, reg, n)?;
+                match want {
+                    Some(want) => {
+                        self.free_to(base);
+                        self.reserve(want, expr.span)?;
+                        return Ok((base as Reg, want as u8));
+                    }
+                    None => {
+                        self.free_to(reg as usize);
+                        return Ok((base as Reg, MULTI));
+                    }
+                }
+            }
+            let reg = self.alloc(expr.span)?;
+            match (&expr.kind, ty) {
+                (ExprKind::Function(func), Some(_)) if func.ret.is_none() => {
+                    self.closure(func, ty.cloned(), false, reg, expr.span)?
+                }
+                _ => self.expr(expr, reg)?,
+            }
+        }
+        let count = exprs.len();
+        match want {
+            Some(want) => {
+                if count < want {
+                    let reg = self.reserve(
+                        want - count,
+                        exprs.last().map_or(Span::default(), |e| e.span),
+                    )?;
+                    let n = (want - count) as u8;
+                    self.emit(Instr::LoadNil { a: reg, n }, Span::default());
+                }
+                self.free_to(base + want);
+                Ok((base as Reg, want as u8))
+            }
+            None => Ok((base as Reg, count as u8)),
+        }
+    }
+
+    /// Compiles a call or `...` producing `n` values from `dst`, which must
+    /// be the topmost allocated register.
+    fn multi(&mut self, expr: &Expr, dst: Reg, n: u8) -> CompileResult<()> {
+        match &expr.kind {
+            ExprKind::Vararg => {
+                self.emit(Instr::Vararg { a: dst, n }, expr.span);
+                Ok(())
+            }
+            _ => self.call(expr, dst, n),
+        }
+    }
+
+    /// Compiles a call with the function in `base`, the topmost allocated
+    /// register.
+    fn call(&mut self, expr: &Expr, base: Reg, nresults: u8) -> CompileResult<()> {
+        let args = match &expr.kind {
+            ExprKind::Call { func, args } => {
+                self.expr(func, base)?;
+                args
+            }
+            ExprKind::MethodCall {
+                object,
+                method,
+                args,
+            } => {
+                let object = self.expr_any(object)?;
+                self.free_to(base as usize + 1);
+                self.alloc(expr.span)?;
+                let k = self.string_constant(&method.name);
+                self.emit(
+                    Instr::Method {
+                        a: base,
+                        b: object,
+                        k,
+                    },
+                    expr.span,
+                );
+                args
+            }
+            _ => unreachable!("not a call: {:?}", expr.kind),
+        };
+        let (_, mut nargs) = self.expr_list(args, None, None)?;
+        if nargs != MULTI && matches!(expr.kind, ExprKind::MethodCall { .. }) {
+            nargs += 1;
+        }
+        self.emit(
+            Instr::Call {
+                a: base,
+                nargs,
+                nresults,
+            },
+            expr.span,
+        );
+        self.free_to(base as usize + 1);
+        Ok(())
+    }
+
+    /// Register holding the value of `expr`: the local itself for a local
+    /// variable, otherwise a new temporary.
+    fn expr_any(&mut self, expr: &Expr) -> CompileResult<Reg> {
+        if let ExprKind::Name(name) = &expr.kind {
+            if let Var::Local(reg, _) = self.resolve(name, expr.span)? {
+                return Ok(reg);
+            }
+        }
+        let reg = self.alloc(expr.span)?;
+        self.expr(expr, reg)?;
+        Ok(reg)
+    }
+
+    fn name_to_reg(&mut self, name: &str, span: Span) -> CompileResult<Reg> {
+        let expr = Expr {
+            kind: ExprKind::Name(name.to_string()),
+            span,
+        };
+        self.expr_any(&expr)
+    }
+
+    /// Compiles `expr` into `dst`, freeing any temporaries it needed.
+    fn expr(&mut self, expr: &Expr, dst: Reg) -> CompileResult<()> {
+        let span = expr.span;
+        let saved = self.free_reg();
+        match &expr.kind {
+            ExprKind::Nil => {
+                self.emit(Instr::LoadNil { a: dst, n: 1 }, span);
+            }
+            ExprKind::True | ExprKind::False => {
+                let value = matches!(expr.kind, ExprKind::True);
+                self.emit(Instr::LoadBool { a: dst, value }, span);
+            }
+            ExprKind::Integer(n) => {
+                let k = self.constant(Value::Int(*n));
+                self.emit(Instr::LoadK { a: dst, k }, span);
+            }
+            ExprKind::Float(n) => {
+                let k = self.constant(Value::Float(*n));
+                self.emit(Instr::LoadK { a: dst, k }, span);
+            }
+            ExprKind::String(s) => {
+                let k = self.string_constant(s);
+                self.emit(Instr::LoadK { a: dst, k }, span);
+            }
+            ExprKind::Char(c) => {
+                let k = self.string_constant(c.encode_utf8(&mut [0; 4]));
+                self.emit(Instr::LoadK { a: dst, k }, span);
+            }
+            ExprKind::Vararg | ExprKind::Call { .. } | ExprKind::MethodCall { .. } => {
+                // Calls need the function at the top of the registers
+                let base = if dst as usize + 1 == saved {
+                    dst
+                } else {
+                    self.alloc(span)?
+                };
+                self.multi(expr, base, 1)?;
+                if base != dst {
+                    self.emit(Instr::Move { a: dst, b: base }, span);
+                }
+            }
+            ExprKind::Function(func) => self.closure(func, func.ret.clone(), false, dst, span)?,
+            ExprKind::Table(fields) => self.table(fields, false, dst, span)?,
+            ExprKind::Vec(items) => {
+                let fields: Vec<TableField> =
+                    items.iter().cloned().map(TableField::Positional).collect();
+                self.table(&fields, true, dst, span)?
+            }
+            ExprKind::Name(name) => {
+                let instr = match self.resolve(name, span)? {
+                    Var::Local(reg, _) if reg == dst => None,
+                    Var::Local(reg, _) => Some(Instr::Move { a: dst, b: reg }),
+                    Var::Upval(up, _) => Some(Instr::GetUpval { a: dst, up }),
+                    Var::Global(k) => Some(Instr::GetGlobal { a: dst, k }),
+                };
+                if let Some(instr) = instr {
+                    self.emit(instr, span);
+                }
+            }
+            ExprKind::Index { object, key } => {
+                let object = self.expr_any(object)?;
+                let instr = match &key.kind {
+                    ExprKind::String(s) => Instr::GetField {
+                        a: dst,
+                        b: object,
+                        k: self.string_constant(s),
+                    },
+                    _ => Instr::GetIndex {
+                        a: dst,
+                        b: object,
+                        c: self.expr_any(key)?,
+                    },
+                };
+                self.emit(instr, span);
+            }
+            ExprKind::Paren(inner) => self.expr(inner, dst)?,
+            ExprKind::Cast { ty, expr: inner } => {
+                self.expr(inner, dst)?;
+                let ty = self.type_index(ty);
+                self.emit(Instr::Cast { a: dst, ty }, span);
+            }
+            ExprKind::Unary { op, expr: inner } => {
+                let b = self.expr_any(inner)?;
+                self.emit(Instr::Unary { op: *op, a: dst, b }, span);
+            }
+            ExprKind::Binary {
+                op: op @ (BinOp::And | BinOp::Or),
+                lhs,
+                rhs,
+            } => {
+                self.expr(lhs, dst)?;
+                let instr = match op {
+                    BinOp::And => Instr::JumpIfFalse { a: dst, target: 0 },
+                    _ => Instr::JumpIfTrue { a: dst, target: 0 },
+                };
+                let skip = self.emit(instr, span);
+                self.expr(rhs, dst)?;
+                self.patch_here(skip);
+            }
+            ExprKind::Binary { op, lhs, rhs } => {
+                let kinds = (self.kind_of(lhs), self.kind_of(rhs));
+                let b = self.expr_any(lhs)?;
+                let c = self.expr_any(rhs)?;
+                self.emit(binary_instr(*op, kinds, dst, b, c), span);
+            }
+        }
+        self.free_to(saved);
+        Ok(())
+    }
+
+    /// Compiles a table constructor, or a vec literal when `vec` is set.
+    fn table(
+        &mut self,
+        fields: &[TableField],
+        vec: bool,
+        dst: Reg,
+        span: Span,
+    ) -> CompileResult<()> {
+        // Positional items are gathered in the registers above the table
+        let table = if dst as usize + 1 == self.free_reg() {
+            dst
+        } else {
+            self.alloc(span)?
+        };
+        let new = if vec {
+            Instr::NewVec { a: table }
+        } else {
+            Instr::NewTable { a: table }
+        };
+        self.emit(new, span);
+        let mut pending = 0;
+        let mut next_index = 1;
+        for (i, field) in fields.iter().enumerate() {
+            match field {
+                TableField::Positional(value) if i + 1 == fields.len() && value.is_multi() => {
+                    let reg = self.alloc(value.span)?;
+                    self.multi(value, reg, MULTI)?;
+                    let set = Instr::SetList {
+                        a: table,
+                        n: MULTI,
+                        start: next_index,
+                    };
+                    self.emit(set, value.span);
+                    pending = 0;
+                    self.free_to(table as usize + 1);
+                }
+                TableField::Positional(value) => {
+                    let reg = self.alloc(value.span)?;
+                    self.expr(value, reg)?;
+                    pending += 1;
+                    if pending == FIELDS_PER_FLUSH {
+                        self.set_list(table, pending, next_index, value.span);
+                        next_index += pending as u32;
+                        pending = 0;
+                    }
+                }
+                TableField::Named { name, ty, value } => {
+                    let saved = self.free_reg();
+                    let reg = self.alloc(value.span)?;
+                    let k = self.string_constant(&name.name);
+                    let mut set_span = name.span;
+                    match (&value.kind, ty) {
+                        (ExprKind::Function(func), Some(_)) if func.ret.is_none() => {
+                            self.closure(func, ty.clone(), false, reg, value.span)?
+                        }
+                        (_, Some(ty)) => {
+                            // The store converts the value, so it fails there
+                            let ty = self.type_index(ty);
+                            self.emit(Instr::TypeField { a: table, k, ty }, name.span);
+                            self.expr(value, reg)?;
+                            set_span = value.span;
+                        }
+                        (_, None) => self.expr(value, reg)?,
+                    }
+                    self.emit(
+                        Instr::SetField {
+                            a: table,
+                            k,
+                            c: reg,
+                        },
+                        set_span,
+                    );
+                    self.free_to(saved);
+                }
+                TableField::Keyed { key, value } => {
+                    let saved = self.free_reg();
+                    let b = self.expr_any(key)?;
+                    let c = self.expr_any(value)?;
+                    self.emit(Instr::SetIndex { a: table, b, c }, key.span);
+                    self.free_to(saved);
+                }
+            }
+        }
+        if pending > 0 {
+            self.set_list(table, pending, next_index, span);
+        }
+        if table != dst {
+            self.emit(Instr::Move { a: dst, b: table }, span);
+        }
+        Ok(())
+    }
+
+    fn set_list(&mut self, table: Reg, n: usize, start: u32, span: Span) {
+        let set = Instr::SetList {
+            a: table,
+            n: n as u8,
+            start,
+        };
+        self.emit(set, span);
+        self.free_to(table as usize + 1);
+    }
+}
+
+/// Picks a specialized instruction when both operands have a known
+/// representation, the generic one otherwise.
+fn binary_instr(
+    op: BinOp,
+    kinds: (Option<NumKind>, Option<NumKind>),
+    a: Reg,
+    b: Reg,
+    c: Reg,
+) -> Instr {
+    use NumKind::*;
+    match (op, kinds) {
+        (BinOp::Add, (Some(Int), Some(Int))) => Instr::AddInt { a, b, c },
+        (BinOp::Sub, (Some(Int), Some(Int))) => Instr::SubInt { a, b, c },
+        (BinOp::Mul, (Some(Int), Some(Int))) => Instr::MulInt { a, b, c },
+        (BinOp::Lt, (Some(Int), Some(Int))) => Instr::LtInt { a, b, c },
+        (BinOp::Le, (Some(Int), Some(Int))) => Instr::LeInt { a, b, c },
+        (BinOp::Gt, (Some(Int), Some(Int))) => Instr::LtInt { a, b: c, c: b },
+        (BinOp::Ge, (Some(Int), Some(Int))) => Instr::LeInt { a, b: c, c: b },
+        (BinOp::Add, (Some(Float), Some(Float))) => Instr::AddFloat { a, b, c },
+        (BinOp::Sub, (Some(Float), Some(Float))) => Instr::SubFloat { a, b, c },
+        (BinOp::Mul, (Some(Float), Some(Float))) => Instr::MulFloat { a, b, c },
+        (BinOp::Div, (Some(Float), Some(Float))) => Instr::DivFloat { a, b, c },
+        (BinOp::Lt, (Some(Float), Some(Float))) => Instr::LtFloat { a, b, c },
+        (BinOp::Le, (Some(Float), Some(Float))) => Instr::LeFloat { a, b, c },
+        (BinOp::Gt, (Some(Float), Some(Float))) => Instr::LtFloat { a, b: c, c: b },
+        (BinOp::Ge, (Some(Float), Some(Float))) => Instr::LeFloat { a, b: c, c: b },
+        _ => Instr::Binary { op, a, b, c },
+    }
+}
@@ -0,0 +1,655 @@
+use crate::ast::*;
+use crate::lexer::{LosslessToken, Token, TriviaKind};
+use crate::span::Span;
+
+const INDENT: &str = "    ";
+
+/// A comment from the source, re-emitted when the formatter passes its
+/// position.
+#[derive(Debug)]
+struct Comment {
+    text: String,
+    offset: usize,
+    /// Code precedes it on its line
+    trailing: bool,
+}
+
+/// Prints a parsed chunk in the canonical layout: four space indentation,
+/// one statement per line, spaces around binary operators and after commas,
+/// and typed declarations without the redundant `local`. Literals are copied
+/// from the source as written, comments are kept where they were relative
+/// to statements and table fields, and single blank lines between them are
+/// preserved. Formatting the output again gives the same text.
+pub struct Formatter<'a> {
+    source: &'a str,
+    comments: Vec<Comment>,
+    next_comment: usize,
+    /// Offsets of tokens and comments preceded by an empty line, in order
+    blank_before: Vec<usize>,
+    /// End of the last statement, field or comment written
+    last_offset: usize,
+    /// Tokens that close a block, by offset
+    closers: Vec<usize>,
+    out: String,
+    indent: usize,
+    /// Nothing was written since the enclosing block or table opened
+    at_open: bool,
+}
+
+impl<'a> Formatter<'a> {
+    /// `tokens` must be the lossless tokens `source` was parsed from.
+    pub fn new(source: &'a str, tokens: &[LosslessToken]) -> Self {
+        let mut comments = Vec::new();
+        let mut blank_before = Vec::new();
+        let mut closers = Vec::new();
+        for token in tokens {
+            let mut newlines = 0;
+            for trivia in &token.leading {
+                match trivia.kind {
+                    TriviaKind::Newline => newlines += 1,
+                    TriviaKind::Whitespace => {}
+                    TriviaKind::LineComment | TriviaKind::LongComment { .. } => {
+                        if newlines >= 2 {
+                            blank_before.push(trivia.span.start.offset);
+                        }
+                        newlines = 0;
+                        comments.push(Comment::new(&trivia.text, trivia.kind, trivia.span, false));
+                    }
+                }
+            }
+            let offset = token.token.span.start.offset;
+            if newlines >= 2 {
+                blank_before.push(offset);
+            }
+            if matches!(
+                token.token.token,
+                Token::End | Token::Else | Token::ElseIf | Token::Until | Token::Eof
+            ) {
+                closers.push(offset);
+            }
+            for trivia in &token.trailing {
+                if trivia.kind != TriviaKind::Whitespace {
+                    comments.push(Comment::new(&trivia.text, trivia.kind, trivia.span, true));
+                }
+            }
+        }
+        Formatter {
+            source,
+            comments,
+            next_comment: 0,
+            blank_before,
+            last_offset: 0,
+            closers,
+            out: String::new(),
+            indent: 0,
+            at_open: true,
+        }
+    }
+
+    pub fn format(mut self, chunk: &Block) -> String {
+        self.block(chunk);
+        self.flush_comments(usize::MAX);
+        self.out
+    }
+
+    fn block(&mut self, block: &Block) {
+        for (i, stmt) in block.stmts.iter().enumerate() {
+            self.flush_comments(stmt.span.start.offset);
+            self.blank_line(stmt.span.start.offset);
+            self.start_line();
+            // `a = b` followed by `(f)()` would read as the call `b(f)()`
+            if i > 0 && self.source.as_bytes()[stmt.span.start.offset] == b'(' {
+                self.out.push(';');
+            }
+            self.stmt(stmt);
+            self.end_line();
+            self.last_offset = stmt.span.end.offset;
+        }
+        // Comments before the closing keyword stay inside the block
+        let closer = self.closer(block.span.end.offset);
+        self.flush_comments(closer);
+    }
+
+    /// Prints `block` indented on the lines after the current one.
+    fn body(&mut self, block: &Block) {
+        self.end_line();
+        self.indent += 1;
+        self.at_open = true;
+        self.block(block);
+        self.indent -= 1;
+        self.start_line();
+    }
+
+    fn stmt(&mut self, stmt: &Stmt) {
+        match &stmt.kind {
+            StmtKind::Local {
+                scope,
+                ty,
+                names,
+                attribs,
+                values,
+            } => {
+                self.declaration_prefix(*scope, ty.as_ref(), false);
+                let names: Vec<String> = names
+                    .iter()
+                    .zip(attribs)
+                    .map(|(name, attrib)| match attrib {
+                        Some(attrib) => format!("{} <{}>", name.name, attrib),
+                        None => name.name.clone(),
+                    })
+                    .collect();
+                self.out.push_str(&names.join(", "));
+                if !values.is_empty() {
+                    self.out.push_str(" = ");
+                    self.exprs(values);
+                }
+            }
+            StmtKind::Function { scope, name, func } => {
+                self.declaration_prefix(*scope, func.ret.as_ref(), true);
+                self.out.push_str("function ");
+                let path: Vec<&str> = name.path.iter().map(|n| n.name.as_str()).collect();
+                self.out.push_str(&path.join("."));
+                if let Some(method) = &name.method {
+                    self.out.push(':');
+                    self.out.push_str(&method.name);
+                }
+                self.function_body(func);
+            }
+            StmtKind::Assign { targets, values } => {
+                self.exprs(targets);
+                self.out.push_str(" = ");
+                self.exprs(values);
+            }
+            StmtKind::Call(expr) | StmtKind::Expr(expr) => self.expr(expr),
+            StmtKind::Do(body) => {
+                self.out.push_str("do");
+                self.body(body);
+                self.out.push_str("end");
+            }
+            StmtKind::While { cond, body } => {
+                self.out.push_str("while ");
+                self.expr(cond);
+                self.out.push_str(" do");
+                self.body(body);
+                self.out.push_str("end");
+            }
+            StmtKind::Repeat { body, cond } => {
+                self.out.push_str("repeat");
+                self.body(body);
+                self.out.push_str("until ");
+                self.expr(cond);
+            }
+            StmtKind::If {
+                branches,
+                else_block,
+            } => {
+                for (i, (cond, body)) in branches.iter().enumerate() {
+                    self.out.push_str(if i == 0 { "if " } else { "elseif " });
+                    self.expr(cond);
+                    self.out.push_str(" then");
+                    self.body(body);
+                }
+                if let Some(body) = else_block {
+                    self.out.push_str("else");
+                    self.body(body);
+                }
+                self.out.push_str("end");
+            }
+            StmtKind::NumericFor {
+                var,
+                start,
+                limit,
+                step,
+                body,
+            } => {
+                self.out.push_str("for ");
+                self.out.push_str(&var.name);
+                self.out.push_str(" = ");
+                self.expr(start);
+                self.out.push_str(", ");
+                self.expr(limit);
+                if let Some(step) = step {
+                    self.out.push_str(", ");
+                    self.expr(step);
+                }
+                self.out.push_str(" do");
+                self.body(body);
+                self.out.push_str("end");
+            }
+            StmtKind::GenericFor { names, exprs, body } => {
+                self.out.push_str("for ");
+                self.names(names);
+                self.out.push_str(" in ");
+                self.exprs(exprs);
+                self.out.push_str(" do");
+                self.body(body);
+                self.out.push_str("end");
+            }
+            StmtKind::Return(values) => {
+                self.out.push_str("return");
+                if !values.is_empty() {
+                    self.out.push(' ');
+                    self.exprs(values);
+                }
+            }
+            StmtKind::Break => self.out.push_str("break"),
+            StmtKind::Goto(name) => {
+                self.out.push_str("goto ");
+                self.out.push_str(&name.name);
+            }
+            StmtKind::Label(name) => {
+                self.out.push_str("::");
+                self.out.push_str(&name.name);
+                self.out.push_str("::");
+            }
+        }
+    }
+
+    /// `global` and the type. Declarations are local by default, so `local`
+    /// is only needed on an untyped variable.
+    fn declaration_prefix(&mut self, scope: Scope, ty: Option<&TypeName>, function: bool) {
+        match (scope, ty) {
+            (Scope::Global, _) => self.out.push_str("global "),
+            (Scope::Local, None) if !function => self.out.push_str("local "),
+            (Scope::Local, _) => {}
+        }
+        if let Some(ty) = ty {
+            self.out.push_str(&ty.to_string());
+            self.out.push(' ');
+        }
+    }
+
+    fn names(&mut self, names: &[Name]) {
+        let names: Vec<&str> = names.iter().map(|n| n.name.as_str()).collect();
+        self.out.push_str(&names.join(", "));
+    }
+
+    fn exprs(&mut self, exprs: &[Expr]) {
+        for (i, expr) in exprs.iter().enumerate() {
+            if i > 0 {
+                self.out.push_str(", ");
+            }
+            self.expr(expr);
+        }
+    }
+
+    fn expr(&mut self, expr: &Expr) {
+        match &expr.kind {
+            ExprKind::Nil => self.out.push_str("nil"),
+            ExprKind::True => self.out.push_str("true"),
+            ExprKind::False => self.out.push_str("false"),
+            ExprKind::Vararg => self.out.push_str("..."),
+            ExprKind::Integer(_) | ExprKind::Float(_) | ExprKind::String(_) | ExprKind::Char(_) => {
+                self.out.push_str(self.text(expr.span))
+            }
+            ExprKind::Function(func) => {
+                if let Some(ret) = &func.ret {
+                    self.out.push_str(&ret.to_string());
+                    self.out.push(' ');
+                }
+                if !func.fastcall {
+                    self.out.push_str("function");
+                }
+                self.function_body(func);
+            }
+            ExprKind::Table(fields) => self.table(expr, fields),
+            ExprKind::Vec(items) => {
+                if self.breaks(expr) {
+                    self.out.push('[');
+                    self.open_fields();
+                    for item in items {
+                        self.field_line(item.span, |this| this.expr(item));
+                    }
+                    self.close_fields(expr.span);
+                    self.out.push(']');
+                } else {
+                    let text = self.nested(|this| this.exprs(items));
+                    self.bracketed(&text);
+                }
+            }
+            ExprKind::Name(name) => self.out.push_str(name),
+            ExprKind::Index { object, key } => {
+                self.expr(object);
+                let dotted = matches!(key.kind, ExprKind::String(_))
+                    && !matches!(
+                        self.source.as_bytes()[key.span.start.offset],
+                        b'"' | b'\'' | b'['
+                    );
+                if dotted {
+                    self.out.push('.');
+                    self.out.push_str(self.text(key.span));
+                } else {
+                    let text = self.nested(|this| this.expr(key));
+                    self.bracketed(&text);
+                }
+            }
+            ExprKind::Call { func, args } => {
+                self.expr(func);
+                self.args(args);
+            }
+            ExprKind::MethodCall {
+                object,
+                method,
+                args,
+            } => {
+                self.expr(object);
+                self.out.push(':');
+                self.out.push_str(&method.name);
+                self.args(args);
+            }
+            ExprKind::Paren(inner) => {
+                self.out.push('(');
+                self.expr(inner);
+                self.out.push(')');
+            }
+            ExprKind::Cast { ty, expr } => {
+                self.out.push('(');
+                self.out.push_str(&ty.to_string());
+                self.out.push(')');
+                self.expr(expr);
+            }
+            ExprKind::Unary { op, expr } => {
+                let text = self.nested(|this| this.expr(expr));
+                self.out.push_str(match op {
+                    UnOp::Neg => "-",
+                    UnOp::Not => "not ",
+                    UnOp::Len => "#",
+                    UnOp::BitNot => "~",
+                });
+                // `--` would start a comment
+                if *op == UnOp::Neg && text.starts_with('-') {
+                    self.out.push(' ');
+                }
+                self.out.push_str(&text);
+            }
+            ExprKind::Binary { op, lhs, rhs } => {
+                self.expr(lhs);
+                self.out.push(' ');
+                self.out.push_str(binary_symbol(*op));
+                self.out.push(' ');
+                self.expr(rhs);
+            }
+        }
+    }
+
+    /// Arguments always get parentheses, `f "s"` and `f {}` included.
+    fn args(&mut self, args: &[Expr]) {
+        self.out.push('(');
+        self.exprs(args);
+        self.out.push(')');
+    }
+
+    fn function_body(&mut self, func: &Function) {
+        self.out.push('(');
+        for (i, param) in func.params.iter().enumerate() {
+            if i > 0 {
+                self.out.push_str(", ");
+            }
+            if let Some(ty) = &param.ty {
+                self.out.push_str(&ty.to_string());
+                self.out.push(' ');
+            }
+            self.out.push_str(&param.name.name);
+        }
+        if func.is_vararg {
+            if !func.params.is_empty() {
+                self.out.push_str(", ");
+            }
+            self.out.push_str("...");
+        }
+        self.out.push(')');
+        if func.body.stmts.is_empty() && !self.has_comments(func.span) {
+            self.out.push_str(" end");
+        } else {
+            self.body(&func.body);
+            self.out.push_str("end");
+        }
+    }
+
+    fn table(&mut self, expr: &Expr, fields: &[TableField]) {
+        if fields.is_empty() && !self.has_comments(expr.span) {
+            self.out.push_str("{}");
+            return;
+        }
+        let multiline = self.breaks(expr);
+        if multiline {
+            self.out.push('{');
+            self.open_fields();
+        } else {
+            self.out.push_str("{ ");
+        }
+        for (i, field) in fields.iter().enumerate() {
+            let span = match field {
+                TableField::Positional(value) => value.span,
+                TableField::Named { name, value, .. } => name.span.to(value.span),
+                TableField::Keyed { key, value } => key.span.to(value.span),
+            };
+            let print = |this: &mut Self| match field {
+                TableField::Positional(value) => this.expr(value),
+                TableField::Named { name, ty, value } => {
+                    if let Some(ty) = ty {
+                        this.out.push_str(&ty.to_string());
+                        this.out.push(' ');
+                    }
+                    this.out.push_str(&name.name);
+                    this.out.push_str(" = ");
+                    this.expr(value);
+                }
+                TableField::Keyed { key, value } => {
+                    let text = this.nested(|this| this.expr(key));
+                    this.bracketed(&text);
+                    this.out.push_str(" = ");
+                    this.expr(value);
+                }
+            };
+            if multiline {
+                self.field_line(span, print);
+            } else {
+                if i > 0 {
+                    self.out.push_str(", ");
+                }
+                print(self);
+            }
+        }
+        if multiline {
+            self.close_fields(expr.span);
+            self.out.push('}');
+        } else {
+            self.out.push_str(" }");
+        }
+    }
+
+    /// Starts the fields of a broken table or vec on the next line.
+    fn open_fields(&mut self) {
+        self.end_line();
+        self.indent += 1;
+        self.at_open = true;
+    }
+
+    /// Prints one field of a broken table or vec on its own line, with the
+    /// comments and blank line before it.
+    fn field_line(&mut self, span: Span, print: impl FnOnce(&mut Self)) {
+        self.flush_comments(span.start.offset);
+        self.blank_line(span.start.offset);
+        self.start_line();
+        print(self);
+        self.out.push(',');
+        self.end_line();
+        self.last_offset = span.end.offset;
+    }
+
+    /// Ends the fields of a broken table or vec, whose closing bracket is
+    /// the last char of `span`.
+    fn close_fields(&mut self, span: Span) {
+        self.flush_comments(span.end.offset - 1);
+        self.indent -= 1;
+        self.start_line();
+    }
+
+    /// Whether printing `expr` spreads over several lines. Tables and vecs
+    /// keep the layout they were written in, a function with a body always
+    /// breaks, and so does anything holding a comment.
+    fn breaks(&self, expr: &Expr) -> bool {
+        match &expr.kind {
+            ExprKind::Function(func) => !func.body.stmts.is_empty() || self.has_comments(expr.span),
+            ExprKind::Table(fields) if fields.is_empty() => self.has_comments(expr.span),
+            ExprKind::Table(fields) => {
+                expr.span.start.line != expr.span.end.line
+                    || self.has_comments(expr.span)
+                    || fields.iter().any(|field| match field {
+                        TableField::Positional(value) | TableField::Named { value, .. } => {
+                            self.breaks(value)
+                        }
+                        TableField::Keyed { key, value } => self.breaks(key) || self.breaks(value),
+                    })
+            }
+            ExprKind::Vec(items) => {
+                !items.is_empty()
+                    && (expr.span.start.line != expr.span.end.line
+                        || self.has_comments(expr.span)
+                        || items.iter().any(|item| self.breaks(item)))
+            }
+            ExprKind::Index { object, key } => self.breaks(object) || self.breaks(key),
+            ExprKind::Call { func: object, args } | ExprKind::MethodCall { object, args, .. } => {
+                self.breaks(object) || args.iter().any(|arg| self.breaks(arg))
+            }
+            ExprKind::Paren(inner)
+            | ExprKind::Cast { expr: inner, .. }
+            | ExprKind::Unary { expr: inner, .. } => self.breaks(inner),
+            ExprKind::Binary { lhs, rhs, .. } => self.breaks(lhs) || self.breaks(rhs),
+            _ => false,
+        }
+    }
+
+    /// Prints into a scratch buffer and returns the text, so the caller can
+    /// look at it before writing it out.
+    fn nested(&mut self, print: impl FnOnce(&mut Self)) -> String {
+        let outer = std::mem::take(&mut self.out);
+        print(self);
+        std::mem::replace(&mut self.out, outer)
+    }
+
+    /// `[text]`, with spaces inside when `text` starts with `[` and would
+    /// otherwise open a long string.
+    fn bracketed(&mut self, text: &str) {
+        if text.starts_with('[') {
+            self.out.push_str("[ ");
+            self.out.push_str(text);
+            self.out.push_str(" ]");
+        } else {
+            self.out.push('[');
+            self.out.push_str(text);
+            self.out.push(']');
+        }
+    }
+
+    fn text(&self, span: Span) -> &'a str {
+        &self.source[span.start.offset..span.end.offset]
+    }
+
+    /// Offset of the keyword closing a block that ends at `offset`.
+    fn closer(&self, offset: usize) -> usize {
+        let i = self.closers.partition_point(|&closer| closer < offset);
+        self.closers.get(i).copied().unwrap_or(usize::MAX)
+    }
+
+    fn has_comments(&self, span: Span) -> bool {
+        let i = self
+            .comments
+            .partition_point(|c| c.offset <= span.start.offset);
+        self.comments
+            .get(i)
+            .is_some_and(|c| c.offset < span.end.offset)
+    }
+
+    /// Writes out the comments before `offset`. Only called at the start of
+    /// a line: a comment that followed code goes at the end of the previous
+    /// line, any other on a line of its own.
+    fn flush_comments(&mut self, offset: usize) {
+        while let Some(comment) = self.comments.get(self.next_comment) {
+            if comment.offset >= offset {
+                break;
+            }
+            let (text, offset) = (comment.text.clone(), comment.offset);
+            self.next_comment += 1;
+            if comment.trailing && self.out.ends_with('\n') {
+                self.out.pop();
+                self.out.push(' ');
+                self.out.push_str(&text);
+            } else {
+                self.blank_line(offset);
+                self.start_line();
+                self.out.push_str(&text);
+            }
+            self.end_line();
+        }
+    }
+
+    /// Keeps an empty line from the source between what was written last
+    /// and `offset`, except right after an opening.
+    fn blank_line(&mut self, offset: usize) {
+        let i = self
+            .blank_before
+            .partition_point(|&blank| blank <= self.last_offset);
+        if !self.at_open
+            && self
+                .blank_before
+                .get(i)
+                .is_some_and(|&blank| blank <= offset)
+        {
+            self.out.push('\n');
+        }
+        self.last_offset = offset;
+    }
+
+    fn start_line(&mut self) {
+        for _ in 0..self.indent {
+            self.out.push_str(INDENT);
+        }
+        self.at_open = false;
+    }
+
+    fn end_line(&mut self) {
+        self.out.push('\n');
+    }
+}
+
+impl Comment {
+    fn new(text: &str, kind: TriviaKind, span: Span, trailing: bool) -> Self {
+        let text = match kind {
+            TriviaKind::LineComment => text.trim_end(),
+            _ => text,
+        };
+        Comment {
+            text: text.to_string(),
+            offset: span.start.offset,
+            trailing,
+        }
+    }
+}
+
+fn binary_symbol(op: BinOp) -> &'static str {
+    match op {
+        BinOp::Add => "+",
+        BinOp::Sub => "-",
+        BinOp::Mul => "*",
+        BinOp::Div => "/",
+        BinOp::FloorDiv => "//",
+        BinOp::Mod => "%",
+        BinOp::Pow => "^",
+        BinOp::BitAnd => "&",
+        BinOp::BitOr => "|",
+        BinOp::BitXor => "~",
+        BinOp::Shl => "<<",
+        BinOp::Shr => ">>",
+        BinOp::Concat => "..",
+        BinOp::Eq => "==",
+        BinOp::Ne => "~=",
+        BinOp::Lt => "<",
+        BinOp::Le => "<=",
+        BinOp::Gt => ">",
+        BinOp::Ge => ">=",
+        BinOp::And => "and",
+        BinOp::Or => "or",
+    }
+}
@@ -0,0 +1,755 @@
+use std::any::Any;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::rc::{Rc, Weak};
+
+/// Bytes allocated before the first automatic collection.
+const MIN_THRESHOLD: usize = 256 * 1024;
+
+/// A heap object the collector can look into. Heap values are reference
+/// counted, which frees everything but cycles; the collector finds the
+/// cycles nothing outside the heap refers to and clears them so reference
+/// counting can free them too.
+pub trait Trace {
+    /// Calls `visit` with the address of every tracked object this one
+    /// refers to. Returns `false` when the contents are borrowed and cannot
+    /// be looked at, which keeps the object and all it refers to alive.
+    fn trace(&self, visit: &mut dyn FnMut(usize)) -> bool;
+
+    /// Takes out everything the object refers to, returned so it is dropped
+    /// once the collector is done.
+    fn clear(&self) -> Box<dyn Any>;
+
+    /// Estimated bytes used, for `collectgarbage("count")`.
+    fn size(&self) -> usize;
+
+    /// Calls `visit` with the address of every tracked object this one
+    /// refers to weakly. Weak references count as coming from inside the
+    /// heap but do not keep anything alive.
+    fn trace_weak(&self, _visit: &mut dyn FnMut(usize)) {}
+
+    /// Calls `visit` with the key and value addresses of the entries whose
+    /// value is alive only as long as their key is.
+    fn trace_ephemerons(&self, _visit: &mut dyn FnMut(usize, usize)) {}
+
+    /// Drops the weak references to the objects `dead` reports collected,
+    /// returning them to be dropped once the collector is done.
+    fn clear_weak(&self, _dead: &dyn Fn(usize) -> bool) -> Box<dyn Any> {
+        Box::new(())
+    }
+}
+
+/// Identity of a heap object as reported to `Trace::trace`.
+pub fn address<T: ?Sized>(rc: &Rc<T>) -> usize {
+    Rc::as_ptr(rc) as *const () as usize
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// Cycles run in small steps interleaved with the program
+    Incremental,
+    /// Frequent minor collections of young objects, full ones when the heap
+    /// has grown
+    Generational,
+}
+
+impl Mode {
+    pub fn name(self) -> &'static str {
+        match self {
+            Mode::Incremental => "incremental",
+            Mode::Generational => "generational",
+        }
+    }
+}
+
+/// Tuning of the collector, with the meaning and defaults of Lua 5.4.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Params {
+    /// Heap size, in percent of the live size after a cycle, at which the
+    /// next incremental cycle starts
+    pub pause: usize,
+    /// Speed of incremental cycles relative to allocation, in percent: at
+    /// 100 a step looks at 128 objects per KB allocated
+    pub stepmul: usize,
+    /// Log2 of the bytes allocated between incremental steps
+    pub stepsize: u32,
+    /// Bytes allocated between minor collections, in percent of the heap
+    /// after the last major one
+    pub minormul: usize,
+    /// Growth of the heap since the last major collection, in percent, at
+    /// which a minor collection becomes a major one
+    pub majormul: usize,
+}
+
+impl Default for Params {
+    fn default() -> Self {
+        Params {
+            pause: 200,
+            stepmul: 100,
+            stepsize: 13,
+            minormul: 20,
+            majormul: 100,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Age {
+    /// Surviving two minor collections makes an object old
+    Old,
+    Survival,
+    New,
+}
+
+struct Entry {
+    object: Weak<dyn Trace>,
+    age: Age,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Phase {
+    /// Counting the references between the objects of the cycle
+    Count,
+    /// Marking from the objects referenced from outside the heap
+    Mark,
+}
+
+/// State of an incremental cycle. It covers the objects that existed when
+/// it started, later ones are kept until the next cycle.
+///
+/// Counts and marks are taken over several steps while the program changes
+/// the heap, so the unmarked objects are only candidates: the cycle ends by
+/// checking, atomically, which of them nothing outside the candidates
+/// refers to.
+struct Cycle {
+    len: usize,
+    phase: Phase,
+    cursor: usize,
+    /// Strong count of each object, `usize::MAX` when it could not be traced
+    counts: Vec<usize>,
+    /// References found from objects of the cycle, by address
+    internal: HashMap<usize, usize>,
+    index: HashMap<usize, usize>,
+    marked: Vec<bool>,
+    gray: Vec<usize>,
+}
+
+impl Cycle {
+    fn new(len: usize) -> Cycle {
+        Cycle {
+            len,
+            phase: Phase::Count,
+            cursor: 0,
+            counts: vec![0; len],
+            internal: HashMap::new(),
+            index: HashMap::new(),
+            marked: vec![false; len],
+            gray: Vec::new(),
+        }
+    }
+
+    fn mark(&mut self, i: usize) {
+        if !self.marked[i] {
+            self.marked[i] = true;
+            self.gray.push(i);
+        }
+    }
+}
+
+struct Heap {
+    /// In allocation order, so ages only decrease along the list
+    objects: Vec<Entry>,
+    /// Strings never form cycles, they are only tracked to count them
+    strings: Vec<Weak<[u8]>>,
+    /// Strings that were alive at the last collection
+    old_strings: usize,
+    /// Estimated bytes allocated since the last collection or step
+    allocated: usize,
+    /// Estimated bytes alive after the last collection
+    live: usize,
+    /// Estimated bytes alive after the last major collection
+    major_base: usize,
+    mode: Mode,
+    params: Params,
+    cycle: Option<Cycle>,
+    stopped: bool,
+    stress: bool,
+    special: Special,
+}
+
+/// Objects the collector treats specially, registered by the program.
+#[derive(Default)]
+struct Special {
+    /// Objects that may hold weak references, by address
+    weak: HashMap<usize, Weak<dyn Trace>>,
+    /// Objects to finalize once unreachable, by address, with the order they
+    /// were registered in and the value the heap keeps them alive with
+    finalizable: HashMap<usize, (u64, Box<dyn Any>)>,
+    registered: u64,
+    /// Objects found unreachable, waiting for their finalizer to run
+    pending: Vec<Box<dyn Any>>,
+}
+
+impl Default for Heap {
+    fn default() -> Self {
+        Heap {
+            objects: Vec::new(),
+            strings: Vec::new(),
+            old_strings: 0,
+            allocated: 0,
+            live: 0,
+            major_base: 0,
+            mode: Mode::Incremental,
+            params: Params::default(),
+            cycle: None,
+            stopped: false,
+            stress: false,
+            special: Special::default(),
+        }
+    }
+}
+
+thread_local! {
+    static HEAP: RefCell<Heap> = RefCell::new(Heap::default());
+    /// Set while an incremental cycle is marking, so the write barrier costs
+    /// a flag check the rest of the time
+    static MARKING: Cell<bool> = const { Cell::new(false) };
+    /// Set while finalizers are waiting to run, checked by the engines
+    static PENDING: Cell<bool> = const { Cell::new(false) };
+}
+
+/// What the collector should do after an allocation.
+enum Work {
+    None,
+    Step,
+    Full,
+}
+
+/// Hands a new heap object to the collector, possibly running a step of
+/// collection first if enough was allocated.
+pub fn track<T: Trace + 'static>(rc: Rc<T>) -> Rc<T> {
+    let object: Weak<dyn Trace> = Rc::downgrade(&rc) as Weak<dyn Trace>;
+    let work = HEAP.with(|heap| {
+        let mut heap = heap.borrow_mut();
+        heap.objects.push(Entry {
+            object,
+            age: Age::New,
+        });
+        heap.allocate(rc.size())
+    });
+    run(work);
+    rc
+}
+
+/// Counts memory a tracked object took as it grew. Collecting is left to
+/// the next allocation, as the object is usually borrowed at this point.
+pub fn grow(bytes: usize) {
+    HEAP.with(|heap| heap.borrow_mut().allocated += bytes);
+}
+
+/// Counts a new string towards the heap size.
+pub fn track_str(s: Rc<[u8]>) -> Rc<[u8]> {
+    let work = HEAP.with(|heap| {
+        let mut heap = heap.borrow_mut();
+        heap.strings.push(Rc::downgrade(&s));
+        heap.allocate(s.len() + std::mem::size_of::<Rc<[u8]>>())
+    });
+    run(work);
+    s
+}
+
+fn run(work: Work) {
+    match work {
+        Work::None => {}
+        Work::Step => {
+            step(0);
+        }
+        Work::Full => collect(),
+    }
+}
+
+/// Runs a full collection, abandoning any incremental cycle in progress.
+pub fn collect() {
+    let garbage = HEAP.with(|heap| heap.borrow_mut().full());
+    // Dropping the contents frees the cycles they formed
+    drop(garbage);
+    HEAP.with(|heap| heap.borrow_mut().finish_full());
+}
+
+/// Performs a step of collection as if `kbytes` KB had been allocated, at
+/// least a basic one. In generational mode a step is a minor collection, or
+/// a major one when due. Returns whether a cycle finished.
+pub fn step(kbytes: usize) -> bool {
+    let (mode, major) = HEAP.with(|heap| {
+        let heap = heap.borrow();
+        (heap.mode, heap.major_due())
+    });
+    match mode {
+        Mode::Generational if major => collect(),
+        Mode::Generational => {
+            let garbage = HEAP.with(|heap| heap.borrow_mut().minor());
+            drop(garbage);
+            HEAP.with(|heap| heap.borrow_mut().finish_minor());
+        }
+        Mode::Incremental => {
+            let garbage = HEAP.with(|heap| {
+                let mut heap = heap.borrow_mut();
+                let kbytes = kbytes.max((1 << heap.params.stepsize) / 1024);
+                let work = (heap.params.stepmul * kbytes * 128 / 100).max(1);
+                heap.step(work)
+            });
+            let Some(garbage) = garbage else {
+                return false;
+            };
+            drop(garbage);
+            HEAP.with(|heap| heap.borrow_mut().finish_full());
+        }
+    }
+    true
+}
+
+/// Write barrier, called with the address of a value stored into a heap
+/// object. While an incremental cycle is marking, the stored value may have
+/// been reachable only from places already looked at, so it is marked.
+pub fn barrier(address: usize) {
+    if !MARKING.with(Cell::get) {
+        return;
+    }
+    HEAP.with(|heap| {
+        // Stores made while the collector runs need no barrier
+        let Ok(mut heap) = heap.try_borrow_mut() else {
+            return;
+        };
+        if let Some(cycle) = &mut heap.cycle {
+            if let Some(&i) = cycle.index.get(&address) {
+                cycle.mark(i);
+            }
+        }
+    });
+}
+
+/// Estimated bytes in use by tracked objects.
+pub fn count() -> usize {
+    HEAP.with(|heap| heap.borrow().size())
+}
+
+/// Stops or restarts automatic collections.
+pub fn set_running(running: bool) {
+    HEAP.with(|heap| heap.borrow_mut().stopped = !running);
+}
+
+pub fn is_running() -> bool {
+    HEAP.with(|heap| !heap.borrow().stopped)
+}
+
+/// Restores the default mode and tuning and restarts automatic collections,
+/// for a new program to start from.
+pub fn reset() {
+    set_mode(Mode::Incremental, Params::default());
+    set_running(true);
+}
+
+/// Collects on every allocation, to shake out objects the engines fail to
+/// keep reachable.
+pub fn set_stress(stress: bool) {
+    HEAP.with(|heap| heap.borrow_mut().stress = stress);
+}
+
+/// Switches to `mode` with `params`, returning the previous mode.
+pub fn set_mode(mode: Mode, params: Params) -> Mode {
+    HEAP.with(|heap| {
+        let mut heap = heap.borrow_mut();
+        heap.cancel_cycle();
+        heap.params = params;
+        std::mem::replace(&mut heap.mode, mode)
+    })
+}
+
+pub fn params() -> Params {
+    HEAP.with(|heap| heap.borrow().params)
+}
+
+pub fn set_params(params: Params) {
+    HEAP.with(|heap| heap.borrow_mut().params = params);
+}
+
+/// Lets the collector clear the weak references `rc` holds, as reported by
+/// `Trace::trace_weak`, when it is not part of a collection itself.
+pub fn register_weak<T: Trace + 'static>(rc: &Rc<T>) {
+    let object: Weak<dyn Trace> = Rc::downgrade(rc) as Weak<dyn Trace>;
+    HEAP.with(|heap| heap.borrow_mut().special.weak.insert(address(rc), object));
+}
+
+/// Marks `rc` for finalization. The heap keeps `holder`, a value referring
+/// to it, until the object is found unreachable; `holder` is then returned
+/// by `pending_finalizers`. Marking an object twice does nothing.
+pub fn register_finalizer<T: ?Sized>(rc: &Rc<T>, holder: Box<dyn Any>) {
+    HEAP.with(|heap| {
+        let special = &mut heap.borrow_mut().special;
+        if let std::collections::hash_map::Entry::Vacant(entry) =
+            special.finalizable.entry(address(rc))
+        {
+            entry.insert((special.registered, holder));
+            special.registered += 1;
+        }
+    });
+}
+
+/// Whether finalizers are waiting to run.
+pub fn has_pending() -> bool {
+    PENDING.with(Cell::get)
+}
+
+/// Takes the holders of the objects waiting to be finalized, the most
+/// recently marked first.
+pub fn pending_finalizers() -> Vec<Box<dyn Any>> {
+    PENDING.with(|pending| pending.set(false));
+    HEAP.with(|heap| std::mem::take(&mut heap.borrow_mut().special.pending))
+}
+
+/// Queues every object marked for finalization, reachable or not, as when
+/// a program ends.
+pub fn finalize_all() {
+    HEAP.with(|heap| {
+        let special = &mut heap.borrow_mut().special;
+        let mut all: Vec<(u64, Box<dyn Any>)> =
+            special.finalizable.drain().map(|(_, f)| f).collect();
+        all.sort_by_key(|(order, _)| std::cmp::Reverse(*order));
+        special
+            .pending
+            .extend(all.into_iter().map(|(_, holder)| holder));
+        PENDING.with(|pending| pending.set(!special.pending.is_empty()));
+    });
+}
+
+impl Heap {
+    /// Records an allocation and decides whether to collect.
+    fn allocate(&mut self, bytes: usize) -> Work {
+        self.allocated += bytes;
+        if self.stopped {
+            return Work::None;
+        }
+        if self.stress {
+            return Work::Full;
+        }
+        let threshold = match (self.mode, &self.cycle) {
+            (Mode::Incremental, None) => {
+                self.live.max(MIN_THRESHOLD) * self.params.pause.saturating_sub(100) / 100
+            }
+            (Mode::Incremental, Some(_)) => 1 << self.params.stepsize,
+            (Mode::Generational, _) => {
+                self.major_base.max(MIN_THRESHOLD) * self.params.minormul / 100
+            }
+        };
+        if self.allocated >= threshold {
+            Work::Step
+        } else {
+            Work::None
+        }
+    }
+
+    fn cancel_cycle(&mut self) {
+        self.cycle = None;
+        MARKING.with(|marking| marking.set(false));
+    }
+
+    fn size(&self) -> usize {
+        let objects: usize = self
+            .objects
+            .iter()
+            .filter_map(|e| e.object.upgrade())
+            .map(|o| o.size())
+            .sum();
+        let strings: usize = self.strings.iter().map(string_size).sum();
+        objects + strings
+    }
+
+    fn full(&mut self) -> Vec<Box<dyn Any>> {
+        self.cancel_cycle();
+        let objects = self
+            .objects
+            .iter()
+            .filter_map(|e| e.object.upgrade())
+            .collect();
+        find_garbage(objects, &mut self.special)
+    }
+
+    /// Forgets everything freed once the garbage of a full collection or
+    /// an incremental cycle was dropped.
+    fn finish_full(&mut self) {
+        self.objects.retain(|e| e.object.strong_count() > 0);
+        self.special.weak.retain(|_, o| o.strong_count() > 0);
+        for entry in &mut self.objects {
+            entry.age = Age::Old;
+        }
+        self.strings.retain(|s| s.strong_count() > 0);
+        self.old_strings = self.strings.len();
+        self.live = self.size();
+        self.major_base = self.live;
+        self.allocated = 0;
+    }
+
+    /// Start of the objects younger than the old generation.
+    fn young(&self) -> usize {
+        self.objects.partition_point(|e| e.age == Age::Old)
+    }
+
+    fn major_due(&self) -> bool {
+        let limit = self.major_base.max(MIN_THRESHOLD) * (100 + self.params.majormul) / 100;
+        self.live > limit
+    }
+
+    /// Collects among the objects that survived fewer than two minor
+    /// collections. References from old objects count as coming from
+    /// outside, so old objects stay until the next major collection.
+    fn minor(&mut self) -> Vec<Box<dyn Any>> {
+        let young = self.young();
+        let objects = self.objects[young..]
+            .iter()
+            .filter_map(|e| e.object.upgrade())
+            .collect();
+        find_garbage(objects, &mut self.special)
+    }
+
+    fn finish_minor(&mut self) {
+        let young = self.young();
+        let survivors: Vec<Entry> = self
+            .objects
+            .drain(young..)
+            .filter(|e| e.object.strong_count() > 0)
+            .map(|e| Entry {
+                age: match e.age {
+                    Age::New => Age::Survival,
+                    _ => Age::Old,
+                },
+                object: e.object,
+            })
+            .collect();
+        self.special.weak.retain(|_, o| o.strong_count() > 0);
+        self.live += survivors
+            .iter()
+            .filter_map(|e| e.object.upgrade())
+            .map(|o| o.size())
+            .sum::<usize>();
+        self.objects.extend(survivors);
+        let strings = self.strings.split_off(self.old_strings);
+        let strings: Vec<_> = strings
+            .into_iter()
+            .filter(|s| s.strong_count() > 0)
+            .collect();
+        self.live += strings.iter().map(string_size).sum::<usize>();
+        self.strings.extend(strings);
+        self.old_strings = self.strings.len();
+        self.allocated = 0;
+    }
+
+    /// Does `work` units of an incremental cycle, starting one if needed.
+    /// Returns the garbage once the cycle is done.
+    fn step(&mut self, mut work: usize) -> Option<Vec<Box<dyn Any>>> {
+        self.allocated = 0;
+        let objects = &self.objects;
+        let cycle = self.cycle.get_or_insert_with(|| Cycle::new(objects.len()));
+        if cycle.phase == Phase::Count {
+            while cycle.cursor < cycle.len && work > 0 {
+                let i = cycle.cursor;
+                let object = &objects[i].object;
+                let address = object.as_ptr() as *const () as usize;
+                // The reference keeping a finalizable object alive is not
+                // one of the program's
+                let holders = self.special.finalizable.contains_key(&address) as usize;
+                cycle.counts[i] = object.strong_count() - holders;
+                cycle.index.insert(address, i);
+                if let Some(object) = object.upgrade() {
+                    let internal = &mut cycle.internal;
+                    let mut count = |child| *internal.entry(child).or_default() += 1;
+                    if object.trace(&mut count) {
+                        object.trace_weak(&mut count);
+                    } else {
+                        cycle.counts[i] = usize::MAX;
+                    }
+                }
+                cycle.cursor += 1;
+                work -= 1;
+            }
+            if cycle.cursor < cycle.len {
+                return None;
+            }
+            cycle.phase = Phase::Mark;
+            cycle.cursor = 0;
+            MARKING.with(|marking| marking.set(true));
+        }
+        while work > 0 {
+            work -= 1;
+            if cycle.cursor < cycle.len {
+                let i = cycle.cursor;
+                let address = objects[i].object.as_ptr() as *const () as usize;
+                let internal = cycle.internal.get(&address).copied().unwrap_or(0);
+                if cycle.counts[i] > internal {
+                    cycle.mark(i);
+                }
+                cycle.cursor += 1;
+            } else if let Some(i) = cycle.gray.pop() {
+                if let Some(object) = objects[i].object.upgrade() {
+                    let mut children = Vec::new();
+                    object.trace(&mut |child| {
+                        if let Some(&j) = cycle.index.get(&child) {
+                            children.push(j);
+                        }
+                    });
+                    for j in children {
+                        cycle.mark(j);
+                    }
+                }
+            } else {
+                break;
+            }
+        }
+        if cycle.cursor < cycle.len || !cycle.gray.is_empty() {
+            return None;
+        }
+        let cycle = self.cycle.take().unwrap();
+        MARKING.with(|marking| marking.set(false));
+        let candidates = self.objects[..cycle.len]
+            .iter()
+            .zip(&cycle.marked)
+            .filter(|(_, marked)| !**marked)
+            .filter_map(|(e, _)| e.object.upgrade())
+            .collect();
+        Some(find_garbage(candidates, &mut self.special))
+    }
+}
+
+fn string_size(s: &Weak<[u8]>) -> usize {
+    s.upgrade()
+        .map_or(0, |s| s.len() + std::mem::size_of::<Rc<[u8]>>())
+}
+
+/// Clears the objects of `candidates` that nothing outside of them refers
+/// to, directly or through other candidates.
+///
+/// Roots are not enumerated: a candidate whose strong count is higher than
+/// the number of references from other candidates is held from elsewhere,
+/// by another object, a register, a variable of the engine or a Rust local.
+/// Everything reachable from those is alive. Weak references, from
+/// candidates or from the registered objects outside them, are counted but
+/// not followed, and are cleared when they refer to garbage. Unreachable
+/// objects marked for finalization are kept, with everything they refer
+/// to, until their finalizer has run.
+fn find_garbage(candidates: Vec<Rc<dyn Trace>>, special: &mut Special) -> Vec<Box<dyn Any>> {
+    let index: HashMap<usize, usize> = candidates
+        .iter()
+        .enumerate()
+        .map(|(i, o)| (address(o), i))
+        .collect();
+    // Minus the reference `candidates` itself holds
+    let mut external: Vec<usize> = candidates.iter().map(|o| Rc::strong_count(o) - 1).collect();
+    for address in special.finalizable.keys() {
+        if let Some(&j) = index.get(address) {
+            external[j] -= 1;
+        }
+    }
+    let mut marked = vec![false; candidates.len()];
+    let mut gray = Vec::new();
+    // Objects holding weak references, candidates first
+    let mut weak = Vec::new();
+    // Ephemeron entries as (owner, key, value), owners outside the
+    // candidates being `None`
+    let mut ephemerons = Vec::new();
+    let mut internal = |child: usize| {
+        if let Some(&j) = index.get(&child) {
+            external[j] = external[j].saturating_sub(1);
+        }
+    };
+    for (i, object) in candidates.iter().enumerate() {
+        if !object.trace(&mut internal) {
+            marked[i] = true;
+            gray.push(i);
+            continue;
+        }
+        let mut has_weak = false;
+        object.trace_weak(&mut |child| {
+            has_weak = true;
+            internal(child);
+        });
+        if has_weak {
+            weak.push(object.clone());
+            object.trace_ephemerons(&mut |key, value| ephemerons.push((Some(i), key, value)));
+        }
+    }
+    let outside: Vec<Rc<dyn Trace>> = special
+        .weak
+        .iter()
+        .filter(|(address, _)| !index.contains_key(address))
+        .filter_map(|(_, object)| object.upgrade())
+        .collect();
+    for object in outside {
+        object.trace_weak(&mut internal);
+        object.trace_ephemerons(&mut |key, value| ephemerons.push((None, key, value)));
+        weak.push(object);
+    }
+    for i in 0..candidates.len() {
+        if external[i] > 0 && !marked[i] {
+            marked[i] = true;
+            gray.push(i);
+        }
+    }
+    let alive = |marked: &[bool], address: usize| index.get(&address).is_none_or(|&j| marked[j]);
+    let propagate = |marked: &mut Vec<bool>, gray: &mut Vec<usize>| loop {
+        while let Some(i) = gray.pop() {
+            candidates[i].trace(&mut |child| {
+                if let Some(&j) = index.get(&child) {
+                    if !marked[j] {
+                        marked[j] = true;
+                        gray.push(j);
+                    }
+                }
+            });
+        }
+        // The value of an entry whose table and key are alive is alive
+        for &(owner, key, value) in &ephemerons {
+            let owner_alive = owner.is_none_or(|i| marked[i]);
+            if let Some(&j) = index.get(&value) {
+                if !marked[j] && owner_alive && alive(marked, key) {
+                    marked[j] = true;
+                    gray.push(j);
+                }
+            }
+        }
+        if gray.is_empty() {
+            break;
+        }
+    };
+    propagate(&mut marked, &mut gray);
+    // Unreachable objects to finalize come back to life until then
+    let mut resurrected = Vec::new();
+    for (i, object) in candidates.iter().enumerate() {
+        if !marked[i] {
+            if let Some(entry) = special.finalizable.remove(&address(object)) {
+                resurrected.push(entry);
+                marked[i] = true;
+                gray.push(i);
+            }
+        }
+    }
+    if !resurrected.is_empty() {
+        propagate(&mut marked, &mut gray);
+        resurrected.sort_by_key(|(order, _)| std::cmp::Reverse(*order));
+        special
+            .pending
+            .extend(resurrected.into_iter().map(|(_, holder)| holder));
+        PENDING.with(|pending| pending.set(true));
+    }
+    let mut garbage: Vec<Box<dyn Any>> = weak
+        .iter()
+        .filter(|object| alive(&marked, address(object)))
+        .map(|object| object.clear_weak(&|child| !alive(&marked, child)))
+        .collect();
+    garbage.extend(
+        candidates
+            .iter()
+            .zip(marked)
+            .filter(|(_, marked)| !marked)
+            .map(|(object, _)| object.clear()),
+    );
+    garbage
+}
@@ -0,0 +1,959 @@
+use std::any::Any;
+use std::cell::RefCell;
+use std::io::{self, Write};
+use std::rc::Rc;
+
+use crate::ast::*;
+use crate::gc::{self, Trace};
+use crate::stdlib::{self, Host};
+use crate::value::*;
+
+/// Native stack a script may use before it is stopped with "stack overflow",
+/// sized to fit in the 2 MiB default of spawned threads.
+pub const DEFAULT_STACK_LIMIT: usize = 1024 * 1024;
+
+/// Rough position of the native stack pointer.
+fn stack_address() -> usize {
+    let marker = 0u8;
+    std::hint::black_box(&marker) as *const u8 as usize
+}
+
+/// A local variable. Declared types stay attached so later assignments are
+/// converted and checked the same way the declaration was.
+pub struct Binding {
+    value: RefCell<Value>,
+    ty: Option<TypeName>,
+}
+
+impl Binding {
+    fn new(value: Value, ty: Option<TypeName>) -> Rc<Binding> {
+        gc::track(Rc::new(Binding {
+            value: RefCell::new(value),
+            ty,
+        }))
+    }
+
+    fn set(&self, value: Value) -> Result<(), RuntimeError> {
+        let value = match &self.ty {
+            Some(ty) => conform(ty, value)?,
+            None => value,
+        };
+        value.write_barrier();
+        *self.value.borrow_mut() = value;
+        Ok(())
+    }
+}
+
+impl Trace for Binding {
+    fn trace(&self, visit: &mut dyn FnMut(usize)) -> bool {
+        let Ok(value) = self.value.try_borrow() else {
+            return false;
+        };
+        value.trace(visit);
+        true
+    }
+
+    fn clear(&self) -> Box<dyn Any> {
+        Box::new(self.value.take())
+    }
+
+    fn size(&self) -> usize {
+        std::mem::size_of::<Binding>()
+    }
+}
+
+/// One lexical block. Variables are only ever appended, so remembering how
+/// many were declared when a child scope or closure was created is enough to
+/// hide names declared later in the same block.
+pub struct Env {
+    vars: RefCell<Vec<(String, Rc<Binding>)>>,
+    /// Values of the `<close>` variables declared so far, closed in reverse
+    /// when the block is left
+    closing: RefCell<Vec<Value>>,
+    parent: Option<(Rc<Env>, usize)>,
+}
+
+impl Env {
+    fn root() -> Rc<Env> {
+        gc::track(Rc::new(Env {
+            vars: RefCell::new(Vec::new()),
+            closing: RefCell::new(Vec::new()),
+            parent: None,
+        }))
+    }
+
+    fn child(parent: &Rc<Env>) -> Rc<Env> {
+        Env::child_visible(parent, parent.visible())
+    }
+
+    fn child_visible(parent: &Rc<Env>, visible: usize) -> Rc<Env> {
+        gc::track(Rc::new(Env {
+            vars: RefCell::new(Vec::new()),
+            closing: RefCell::new(Vec::new()),
+            parent: Some((parent.clone(), visible)),
+        }))
+    }
+
+    fn visible(&self) -> usize {
+        self.vars.borrow().len()
+    }
+
+    fn declare(&self, name: &str, binding: Rc<Binding>) {
+        self.vars.borrow_mut().push((name.to_string(), binding));
+    }
+
+    fn lookup(&self, name: &str) -> Option<Rc<Binding>> {
+        self.lookup_visible(name, self.visible())
+    }
+
+    fn lookup_visible(&self, name: &str, visible: usize) -> Option<Rc<Binding>> {
+        let vars = self.vars.borrow();
+        if let Some((_, binding)) = vars[..visible].iter().rev().find(|(n, _)| n == name) {
+            return Some(binding.clone());
+        }
+        let (parent, visible) = self.parent.as_ref()?;
+        parent.lookup_visible(name, *visible)
+    }
+}
+
+impl Trace for Env {
+    fn trace(&self, visit: &mut dyn FnMut(usize)) -> bool {
+        let (Ok(vars), Ok(closing)) = (self.vars.try_borrow(), self.closing.try_borrow()) else {
+            return false;
+        };
+        for (_, binding) in vars.iter() {
+            visit(gc::address(binding));
+        }
+        for value in closing.iter() {
+            value.trace(visit);
+        }
+        if let Some((parent, _)) = &self.parent {
+            visit(gc::address(parent));
+        }
+        true
+    }
+
+    fn clear(&self) -> Box<dyn Any> {
+        Box::new((self.vars.take(), self.closing.take()))
+    }
+
+    fn size(&self) -> usize {
+        let vars = self.vars.try_borrow().map_or(0, |vars| {
+            vars.capacity() * std::mem::size_of::<(String, Rc<Binding>)>()
+        });
+        std::mem::size_of::<Env>() + vars
+    }
+}
+
+/// A function value created by the tree-walking interpreter.
+pub struct Closure {
+    pub func: Rc<Function>,
+    pub scope: Rc<Env>,
+    pub visible: usize,
+    /// Return type, taken from the declaration for `void f = function() end`
+    pub ret: Option<TypeName>,
+    /// Declared with `a:b()` syntax, receives `self` as a hidden first param
+    pub is_method: bool,
+}
+
+enum Flow {
+    Normal,
+    Break,
+    Return(Vec<Value>),
+    Goto(String),
+}
+
+struct Frame {
+    varargs: Vec<Value>,
+}
+
+/// Tree-walking evaluator over the AST.
+pub struct Interpreter {
+    globals: TableRef,
+    out: Box<dyn Write>,
+    /// Stack address when `run` was entered, and how far below it calls may go
+    stack_base: usize,
+    stack_limit: usize,
+}
+
+impl Default for Interpreter {
+    fn default() -> Self {
+        Interpreter::new()
+    }
+}
+
+impl Interpreter {
+    pub fn new() -> Self {
+        Interpreter::with_output(Box::new(io::stdout()))
+    }
+
+    pub fn with_output(out: Box<dyn Write>) -> Self {
+        gc::reset();
+        let mut globals = Table::default();
+        stdlib::open_base(&mut globals);
+        Interpreter {
+            globals: new_table_ref(globals),
+            out,
+            stack_base: 0,
+            stack_limit: DEFAULT_STACK_LIMIT,
+        }
+    }
+
+    /// Bytes of native stack recursion may use, for callers running the
+    /// interpreter on a thread with a larger stack.
+    pub fn set_stack_limit(&mut self, bytes: usize) {
+        self.stack_limit = bytes;
+    }
+
+    pub fn run(&mut self, chunk: &Block) -> Result<Vec<Value>, RuntimeError> {
+        self.stack_base = stack_address();
+        let mut scope = Env::root();
+        let frame = Frame {
+            varargs: Vec::new(),
+        };
+        let result = match self.exec_stmts(&chunk.stmts, &mut scope, &frame) {
+            Ok(Flow::Return(values)) => Ok(values),
+            Ok(Flow::Normal) => Ok(Vec::new()),
+            Ok(Flow::Break) => Err(RuntimeError::new("break outside a loop")),
+            Ok(Flow::Goto(label)) => Err(no_label(&label)),
+            Err(e) => Err(e),
+        };
+        // Objects still alive when the program ends are finalized too
+        gc::finalize_all();
+        run_finalizers(self);
+        result
+    }
+
+    fn exec_block(
+        &mut self,
+        block: &Block,
+        parent: &Rc<Env>,
+        frame: &Frame,
+    ) -> Result<Flow, RuntimeError> {
+        let mut scope = Env::child(parent);
+        self.exec_stmts(&block.stmts, &mut scope, frame)
+    }
+
+    /// Runs statements in `scope`, which is left at the scope of the last
+    /// pass over them (see `exec_stmts_unclosed`), and closes it.
+    fn exec_stmts(
+        &mut self,
+        stmts: &[Stmt],
+        scope: &mut Rc<Env>,
+        frame: &Frame,
+    ) -> Result<Flow, RuntimeError> {
+        let base = scope.clone();
+        let result = self.exec_stmts_unclosed(stmts, scope, frame);
+        self.close_scopes(scope, &base, 0, result)
+    }
+
+    /// A backward goto starts a new pass over the statements after its
+    /// label, in a fresh child of the scope the label was reached in that
+    /// sees only the variables declared before the label. The locals after
+    /// it are declared anew, as in Lua, closures from the earlier pass keep
+    /// theirs and the bindings nothing captured are dropped.
+    fn exec_stmts_unclosed(
+        &mut self,
+        stmts: &[Stmt],
+        scope: &mut Rc<Env>,
+        frame: &Frame,
+    ) -> Result<Flow, RuntimeError> {
+        // The labels passed in this pass, with the scope they were reached
+        // in and how many variables and `<close>` values it held then
+        let mut labels: Vec<(usize, Rc<Env>, usize, usize)> = Vec::new();
+        let pass = |labels: &mut Vec<_>, at, scope: &Rc<Env>| {
+            labels.push((
+                at,
+                scope.clone(),
+                scope.visible(),
+                scope.closing.borrow().len(),
+            ));
+        };
+        let mut pc = 0;
+        while pc < stmts.len() {
+            if let StmtKind::Label(_) = stmts[pc].kind {
+                pass(&mut labels, pc, scope);
+            }
+            match self.exec_stmt(&stmts[pc], scope, frame)? {
+                Flow::Normal => pc += 1,
+                Flow::Goto(label) => {
+                    let target = stmts
+                        .iter()
+                        .position(|s| matches!(&s.kind, StmtKind::Label(n) if n.name == label));
+                    let Some(i) = target else {
+                        return Ok(Flow::Goto(label));
+                    };
+                    let Some(k) = labels.iter().position(|(at, ..)| *at == i) else {
+                        // A forward jump cannot skip a local declaration, so
+                        // the labels up to its target see the scope as it is
+                        for (at, stmt) in stmts.iter().enumerate().take(i).skip(pc + 1) {
+                            if let StmtKind::Label(_) = stmt.kind {
+                                pass(&mut labels, at, scope);
+                            }
+                        }
+                        pc = i;
+                        continue;
+                    };
+                    labels.truncate(k + 1);
+                    let (_, env, visible, level) = &labels[k];
+                    self.close_scopes(scope, env, *level, Ok(Flow::Normal))?;
+                    *scope = Env::child_visible(env, *visible);
+                    pc = i + 1;
+                }
+                flow => return Ok(flow),
+            }
+        }
+        Ok(Flow::Normal)
+    }
+
+    /// Closes the `<close>` variables of `scope` and of its parents up to
+    /// `base`, where only the ones above `level` are closed.
+    fn close_scopes(
+        &mut self,
+        scope: &Rc<Env>,
+        base: &Rc<Env>,
+        level: usize,
+        mut result: Result<Flow, RuntimeError>,
+    ) -> Result<Flow, RuntimeError> {
+        let mut env = scope.clone();
+        while !Rc::ptr_eq(&env, base) {
+            result = self.close_scope(&env, 0, result);
+            let parent = env.parent.as_ref().map(|(parent, _)| parent.clone());
+            env = parent.expect("pass scopes descend from their base");
+        }
+        self.close_scope(base, level, result)
+    }
+
+    /// Closes the `<close>` variables of a scope above `level`, newest first.
+    /// An error raised while closing replaces the result.
+    fn close_scope(
+        &mut self,
+        scope: &Env,
+        level: usize,
+        mut result: Result<Flow, RuntimeError>,
+    ) -> Result<Flow, RuntimeError> {
+        loop {
+            let value = {
+                let mut closing = scope.closing.borrow_mut();
+                if closing.len() <= level {
+                    break;
+                }
+                closing.pop().unwrap()
+            };
+            if let Err(e) = close_value(self, &value, result.as_ref().err().cloned()) {
+                result = Err(e);
+            }
+        }
+        result
+    }
+
+    fn exec_stmt(
+        &mut self,
+        stmt: &Stmt,
+        scope: &Rc<Env>,
+        frame: &Frame,
+    ) -> Result<Flow, RuntimeError> {
+        match &stmt.kind {
+            StmtKind::Local {
+                scope: var_scope,
+                ty,
+                names,
+                attribs,
+                values,
+            } => {
+                let mut evaluated = Vec::with_capacity(values.len());
+                for (i, expr) in values.iter().enumerate() {
+                    match (&expr.kind, ty) {
+                        // `T f = function() end` declares the return type
+                        (ExprKind::Function(func), Some(_)) if func.ret.is_none() => {
+                            evaluated.push(self.make_closure(func, scope, ty.clone(), false));
+                        }
+                        _ if i + 1 == values.len() => {
+                            evaluated.extend(self.eval_multi(expr, scope, frame)?)
+                        }
+                        _ => evaluated.push(self.eval(expr, scope, frame)?),
+                    }
+                }
+                // Only names left without an expression get a default,
+                // values missing from a call's results are nil
+                let ends_multi = values.last().is_some_and(Expr::is_multi);
+                let mut evaluated = evaluated.into_iter();
+                for (i, name) in names.iter().enumerate() {
+                    let value = values.get(i);
+                    let is_function =
+                        matches!(value, Some(v) if matches!(v.kind, ExprKind::Function(_)));
+                    // The declared type of a function literal is its return type
+                    let ty = ty.as_ref().filter(|_| !is_function);
+                    let mut value = match evaluated.next() {
+                        Some(value) => value,
+                        None if value.is_none() && !ends_multi => default_value(ty),
+                        None => Value::Nil,
+                    };
+                    if let Some(ty) = ty {
+                        let span = values.get(i).map_or(name.span, |v| v.span);
+                        value = conform(ty, value).map_err(|e| e.at(span))?;
+                    }
+                    if let Some(Attrib::Close) = attribs[i] {
+                        check_closable(&value, &name.name).map_err(|e| e.at(name.span))?;
+                        scope.closing.borrow_mut().push(value.clone());
+                    }
+                    match var_scope {
+                        Scope::Local => scope.declare(&name.name, Binding::new(value, ty.cloned())),
+                        Scope::Global => self
+                            .globals
+                            .borrow_mut()
+                            .set(Value::str(&name.name), value)?,
+                    }
+                }
+            }
+            StmtKind::Function {
+                scope: var_scope,
+                name,
+                func,
+            } => self.exec_function_decl(*var_scope, name, func, scope)?,
+            StmtKind::Assign { targets, values } => {
+                self.exec_assign(targets, values, scope, frame)?
+            }
+            StmtKind::Call(expr) => {
+                self.eval_multi(expr, scope, frame)?;
+            }
+            StmtKind::Expr(expr) => {
+                // A fastcall function written as a statement runs right away
+                if let ExprKind::Function(func) = &expr.kind {
+                    if func.fastcall {
+                        let closure = self.make_closure(func, scope, func.ret.clone(), false);
+                        self.call_value(&closure, Vec::new())
+                            .map_err(|e| e.at(expr.span))?;
+                    }
+                }
+            }
+            StmtKind::Do(body) => return self.exec_block(body, scope, frame),
+            StmtKind::While { cond, body } => {
+                while self.eval(cond, scope, frame)?.is_truthy() {
+                    match self.exec_block(body, scope, frame)? {
+                        Flow::Break => break,
+                        Flow::Normal => {}
+                        flow => return Ok(flow),
+                    }
+                }
+            }
+            StmtKind::Repeat { body, cond } => loop {
+                // The condition can see locals declared in the body
+                let mut inner = Env::child(scope);
+                match self.exec_stmts(&body.stmts, &mut inner, frame)? {
+                    Flow::Break => break,
+                    Flow::Normal => {}
+                    flow => return Ok(flow),
+                }
+                if self.eval(cond, &inner, frame)?.is_truthy() {
+                    break;
+                }
+            },
+            StmtKind::If {
+                branches,
+                else_block,
+            } => {
+                for (cond, body) in branches {
+                    if self.eval(cond, scope, frame)?.is_truthy() {
+                        return self.exec_block(body, scope, frame);
+                    }
+                }
+                if let Some(body) = else_block {
+                    return self.exec_block(body, scope, frame);
+                }
+            }
+            StmtKind::NumericFor {
+                var,
+                start,
+                limit,
+                step,
+                body,
+            } => {
+                return self.exec_numeric_for(var, start, limit, step.as_ref(), body, scope, frame)
+            }
+            StmtKind::GenericFor { names, exprs, body } => {
+                let mut init = self.eval_list(exprs, scope, frame)?.into_iter();
+                let func = init.next().unwrap_or_default();
+                let state = init.next().unwrap_or_default();
+                let mut control = init.next().unwrap_or_default();
+                loop {
+                    let results = self
+                        .call_value(&func, vec![state.clone(), control.clone()])
+                        .map_err(|e| e.at(stmt.span))?;
+                    let first = results.first().cloned().unwrap_or_default();
+                    if matches!(first, Value::Nil) {
+                        break;
+                    }
+                    control = first;
+                    // Every iteration gets fresh variables, closures created
+                    // in the body keep the values of their own iteration
+                    let inner = Env::child(scope);
+                    let mut results = results.into_iter();
+                    for name in names {
+                        inner.declare(
+                            &name.name,
+                            Binding::new(results.next().unwrap_or_default(), None),
+                        );
+                    }
+                    match self.exec_block(body, &inner, frame)? {
+                        Flow::Break => break,
+                        Flow::Normal => {}
+                        flow => return Ok(flow),
+                    }
+                }
+            }
+            StmtKind::Return(values) => {
+                return Ok(Flow::Return(self.eval_list(values, scope, frame)?));
+            }
+            StmtKind::Break => return Ok(Flow::Break),
+            StmtKind::Goto(label) => return Ok(Flow::Goto(label.name.clone())),
+            StmtKind::Label(_) => {}
+        }
+        Ok(Flow::Normal)
+    }
+
+    fn exec_function_decl(
+        &mut self,
+        var_scope: Scope,
+        name: &FunctionName,
+        func: &Rc<Function>,
+        scope: &Rc<Env>,
+    ) -> Result<(), RuntimeError> {
+        let first = &name.path[0];
+        if name.path.len() == 1 && name.method.is_none() {
+            match var_scope {
+                Scope::Local => {
+                    // Declared before the closure is made so it can recurse
+                    let binding = Binding::new(Value::Nil, None);
+                    scope.declare(&first.name, binding.clone());
+                    let closure = self.make_closure(func, scope, func.ret.clone(), false);
+                    binding.set(closure)?;
+                }
+                Scope::Global => {
+                    let closure = self.make_closure(func, scope, func.ret.clone(), false);
+                    self.globals
+                        .borrow_mut()
+                        .set(Value::str(&first.name), closure)?;
+                }
+            }
+            return Ok(());
+        }
+        let mut object = self.lookup(&first.name, scope);
+        let (last, path) = match &name.method {
+            Some(method) => (method, &name.path[1..]),
+            None => name.path[1..].split_last().unwrap(),
+        };
+        for field in path {
+            object =
+                index(self, &object, &Value::str(&field.name)).map_err(|e| e.at(field.span))?;
+        }
+        let closure = self.make_closure(func, scope, func.ret.clone(), name.method.is_some());
+        set_index(self, &object, Value::str(&last.name), closure).map_err(|e| e.at(last.span))
+    }
+
+    fn exec_assign(
+        &mut self,
+        targets: &[Expr],
+        values: &[Expr],
+        scope: &Rc<Env>,
+        frame: &Frame,
+    ) -> Result<(), RuntimeError> {
+        enum Place<'a> {
+            Name(&'a str),
+            Index(Value, Value),
+        }
+        let mut places = Vec::with_capacity(targets.len());
+        for target in targets {
+            places.push(match &target.kind {
+                ExprKind::Name(name) => Place::Name(name),
+                ExprKind::Index { object, key } => Place::Index(
+                    self.eval(object, scope, frame)?,
+                    self.eval(key, scope, frame)?,
+                ),
+                _ => unreachable!("parser only produces names and indexes as targets"),
+            });
+        }
+        let mut values = self.eval_list(values, scope, frame)?.into_iter();
+        for (place, target) in places.into_iter().zip(targets) {
+            let value = values.next().unwrap_or_default();
+            match place {
+                Place::Name(name) => self.assign_name(name, value, scope),
+                Place::Index(object, key) => set_index(self, &object, key, value),
+            }
+            .map_err(|e| e.at(target.span))?;
+        }
+        Ok(())
+    }
+
+    /// Assigns to a visible local, or else to a global as in Lua.
+    fn assign_name(
+        &mut self,
+        name: &str,
+        value: Value,
+        scope: &Rc<Env>,
+    ) -> Result<(), RuntimeError> {
+        match scope.lookup(name) {
+            Some(binding) => binding.set(value),
+            None => self.globals.borrow_mut().set(Value::str(name), value),
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn exec_numeric_for(
+        &mut self,
+        var: &Name,
+        start: &Expr,
+        limit: &Expr,
+        step: Option<&Expr>,
+        body: &Block,
+        scope: &Rc<Env>,
+        frame: &Frame,
+    ) -> Result<Flow, RuntimeError> {
+        let mut numbers = Vec::with_capacity(3);
+        for (expr, what) in [
+            (Some(start), "initial"),
+            (Some(limit), "limit"),
+            (step, "step"),
+        ] {
+            let value = match expr {
+                Some(expr) => self.eval(expr, scope, frame)?,
+                None => Value::Int(1),
+            };
+            match value.to_number() {
+                Some(n) => numbers.push(n),
+                None => {
+                    return Err(
+                        RuntimeError::new(format!("'for' {} value must be a number", what))
+                            .at(var.span),
+                    )
+                }
+            }
+        }
+        let run_body = |this: &mut Self, value: Value| -> Result<Option<Flow>, RuntimeError> {
+            let inner = Env::child(scope);
+            inner.declare(&var.name, Binding::new(value, None));
+            match this.exec_block(body, &inner, frame)? {
+                Flow::Break => Ok(Some(Flow::Normal)),
+                Flow::Normal => Ok(None),
+                flow => Ok(Some(flow)),
+            }
+        };
+        match (&numbers[0], &numbers[1], &numbers[2]) {
+            (Value::Int(start), limit, Value::Int(step)) => {
+                let (start, step) = (*start, *step);
+                if step == 0 {
+                    return Err(RuntimeError::new("'for' step is zero").at(var.span));
+                }
+                // Clip a float limit to the integer range, as Lua does
+                let limit = match limit {
+                    Value::Int(l) => *l,
+                    Value::Float(f) if step > 0 => {
+                        f.floor().clamp(i64::MIN as f64, i64::MAX as f64) as i64
+                    }
+                    Value::Float(f) => f.ceil().clamp(i64::MIN as f64, i64::MAX as f64) as i64,
+                    _ => unreachable!(),
+                };
+                if (step > 0 && start > limit) || (step < 0 && start < limit) {
+                    return Ok(Flow::Normal);
+                }
+                // Iteration count computed up front so the loop cannot overflow
+                let mut count = if step > 0 {
+                    (limit as u64).wrapping_sub(start as u64) / step as u64
+                } else {
+                    (start as u64).wrapping_sub(limit as u64) / (step as u64).wrapping_neg()
+                };
+                let mut i = start;
+                loop {
+                    if let Some(flow) = run_body(self, Value::Int(i))? {
+                        return Ok(flow);
+                    }
+                    if count == 0 {
+                        break;
+                    }
+                    count -= 1;
+                    i = i.wrapping_add(step);
+                }
+            }
+            (start, limit, step) => {
+                let (mut i, limit, step) = (
+                    start.to_float().unwrap(),
+                    limit.to_float().unwrap(),
+                    step.to_float().unwrap(),
+                );
+                if step == 0.0 {
+                    return Err(RuntimeError::new("'for' step is zero").at(var.span));
+                }
+                while (step > 0.0 && i <= limit) || (step < 0.0 && i >= limit) {
+                    if let Some(flow) = run_body(self, Value::Float(i))? {
+                        return Ok(flow);
+                    }
+                    i += step;
+                }
+            }
+        }
+        Ok(Flow::Normal)
+    }
+
+    fn make_closure(
+        &mut self,
+        func: &Rc<Function>,
+        scope: &Rc<Env>,
+        ret: Option<TypeName>,
+        is_method: bool,
+    ) -> Value {
+        Value::Function(gc::track(Rc::new(Callable::Closure(Closure {
+            func: func.clone(),
+            scope: scope.clone(),
+            visible: scope.visible(),
+            ret,
+            is_method,
+        }))))
+    }
+
+    pub fn call_value(
+        &mut self,
+        func: &Value,
+        mut args: Vec<Value>,
+    ) -> Result<Vec<Value>, RuntimeError> {
+        if gc::has_pending() {
+            run_finalizers(self);
+        }
+        let Value::Function(f) = func else {
+            if let Some(handler) = call_handler(func) {
+                args.insert(0, func.clone());
+                return self.call_value(&handler, args);
+            }
+            return Err(call_error(func));
+        };
+        if stack_address().abs_diff(self.stack_base) > self.stack_limit {
+            return Err(RuntimeError::new("stack overflow"));
+        }
+        match &**f {
+            Callable::Native(native) => (native.func)(self, args),
+            Callable::Closure(closure) => self.call_closure(closure, args),
+            Callable::Bytecode(_) => Err(RuntimeError::new(
+                "cannot call a bytecode function from the interpreter",
+            )),
+        }
+    }
+
+    fn call_closure(
+        &mut self,
+        closure: &Closure,
+        args: Vec<Value>,
+    ) -> Result<Vec<Value>, RuntimeError> {
+        let func = &closure.func;
+        let mut scope = Env::child_visible(&closure.scope, closure.visible);
+        let mut args = args.into_iter();
+        if closure.is_method {
+            scope.declare("self", Binding::new(args.next().unwrap_or_default(), None));
+        }
+        for param in &func.params {
+            let mut value = args.next().unwrap_or_default();
+            if let Some(ty) = &param.ty {
+                value = conform(ty, value).map_err(|e| e.at(param.name.span))?;
+            }
+            scope.declare(&param.name.name, Binding::new(value, param.ty.clone()));
+        }
+        let frame = Frame {
+            varargs: if func.is_vararg {
+                args.collect()
+            } else {
+                Vec::new()
+            },
+        };
+        let mut values = match self.exec_stmts(&func.body.stmts, &mut scope, &frame)? {
+            Flow::Return(values) => values,
+            Flow::Normal => Vec::new(),
+            Flow::Break => return Err(RuntimeError::new("break outside a loop")),
+            Flow::Goto(label) => return Err(no_label(&label)),
+        };
+        match &closure.ret {
+            Some(TypeName::Void) => values.clear(),
+            Some(ty) => {
+                if let Some(first) = values.first_mut() {
+                    *first = conform(ty, std::mem::take(first))?;
+                }
+            }
+            None => {}
+        }
+        Ok(values)
+    }
+
+    fn lookup(&self, name: &str, scope: &Rc<Env>) -> Value {
+        match scope.lookup(name) {
+            Some(binding) => binding.value.borrow().clone(),
+            None => self.globals.borrow().get(&Value::str(name)),
+        }
+    }
+
+    fn eval(&mut self, expr: &Expr, scope: &Rc<Env>, frame: &Frame) -> Result<Value, RuntimeError> {
+        self.eval_inner(expr, scope, frame)
+            .map_err(|e| e.at(expr.span))
+    }
+
+    /// Evaluates an expression that may produce several values: calls and `...`.
+    fn eval_multi(
+        &mut self,
+        expr: &Expr,
+        scope: &Rc<Env>,
+        frame: &Frame,
+    ) -> Result<Vec<Value>, RuntimeError> {
+        match &expr.kind {
+            ExprKind::Vararg => Ok(frame.varargs.clone()),
+            ExprKind::Call { func, args } => {
+                let func = self.eval(func, scope, frame)?;
+                let args = self.eval_list(args, scope, frame)?;
+                self.call_value(&func, args).map_err(|e| e.at(expr.span))
+            }
+            ExprKind::MethodCall {
+                object,
+                method,
+                args,
+            } => {
+                let object = self.eval(object, scope, frame)?;
+                let func =
+                    index(self, &object, &Value::str(&method.name)).map_err(|e| e.at(expr.span))?;
+                let mut call_args = vec![object];
+                call_args.extend(self.eval_list(args, scope, frame)?);
+                self.call_value(&func, call_args)
+                    .map_err(|e| e.at(expr.span))
+            }
+            _ => Ok(vec![self.eval(expr, scope, frame)?]),
+        }
+    }
+
+    /// Evaluates a comma separated list, expanding only the last expression.
+    fn eval_list(
+        &mut self,
+        exprs: &[Expr],
+        scope: &Rc<Env>,
+        frame: &Frame,
+    ) -> Result<Vec<Value>, RuntimeError> {
+        let mut values = Vec::with_capacity(exprs.len());
+        for (i, expr) in exprs.iter().enumerate() {
+            if i + 1 == exprs.len() {
+                values.extend(self.eval_multi(expr, scope, frame)?);
+            } else {
+                values.push(self.eval(expr, scope, frame)?);
+            }
+        }
+        Ok(values)
+    }
+
+    fn eval_inner(
+        &mut self,
+        expr: &Expr,
+        scope: &Rc<Env>,
+        frame: &Frame,
+    ) -> Result<Value, RuntimeError> {
+        Ok(match &expr.kind {
+            ExprKind::Nil => Value::Nil,
+            ExprKind::True => Value::Bool(true),
+            ExprKind::False => Value::Bool(false),
+            ExprKind::Integer(n) => Value::Int(*n),
+            ExprKind::Float(n) => Value::Float(*n),
+            ExprKind::String(s) => Value::bytes(s.as_slice()),
+            ExprKind::Char(c) => Value::str(c.encode_utf8(&mut [0; 4])),
+            ExprKind::Vararg | ExprKind::Call { .. } | ExprKind::MethodCall { .. } => self
+                .eval_multi(expr, scope, frame)?
+                .into_iter()
+                .next()
+                .unwrap_or_default(),
+            ExprKind::Function(func) => self.make_closure(func, scope, func.ret.clone(), false),
+            ExprKind::Table(fields) => {
+                let table = Value::new_table();
+                let Value::Table(t) = &table else {
+                    unreachable!()
+                };
+                let mut next_index = 1;
+                for (i, field) in fields.iter().enumerate() {
+                    match field {
+                        TableField::Positional(value) if i + 1 == fields.len() => {
+                            for value in self.eval_multi(value, scope, frame)? {
+                                t.borrow_mut().set(Value::Int(next_index), value)?;
+                                next_index += 1;
+                            }
+                        }
+                        TableField::Positional(value) => {
+                            let value = self.eval(value, scope, frame)?;
+                            t.borrow_mut().set(Value::Int(next_index), value)?;
+                            next_index += 1;
+                        }
+                        TableField::Named { name, ty, value } => {
+                            let key = Value::str(&name.name);
+                            let (v, span) = match (&value.kind, ty) {
+                                (ExprKind::Function(func), Some(_)) if func.ret.is_none() => {
+                                    let closure = self.make_closure(func, scope, ty.clone(), false);
+                                    (closure, name.span)
+                                }
+                                (_, Some(ty)) => {
+                                    if let Value::Str(name) = &key {
+                                        t.borrow_mut().type_field(name.clone(), ty.clone());
+                                    }
+                                    (self.eval(value, scope, frame)?, value.span)
+                                }
+                                (_, None) => (self.eval(value, scope, frame)?, name.span),
+                            };
+                            let v = t.borrow().conform_field(&key, v).map_err(|e| e.at(span))?;
+                            t.borrow_mut().set(key, v)?;
+                        }
+                        TableField::Keyed { key, value } => {
+                            let k = self.eval(key, scope, frame)?;
+                            let v = self.eval(value, scope, frame)?;
+                            let v = t
+                                .borrow()
+                                .conform_field(&k, v)
+                                .map_err(|e| e.at(key.span))?;
+                            t.borrow_mut().set(k, v).map_err(|e| e.at(key.span))?;
+                        }
+                    }
+                }
+                table
+            }
+            ExprKind::Vec(items) => Value::new_vec(self.eval_list(items, scope, frame)?),
+            ExprKind::Name(name) => self.lookup(name, scope),
+            ExprKind::Index { object, key } => {
+                let object = self.eval(object, scope, frame)?;
+                let key = self.eval(key, scope, frame)?;
+                index(self, &object, &key)?
+            }
+            ExprKind::Paren(inner) => self.eval(inner, scope, frame)?,
+            ExprKind::Cast { ty, expr } => {
+                let value = self.eval(expr, scope, frame)?;
+                cast(ty, value)?
+            }
+            ExprKind::Unary { op, expr } => {
+                let value = self.eval(expr, scope, frame)?;
+                unary(self, *op, &value)?
+            }
+            ExprKind::Binary { op, lhs, rhs } => {
+                let left = self.eval(lhs, scope, frame)?;
+                match op {
+                    BinOp::And if !left.is_truthy() => return Ok(left),
+                    BinOp::Or if left.is_truthy() => return Ok(left),
+                    BinOp::And | BinOp::Or => return self.eval(rhs, scope, frame),
+                    _ => {}
+                }
+                let right = self.eval(rhs, scope, frame)?;
+                binary(self, *op, &left, &right)?
+            }
+        })
+    }
+}
+
+fn no_label(label: &str) -> RuntimeError {
+    RuntimeError::new(format!("no visible label '{}' for goto", label))
+}
+
+impl Host for Interpreter {
+    fn call(&mut self, func: &Value, args: Vec<Value>) -> Result<Vec<Value>, RuntimeError> {
+        self.call_value(func, args)
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> Result<(), RuntimeError> {
+        self.out
+            .write_all(bytes)
+            .map_err(|e| RuntimeError::new(e.to_string()))
+    }
+}
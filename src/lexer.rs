@@ -1,12 +1,20 @@
+use std::fmt;
 use std::iter::Peekable;
 use std::str::Chars;
 
+use crate::span::{FileId, Position, Span};
+
 #[derive(Debug, PartialEq, Clone)]
 pub enum Token {
     // Identifiers and literals
     Ident(String),
-    StringLiteral(String),
-    NumberLiteral(f64),
+    /// Byte string, escapes already decoded
+    StringLiteral(Vec<u8>),
+    /// Single quoted literal holding exactly one character
+    CharLiteral(char),
+    /// Numeral without a fraction or exponent that fits in 64 bits
+    IntegerLiteral(i64),
+    FloatLiteral(f64),
 
     // Keywords
     And,
@@ -53,6 +61,13 @@ pub enum Token {
     Mod,
     Pow,
     Len,
+    FloorDiv,
+    BitAnd,
+    BitOr,
+    /// `~`, binary exclusive or and unary bitwise not
+    Tilde,
+    Shl,
+    Shr,
     Eq,
     Ne,
     Le,
@@ -74,245 +89,576 @@ pub enum Token {
     Concat,
     Ellipsis,
 
-    // Comments and whitespace (filtered out)
-    Comment(String),
-
     // End of input
     Eof,
 }
 
+#[derive(Debug, PartialEq, Clone)]
+pub enum LexErrorKind {
+    UnexpectedChar(char),
+    UnterminatedString,
+    UnterminatedLongComment,
+    UnterminatedLongString,
+    MalformedNumber(String),
+    InvalidEscape(String),
+}
+
+impl fmt::Display for LexErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LexErrorKind::UnexpectedChar(c) => write!(f, "unexpected character '{}'", c),
+            LexErrorKind::UnterminatedString => write!(f, "unterminated string literal"),
+            LexErrorKind::UnterminatedLongComment => write!(f, "unterminated long comment"),
+            LexErrorKind::UnterminatedLongString => write!(f, "unterminated long string"),
+            LexErrorKind::MalformedNumber(text) => write!(f, "malformed number near '{}'", text),
+            LexErrorKind::InvalidEscape(text) => write!(f, "invalid escape sequence '{}'", text),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct LexError {
+    pub kind: LexErrorKind,
+    pub span: Span,
+}
+
+impl fmt::Display for LexError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.span, self.kind)
+    }
+}
+
+impl std::error::Error for LexError {}
+
+type LexResult = Result<Token, LexErrorKind>;
+
+/// What a piece of trivia is.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum TriviaKind {
+    /// A run of whitespace other than newlines
+    Whitespace,
+    /// One newline sequence: `\n`, `\r`, `\r\n` or `\n\r`
+    Newline,
+    /// `-- ...` up to the end of the line, without the newline
+    LineComment,
+    /// `--[[ ... ]]`, `--[=[ ... ]=]`, ... with the number of `=`
+    LongComment { level: usize },
+}
+
+/// Source text between tokens that the parser never sees.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Trivia {
+    pub kind: TriviaKind,
+    /// The exact source text
+    pub text: String,
+    pub span: Span,
+}
+
+/// A token together with the source region it was read from.
+#[derive(Debug, PartialEq, Clone)]
+pub struct SpannedToken {
+    pub token: Token,
+    pub span: Span,
+}
+
+impl SpannedToken {
+    pub fn new(token: Token, span: Span) -> Self {
+        SpannedToken { token, span }
+    }
+}
+
+impl PartialEq<Token> for SpannedToken {
+    fn eq(&self, other: &Token) -> bool {
+        self.token == *other
+    }
+}
+
+/// A token with the trivia around it, as produced by
+/// `Lexer::tokenize_lossless`. Trailing trivia runs up to the end of the
+/// token's line, everything after that leads the next token, so the `Eof`
+/// token holds whatever follows the last one. Writing out every token in
+/// order gives back the source byte for byte.
+#[derive(Debug, PartialEq, Clone)]
+pub struct LosslessToken {
+    pub token: SpannedToken,
+    /// The exact source text of the token, empty for `Eof`
+    pub text: String,
+    pub leading: Vec<Trivia>,
+    pub trailing: Vec<Trivia>,
+}
+
+impl fmt::Display for LosslessToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for trivia in &self.leading {
+            f.write_str(&trivia.text)?;
+        }
+        f.write_str(&self.text)?;
+        for trivia in &self.trailing {
+            f.write_str(&trivia.text)?;
+        }
+        Ok(())
+    }
+}
+
 #[derive(Debug)]
 pub struct Lexer<'a> {
+    source: &'a str,
     input: Peekable<Chars<'a>>,
+    file_id: FileId,
+    pos: Position,
 }
 
 impl<'a> Lexer<'a> {
     pub fn new(input: &'a str) -> Self {
+        Lexer::with_file_id(input, 0)
+    }
+
+    pub fn with_file_id(input: &'a str, file_id: FileId) -> Self {
         Lexer {
+            source: input,
             input: input.chars().peekable(),
+            file_id,
+            pos: Position::default(),
         }
     }
 
-    pub fn tokenize(&mut self) -> Vec<Token> {
+    /// Lexes the whole input. Lexing keeps going after an error so that every
+    /// problem in the source is reported at once.
+    pub fn tokenize(&mut self) -> Result<Vec<SpannedToken>, Vec<LexError>> {
         let mut tokens = Vec::new();
-        while let Some(token) = self.next_token() {
-            match token {
-                Token::Comment(_) => continue,
-                _ => tokens.push(token),
+        let mut errors = Vec::new();
+        loop {
+            self.read_trivia(None, true, &mut errors);
+            let start = self.pos;
+            let Some(result) = self.next_token() else {
+                break;
+            };
+            let span = self.span_from(start);
+            match result {
+                Ok(token) => tokens.push(SpannedToken::new(token, span)),
+                Err(kind) => errors.push(LexError { kind, span }),
             }
         }
-        tokens.push(Token::Eof);
-        tokens
+        tokens.push(SpannedToken::new(Token::Eof, self.span_from(self.pos)));
+        if errors.is_empty() {
+            Ok(tokens)
+        } else {
+            Err(errors)
+        }
     }
 
-    fn next_token(&mut self) -> Option<Token> {
-        self.skip_whitespace();
-        let c = self.input.next()?;
+    /// Like `tokenize`, but keeps whitespace and comments as trivia on the
+    /// tokens, for tools that need to reproduce the source.
+    pub fn tokenize_lossless(&mut self) -> Result<Vec<LosslessToken>, Vec<LexError>> {
+        let mut tokens = Vec::new();
+        let mut errors = Vec::new();
+        let mut leading = Vec::new();
+        loop {
+            self.read_trivia(Some(&mut leading), true, &mut errors);
+            let start = self.pos;
+            let Some(result) = self.next_token() else {
+                break;
+            };
+            let span = self.span_from(start);
+            match result {
+                Ok(token) => {
+                    let text = self.source[start.offset..span.end.offset].to_string();
+                    let mut trailing = Vec::new();
+                    self.read_trivia(Some(&mut trailing), false, &mut errors);
+                    tokens.push(LosslessToken {
+                        token: SpannedToken::new(token, span),
+                        text,
+                        leading: std::mem::take(&mut leading),
+                        trailing,
+                    });
+                }
+                Err(kind) => errors.push(LexError { kind, span }),
+            }
+        }
+        tokens.push(LosslessToken {
+            token: SpannedToken::new(Token::Eof, self.span_from(self.pos)),
+            text: String::new(),
+            leading,
+            trailing: Vec::new(),
+        });
+        if errors.is_empty() {
+            Ok(tokens)
+        } else {
+            Err(errors)
+        }
+    }
 
-        match c {
-            // Single-character tokens
-            '+' => Some(Token::Plus),
-            '-' => {
-                // Check if this is a negative number
-                if let Some(&next_char) = self.input.peek() {
-                    if next_char.is_ascii_digit() {
-                        // Process as negative number
-                        let next_char = self.input.next().unwrap();
-                        return self.read_number(next_char, true);
+    /// Reads trivia up to the next token, into `out` if given. Without
+    /// `multiline` it stops before the first newline.
+    fn read_trivia(
+        &mut self,
+        mut out: Option<&mut Vec<Trivia>>,
+        multiline: bool,
+        errors: &mut Vec<LexError>,
+    ) {
+        loop {
+            let start = self.pos;
+            let kind = match self.input.peek().copied() {
+                Some('\n' | '\r') if multiline => {
+                    self.read_newline();
+                    TriviaKind::Newline
+                }
+                Some(c) if c.is_whitespace() && !matches!(c, '\n' | '\r') => {
+                    while self
+                        .input
+                        .peek()
+                        .is_some_and(|&c| c.is_whitespace() && !matches!(c, '\n' | '\r'))
+                    {
+                        self.advance();
                     }
-                    if next_char == '.' {
-                        /*
-
-                        creating tmp clone of the input for multipeek
-                        if its a digit we advance the original input and drop the clone
-                        otherwise we just drop the tmp_input for fast free allocation
-
-                        */
-                        let mut tmp_input = self.input.clone();
-                        tmp_input.next()?;
-
-                        if let Some(&next_next_char) = tmp_input.peek() {
-                            if next_next_char.is_ascii_digit() {
-                                self.input.next()?;
-                                drop(tmp_input);
-                                return self.read_number(next_char, true);
-                            }
+                    TriviaKind::Whitespace
+                }
+                Some('-') if self.input.clone().nth(1) == Some('-') => {
+                    self.advance();
+                    self.advance();
+                    match self.read_comment() {
+                        Ok(kind) => kind,
+                        Err(kind) => {
+                            errors.push(LexError {
+                                kind,
+                                span: self.span_from(start),
+                            });
+                            continue;
                         }
-                        drop(tmp_input);
                     }
                 }
+                _ => return,
+            };
+            if let Some(out) = out.as_deref_mut() {
+                out.push(Trivia {
+                    kind,
+                    text: self.source[start.offset..self.pos.offset].to_string(),
+                    span: self.span_from(start),
+                });
+            }
+        }
+    }
 
-                // Otherwise handle as normal minus or comment
-                if self.match_char('-') {
-                    self.read_comment()
+    fn next_token(&mut self) -> Option<LexResult> {
+        let c = self.advance()?;
+
+        match c {
+            // Single-character tokens
+            '+' => Some(Ok(Token::Plus)),
+            // Comments were already read as trivia, and a sign is never part
+            // of a numeral: `-1` is the unary operator applied to `1`
+            '-' => Some(Ok(Token::Minus)),
+            '*' => Some(Ok(Token::Mul)),
+            '/' => {
+                if self.match_char('/') {
+                    Some(Ok(Token::FloorDiv))
                 } else {
-                    Some(Token::Minus)
+                    Some(Ok(Token::Div))
                 }
             }
-            '*' => Some(Token::Mul),
-            '/' => Some(Token::Div),
-            '%' => Some(Token::Mod),
-            '^' => Some(Token::Pow),
-            '#' => Some(Token::Len),
-            '(' => Some(Token::LParen),
-            ')' => Some(Token::RParen),
-            '{' => Some(Token::LBrace),
-            '}' => Some(Token::RBrace),
-            '[' => Some(Token::LBracket),
-            ']' => Some(Token::RBracket),
-            ';' => Some(Token::Semicolon),
-            ',' => Some(Token::Comma),
+            '%' => Some(Ok(Token::Mod)),
+            '^' => Some(Ok(Token::Pow)),
+            '#' => Some(Ok(Token::Len)),
+            '&' => Some(Ok(Token::BitAnd)),
+            '|' => Some(Ok(Token::BitOr)),
+            '(' => Some(Ok(Token::LParen)),
+            ')' => Some(Ok(Token::RParen)),
+            '{' => Some(Ok(Token::LBrace)),
+            '}' => Some(Ok(Token::RBrace)),
+            '[' => match self.long_bracket_level() {
+                Some(level) => Some(
+                    self.read_long_bracket(level)
+                        .map(|s| Token::StringLiteral(s.into_bytes()))
+                        .ok_or(LexErrorKind::UnterminatedLongString),
+                ),
+                None => Some(Ok(Token::LBracket)),
+            },
+            ']' => Some(Ok(Token::RBracket)),
+            ';' => Some(Ok(Token::Semicolon)),
+            ',' => Some(Ok(Token::Comma)),
             '.' => {
                 if let Some(&next_char) = self.input.peek() {
                     if next_char.is_ascii_digit() {
-                        return self.read_number(c, false);
+                        return Some(self.read_number(c));
                     }
                 }
                 if self.match_char('.') {
                     if self.match_char('.') {
-                        Some(Token::Ellipsis)
+                        Some(Ok(Token::Ellipsis))
                     } else {
-                        Some(Token::Concat)
+                        Some(Ok(Token::Concat))
                     }
                 } else {
-                    Some(Token::Dot)
+                    Some(Ok(Token::Dot))
                 }
             }
             ':' => {
                 if self.match_char(':') {
-                    Some(Token::DoubleColon)
+                    Some(Ok(Token::DoubleColon))
                 } else {
-                    Some(Token::Colon)
+                    Some(Ok(Token::Colon))
                 }
             }
             '=' => {
                 if self.match_char('=') {
-                    Some(Token::Eq)
+                    Some(Ok(Token::Eq))
                 } else {
-                    Some(Token::Assign)
+                    Some(Ok(Token::Assign))
                 }
             }
             '<' => {
                 if self.match_char('=') {
-                    Some(Token::Le)
+                    Some(Ok(Token::Le))
+                } else if self.match_char('<') {
+                    Some(Ok(Token::Shl))
                 } else {
-                    Some(Token::Lt)
+                    Some(Ok(Token::Lt))
                 }
             }
             '>' => {
                 if self.match_char('=') {
-                    Some(Token::Ge)
+                    Some(Ok(Token::Ge))
+                } else if self.match_char('>') {
+                    Some(Ok(Token::Shr))
                 } else {
-                    Some(Token::Gt)
+                    Some(Ok(Token::Gt))
                 }
             }
             '~' => {
                 if self.match_char('=') {
-                    Some(Token::Ne)
+                    Some(Ok(Token::Ne))
                 } else {
-                    panic!("Unexpected character '~'");
+                    Some(Ok(Token::Tilde))
                 }
             }
-            '"' | '\'' => self.read_string(c),
+            '"' | '\'' => Some(self.read_string(c)),
 
             // Identifiers and keywords
-            c if c.is_alphabetic() || c == '_' => self.read_identifier(c),
+            c if c.is_alphabetic() || c == '_' => Some(Ok(self.read_identifier(c))),
 
             // Numbers
-            c if c.is_ascii_digit() => self.read_number(c, false),
+            c if c.is_ascii_digit() => Some(self.read_number(c)),
 
-            _ => None,
+            c => Some(Err(LexErrorKind::UnexpectedChar(c))),
         }
     }
 
-    fn read_comment(&mut self) -> Option<Token> {
+    /// Reads a comment after its `--`.
+    fn read_comment(&mut self) -> Result<TriviaKind, LexErrorKind> {
         if self.match_char('[') {
-            self.read_long_comment()
-        } else {
-            self.read_line_comment()
-        }
-    }
-
-    fn read_line_comment(&mut self) -> Option<Token> {
-        let mut content = String::new();
-        while let Some(&c) = self.input.peek() {
-            if c == '\n' {
-                break;
+            if let Some(level) = self.long_bracket_level() {
+                return self
+                    .read_long_bracket(level)
+                    .map(|_| TriviaKind::LongComment { level })
+                    .ok_or(LexErrorKind::UnterminatedLongComment);
             }
-            content.push(c);
-            self.input.next();
+            // Not a long bracket after all, the rest of the line is the comment
+        }
+        while self
+            .input
+            .peek()
+            .is_some_and(|&c| !matches!(c, '\n' | '\r'))
+        {
+            self.advance();
         }
-        Some(Token::Comment(content))
+        Ok(TriviaKind::LineComment)
     }
 
-    fn read_long_comment(&mut self) -> Option<Token> {
-        let mut content = String::new();
+    /// Called just after a `[`. If it opens a long bracket (`[[`, `[=[`,
+    /// `[==[`, ...) consumes the rest of the opening and returns its level,
+    /// the number of `=`; otherwise consumes nothing.
+    fn long_bracket_level(&mut self) -> Option<usize> {
+        let mut ahead = self.input.clone();
         let mut level = 0;
-
-        // Check for opening long bracket
-        while self.match_char('=') {
+        while ahead.next_if_eq(&'=').is_some() {
             level += 1;
         }
-
-        if !self.match_char('[') {
-            // Fallback to line comment
-            content.push('[');
-            return Some(Token::Comment(content));
+        if ahead.peek() != Some(&'[') {
+            return None;
         }
+        for _ in 0..=level {
+            self.advance();
+        }
+        Some(level)
+    }
 
-        // Read until matching closing bracket
-        while let Some(c) = self.input.next() {
-            if c == ']' {
-                let mut closing_level = 0;
-                while self.match_char('=') && closing_level < level {
-                    closing_level += 1;
-                }
-                if closing_level == level && self.match_char(']') {
-                    break;
-                } else {
-                    content.push(']');
-                    for _ in 0..closing_level {
-                        content.push('=');
+    /// Reads the body of a long bracket up to the closing bracket of the
+    /// same level, which is consumed. As in Lua, a newline right after the
+    /// opening bracket is skipped and every newline sequence (`\n`, `\r`,
+    /// `\r\n` or `\n\r`) reads as `\n`. Returns `None` at end of input.
+    fn read_long_bracket(&mut self, level: usize) -> Option<String> {
+        let mut content = String::new();
+        if matches!(self.input.peek(), Some('\n' | '\r')) {
+            self.read_newline();
+        }
+        loop {
+            match self.advance()? {
+                ']' => {
+                    let mut closing_level = 0;
+                    while self.match_char('=') {
+                        closing_level += 1;
                     }
+                    if closing_level == level && self.match_char(']') {
+                        return Some(content);
+                    }
+                    content.push(']');
+                    content.extend(std::iter::repeat_n('=', closing_level));
                 }
-            } else {
-                content.push(c);
+                '\n' | '\r' => content.push('\n'),
+                c => content.push(c),
             }
         }
+    }
 
-        Some(Token::Comment(content))
+    /// Consumes one newline sequence: `\n`, `\r`, `\r\n` or `\n\r`.
+    fn read_newline(&mut self) {
+        self.advance();
     }
 
-    fn read_string(&mut self, quote: char) -> Option<Token> {
-        let mut string = String::new();
-        while let Some(c) = self.input.next() {
+    /// Reads a short string. Escapes follow Lua 5.4, so the result is a byte
+    /// string that need not be valid UTF-8 (`"\xFF"`). After a malformed
+    /// escape the rest of the string is still consumed, so lexing resumes
+    /// after the closing quote.
+    fn read_string(&mut self, quote: char) -> LexResult {
+        let mut bytes = Vec::new();
+        let mut error = None;
+        loop {
+            // Short strings may not span lines unless the newline is escaped
+            let c = match self.input.peek() {
+                None | Some('\n' | '\r') => return Err(LexErrorKind::UnterminatedString),
+                Some(_) => self.advance().unwrap(),
+            };
             match c {
                 '\\' => {
-                    if let Some(esc) = self.input.next() {
-                        match esc {
-                            'n' => string.push('\n'),
-                            't' => string.push('\t'),
-                            'r' => string.push('\r'),
-                            '\\' => string.push('\\'),
-                            '"' => string.push('"'),
-                            '\'' => string.push('\''),
-                            _ => string.push(esc),
+                    if let Err(kind) = self.read_escape(&mut bytes) {
+                        if kind == LexErrorKind::UnterminatedString {
+                            return Err(kind);
                         }
+                        error.get_or_insert(kind);
                     }
                 }
                 c if c == quote => break,
-                _ => string.push(c),
+                _ => bytes.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes()),
             }
         }
-        Some(Token::StringLiteral(string))
+        if let Some(kind) = error {
+            return Err(kind);
+        }
+        // `'f'` is a char, `'fo'` and `"f"` are strings
+        let mut chars = std::str::from_utf8(&bytes).ok().map(str::chars);
+        match chars.as_mut().map(|c| (c.next(), c.next())) {
+            Some((Some(c), None)) if quote == '\'' => Ok(Token::CharLiteral(c)),
+            _ => Ok(Token::StringLiteral(bytes)),
+        }
     }
 
-    fn read_identifier(&mut self, first: char) -> Option<Token> {
+    /// Reads one escape sequence, just after its backslash, into `bytes`.
+    fn read_escape(&mut self, bytes: &mut Vec<u8>) -> Result<(), LexErrorKind> {
+        let Some(&c) = self.input.peek() else {
+            return Err(LexErrorKind::UnterminatedString);
+        };
+        let simple = match c {
+            'a' => Some(0x07),
+            'b' => Some(0x08),
+            'f' => Some(0x0c),
+            'n' => Some(b'\n'),
+            'r' => Some(b'\r'),
+            't' => Some(b'\t'),
+            'v' => Some(0x0b),
+            '\\' | '"' | '\'' => Some(c as u8),
+            _ => None,
+        };
+        if let Some(byte) = simple {
+            self.advance();
+            bytes.push(byte);
+            return Ok(());
+        }
+
+        let mut text = String::from('\\');
+        match c {
+            '\n' | '\r' => {
+                self.read_newline();
+                bytes.push(b'\n');
+            }
+            'z' => {
+                self.advance();
+                self.skip_whitespace();
+            }
+            'x' => {
+                text.push(self.advance().unwrap());
+                let mut byte = 0;
+                for _ in 0..2 {
+                    byte = byte * 16 + self.escape_digit(&mut text, 16)?;
+                }
+                bytes.push(byte as u8);
+            }
+            'u' => {
+                text.push(self.advance().unwrap());
+                if !self.match_char('{') {
+                    return Err(self.invalid_escape(text));
+                }
+                text.push('{');
+                let mut code = self.escape_digit(&mut text, 16)?;
+                while self.input.peek().is_some_and(char::is_ascii_hexdigit) {
+                    // The next digit would take it past 2^31 - 1
+                    if code > 0x7FF_FFFF {
+                        return Err(self.invalid_escape(text));
+                    }
+                    code = code * 16 + self.escape_digit(&mut text, 16)?;
+                }
+                if !self.match_char('}') {
+                    return Err(self.invalid_escape(text));
+                }
+                push_utf8(bytes, code);
+            }
+            c if c.is_ascii_digit() => {
+                let mut byte = 0;
+                for _ in 0..3 {
+                    if !self.input.peek().is_some_and(char::is_ascii_digit) {
+                        break;
+                    }
+                    byte = byte * 10 + self.escape_digit(&mut text, 10)?;
+                }
+                if byte > 255 {
+                    return Err(LexErrorKind::InvalidEscape(text));
+                }
+                bytes.push(byte as u8);
+            }
+            _ => {
+                text.push(self.advance().unwrap());
+                return Err(LexErrorKind::InvalidEscape(text));
+            }
+        }
+        Ok(())
+    }
+
+    /// Consumes one digit of an escape in the given radix, adding it to the
+    /// escape's `text` for error messages.
+    fn escape_digit(&mut self, text: &mut String, radix: u32) -> Result<u32, LexErrorKind> {
+        match self.input.peek().and_then(|c| c.to_digit(radix)) {
+            Some(digit) => {
+                text.push(self.advance().unwrap());
+                Ok(digit)
+            }
+            None => Err(self.invalid_escape(std::mem::take(text))),
+        }
+    }
+
+    /// Error for a malformed escape; like Lua, the message shows the
+    /// offending character without consuming it.
+    fn invalid_escape(&mut self, mut text: String) -> LexErrorKind {
+        if let Some(&c) = self.input.peek().filter(|c| !matches!(c, '\n' | '\r')) {
+            text.push(c);
+        }
+        LexErrorKind::InvalidEscape(text)
+    }
+
+    fn read_identifier(&mut self, first: char) -> Token {
         let mut ident = String::from(first);
         while let Some(&c) = self.input.peek() {
             if c.is_alphanumeric() || c == '_' {
                 ident.push(c);
-                self.input.next();
+                self.advance();
             } else {
                 break;
             }
@@ -320,47 +666,50 @@ impl<'a> Lexer<'a> {
 
         match ident.as_str() {
             // Standard keywords
-            "and" => Some(Token::And),
-            "break" => Some(Token::Break),
-            "do" => Some(Token::Do),
-            "else" => Some(Token::Else),
-            "elseif" => Some(Token::ElseIf),
-            "end" => Some(Token::End),
-            "false" => Some(Token::False),
-            "for" => Some(Token::For),
-            "function" => Some(Token::Function),
-            "goto" => Some(Token::Goto),
-            "if" => Some(Token::If),
-            "in" => Some(Token::In),
-            "local" => Some(Token::Local),
-            "global" => Some(Token::Global),
-            "nil" => Some(Token::Nil),
-            "not" => Some(Token::Not),
-            "or" => Some(Token::Or),
-            "repeat" => Some(Token::Repeat),
-            "return" => Some(Token::Return),
-            "then" => Some(Token::Then),
-            "true" => Some(Token::True),
-            "until" => Some(Token::Until),
-            "while" => Some(Token::While),
+            "and" => Token::And,
+            "break" => Token::Break,
+            "do" => Token::Do,
+            "else" => Token::Else,
+            "elseif" => Token::ElseIf,
+            "end" => Token::End,
+            "false" => Token::False,
+            "for" => Token::For,
+            "function" => Token::Function,
+            "goto" => Token::Goto,
+            "if" => Token::If,
+            "in" => Token::In,
+            "local" => Token::Local,
+            "global" => Token::Global,
+            "nil" => Token::Nil,
+            "not" => Token::Not,
+            "or" => Token::Or,
+            "repeat" => Token::Repeat,
+            "return" => Token::Return,
+            "then" => Token::Then,
+            "true" => Token::True,
+            "until" => Token::Until,
+            "while" => Token::While,
 
             // Type keywords
-            "int" => Some(Token::Int),
-            "long" => Some(Token::Long),
-            "float" => Some(Token::Float),
-            "double" => Some(Token::Double),
-            "string" => Some(Token::String),
-            "table" => Some(Token::Table),
-            "bool" => Some(Token::Bool),
-            "char" => Some(Token::Char),
-            "void" => Some(Token::Void),
-            "vec" => Some(Token::Vec),
-
-            _ => Some(Token::Ident(ident)),
+            "int" => Token::Int,
+            "long" => Token::Long,
+            "float" => Token::Float,
+            "double" => Token::Double,
+            "string" => Token::String,
+            "table" => Token::Table,
+            "bool" => Token::Bool,
+            "char" => Token::Char,
+            "void" => Token::Void,
+            "vec" => Token::Vec,
+
+            _ => Token::Ident(ident),
         }
     }
 
-    fn read_number(&mut self, first: char, is_negative: bool) -> Option<Token> {
+    fn read_number(&mut self, first: char) -> LexResult {
+        if first == '0' && matches!(self.input.peek(), Some('x' | 'X')) {
+            return self.read_hex_number();
+        }
         let mut num_str = String::from(first);
 
         let mut has_dot = false;
@@ -371,43 +720,103 @@ impl<'a> Lexer<'a> {
                 '.' if !has_dot && !has_exp => {
                     has_dot = true;
                     num_str.push('.');
-                    self.input.next();
+                    self.advance();
                 }
                 'e' | 'E' if !has_exp => {
                     has_exp = true;
                     num_str.push(c);
-                    self.input.next();
+                    self.advance();
 
                     if let Some(&sign) = self.input.peek() {
                         if sign == '+' || sign == '-' {
                             num_str.push(sign);
-                            self.input.next();
+                            self.advance();
                         }
                     }
                 }
                 '_' => {
-                    self.input.next(); // Skip underscores
+                    self.advance(); // Skip underscores
                 }
                 c if c.is_ascii_digit() => {
                     num_str.push(c);
-                    self.input.next();
+                    self.advance();
+                }
+                _ => break,
+            }
+        }
+        // As in Lua, a decimal integer too large for 64 bits becomes a float
+        if !has_dot && !has_exp {
+            if let Ok(n) = num_str.parse::<i64>() {
+                return Ok(Token::IntegerLiteral(n));
+            }
+        }
+        num_str
+            .parse::<f64>()
+            .map(Token::FloatLiteral)
+            .map_err(|_| LexErrorKind::MalformedNumber(num_str))
+    }
+
+    /// Reads the rest of a numeral after its leading `0`, starting at the
+    /// `x`. Like Lua, this swallows every trailing letter and digit so that
+    /// `0xG` is one malformed number rather than `0x` followed by a name.
+    fn read_hex_number(&mut self) -> LexResult {
+        let mut text = String::from("0");
+        text.push(self.advance().unwrap());
+        let prefix_len = text.len();
+
+        while let Some(&c) = self.input.peek() {
+            match c {
+                'p' | 'P' => {
+                    text.push(c);
+                    self.advance();
+                    if let Some(&sign) = self.input.peek() {
+                        if sign == '+' || sign == '-' {
+                            text.push(sign);
+                            self.advance();
+                        }
+                    }
+                }
+                '_' => {
+                    self.advance(); // Skip underscores
+                }
+                c if c.is_ascii_alphanumeric() || c == '.' => {
+                    text.push(c);
+                    self.advance();
                 }
                 _ => break,
             }
         }
-        if is_negative {
-            num_str = format!("-{}", num_str);
-            dbg!(&num_str);
-            num_str.parse::<f64>().map(Token::NumberLiteral).ok()
+
+        parse_hex(&text[prefix_len..]).ok_or(LexErrorKind::MalformedNumber(text))
+    }
+
+    /// Consumes one char, keeping the byte offset, line and column in sync.
+    /// As in Lua, a newline is any of `\n`, `\r`, `\r\n` or `\n\r`: a pair
+    /// is consumed whole and counts as one line break.
+    fn advance(&mut self) -> Option<char> {
+        let c = self.input.next()?;
+        self.pos.offset += c.len_utf8();
+        if let '\n' | '\r' = c {
+            let other = if c == '\n' { '\r' } else { '\n' };
+            if self.input.next_if_eq(&other).is_some() {
+                self.pos.offset += 1;
+            }
+            self.pos.line += 1;
+            self.pos.column = 1;
         } else {
-            num_str.parse::<f64>().map(Token::NumberLiteral).ok()
+            self.pos.column += 1;
         }
+        Some(c)
+    }
+
+    fn span_from(&self, start: Position) -> Span {
+        Span::new(self.file_id, start, self.pos)
     }
 
     fn skip_whitespace(&mut self) {
         while let Some(&c) = self.input.peek() {
             if c.is_whitespace() {
-                self.input.next();
+                self.advance();
             } else {
                 break;
             }
@@ -416,10 +825,92 @@ impl<'a> Lexer<'a> {
 
     fn match_char(&mut self, expected: char) -> bool {
         if self.input.peek() == Some(&expected) {
-            self.input.next();
+            self.advance();
             true
         } else {
             false
         }
     }
 }
+
+/// Converts the digits of a hex numeral (after `0x`) to a token. Integers
+/// wrap around modulo 2^64 as in Lua; a `.` or a binary exponent `p`
+/// makes it a float. Returns `None` when the text is not a valid numeral.
+fn parse_hex(body: &str) -> Option<Token> {
+    let (mantissa, exponent) = match body.find(['p', 'P']) {
+        Some(i) => (&body[..i], Some(&body[i + 1..])),
+        None => (body, None),
+    };
+    let (whole, fraction) = match mantissa.split_once('.') {
+        Some((whole, fraction)) => (whole, Some(fraction)),
+        None => (mantissa, None),
+    };
+    let all_hex = |s: &str| s.chars().all(|c| c.is_ascii_hexdigit());
+    if whole.len() + fraction.map_or(0, str::len) == 0
+        || !all_hex(whole)
+        || !fraction.is_none_or(all_hex)
+    {
+        return None;
+    }
+
+    if fraction.is_none() && exponent.is_none() {
+        let n = whole.chars().fold(0i64, |n, c| {
+            n.wrapping_mul(16)
+                .wrapping_add(c.to_digit(16).unwrap() as i64)
+        });
+        return Some(Token::IntegerLiteral(n));
+    }
+
+    let exponent = match exponent {
+        Some(e) => {
+            let digits = e.strip_prefix(['+', '-']).unwrap_or(e);
+            if digits.is_empty() || !digits.chars().all(|c| c.is_ascii_digit()) {
+                return None;
+            }
+            e.parse::<i32>().unwrap_or(if e.starts_with('-') {
+                i32::MIN
+            } else {
+                i32::MAX
+            })
+        }
+        None => 0,
+    };
+    let fraction = fraction.unwrap_or("");
+    let value = whole
+        .chars()
+        .chain(fraction.chars())
+        .fold(0.0f64, |v, c| v * 16.0 + c.to_digit(16).unwrap() as f64);
+    let mut scale = exponent.saturating_sub(4 * fraction.len() as i32);
+    let mut value = value;
+    // Scale in steps so huge mantissas with tiny exponents (and the
+    // reverse) don't overflow or underflow halfway through.
+    while value != 0.0 && value.is_finite() && scale.abs() > 512 {
+        let step = 512 * scale.signum();
+        value *= 2f64.powi(step);
+        scale -= step;
+    }
+    Some(Token::FloatLiteral(value * 2f64.powi(scale)))
+}
+
+/// Appends `code` as UTF-8, extended like Lua's `\u{...}` to code points up
+/// to 2^31 using the original five- and six-byte forms.
+fn push_utf8(bytes: &mut Vec<u8>, code: u32) {
+    if code < 0x80 {
+        bytes.push(code as u8);
+        return;
+    }
+    let mut tail = Vec::new();
+    let mut code = code;
+    // Largest value that still fits beside the prefix of the first byte
+    let mut first_max = 0x3f;
+    loop {
+        tail.push(0x80 | (code & 0x3f) as u8);
+        code >>= 6;
+        first_max >>= 1;
+        if code <= first_max {
+            break;
+        }
+    }
+    bytes.push(((!first_max << 1) | code) as u8);
+    bytes.extend(tail.iter().rev());
+}
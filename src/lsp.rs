@@ -0,0 +1,837 @@
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+
+use crate::ast::*;
+use crate::checker::Checker;
+use crate::json::{self, Json};
+use crate::lexer::Lexer;
+use crate::parser::Parser;
+use crate::span::Span;
+use crate::stdlib;
+
+const KEYWORDS: [&str; 33] = [
+    "and", "break", "do", "else", "elseif", "end", "false", "for", "function", "goto", "if", "in",
+    "local", "global", "nil", "not", "or", "repeat", "return", "then", "true", "until", "while",
+    "int", "long", "float", "double", "string", "table", "bool", "char", "void", "vec",
+];
+
+// LSP enumerations
+const SYMBOL_METHOD: usize = 6;
+const SYMBOL_FUNCTION: usize = 12;
+const SYMBOL_VARIABLE: usize = 13;
+const COMPLETION_FUNCTION: usize = 3;
+const COMPLETION_VARIABLE: usize = 6;
+const COMPLETION_KEYWORD: usize = 14;
+const METHOD_NOT_FOUND: f64 = -32601.0;
+const PARSE_ERROR: f64 = -32700.0;
+
+/// Serves the Language Server Protocol on `input` and `output` until the
+/// client sends `exit` or closes the stream. Documents are synced in full
+/// on every change and analysed with the lexer, parser and checker.
+pub fn serve(mut input: impl BufRead, output: impl Write) -> io::Result<()> {
+    let mut server = Server {
+        out: output,
+        documents: HashMap::new(),
+    };
+    while let Some(body) = read_message(&mut input)? {
+        let message = match json::parse(&body) {
+            Ok(message) => message,
+            Err(e) => {
+                server.error(Json::Null, PARSE_ERROR, &e.to_string())?;
+                continue;
+            }
+        };
+        let method = message.get("method").as_str().unwrap_or_default();
+        if method == "exit" {
+            break;
+        }
+        server.handle(method, message.get("id"), message.get("params"))?;
+    }
+    Ok(())
+}
+
+/// Reads one `Content-Length` framed message, `None` at end of input.
+fn read_message(input: &mut impl BufRead) -> io::Result<Option<String>> {
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                length = value.trim().parse::<usize>().ok();
+            }
+        }
+    }
+    let Some(length) = length else {
+        return Err(io::Error::other("message without Content-Length"));
+    };
+    let mut body = vec![0; length];
+    input.read_exact(&mut body)?;
+    String::from_utf8(body).map(Some).map_err(io::Error::other)
+}
+
+struct Server<W> {
+    out: W,
+    documents: HashMap<String, Document>,
+}
+
+impl<W: Write> Server<W> {
+    fn handle(&mut self, method: &str, id: &Json, params: &Json) -> io::Result<()> {
+        let uri = params
+            .get("textDocument")
+            .get("uri")
+            .as_str()
+            .unwrap_or_default();
+        let result = match method {
+            "initialize" => Json::object([
+                (
+                    "capabilities",
+                    Json::object([
+                        // Full document sync
+                        ("textDocumentSync", 1.into()),
+                        ("hoverProvider", true.into()),
+                        ("definitionProvider", true.into()),
+                        ("completionProvider", Json::object([])),
+                        ("documentSymbolProvider", true.into()),
+                    ]),
+                ),
+                ("serverInfo", Json::object([("name", "rlua".into())])),
+            ]),
+            "shutdown" => Json::Null,
+            "textDocument/didOpen" => {
+                let text = params.get("textDocument").get("text").as_str();
+                return self.update(uri, text.unwrap_or_default());
+            }
+            "textDocument/didChange" => {
+                let changes = params.get("contentChanges").as_array();
+                let text = changes.last().and_then(|c| c.get("text").as_str());
+                return self.update(uri, text.unwrap_or_default());
+            }
+            "textDocument/didClose" => {
+                self.documents.remove(uri);
+                return self.notify(
+                    "textDocument/publishDiagnostics",
+                    Json::object([
+                        ("uri", uri.into()),
+                        ("diagnostics", Json::Array(Vec::new())),
+                    ]),
+                );
+            }
+            "textDocument/hover"
+            | "textDocument/definition"
+            | "textDocument/completion"
+            | "textDocument/documentSymbol" => match self.documents.get(uri) {
+                Some(doc) => doc.request(method, uri, params.get("position")),
+                None => Json::Null,
+            },
+            // Notifications we have no use for, like `initialized`
+            _ if *id == Json::Null => return Ok(()),
+            _ => {
+                return self.error(
+                    id.clone(),
+                    METHOD_NOT_FOUND,
+                    &format!("unsupported method '{}'", method),
+                )
+            }
+        };
+        self.send(Json::object([
+            ("jsonrpc", "2.0".into()),
+            ("id", id.clone()),
+            ("result", result),
+        ]))
+    }
+
+    fn update(&mut self, uri: &str, text: &str) -> io::Result<()> {
+        let last = self
+            .documents
+            .remove(uri)
+            .and_then(|doc| doc.index.or(doc.last_index));
+        let doc = Document::analyse(text.to_string(), last);
+        let diagnostics = doc.diagnostics.clone();
+        self.documents.insert(uri.to_string(), doc);
+        self.notify(
+            "textDocument/publishDiagnostics",
+            Json::object([
+                ("uri", uri.into()),
+                ("diagnostics", Json::Array(diagnostics)),
+            ]),
+        )
+    }
+
+    fn notify(&mut self, method: &str, params: Json) -> io::Result<()> {
+        self.send(Json::object([
+            ("jsonrpc", "2.0".into()),
+            ("method", method.into()),
+            ("params", params),
+        ]))
+    }
+
+    fn error(&mut self, id: Json, code: f64, message: &str) -> io::Result<()> {
+        self.send(Json::object([
+            ("jsonrpc", "2.0".into()),
+            ("id", id),
+            (
+                "error",
+                Json::object([("code", Json::Number(code)), ("message", message.into())]),
+            ),
+        ]))
+    }
+
+    fn send(&mut self, message: Json) -> io::Result<()> {
+        let body = message.to_string();
+        write!(self.out, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+        self.out.flush()
+    }
+}
+
+/// An open document and what analysing its current text found.
+struct Document {
+    text: String,
+    lines: LineIndex,
+    diagnostics: Vec<Json>,
+    /// Symbols of the current text, `None` when it does not parse
+    index: Option<Index>,
+    /// Symbols of the last text that parsed, so completion keeps working
+    /// while an edit is half typed
+    last_index: Option<Index>,
+}
+
+impl Document {
+    fn analyse(text: String, last_index: Option<Index>) -> Document {
+        let lines = LineIndex::new(&text);
+        let mut doc = Document {
+            text,
+            lines,
+            diagnostics: Vec::new(),
+            index: None,
+            last_index,
+        };
+        let tokens = match Lexer::new(&doc.text).tokenize() {
+            Ok(tokens) => tokens,
+            Err(errors) => {
+                for e in errors {
+                    doc.diagnostic(e.span, e.kind.to_string());
+                }
+                return doc;
+            }
+        };
+        let chunk = match Parser::new(tokens).parse() {
+            Ok(chunk) => chunk,
+            Err(e) => {
+                doc.diagnostic(e.span, e.kind.to_string());
+                return doc;
+            }
+        };
+        if let Err(errors) = Checker::new().check(&chunk) {
+            for e in errors {
+                doc.diagnostic(e.span, e.kind.to_string());
+            }
+        }
+        doc.index = Some(Index::build(&chunk));
+        doc
+    }
+
+    fn diagnostic(&mut self, span: Span, message: String) {
+        let range = self.range(span);
+        self.diagnostics.push(Json::object([
+            ("range", range),
+            // Error
+            ("severity", 1.into()),
+            ("source", "rlua".into()),
+            ("message", message.into()),
+        ]));
+    }
+
+    fn request(&self, method: &str, uri: &str, position: &Json) -> Json {
+        let offset = self.lines.offset(
+            &self.text,
+            position.get("line").as_usize().unwrap_or_default(),
+            position.get("character").as_usize().unwrap_or_default(),
+        );
+        if method == "textDocument/completion" {
+            return self.completion(offset);
+        }
+        let Some(index) = &self.index else {
+            return Json::Null;
+        };
+        match method {
+            "textDocument/hover" => match index.reference_at(offset) {
+                Some((span, symbol)) => Json::object([
+                    (
+                        "contents",
+                        Json::object([
+                            ("kind", "markdown".into()),
+                            ("value", format!("```rlua\n{}\n```", symbol.detail).into()),
+                        ]),
+                    ),
+                    ("range", self.range(span)),
+                ]),
+                None => Json::Null,
+            },
+            "textDocument/definition" => match index.reference_at(offset) {
+                Some((
+                    _,
+                    Symbol {
+                        span: Some(span), ..
+                    },
+                )) => Json::object([("uri", uri.into()), ("range", self.range(*span))]),
+                _ => Json::Null,
+            },
+            _ => Json::Array(index.outline.iter().map(|o| self.outline(o)).collect()),
+        }
+    }
+
+    /// Keywords and the names visible at `offset`.
+    fn completion(&self, offset: usize) -> Json {
+        let mut items: Vec<Json> = KEYWORDS
+            .iter()
+            .map(|k| completion_item(k, COMPLETION_KEYWORD, None))
+            .collect();
+        let mut seen = std::collections::HashSet::new();
+        if let Some(index) = self.index.as_ref().or(self.last_index.as_ref()) {
+            // Innermost declarations come last and shadow the outer ones
+            for symbol in index.symbols.iter().rev() {
+                let visible = symbol
+                    .visible
+                    .is_none_or(|(from, to)| from <= offset && offset <= to);
+                if visible && seen.insert(symbol.name.as_str()) {
+                    let kind = if symbol.is_function {
+                        COMPLETION_FUNCTION
+                    } else {
+                        COMPLETION_VARIABLE
+                    };
+                    items.push(completion_item(&symbol.name, kind, Some(&symbol.detail)));
+                }
+            }
+        }
+        for name in stdlib::base_names() {
+            if seen.insert(name) {
+                let detail = format!("builtin function {}", name);
+                items.push(completion_item(name, COMPLETION_FUNCTION, Some(&detail)));
+            }
+        }
+        Json::Array(items)
+    }
+
+    fn outline(&self, outline: &Outline) -> Json {
+        Json::object([
+            ("name", outline.name.as_str().into()),
+            ("detail", outline.detail.as_str().into()),
+            ("kind", outline.kind.into()),
+            ("range", self.range(outline.span)),
+            ("selectionRange", self.range(outline.name_span)),
+            (
+                "children",
+                Json::Array(outline.children.iter().map(|o| self.outline(o)).collect()),
+            ),
+        ])
+    }
+
+    fn range(&self, span: Span) -> Json {
+        Json::object([
+            ("start", self.lines.position(&self.text, span.start.offset)),
+            ("end", self.lines.position(&self.text, span.end.offset)),
+        ])
+    }
+}
+
+fn completion_item(label: &str, kind: usize, detail: Option<&str>) -> Json {
+    let mut item = vec![
+        ("label".to_string(), label.into()),
+        ("kind".to_string(), kind.into()),
+    ];
+    if let Some(detail) = detail {
+        item.push(("detail".to_string(), detail.into()));
+    }
+    Json::Object(item)
+}
+
+/// Converts between byte offsets and LSP positions, which count lines from
+/// 0 and characters in UTF-16 code units.
+struct LineIndex {
+    starts: Vec<usize>,
+}
+
+impl LineIndex {
+    fn new(text: &str) -> LineIndex {
+        let mut starts = vec![0];
+        starts.extend(text.match_indices('\n').map(|(i, _)| i + 1));
+        LineIndex { starts }
+    }
+
+    fn position(&self, text: &str, offset: usize) -> Json {
+        let line = self.starts.partition_point(|&start| start <= offset) - 1;
+        let start = self.starts[line];
+        let character = text
+            .get(start..offset.min(text.len()))
+            .map_or(0, |s| s.encode_utf16().count());
+        Json::object([("line", line.into()), ("character", character.into())])
+    }
+
+    fn offset(&self, text: &str, line: usize, character: usize) -> usize {
+        let Some(&start) = self.starts.get(line) else {
+            return text.len();
+        };
+        let mut units = 0;
+        for (i, c) in text[start..].char_indices() {
+            if units >= character || c == '\n' {
+                return start + i;
+            }
+            units += c.len_utf16();
+        }
+        text.len()
+    }
+}
+
+/// A declared name.
+#[derive(Debug)]
+struct Symbol {
+    name: String,
+    /// The declaration as hover shows it, like `int function f(int n)`
+    detail: String,
+    is_function: bool,
+    /// The declaring name, `None` for builtins
+    span: Option<Span>,
+    /// Offsets a local can be referred to from, `None` for globals
+    visible: Option<(usize, usize)>,
+}
+
+/// An entry of the document outline.
+#[derive(Debug)]
+struct Outline {
+    name: String,
+    detail: String,
+    kind: usize,
+    span: Span,
+    name_span: Span,
+    children: Vec<Outline>,
+}
+
+/// Every declaration of a chunk and what each name refers to, following
+/// the scoping rules of the engines: declarations are local to their block
+/// unless made `global`, and assigning to a name that is not a visible
+/// local declares a global.
+#[derive(Debug, Default)]
+struct Index {
+    symbols: Vec<Symbol>,
+    /// Declarations and uses of names with their symbol
+    references: Vec<(Span, usize)>,
+    outline: Vec<Outline>,
+}
+
+impl Index {
+    fn build(chunk: &Block) -> Index {
+        let mut indexer = Indexer {
+            index: Index::default(),
+            scopes: Vec::new(),
+            globals: HashMap::new(),
+            unresolved: Vec::new(),
+        };
+        let mut outline = Vec::new();
+        indexer.block(chunk, usize::MAX, &mut outline);
+        for (span, name) in std::mem::take(&mut indexer.unresolved) {
+            let symbol = match indexer.globals.get(&name) {
+                Some(&symbol) => symbol,
+                None if stdlib::base_names().any(|n| n == name) => {
+                    let symbol = indexer.index.symbols.len();
+                    indexer.index.symbols.push(Symbol {
+                        detail: format!("builtin function {}", name),
+                        name: name.clone(),
+                        is_function: true,
+                        span: None,
+                        visible: None,
+                    });
+                    indexer.globals.insert(name, symbol);
+                    symbol
+                }
+                None => continue,
+            };
+            indexer.index.references.push((span, symbol));
+        }
+        let mut index = indexer.index;
+        index.outline = outline;
+        index
+    }
+
+    fn reference_at(&self, offset: usize) -> Option<(Span, &Symbol)> {
+        self.references
+            .iter()
+            .find(|(span, _)| span.start.offset <= offset && offset <= span.end.offset)
+            .map(|&(span, symbol)| (span, &self.symbols[symbol]))
+    }
+}
+
+struct Indexer {
+    index: Index,
+    /// Locals of each enclosing block
+    scopes: Vec<Vec<usize>>,
+    globals: HashMap<String, usize>,
+    /// Names that are not locals, resolved against the globals once they
+    /// are all known
+    unresolved: Vec<(Span, String)>,
+}
+
+impl Indexer {
+    /// Walks a block whose locals stay visible up to offset `end`.
+    fn block(&mut self, block: &Block, end: usize, outline: &mut Vec<Outline>) {
+        self.scopes.push(Vec::new());
+        for stmt in &block.stmts {
+            self.stmt(stmt, outline);
+        }
+        self.close_scope(end);
+    }
+
+    fn close_scope(&mut self, end: usize) {
+        for symbol in self.scopes.pop().unwrap_or_default() {
+            if let Some((_, to)) = &mut self.index.symbols[symbol].visible {
+                *to = end;
+            }
+        }
+    }
+
+    fn stmt(&mut self, stmt: &Stmt, outline: &mut Vec<Outline>) {
+        let end = stmt.span.end.offset;
+        match &stmt.kind {
+            StmtKind::Local {
+                scope,
+                ty,
+                names,
+                values,
+                ..
+            } => {
+                // Functions assigned to a name nest under it in the outline
+                let mut bodies = Vec::new();
+                for value in values {
+                    match &value.kind {
+                        ExprKind::Function(func) => {
+                            bodies.push(Some(self.function(func, false)));
+                        }
+                        _ => {
+                            self.expr(value, outline);
+                            bodies.push(None);
+                        }
+                    }
+                }
+                for (i, name) in names.iter().enumerate() {
+                    let func = match values.get(i).map(|v| &v.kind) {
+                        Some(ExprKind::Function(func)) => Some(func),
+                        _ => None,
+                    };
+                    let detail = match func {
+                        Some(func) => function_detail(
+                            *scope,
+                            ty.as_ref().or(func.ret.as_ref()),
+                            &name.name,
+                            func,
+                        ),
+                        None => format!(
+                            "{}{}{}",
+                            match scope {
+                                Scope::Global => "global ",
+                                Scope::Local if ty.is_none() => "local ",
+                                Scope::Local => "",
+                            },
+                            type_prefix(ty.as_ref()),
+                            name.name
+                        ),
+                    };
+                    self.declare(*scope, name, detail.clone(), func.is_some(), end);
+                    if func.is_some() || ty.is_some() {
+                        outline.push(Outline {
+                            name: name.name.clone(),
+                            detail,
+                            kind: if func.is_some() {
+                                SYMBOL_FUNCTION
+                            } else {
+                                SYMBOL_VARIABLE
+                            },
+                            span: stmt.span,
+                            name_span: name.span,
+                            children: bodies.get_mut(i).and_then(Option::take).unwrap_or_default(),
+                        });
+                    }
+                }
+                // Functions beyond the names are still part of the outline
+                outline.extend(bodies.into_iter().flatten().flatten());
+            }
+            StmtKind::Function { scope, name, func } => {
+                let path: Vec<&str> = name.path.iter().map(|n| n.name.as_str()).collect();
+                let mut full_name = path.join(".");
+                if let Some(method) = &name.method {
+                    full_name = format!("{}:{}", full_name, method.name);
+                }
+                let detail = function_detail(*scope, func.ret.as_ref(), &full_name, func);
+                let first = &name.path[0];
+                if name.path.len() == 1 && name.method.is_none() {
+                    // Visible in its own body so it can recurse
+                    self.declare(*scope, first, detail.clone(), true, first.span.end.offset);
+                } else {
+                    self.reference(&first.name, first.span);
+                }
+                let last = name.method.as_ref().unwrap_or(name.path.last().unwrap());
+                let children = self.function(func, name.method.is_some());
+                outline.push(Outline {
+                    name: full_name,
+                    detail,
+                    kind: if name.method.is_some() {
+                        SYMBOL_METHOD
+                    } else {
+                        SYMBOL_FUNCTION
+                    },
+                    span: stmt.span,
+                    name_span: last.span,
+                    children,
+                });
+            }
+            StmtKind::Assign { targets, values } => {
+                for value in values {
+                    self.expr(value, outline);
+                }
+                for target in targets {
+                    match &target.kind {
+                        ExprKind::Name(name) if self.local(name).is_none() => {
+                            if self.globals.contains_key(name) {
+                                self.unresolved.push((target.span, name.clone()));
+                            } else {
+                                let name = Name {
+                                    name: name.clone(),
+                                    span: target.span,
+                                };
+                                let detail = format!("global {}", name.name);
+                                self.declare(Scope::Global, &name, detail, false, end);
+                            }
+                        }
+                        _ => self.expr(target, outline),
+                    }
+                }
+            }
+            StmtKind::Call(expr) | StmtKind::Expr(expr) => self.expr(expr, outline),
+            StmtKind::Do(body) => self.block(body, end, outline),
+            StmtKind::While { cond, body } => {
+                self.expr(cond, outline);
+                self.block(body, end, outline);
+            }
+            StmtKind::Repeat { body, cond } => {
+                // The condition sees the body's locals
+                self.scopes.push(Vec::new());
+                for stmt in &body.stmts {
+                    self.stmt(stmt, outline);
+                }
+                self.expr(cond, outline);
+                self.close_scope(end);
+            }
+            StmtKind::If {
+                branches,
+                else_block,
+            } => {
+                for (cond, body) in branches {
+                    self.expr(cond, outline);
+                    self.block(body, body.span.end.offset, outline);
+                }
+                if let Some(body) = else_block {
+                    self.block(body, end, outline);
+                }
+            }
+            StmtKind::NumericFor {
+                var,
+                start,
+                limit,
+                step,
+                body,
+            } => {
+                for expr in [Some(start), Some(limit), step.as_ref()]
+                    .into_iter()
+                    .flatten()
+                {
+                    self.expr(expr, outline);
+                }
+                self.scopes.push(Vec::new());
+                self.declare(Scope::Local, var, format!("local {}", var.name), false, end);
+                self.block(body, end, outline);
+                self.close_scope(end);
+            }
+            StmtKind::GenericFor { names, exprs, body } => {
+                for expr in exprs {
+                    self.expr(expr, outline);
+                }
+                self.scopes.push(Vec::new());
+                for name in names {
+                    self.declare(
+                        Scope::Local,
+                        name,
+                        format!("local {}", name.name),
+                        false,
+                        end,
+                    );
+                }
+                self.block(body, end, outline);
+                self.close_scope(end);
+            }
+            StmtKind::Return(values) => {
+                for value in values {
+                    self.expr(value, outline);
+                }
+            }
+            StmtKind::Break | StmtKind::Goto(_) | StmtKind::Label(_) => {}
+        }
+    }
+
+    fn expr(&mut self, expr: &Expr, outline: &mut Vec<Outline>) {
+        match &expr.kind {
+            ExprKind::Name(name) => self.reference(name, expr.span),
+            ExprKind::Function(func) => {
+                let children = self.function(func, false);
+                outline.extend(children);
+            }
+            ExprKind::Table(fields) => {
+                for field in fields {
+                    match field {
+                        TableField::Positional(value) | TableField::Named { value, .. } => {
+                            self.expr(value, outline)
+                        }
+                        TableField::Keyed { key, value } => {
+                            self.expr(key, outline);
+                            self.expr(value, outline);
+                        }
+                    }
+                }
+            }
+            ExprKind::Vec(items) => {
+                for item in items {
+                    self.expr(item, outline);
+                }
+            }
+            ExprKind::Index { object, key } => {
+                self.expr(object, outline);
+                self.expr(key, outline);
+            }
+            ExprKind::Call { func: object, args } | ExprKind::MethodCall { object, args, .. } => {
+                self.expr(object, outline);
+                for arg in args {
+                    self.expr(arg, outline);
+                }
+            }
+            ExprKind::Paren(inner)
+            | ExprKind::Cast { expr: inner, .. }
+            | ExprKind::Unary { expr: inner, .. } => self.expr(inner, outline),
+            ExprKind::Binary { lhs, rhs, .. } => {
+                self.expr(lhs, outline);
+                self.expr(rhs, outline);
+            }
+            _ => {}
+        }
+    }
+
+    /// Walks a function's parameters and body, returning the outline of the
+    /// functions declared inside.
+    fn function(&mut self, func: &Function, method: bool) -> Vec<Outline> {
+        let end = func.span.end.offset;
+        self.scopes.push(Vec::new());
+        if method {
+            let name = Name {
+                name: "self".to_string(),
+                span: Span::new(func.span.file_id, func.span.start, func.span.start),
+            };
+            self.declare(
+                Scope::Local,
+                &name,
+                "(parameter) self".to_string(),
+                false,
+                end,
+            );
+        }
+        for param in &func.params {
+            let detail = format!(
+                "(parameter) {}{}",
+                type_prefix(param.ty.as_ref()),
+                param.name.name
+            );
+            self.declare(
+                Scope::Local,
+                &param.name,
+                detail,
+                false,
+                func.body.span.start.offset,
+            );
+        }
+        let mut children = Vec::new();
+        self.block(&func.body, end, &mut children);
+        self.close_scope(end);
+        children
+    }
+
+    fn declare(
+        &mut self,
+        scope: Scope,
+        name: &Name,
+        detail: String,
+        is_function: bool,
+        from: usize,
+    ) -> usize {
+        let symbol = self.index.symbols.len();
+        let global = scope == Scope::Global;
+        self.index.symbols.push(Symbol {
+            name: name.name.clone(),
+            detail,
+            is_function,
+            span: Some(name.span),
+            visible: (!global).then_some((from, usize::MAX)),
+        });
+        self.index.references.push((name.span, symbol));
+        if global {
+            self.globals.entry(name.name.clone()).or_insert(symbol);
+        } else if let Some(scope) = self.scopes.last_mut() {
+            scope.push(symbol);
+        }
+        symbol
+    }
+
+    fn local(&self, name: &str) -> Option<usize> {
+        self.scopes
+            .iter()
+            .rev()
+            .flat_map(|scope| scope.iter().rev())
+            .copied()
+            .find(|&symbol| self.index.symbols[symbol].name == name)
+    }
+
+    fn reference(&mut self, name: &str, span: Span) {
+        match self.local(name) {
+            Some(symbol) => self.index.references.push((span, symbol)),
+            None => self.unresolved.push((span, name.to_string())),
+        }
+    }
+}
+
+fn type_prefix(ty: Option<&TypeName>) -> String {
+    ty.map_or(String::new(), |ty| format!("{} ", ty))
+}
+
+/// `[global ][T ]function name(params)`
+fn function_detail(scope: Scope, ret: Option<&TypeName>, name: &str, func: &Function) -> String {
+    let mut params: Vec<String> = func
+        .params
+        .iter()
+        .map(|p| format!("{}{}", type_prefix(p.ty.as_ref()), p.name.name))
+        .collect();
+    if func.is_vararg {
+        params.push("...".to_string());
+    }
+    format!(
+        "{}{}function {}({})",
+        if scope == Scope::Global {
+            "global "
+        } else {
+            ""
+        },
+        type_prefix(ret),
+        name,
+        params.join(", ")
+    )
+}
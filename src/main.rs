@@ -1,18 +1,46 @@
 use std::io::{BufRead, Write};
 use std::process::exit;
-use std::{env, fs, io};
+use std::{env, fs, io, thread};
 
+mod ast;
+mod checker;
+use crate::checker::Checker;
+mod compiler;
+use crate::compiler::Compiler;
+mod formatter;
+use crate::formatter::Formatter;
+mod gc;
+mod interp;
+use crate::interp::Interpreter;
+mod json;
 mod lexer;
 use crate::lexer::*;
+mod lsp;
+mod parser;
+use crate::parser::Parser;
+mod span;
+mod stdlib;
 mod test;
+mod value;
+mod vm;
+use crate::vm::Vm;
 
-fn run_file(file_path: &str) -> Result<(), io::Error> {
+/// Which engine runs scripts.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Engine {
+    /// Compile to bytecode and run it on the VM
+    Vm,
+    /// Walk the AST directly
+    Interp,
+}
+
+fn run_file(file_path: &str, engine: Engine) -> Result<(), io::Error> {
     match fs::read_to_string(file_path) {
-        Ok(content) => return run(&content),
-        Err(msg) => return Err(io::Error::new(io::ErrorKind::Other, msg)),
+        Ok(content) => run(&content, engine),
+        Err(msg) => Err(io::Error::other(msg)),
     }
 }
-fn run_prompt() -> Result<(), io::Error> {
+fn run_prompt(engine: Engine) -> Result<(), io::Error> {
     loop {
         print!("> ");
         io::stdout().flush()?;
@@ -26,34 +54,151 @@ fn run_prompt() -> Result<(), io::Error> {
                     return Ok(());
                 }
             }
-            Err(msg) => return Err(io::Error::new(io::ErrorKind::Other, msg)),
+            Err(msg) => return Err(io::Error::other(msg)),
         }
         println!("> {}", buffer);
-        match run(&buffer) {
+        match run(&buffer, engine) {
             Ok(_) => (),
-            Err(msg) => return Err(io::Error::new(io::ErrorKind::Other, msg)),
+            Err(msg) => return Err(io::Error::other(msg)),
         }
     }
 }
 
-fn run(contents: &str) -> Result<(), io::Error> {
+fn run(contents: &str, engine: Engine) -> Result<(), io::Error> {
     let mut scanner = Lexer::new(contents);
-    let tokens = scanner.tokenize();
+    let tokens = match scanner.tokenize() {
+        Ok(tokens) => tokens,
+        Err(errors) => return Err(io::Error::other(join_errors(&errors))),
+    };
 
-    for token in tokens {
-        println!("{:?}", token);
+    let mut parser = Parser::new(tokens);
+    let chunk = parser.parse().map_err(io::Error::other)?;
+    if let Err(errors) = Checker::new().check(&chunk) {
+        return Err(io::Error::other(join_errors(&errors)));
+    }
+
+    match engine {
+        Engine::Vm => {
+            let main = Compiler::new().compile(&chunk).map_err(io::Error::other)?;
+            Vm::new()
+                .run(main)
+                .map_err(|e| io::Error::other(e.to_string()))?;
+        }
+        Engine::Interp => {
+            let mut interpreter = Interpreter::new();
+            interpreter.set_stack_limit(SCRIPT_STACK_SIZE - 1024 * 1024);
+            interpreter
+                .run(&chunk)
+                .map_err(|e| io::Error::other(e.to_string()))?;
+        }
     }
     Ok(())
 }
 
+/// Formats `contents`, failing on lexing or parse errors.
+fn format(contents: &str) -> Result<String, io::Error> {
+    let mut scanner = Lexer::new(contents);
+    let tokens = match scanner.tokenize_lossless() {
+        Ok(tokens) => tokens,
+        Err(errors) => return Err(io::Error::other(join_errors(&errors))),
+    };
+    let mut parser = Parser::new(tokens.iter().map(|t| t.token.clone()).collect());
+    let chunk = parser.parse().map_err(io::Error::other)?;
+    Ok(Formatter::new(contents, &tokens).format(&chunk))
+}
+
+/// `rlua fmt [--check] [files]`: rewrites each file in place, or with
+/// `--check` lists the files that are not formatted and fails if there are
+/// any. Without files it formats stdin to stdout.
+fn run_fmt(args: &[String]) -> Result<bool, io::Error> {
+    let check = args.iter().any(|a| a == "--check");
+    let files: Vec<&String> = args.iter().filter(|a| *a != "--check").collect();
+    if files.is_empty() {
+        let mut contents = String::new();
+        io::Read::read_to_string(&mut io::stdin(), &mut contents)?;
+        let formatted = format(&contents)?;
+        if !check {
+            print!("{}", formatted);
+        }
+        return Ok(!check || formatted == contents);
+    }
+    let mut formatted_all = true;
+    for file in files {
+        let contents = fs::read_to_string(file)?;
+        let formatted =
+            format(&contents).map_err(|e| io::Error::other(format!("{}: {}", file, e)))?;
+        if formatted == contents {
+            continue;
+        }
+        if check {
+            println!("{} is not formatted", file);
+            formatted_all = false;
+        } else {
+            fs::write(file, formatted)?;
+        }
+    }
+    Ok(formatted_all)
+}
+
+fn join_errors<E: std::fmt::Display>(errors: &[E]) -> String {
+    errors
+        .iter()
+        .map(|e| e.to_string())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Scripts run on their own thread so deep recursion has room to grow.
+const SCRIPT_STACK_SIZE: usize = 256 * 1024 * 1024;
+
 fn main() {
-    let args: Vec<String> = env::args().collect();
+    let script = thread::Builder::new()
+        .stack_size(SCRIPT_STACK_SIZE)
+        .spawn(rlua_main)
+        .expect("failed to spawn interpreter thread");
+    if script.join().is_err() {
+        exit(101);
+    }
+}
+
+fn rlua_main() {
+    let mut args: Vec<String> = env::args().collect();
+    if args.get(1).is_some_and(|a| a == "fmt") {
+        match run_fmt(&args[2..]) {
+            Ok(true) => exit(0),
+            Ok(false) => exit(1),
+            Err(msg) => {
+                println!("[ERR] {}", msg);
+                exit(1);
+            }
+        }
+    }
+    if args.get(1).is_some_and(|a| a == "lsp") {
+        match lsp::serve(io::stdin().lock(), io::stdout().lock()) {
+            Ok(_) => exit(0),
+            Err(msg) => {
+                eprintln!("[ERR] {}", msg);
+                exit(1);
+            }
+        }
+    }
+    let mut engine = Engine::Vm;
+    if let Some(i) = args.iter().position(|a| a == "--interp") {
+        args.remove(i);
+        engine = Engine::Interp;
+    }
+    if let Some(i) = args.iter().position(|a| a == "--gc-stress") {
+        args.remove(i);
+        gc::set_stress(true);
+    }
 
     if args.len() > 2 {
-        println!("Usage: rlua [script]");
+        println!(
+            "Usage: rlua [--interp] [--gc-stress] [script]\n       rlua fmt [--check] [files]\n       rlua lsp"
+        );
         exit(64)
     } else if args.len() == 2 {
-        match run_file(&args[1]) {
+        match run_file(&args[1], engine) {
             Ok(_) => exit(0),
             Err(msg) => {
                 println!("[ERR] {}", msg);
@@ -61,7 +206,7 @@ fn main() {
             }
         }
     } else {
-        match run_prompt() {
+        match run_prompt(engine) {
             Ok(_) => exit(0),
             Err(msg) => {
                 println!("[ERR] {}", msg);
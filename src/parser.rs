@@ -0,0 +1,888 @@
+use std::fmt;
+use std::rc::Rc;
+
+use crate::ast::*;
+use crate::lexer::{SpannedToken, Token};
+use crate::span::{Position, Span};
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum ParseErrorKind {
+    Expected {
+        expected: &'static str,
+        found: Token,
+    },
+    UnexpectedToken(Token),
+    InvalidAssignTarget,
+    NotAStatement,
+    UnknownAttribute(String),
+    MultipleToBeClosed,
+    TooDeeplyNested,
+}
+
+impl fmt::Display for ParseErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseErrorKind::Expected { expected, found } => {
+                write!(f, "expected {}, found {:?}", expected, found)
+            }
+            ParseErrorKind::UnexpectedToken(token) => write!(f, "unexpected token {:?}", token),
+            ParseErrorKind::InvalidAssignTarget => write!(f, "cannot assign to this expression"),
+            ParseErrorKind::NotAStatement => {
+                write!(f, "expression is not a statement, only calls are")
+            }
+            ParseErrorKind::UnknownAttribute(name) => write!(f, "unknown attribute '{}'", name),
+            ParseErrorKind::MultipleToBeClosed => {
+                write!(f, "multiple to-be-closed variables in local list")
+            }
+            ParseErrorKind::TooDeeplyNested => {
+                write!(f, "more than {} nested levels", MAX_NESTING)
+            }
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct ParseError {
+    pub kind: ParseErrorKind,
+    pub span: Span,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.span, self.kind)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+type ParseResult<T> = Result<T, ParseError>;
+
+/// Maps a type keyword token to the type it declares.
+pub fn type_name(token: &Token) -> Option<TypeName> {
+    match token {
+        Token::Int => Some(TypeName::Int),
+        Token::Long => Some(TypeName::Long),
+        Token::Float => Some(TypeName::Float),
+        Token::Double => Some(TypeName::Double),
+        Token::String => Some(TypeName::String),
+        Token::Table => Some(TypeName::Table),
+        Token::Bool => Some(TypeName::Bool),
+        Token::Char => Some(TypeName::Char),
+        Token::Void => Some(TypeName::Void),
+        Token::Vec => Some(TypeName::Vec(None)),
+        _ => None,
+    }
+}
+
+fn unary_op(token: &Token) -> Option<UnOp> {
+    match token {
+        Token::Minus => Some(UnOp::Neg),
+        Token::Not => Some(UnOp::Not),
+        Token::Len => Some(UnOp::Len),
+        Token::Tilde => Some(UnOp::BitNot),
+        _ => None,
+    }
+}
+
+fn binary_op(token: &Token) -> Option<BinOp> {
+    match token {
+        Token::Plus => Some(BinOp::Add),
+        Token::Minus => Some(BinOp::Sub),
+        Token::Mul => Some(BinOp::Mul),
+        Token::Div => Some(BinOp::Div),
+        Token::FloorDiv => Some(BinOp::FloorDiv),
+        Token::Mod => Some(BinOp::Mod),
+        Token::Pow => Some(BinOp::Pow),
+        Token::BitAnd => Some(BinOp::BitAnd),
+        Token::BitOr => Some(BinOp::BitOr),
+        Token::Tilde => Some(BinOp::BitXor),
+        Token::Shl => Some(BinOp::Shl),
+        Token::Shr => Some(BinOp::Shr),
+        Token::Concat => Some(BinOp::Concat),
+        Token::Eq => Some(BinOp::Eq),
+        Token::Ne => Some(BinOp::Ne),
+        Token::Lt => Some(BinOp::Lt),
+        Token::Le => Some(BinOp::Le),
+        Token::Gt => Some(BinOp::Gt),
+        Token::Ge => Some(BinOp::Ge),
+        Token::And => Some(BinOp::And),
+        Token::Or => Some(BinOp::Or),
+        _ => None,
+    }
+}
+
+/// How deeply statements, expressions and types may nest, as Lua limits
+/// its parser to 200 C levels. Deeper input would overflow the stack of the
+/// parser or of the passes walking the tree after it.
+const MAX_NESTING: usize = 200;
+
+/// Recursive-descent parser turning the token stream of `Lexer::tokenize`
+/// into a `Block` of statements.
+#[derive(Debug)]
+pub struct Parser {
+    tokens: Vec<SpannedToken>,
+    pos: usize,
+    /// Statements, expressions and types being parsed, one inside the other
+    depth: usize,
+}
+
+impl Parser {
+    pub fn new(tokens: Vec<SpannedToken>) -> Self {
+        Parser {
+            tokens,
+            pos: 0,
+            depth: 0,
+        }
+    }
+
+    /// Runs `parse` one nesting level deeper, failing past `MAX_NESTING`.
+    fn nested<T>(&mut self, parse: impl FnOnce(&mut Self) -> ParseResult<T>) -> ParseResult<T> {
+        if self.depth == MAX_NESTING {
+            return Err(ParseError {
+                kind: ParseErrorKind::TooDeeplyNested,
+                span: self.span(),
+            });
+        }
+        self.depth += 1;
+        let result = parse(self);
+        self.depth -= 1;
+        result
+    }
+
+    pub fn parse(&mut self) -> ParseResult<Block> {
+        let block = self.parse_block()?;
+        self.expect(Token::Eof, "end of input")?;
+        Ok(block)
+    }
+
+    fn parse_block(&mut self) -> ParseResult<Block> {
+        let start = self.span();
+        let mut stmts = Vec::new();
+        while !self.block_ends() {
+            if self.check(&Token::Return) {
+                stmts.push(self.parse_return()?);
+                break;
+            }
+            if let Some(stmt) = self.parse_statement()? {
+                stmts.push(stmt);
+            }
+        }
+        let span = match stmts.last() {
+            Some(last) => stmts[0].span.to(last.span),
+            None => Span::new(start.file_id, start.start, start.start),
+        };
+        Ok(Block { stmts, span })
+    }
+
+    fn block_ends(&self) -> bool {
+        matches!(
+            self.peek(),
+            Token::End | Token::Else | Token::ElseIf | Token::Until | Token::Eof
+        )
+    }
+
+    fn parse_statement(&mut self) -> ParseResult<Option<Stmt>> {
+        self.nested(Self::parse_statement_kind)
+    }
+
+    fn parse_statement_kind(&mut self) -> ParseResult<Option<Stmt>> {
+        let start = self.span();
+        let kind = match self.peek() {
+            Token::Semicolon => {
+                self.advance();
+                return Ok(None);
+            }
+            Token::If => self.parse_if()?,
+            Token::While => {
+                self.advance();
+                let cond = self.parse_expr()?;
+                self.expect(Token::Do, "'do'")?;
+                let body = self.parse_block()?;
+                self.expect(Token::End, "'end'")?;
+                StmtKind::While { cond, body }
+            }
+            Token::Do => {
+                self.advance();
+                let body = self.parse_block()?;
+                self.expect(Token::End, "'end'")?;
+                StmtKind::Do(body)
+            }
+            Token::For => self.parse_for()?,
+            Token::Repeat => {
+                self.advance();
+                let body = self.parse_block()?;
+                self.expect(Token::Until, "'until'")?;
+                let cond = self.parse_expr()?;
+                StmtKind::Repeat { body, cond }
+            }
+            Token::Function => self.parse_function_decl(Scope::Local, None, true)?,
+            Token::Local => {
+                self.advance();
+                self.parse_declaration(Scope::Local)?
+            }
+            Token::Global => {
+                self.advance();
+                self.parse_declaration(Scope::Global)?
+            }
+            Token::Break => {
+                self.advance();
+                StmtKind::Break
+            }
+            Token::Goto => {
+                self.advance();
+                StmtKind::Goto(self.expect_name()?)
+            }
+            Token::DoubleColon => {
+                self.advance();
+                let name = self.expect_name()?;
+                self.expect(Token::DoubleColon, "'::'")?;
+                StmtKind::Label(name)
+            }
+            token if type_name(token).is_some() => {
+                // `void () end` and `void function() end` are bare function
+                // literals, anything else is a typed declaration
+                let after = self.type_len(0).unwrap_or(1);
+                let is_literal = match self.peek_at(after) {
+                    Token::LParen => true,
+                    Token::Function => self.peek_at(after + 1) == &Token::LParen,
+                    _ => false,
+                };
+                if is_literal {
+                    StmtKind::Expr(self.parse_expr()?)
+                } else {
+                    self.parse_declaration(Scope::Local)?
+                }
+            }
+            _ => self.parse_expr_statement()?,
+        };
+        Ok(Some(Stmt {
+            kind,
+            span: start.to(self.prev_span()),
+        }))
+    }
+
+    /// Everything after an optional `local`/`global`: `[type] function name
+    /// body` or `[type] name {, name} [= explist]`.
+    fn parse_declaration(&mut self, scope: Scope) -> ParseResult<StmtKind> {
+        let ty = self.parse_type_opt()?;
+        if self.check(&Token::Function) {
+            return self.parse_function_decl(scope, ty, false);
+        }
+        let mut names = Vec::new();
+        let mut attribs = Vec::new();
+        loop {
+            names.push(self.expect_name()?);
+            let start = self.span();
+            let attrib = match scope {
+                Scope::Local => self.parse_attrib()?,
+                Scope::Global => None,
+            };
+            if attrib == Some(Attrib::Close) && attribs.contains(&Some(Attrib::Close)) {
+                return Err(ParseError {
+                    kind: ParseErrorKind::MultipleToBeClosed,
+                    span: start.to(self.prev_span()),
+                });
+            }
+            attribs.push(attrib);
+            if !self.eat(&Token::Comma) {
+                break;
+            }
+        }
+        let values = if self.eat(&Token::Assign) {
+            self.parse_expr_list()?
+        } else {
+            Vec::new()
+        };
+        Ok(StmtKind::Local {
+            scope,
+            ty,
+            names,
+            attribs,
+            values,
+        })
+    }
+
+    /// `<const>` or `<close>` after the name of a local.
+    fn parse_attrib(&mut self) -> ParseResult<Option<Attrib>> {
+        if !self.eat(&Token::Lt) {
+            return Ok(None);
+        }
+        let name = self.expect_name()?;
+        self.expect(Token::Gt, "'>'")?;
+        let attrib = match name.name.as_str() {
+            "const" => Attrib::Const,
+            "close" => Attrib::Close,
+            _ => {
+                return Err(ParseError {
+                    kind: ParseErrorKind::UnknownAttribute(name.name),
+                    span: name.span,
+                })
+            }
+        };
+        Ok(Some(attrib))
+    }
+
+    fn parse_function_decl(
+        &mut self,
+        scope: Scope,
+        ret: Option<TypeName>,
+        allow_path: bool,
+    ) -> ParseResult<StmtKind> {
+        let start = self.span();
+        self.expect(Token::Function, "'function'")?;
+        let mut path = vec![self.expect_name()?];
+        let mut method = None;
+        if allow_path {
+            while self.eat(&Token::Dot) {
+                path.push(self.expect_name()?);
+            }
+            if self.eat(&Token::Colon) {
+                method = Some(self.expect_name()?);
+            }
+        }
+        let func = self.parse_function_body(ret, false, start)?;
+        Ok(StmtKind::Function {
+            scope,
+            name: FunctionName { path, method },
+            func: Rc::new(func),
+        })
+    }
+
+    fn parse_expr_statement(&mut self) -> ParseResult<StmtKind> {
+        let expr = self.parse_suffixed_expr()?;
+        if self.check(&Token::Assign) || self.check(&Token::Comma) {
+            let mut targets = vec![expr];
+            while self.eat(&Token::Comma) {
+                targets.push(self.parse_suffixed_expr()?);
+            }
+            self.expect(Token::Assign, "'='")?;
+            for target in &targets {
+                if !matches!(target.kind, ExprKind::Name(_) | ExprKind::Index { .. }) {
+                    return Err(ParseError {
+                        kind: ParseErrorKind::InvalidAssignTarget,
+                        span: target.span,
+                    });
+                }
+            }
+            let values = self.parse_expr_list()?;
+            return Ok(StmtKind::Assign { targets, values });
+        }
+        match expr.kind {
+            ExprKind::Call { .. } | ExprKind::MethodCall { .. } => Ok(StmtKind::Call(expr)),
+            _ => Err(ParseError {
+                kind: ParseErrorKind::NotAStatement,
+                span: expr.span,
+            }),
+        }
+    }
+
+    fn parse_if(&mut self) -> ParseResult<StmtKind> {
+        self.expect(Token::If, "'if'")?;
+        let mut branches = Vec::new();
+        let cond = self.parse_expr()?;
+        self.expect(Token::Then, "'then'")?;
+        branches.push((cond, self.parse_block()?));
+        let mut else_block = None;
+        loop {
+            if self.eat(&Token::ElseIf) {
+                let cond = self.parse_expr()?;
+                self.expect(Token::Then, "'then'")?;
+                branches.push((cond, self.parse_block()?));
+            } else if self.eat(&Token::Else) {
+                else_block = Some(self.parse_block()?);
+                self.expect(Token::End, "'end'")?;
+                break;
+            } else {
+                self.expect(Token::End, "'end'")?;
+                break;
+            }
+        }
+        Ok(StmtKind::If {
+            branches,
+            else_block,
+        })
+    }
+
+    fn parse_for(&mut self) -> ParseResult<StmtKind> {
+        self.expect(Token::For, "'for'")?;
+        let first = self.expect_name()?;
+        if self.eat(&Token::Assign) {
+            let start = self.parse_expr()?;
+            self.expect(Token::Comma, "','")?;
+            let limit = self.parse_expr()?;
+            let step = if self.eat(&Token::Comma) {
+                Some(self.parse_expr()?)
+            } else {
+                None
+            };
+            self.expect(Token::Do, "'do'")?;
+            let body = self.parse_block()?;
+            self.expect(Token::End, "'end'")?;
+            return Ok(StmtKind::NumericFor {
+                var: first,
+                start,
+                limit,
+                step,
+                body,
+            });
+        }
+        let mut names = vec![first];
+        while self.eat(&Token::Comma) {
+            names.push(self.expect_name()?);
+        }
+        self.expect(Token::In, "'=' or 'in'")?;
+        let exprs = self.parse_expr_list()?;
+        self.expect(Token::Do, "'do'")?;
+        let body = self.parse_block()?;
+        self.expect(Token::End, "'end'")?;
+        Ok(StmtKind::GenericFor { names, exprs, body })
+    }
+
+    fn parse_return(&mut self) -> ParseResult<Stmt> {
+        let start = self.span();
+        self.expect(Token::Return, "'return'")?;
+        let values = if self.block_ends() || self.check(&Token::Semicolon) {
+            Vec::new()
+        } else {
+            self.parse_expr_list()?
+        };
+        self.eat(&Token::Semicolon);
+        Ok(Stmt {
+            kind: StmtKind::Return(values),
+            span: start.to(self.prev_span()),
+        })
+    }
+
+    /// `( [param {, param}] [, ...] ) block end`, the opening token having
+    /// been consumed by the caller and its span passed in as `start`.
+    fn parse_function_body(
+        &mut self,
+        ret: Option<TypeName>,
+        fastcall: bool,
+        start: Span,
+    ) -> ParseResult<Function> {
+        self.expect(Token::LParen, "'('")?;
+        let mut params = Vec::new();
+        let mut is_vararg = false;
+        if !self.check(&Token::RParen) {
+            loop {
+                if self.eat(&Token::Ellipsis) {
+                    is_vararg = true;
+                    break;
+                }
+                let ty = self.parse_type_opt()?;
+                let name = self.expect_name()?;
+                params.push(Param { name, ty });
+                if !self.eat(&Token::Comma) {
+                    break;
+                }
+            }
+        }
+        self.expect(Token::RParen, "')'")?;
+        let body = self.parse_block()?;
+        self.expect(Token::End, "'end'")?;
+        Ok(Function {
+            ret,
+            params,
+            is_vararg,
+            fastcall,
+            body,
+            span: start.to(self.prev_span()),
+        })
+    }
+
+    /// A type keyword, `vec` optionally followed by its element type `<T>`.
+    fn parse_type_opt(&mut self) -> ParseResult<Option<TypeName>> {
+        let Some(ty) = type_name(self.peek()) else {
+            return Ok(None);
+        };
+        self.advance();
+        if ty != TypeName::Vec(None) || !self.eat(&Token::Lt) {
+            return Ok(Some(ty));
+        }
+        let Some(elem) = self.nested(Self::parse_type_opt)? else {
+            return Err(self.error_expected("an element type"));
+        };
+        self.expect_closing_angle()?;
+        Ok(Some(TypeName::Vec(Some(Box::new(elem)))))
+    }
+
+    /// Consumes the `>` closing a `vec<T>`. In `vec<vec<int>>` the lexer
+    /// reads `>>` as a shift, so that token is split in two.
+    fn expect_closing_angle(&mut self) -> ParseResult<()> {
+        if self.check(&Token::Shr) {
+            let tok = &mut self.tokens[self.pos];
+            let start = tok.span.start;
+            tok.token = Token::Gt;
+            tok.span.start = Position::new(start.offset + 1, start.line, start.column + 1);
+            return Ok(());
+        }
+        self.expect(Token::Gt, "'>'")?;
+        Ok(())
+    }
+
+    /// Number of tokens taken by the type starting `n` tokens ahead, if
+    /// there is one there.
+    fn type_len(&self, n: usize) -> Option<usize> {
+        type_name(self.peek_at(n))?;
+        let mut len = 1;
+        let mut depth = 0;
+        while self.peek_at(n + len - 1) == &Token::Vec && self.peek_at(n + len) == &Token::Lt {
+            type_name(self.peek_at(n + len + 1))?;
+            depth += 1;
+            len += 2;
+        }
+        while depth > 0 {
+            match self.peek_at(n + len) {
+                Token::Gt => depth -= 1,
+                Token::Shr if depth >= 2 => depth -= 2,
+                _ => return None,
+            }
+            len += 1;
+        }
+        Some(len)
+    }
+
+    pub fn parse_expr(&mut self) -> ParseResult<Expr> {
+        self.parse_binary(0)
+    }
+
+    /// Pratt loop: parses operators whose left binding power exceeds `limit`.
+    fn parse_binary(&mut self, limit: u8) -> ParseResult<Expr> {
+        self.nested(|parser| parser.parse_binary_operators(limit))
+    }
+
+    fn parse_binary_operators(&mut self, limit: u8) -> ParseResult<Expr> {
+        let start = self.span();
+        let mut lhs = if let Some(op) = unary_op(self.peek()) {
+            self.advance();
+            let expr = self.parse_binary(UNARY_PRIORITY)?;
+            Expr {
+                span: start.to(expr.span),
+                kind: ExprKind::Unary {
+                    op,
+                    expr: Box::new(expr),
+                },
+            }
+        } else {
+            self.parse_simple_expr()?
+        };
+        while let Some(op) = binary_op(self.peek()) {
+            let (left, right) = op.precedence();
+            if left <= limit {
+                break;
+            }
+            self.advance();
+            let rhs = self.parse_binary(right)?;
+            lhs = Expr {
+                span: lhs.span.to(rhs.span),
+                kind: ExprKind::Binary {
+                    op,
+                    lhs: Box::new(lhs),
+                    rhs: Box::new(rhs),
+                },
+            };
+        }
+        Ok(lhs)
+    }
+
+    fn parse_expr_list(&mut self) -> ParseResult<Vec<Expr>> {
+        let mut exprs = vec![self.parse_expr()?];
+        while self.eat(&Token::Comma) {
+            exprs.push(self.parse_expr()?);
+        }
+        Ok(exprs)
+    }
+
+    fn parse_simple_expr(&mut self) -> ParseResult<Expr> {
+        let start = self.span();
+        let kind = match self.peek().clone() {
+            Token::Nil => ExprKind::Nil,
+            Token::True => ExprKind::True,
+            Token::False => ExprKind::False,
+            Token::IntegerLiteral(n) => ExprKind::Integer(n),
+            Token::FloatLiteral(n) => ExprKind::Float(n),
+            Token::StringLiteral(s) => ExprKind::String(s),
+            Token::CharLiteral(c) => ExprKind::Char(c),
+            Token::Ellipsis => ExprKind::Vararg,
+            Token::LBrace => return self.parse_table(),
+            Token::LBracket => return self.parse_vec(),
+            Token::Function => {
+                self.advance();
+                let func = self.parse_function_body(None, false, start)?;
+                return Ok(Expr {
+                    span: func.span,
+                    kind: ExprKind::Function(Rc::new(func)),
+                });
+            }
+            token if type_name(&token).is_some() => {
+                let ret = self.parse_type_opt()?;
+                let fastcall = !self.eat(&Token::Function);
+                let func = self.parse_function_body(ret, fastcall, start)?;
+                return Ok(Expr {
+                    span: func.span,
+                    kind: ExprKind::Function(Rc::new(func)),
+                });
+            }
+            Token::LParen if self.is_cast() => {
+                self.advance();
+                let ty = self.parse_type_opt()?.unwrap();
+                self.expect(Token::RParen, "')'")?;
+                // A cast binds like a unary operator
+                let expr = self.parse_binary(UNARY_PRIORITY)?;
+                return Ok(Expr {
+                    span: start.to(expr.span),
+                    kind: ExprKind::Cast {
+                        ty,
+                        expr: Box::new(expr),
+                    },
+                });
+            }
+            _ => return self.parse_suffixed_expr(),
+        };
+        self.advance();
+        Ok(Expr { kind, span: start })
+    }
+
+    /// `(` type `)` starts a cast, never a parenthesized expression.
+    fn is_cast(&self) -> bool {
+        self.type_len(1)
+            .is_some_and(|len| self.peek_at(1 + len) == &Token::RParen)
+    }
+
+    fn parse_primary_expr(&mut self) -> ParseResult<Expr> {
+        let start = self.span();
+        match self.peek().clone() {
+            Token::Ident(name) => {
+                self.advance();
+                Ok(Expr {
+                    kind: ExprKind::Name(name),
+                    span: start,
+                })
+            }
+            Token::LParen => {
+                self.advance();
+                let inner = self.parse_expr()?;
+                self.expect(Token::RParen, "')'")?;
+                Ok(Expr {
+                    kind: ExprKind::Paren(Box::new(inner)),
+                    span: start.to(self.prev_span()),
+                })
+            }
+            token => Err(ParseError {
+                kind: ParseErrorKind::UnexpectedToken(token),
+                span: start,
+            }),
+        }
+    }
+
+    fn parse_suffixed_expr(&mut self) -> ParseResult<Expr> {
+        let mut expr = self.parse_primary_expr()?;
+        loop {
+            let kind = match self.peek() {
+                Token::Dot => {
+                    self.advance();
+                    let name = self.expect_name()?;
+                    ExprKind::Index {
+                        object: Box::new(expr),
+                        key: Box::new(Expr {
+                            kind: ExprKind::String(name.name.into_bytes()),
+                            span: name.span,
+                        }),
+                    }
+                }
+                Token::LBracket => {
+                    self.advance();
+                    let key = self.parse_expr()?;
+                    self.expect(Token::RBracket, "']'")?;
+                    ExprKind::Index {
+                        object: Box::new(expr),
+                        key: Box::new(key),
+                    }
+                }
+                Token::Colon => {
+                    self.advance();
+                    let method = self.expect_name()?;
+                    let args = self.parse_args()?;
+                    ExprKind::MethodCall {
+                        object: Box::new(expr),
+                        method,
+                        args,
+                    }
+                }
+                Token::LParen | Token::LBrace | Token::StringLiteral(_) | Token::CharLiteral(_) => {
+                    let args = self.parse_args()?;
+                    ExprKind::Call {
+                        func: Box::new(expr),
+                        args,
+                    }
+                }
+                _ => return Ok(expr),
+            };
+            let span = match &kind {
+                ExprKind::Index { object, .. }
+                | ExprKind::MethodCall { object, .. }
+                | ExprKind::Call { func: object, .. } => object.span.to(self.prev_span()),
+                _ => unreachable!(),
+            };
+            expr = Expr { kind, span };
+        }
+    }
+
+    fn parse_args(&mut self) -> ParseResult<Vec<Expr>> {
+        match self.peek().clone() {
+            Token::StringLiteral(_) | Token::CharLiteral(_) => Ok(vec![self.parse_simple_expr()?]),
+            Token::LBrace => Ok(vec![self.parse_table()?]),
+            _ => {
+                self.expect(Token::LParen, "function arguments")?;
+                if self.eat(&Token::RParen) {
+                    return Ok(Vec::new());
+                }
+                let args = self.parse_expr_list()?;
+                self.expect(Token::RParen, "')'")?;
+                Ok(args)
+            }
+        }
+    }
+
+    fn parse_table(&mut self) -> ParseResult<Expr> {
+        let start = self.span();
+        self.expect(Token::LBrace, "'{'")?;
+        let mut fields = Vec::new();
+        while !self.check(&Token::RBrace) {
+            fields.push(self.parse_table_field()?);
+            if !self.eat(&Token::Comma) && !self.eat(&Token::Semicolon) {
+                break;
+            }
+        }
+        self.expect(Token::RBrace, "'}'")?;
+        Ok(Expr {
+            kind: ExprKind::Table(fields),
+            span: start.to(self.prev_span()),
+        })
+    }
+
+    fn parse_table_field(&mut self) -> ParseResult<TableField> {
+        match (self.peek(), self.peek_at(1), self.peek_at(2)) {
+            (Token::Ident(_), Token::Assign, _) => {
+                let name = self.expect_name()?;
+                self.advance();
+                let value = self.parse_expr()?;
+                Ok(TableField::Named {
+                    name,
+                    ty: None,
+                    value,
+                })
+            }
+            _ if self.type_len(0).is_some_and(|len| {
+                matches!(self.peek_at(len), Token::Ident(_))
+                    && self.peek_at(len + 1) == &Token::Assign
+            }) =>
+            {
+                let ty = self.parse_type_opt()?;
+                let name = self.expect_name()?;
+                self.advance();
+                let value = self.parse_expr()?;
+                Ok(TableField::Named { name, ty, value })
+            }
+            (Token::LBracket, _, _) => {
+                // `[k] = v` unless it turns out to be a vec literal
+                let saved = self.pos;
+                self.advance();
+                if let Ok(key) = self.parse_expr() {
+                    if self.eat(&Token::RBracket) && self.eat(&Token::Assign) {
+                        let value = self.parse_expr()?;
+                        return Ok(TableField::Keyed { key, value });
+                    }
+                }
+                self.pos = saved;
+                Ok(TableField::Positional(self.parse_expr()?))
+            }
+            _ => Ok(TableField::Positional(self.parse_expr()?)),
+        }
+    }
+
+    fn parse_vec(&mut self) -> ParseResult<Expr> {
+        let start = self.span();
+        self.expect(Token::LBracket, "'['")?;
+        let mut items = Vec::new();
+        while !self.check(&Token::RBracket) {
+            items.push(self.parse_expr()?);
+            if !self.eat(&Token::Comma) {
+                break;
+            }
+        }
+        self.expect(Token::RBracket, "']'")?;
+        Ok(Expr {
+            kind: ExprKind::Vec(items),
+            span: start.to(self.prev_span()),
+        })
+    }
+
+    fn peek(&self) -> &Token {
+        self.peek_at(0)
+    }
+
+    fn peek_at(&self, n: usize) -> &Token {
+        let idx = (self.pos + n).min(self.tokens.len() - 1);
+        &self.tokens[idx].token
+    }
+
+    fn span(&self) -> Span {
+        self.tokens[self.pos].span
+    }
+
+    fn prev_span(&self) -> Span {
+        self.tokens[self.pos.saturating_sub(1)].span
+    }
+
+    fn advance(&mut self) {
+        if self.pos < self.tokens.len() - 1 {
+            self.pos += 1;
+        }
+    }
+
+    fn check(&self, token: &Token) -> bool {
+        self.peek() == token
+    }
+
+    fn eat(&mut self, token: &Token) -> bool {
+        if self.check(token) {
+            self.advance();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, token: Token, expected: &'static str) -> ParseResult<Span> {
+        if self.check(&token) {
+            let span = self.span();
+            self.advance();
+            Ok(span)
+        } else {
+            Err(self.error_expected(expected))
+        }
+    }
+
+    fn expect_name(&mut self) -> ParseResult<Name> {
+        match self.peek().clone() {
+            Token::Ident(name) => {
+                let span = self.span();
+                self.advance();
+                Ok(Name { name, span })
+            }
+            _ => Err(self.error_expected("a name")),
+        }
+    }
+
+    fn error_expected(&self, expected: &'static str) -> ParseError {
+        ParseError {
+            kind: ParseErrorKind::Expected {
+                expected,
+                found: self.peek().clone(),
+            },
+            span: self.span(),
+        }
+    }
+}
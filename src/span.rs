@@ -0,0 +1,61 @@
+use std::fmt;
+
+/// Identifies the source a span belongs to, `0` for single-file runs.
+pub type FileId = usize;
+
+/// A point in the source. Lines and columns are 1-based, columns count chars.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Position {
+    pub offset: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Position {
+    pub fn new(offset: usize, line: usize, column: usize) -> Self {
+        Position {
+            offset,
+            line,
+            column,
+        }
+    }
+}
+
+impl Default for Position {
+    fn default() -> Self {
+        Position::new(0, 1, 1)
+    }
+}
+
+/// Half-open source region `[start, end)` inside a single file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Hash)]
+pub struct Span {
+    pub file_id: FileId,
+    pub start: Position,
+    pub end: Position,
+}
+
+impl Span {
+    pub fn new(file_id: FileId, start: Position, end: Position) -> Self {
+        Span {
+            file_id,
+            start,
+            end,
+        }
+    }
+
+    /// Smallest span covering both `self` and `other`.
+    pub fn to(self, other: Span) -> Span {
+        Span {
+            file_id: self.file_id,
+            start: self.start.min(other.start),
+            end: self.end.max(other.end),
+        }
+    }
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.start.line, self.start.column)
+    }
+}
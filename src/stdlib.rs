@@ -0,0 +1,414 @@
+use crate::gc;
+use crate::value::*;
+
+/// What builtins need from the engine running them.
+pub trait Host {
+    fn call(&mut self, func: &Value, args: Vec<Value>) -> Result<Vec<Value>, RuntimeError>;
+
+    /// Destination of `print`.
+    fn write(&mut self, bytes: &[u8]) -> Result<(), RuntimeError>;
+}
+
+const BASE_LIBRARY: [(&str, NativeFn); 18] = [
+    ("print", print),
+    ("type", type_),
+    ("tostring", tostring),
+    ("tonumber", tonumber),
+    ("pairs", pairs),
+    ("ipairs", ipairs),
+    ("next", next),
+    ("select", select),
+    ("assert", assert),
+    ("error", error),
+    ("pcall", pcall),
+    ("rawget", rawget),
+    ("rawset", rawset),
+    ("rawequal", rawequal),
+    ("rawlen", rawlen),
+    ("collectgarbage", collectgarbage),
+    ("setmetatable", setmetatable),
+    ("getmetatable", getmetatable),
+];
+
+/// Registers the base library into a globals table.
+pub fn open_base(globals: &mut Table) {
+    for (name, func) in BASE_LIBRARY {
+        globals
+            .set(Value::str(name), Value::native(name, func))
+            .unwrap();
+    }
+}
+
+/// Names of the globals `open_base` defines.
+pub fn base_names() -> impl Iterator<Item = &'static str> {
+    BASE_LIBRARY.iter().map(|(name, _)| *name)
+}
+
+pub fn arg(args: &[Value], i: usize) -> Value {
+    args.get(i).cloned().unwrap_or_default()
+}
+
+pub fn check_table(args: &[Value], i: usize, fname: &str) -> Result<TableRef, RuntimeError> {
+    match args.get(i) {
+        Some(Value::Table(t)) => Ok(t.clone()),
+        other => Err(bad_argument(i, fname, "table", other)),
+    }
+}
+
+pub fn check_vec(args: &[Value], i: usize, fname: &str) -> Result<VecRef, RuntimeError> {
+    match args.get(i) {
+        Some(Value::Vec(v)) => Ok(v.clone()),
+        other => Err(bad_argument(i, fname, "vec", other)),
+    }
+}
+
+pub fn check_int(args: &[Value], i: usize, fname: &str) -> Result<i64, RuntimeError> {
+    match args.get(i).and_then(Value::to_int) {
+        Some(n) => Ok(n),
+        None => Err(bad_argument(i, fname, "integer", args.get(i))),
+    }
+}
+
+/// Integer argument defaulting to 0 when absent or nil.
+fn optional_int(args: &[Value], i: usize, fname: &str) -> Result<i64, RuntimeError> {
+    match args.get(i) {
+        None | Some(Value::Nil) => Ok(0),
+        _ => check_int(args, i, fname),
+    }
+}
+
+pub fn bad_argument(i: usize, fname: &str, expected: &str, found: Option<&Value>) -> RuntimeError {
+    let found = found.map_or("no value", Value::type_name);
+    RuntimeError::new(format!(
+        "bad argument #{} to '{}' ({} expected, got {})",
+        i + 1,
+        fname,
+        expected,
+        found
+    ))
+}
+
+fn print(host: &mut dyn Host, args: Vec<Value>) -> Result<Vec<Value>, RuntimeError> {
+    let mut line = Vec::new();
+    for (i, value) in args.iter().enumerate() {
+        if i > 0 {
+            line.push(b'\t');
+        }
+        line.extend(crate::value::tostring(host, value)?);
+    }
+    line.push(b'\n');
+    host.write(&line)?;
+    Ok(Vec::new())
+}
+
+fn type_(_: &mut dyn Host, args: Vec<Value>) -> Result<Vec<Value>, RuntimeError> {
+    match args.first() {
+        Some(v) => Ok(vec![Value::str(v.type_name())]),
+        None => Err(bad_argument(0, "type", "value", None)),
+    }
+}
+
+fn tostring(host: &mut dyn Host, args: Vec<Value>) -> Result<Vec<Value>, RuntimeError> {
+    let text = crate::value::tostring(host, &arg(&args, 0))?;
+    Ok(vec![Value::bytes(text)])
+}
+
+fn tonumber(_: &mut dyn Host, args: Vec<Value>) -> Result<Vec<Value>, RuntimeError> {
+    let value = arg(&args, 0);
+    if matches!(args.get(1), None | Some(Value::Nil)) {
+        return Ok(vec![value.to_number().unwrap_or_default()]);
+    }
+    let base = check_int(&args, 1, "tonumber")?;
+    if !(2..=36).contains(&base) {
+        return Err(RuntimeError::new(
+            "bad argument #2 to 'tonumber' (base out of range)",
+        ));
+    }
+    let Value::Str(s) = value else {
+        return Err(bad_argument(0, "tonumber", "string", Some(&value)));
+    };
+    let text = String::from_utf8_lossy(&s).trim().to_lowercase();
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, text.as_str()),
+    };
+    let mut n: i64 = 0;
+    for c in digits.chars() {
+        match c.to_digit(base as u32) {
+            Some(d) => n = n.wrapping_mul(base).wrapping_add(d as i64),
+            None => return Ok(vec![Value::Nil]),
+        }
+    }
+    if digits.is_empty() {
+        return Ok(vec![Value::Nil]);
+    }
+    Ok(vec![Value::Int(if negative {
+        n.wrapping_neg()
+    } else {
+        n
+    })])
+}
+
+fn next(_: &mut dyn Host, args: Vec<Value>) -> Result<Vec<Value>, RuntimeError> {
+    let table = check_table(&args, 0, "next")?;
+    let entry = table.borrow().next(&arg(&args, 1))?;
+    Ok(match entry {
+        Some((k, v)) => vec![k, v],
+        None => vec![Value::Nil],
+    })
+}
+
+fn pairs(host: &mut dyn Host, args: Vec<Value>) -> Result<Vec<Value>, RuntimeError> {
+    // A vec has no keys besides its indices
+    if let Some(Value::Vec(_)) = args.first() {
+        return ipairs(host, args);
+    }
+    let table = check_table(&args, 0, "pairs")?;
+    Ok(vec![
+        Value::native("next", next),
+        Value::Table(table),
+        Value::Nil,
+    ])
+}
+
+fn ipairs_iter(host: &mut dyn Host, args: Vec<Value>) -> Result<Vec<Value>, RuntimeError> {
+    let i = check_int(&args, 1, "ipairs")?.wrapping_add(1);
+    let value = match args.first() {
+        // Elements of a vec may be nil, its length ends the loop
+        Some(Value::Vec(v)) => match usize::try_from(i.wrapping_sub(1))
+            .ok()
+            .and_then(|k| v.borrow().items.get(k).cloned())
+        {
+            Some(value) => return Ok(vec![Value::Int(i), value]),
+            None => Value::Nil,
+        },
+        _ => {
+            let table = check_table(&args, 0, "ipairs")?;
+            index(host, &Value::Table(table), &Value::Int(i))?
+        }
+    };
+    Ok(match value {
+        Value::Nil => vec![Value::Nil],
+        value => vec![Value::Int(i), value],
+    })
+}
+
+fn ipairs(_: &mut dyn Host, args: Vec<Value>) -> Result<Vec<Value>, RuntimeError> {
+    let subject = match args.first() {
+        Some(Value::Vec(v)) => Value::Vec(v.clone()),
+        _ => Value::Table(check_table(&args, 0, "ipairs")?),
+    };
+    Ok(vec![
+        Value::native("ipairs_iter", ipairs_iter),
+        subject,
+        Value::Int(0),
+    ])
+}
+
+fn select(_: &mut dyn Host, mut args: Vec<Value>) -> Result<Vec<Value>, RuntimeError> {
+    let count = args.len() as i64 - 1;
+    if let Some(Value::Str(s)) = args.first() {
+        if &**s == b"#" {
+            return Ok(vec![Value::Int(count)]);
+        }
+    }
+    let n = check_int(&args, 0, "select")?;
+    let start = match n {
+        n if n < 0 && n >= -count => count + n,
+        n if n > 0 => (n - 1).min(count),
+        _ => {
+            return Err(RuntimeError::new(
+                "bad argument #1 to 'select' (index out of range)",
+            ))
+        }
+    };
+    Ok(args.split_off(1 + start as usize))
+}
+
+fn assert(_: &mut dyn Host, args: Vec<Value>) -> Result<Vec<Value>, RuntimeError> {
+    match args.first() {
+        Some(v) if v.is_truthy() => Ok(args),
+        None => Err(bad_argument(0, "assert", "value", None)),
+        Some(_) => match args.get(1) {
+            Some(message) => Err(RuntimeError {
+                value: message.clone(),
+                span: None,
+            }),
+            None => Err(RuntimeError::new("assertion failed!")),
+        },
+    }
+}
+
+fn error(_: &mut dyn Host, args: Vec<Value>) -> Result<Vec<Value>, RuntimeError> {
+    Err(RuntimeError {
+        value: arg(&args, 0),
+        span: None,
+    })
+}
+
+fn pcall(host: &mut dyn Host, mut args: Vec<Value>) -> Result<Vec<Value>, RuntimeError> {
+    if args.is_empty() {
+        return Err(bad_argument(0, "pcall", "value", None));
+    }
+    let func = args.remove(0);
+    match host.call(&func, args) {
+        Ok(mut results) => {
+            results.insert(0, Value::Bool(true));
+            Ok(results)
+        }
+        Err(e) => Ok(vec![Value::Bool(false), e.into_value()]),
+    }
+}
+
+fn collectgarbage(host: &mut dyn Host, args: Vec<Value>) -> Result<Vec<Value>, RuntimeError> {
+    let option = match args.first() {
+        None | Some(Value::Nil) => "collect".to_string(),
+        Some(Value::Str(s)) => String::from_utf8_lossy(s).into_owned(),
+        other => return Err(bad_argument(0, "collectgarbage", "string", other)),
+    };
+    let result = match option.as_str() {
+        "collect" => {
+            gc::collect();
+            run_finalizers(host);
+            Value::Int(0)
+        }
+        "count" => Value::Float(gc::count() as f64 / 1024.0),
+        "step" => {
+            let kbytes = optional_int(&args, 1, "collectgarbage")?;
+            let finished = gc::step(kbytes.max(0) as usize);
+            run_finalizers(host);
+            Value::Bool(finished)
+        }
+        "stop" | "restart" => {
+            gc::set_running(option == "restart");
+            Value::Int(0)
+        }
+        "incremental" | "generational" => {
+            let mut params = gc::params();
+            // A zero or missing argument keeps the current setting
+            let setting = |i: usize, current: usize| -> Result<usize, RuntimeError> {
+                let n = optional_int(&args, i, "collectgarbage")?;
+                Ok(if n > 0 { n as usize } else { current })
+            };
+            let mode = if option == "incremental" {
+                params.pause = setting(1, params.pause)?;
+                params.stepmul = setting(2, params.stepmul)?;
+                params.stepsize = setting(3, params.stepsize as usize)?.min(40) as u32;
+                gc::Mode::Incremental
+            } else {
+                params.minormul = setting(1, params.minormul)?;
+                params.majormul = setting(2, params.majormul)?;
+                gc::Mode::Generational
+            };
+            Value::str(gc::set_mode(mode, params).name())
+        }
+        // Lua 5.1 style tuning, returning the previous value
+        "setpause" | "setstepmul" => {
+            let mut params = gc::params();
+            let n = optional_int(&args, 1, "collectgarbage")?.max(0) as usize;
+            let field = if option == "setpause" {
+                &mut params.pause
+            } else {
+                &mut params.stepmul
+            };
+            let previous = std::mem::replace(field, n);
+            gc::set_params(params);
+            Value::Int(previous as i64)
+        }
+        "isrunning" => Value::Bool(gc::is_running()),
+        _ => {
+            return Err(RuntimeError::new(format!(
+                "bad argument #1 to 'collectgarbage' (invalid option '{}')",
+                option
+            )))
+        }
+    };
+    Ok(vec![result])
+}
+
+fn setmetatable(_: &mut dyn Host, args: Vec<Value>) -> Result<Vec<Value>, RuntimeError> {
+    let table = check_table(&args, 0, "setmetatable")?;
+    let meta = match args.get(1) {
+        Some(Value::Table(meta)) => Some(meta.clone()),
+        Some(Value::Nil) => None,
+        other => return Err(bad_argument(1, "setmetatable", "nil or table", other)),
+    };
+    if !matches!(
+        metamethod(&Value::Table(table.clone()), Event::Metatable),
+        Value::Nil
+    ) {
+        return Err(RuntimeError::new("cannot change a protected metatable"));
+    }
+    set_metatable(&table, meta);
+    Ok(vec![Value::Table(table)])
+}
+
+fn getmetatable(_: &mut dyn Host, args: Vec<Value>) -> Result<Vec<Value>, RuntimeError> {
+    let value = arg(&args, 0);
+    let Value::Table(table) = &value else {
+        return Ok(vec![Value::Nil]);
+    };
+    let Some(meta) = table.borrow().metatable().cloned() else {
+        return Ok(vec![Value::Nil]);
+    };
+    // A `__metatable` field is shown in place of the metatable
+    Ok(vec![match metamethod(&value, Event::Metatable) {
+        Value::Nil => Value::Table(meta),
+        protected => protected,
+    }])
+}
+
+fn rawget(_: &mut dyn Host, args: Vec<Value>) -> Result<Vec<Value>, RuntimeError> {
+    let table = check_table(&args, 0, "rawget")?;
+    let value = table.borrow().get(&arg(&args, 1));
+    Ok(vec![value])
+}
+
+fn rawset(_: &mut dyn Host, args: Vec<Value>) -> Result<Vec<Value>, RuntimeError> {
+    let table = check_table(&args, 0, "rawset")?;
+    let value = table
+        .borrow()
+        .conform_field(&arg(&args, 1), arg(&args, 2))?;
+    table.borrow_mut().set(arg(&args, 1), value)?;
+    Ok(vec![Value::Table(table)])
+}
+
+fn rawequal(_: &mut dyn Host, args: Vec<Value>) -> Result<Vec<Value>, RuntimeError> {
+    Ok(vec![Value::Bool(arg(&args, 0).raw_equals(&arg(&args, 1)))])
+}
+
+fn rawlen(_: &mut dyn Host, args: Vec<Value>) -> Result<Vec<Value>, RuntimeError> {
+    match args.first() {
+        Some(Value::Table(t)) => Ok(vec![Value::Int(t.borrow().len())]),
+        Some(Value::Vec(v)) => Ok(vec![Value::Int(v.borrow().items.len() as i64)]),
+        Some(Value::Str(s)) => Ok(vec![Value::Int(s.len() as i64)]),
+        other => Err(bad_argument(0, "rawlen", "table, vec or string", other)),
+    }
+}
+
+/// Methods of `vec` values, found by indexing a vec with their name.
+pub fn vec_method(name: &[u8]) -> Option<Value> {
+    let (name, func): (&'static str, NativeFn) = match name {
+        b"push" => ("push", vec_push),
+        b"pop" => ("pop", vec_pop),
+        _ => return None,
+    };
+    Some(Value::native(name, func))
+}
+
+/// `v:push(x)` appends `x`.
+fn vec_push(_: &mut dyn Host, args: Vec<Value>) -> Result<Vec<Value>, RuntimeError> {
+    let v = check_vec(&args, 0, "push")?;
+    let value = conform_element(&v, arg(&args, 1))?;
+    value.write_barrier();
+    gc::grow(std::mem::size_of::<Value>());
+    v.borrow_mut().items.push(value);
+    Ok(Vec::new())
+}
+
+/// `v:pop()` removes and returns the last element, nil when empty.
+fn vec_pop(_: &mut dyn Host, args: Vec<Value>) -> Result<Vec<Value>, RuntimeError> {
+    let v = check_vec(&args, 0, "pop")?;
+    let last = v.borrow_mut().items.pop();
+    Ok(vec![last.unwrap_or_default()])
+}
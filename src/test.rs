@@ -1,11 +1,25 @@
 #[cfg(test)]
 use super::*;
+#[cfg(test)]
+use crate::ast::*;
+#[cfg(test)]
+use crate::checker::{Type, TypeError, TypeErrorKind};
+#[cfg(test)]
+use crate::parser::{ParseError, ParseErrorKind};
+#[cfg(test)]
+use crate::span::Position;
+#[cfg(test)]
+use crate::value::{RuntimeError, Value};
+#[cfg(test)]
+use std::cell::RefCell;
+#[cfg(test)]
+use std::rc::Rc;
 
 #[test]
 fn handle_one_char_token() {
     let source = "(( ))";
     let mut scanner = Lexer::new(source);
-    let tokens = scanner.tokenize();
+    let tokens = scanner.tokenize().unwrap();
     assert_eq!(tokens.len(), 5);
     assert_eq!(tokens[0], Token::LParen);
     assert_eq!(tokens[1], Token::LParen);
@@ -18,25 +32,190 @@ fn handle_one_char_token() {
 fn handle_floats_token() {
     let source = ".32 -23.44 .0 -.3";
     let mut scanner = Lexer::new(source);
-    let tokens = scanner.tokenize();
+    let tokens = scanner.tokenize().unwrap();
+    // A sign is the unary operator, never part of the literal
+    assert_eq!(tokens.len(), 7);
+    assert_eq!(tokens[0], Token::FloatLiteral(0.32));
+    assert_eq!(tokens[1], Token::Minus);
+    assert_eq!(tokens[2], Token::FloatLiteral(23.44));
+    assert_eq!(tokens[3], Token::FloatLiteral(0.0));
+    assert_eq!(tokens[4], Token::Minus);
+    assert_eq!(tokens[5], Token::FloatLiteral(0.3));
+    assert_eq!(tokens[6], Token::Eof);
+}
+
+#[test]
+fn handle_integer_and_float_literals() {
+    let source = "10 9007199254740993 9223372036854775807 9223372036854775808 1.0 1e2 3.";
+    let mut scanner = Lexer::new(source);
+    let tokens = scanner.tokenize().unwrap();
+    assert_eq!(tokens.len(), 8);
+    assert_eq!(tokens[0], Token::IntegerLiteral(10));
+    assert_eq!(tokens[1], Token::IntegerLiteral(9007199254740993));
+    assert_eq!(tokens[2], Token::IntegerLiteral(i64::MAX));
+    assert_eq!(tokens[3], Token::FloatLiteral(9223372036854775808.0));
+    assert_eq!(tokens[4], Token::FloatLiteral(1.0));
+    assert_eq!(tokens[5], Token::FloatLiteral(100.0));
+    assert_eq!(tokens[6], Token::FloatLiteral(3.0));
+    assert_eq!(tokens[7], Token::Eof);
+}
+
+#[test]
+fn handle_hex_numbers() {
+    let source = "0xFF 0Xa_0 0x7fffffffffffffff 0xffffffffffffffff 0x10000000000000000 -0x10 0x1p-4 0xA.8p0 0x.8 0x1P+2 -0x1p1";
+    let mut scanner = Lexer::new(source);
+    let tokens = scanner.tokenize().unwrap();
+    assert_eq!(
+        tokens,
+        vec![
+            Token::IntegerLiteral(255),
+            Token::IntegerLiteral(160),
+            Token::IntegerLiteral(i64::MAX),
+            Token::IntegerLiteral(-1),
+            Token::IntegerLiteral(0),
+            Token::Minus,
+            Token::IntegerLiteral(16),
+            Token::FloatLiteral(0.0625),
+            Token::FloatLiteral(10.5),
+            Token::FloatLiteral(0.5),
+            Token::FloatLiteral(4.0),
+            Token::Minus,
+            Token::FloatLiteral(2.0),
+            Token::Eof,
+        ]
+    );
+}
+
+#[test]
+fn handle_malformed_hex_numbers() {
+    let source = "a = 0x\nb = 0xG c = 0x1p d = 0x.p1 e = 0x1.2.3";
+    let mut scanner = Lexer::new(source);
+    let errors = scanner.tokenize().unwrap_err();
+
+    assert_eq!(errors.len(), 5);
+    assert_eq!(
+        errors[0].kind,
+        LexErrorKind::MalformedNumber("0x".to_string())
+    );
+    assert_eq!(errors[0].span.start.offset, 4);
+    assert_eq!(errors[0].span.end.offset, 6);
+    assert_eq!(errors[1].to_string(), "2:5: malformed number near '0xG'");
+    assert_eq!(
+        errors[2].kind,
+        LexErrorKind::MalformedNumber("0x1p".to_string())
+    );
+    assert_eq!(
+        errors[3].kind,
+        LexErrorKind::MalformedNumber("0x.p1".to_string())
+    );
+    assert_eq!(
+        errors[4].kind,
+        LexErrorKind::MalformedNumber("0x1.2.3".to_string())
+    );
+}
+
+#[test]
+fn handle_string_escapes() {
+    let source = r#"
+        "\a\b\f\n\r\t\v\\\"\'" '\65\066\0677\x41\x7a\xFF'
+        "\u{41}\u{E9}\u{20AC}\u{1F600}\u{7FFFFFFF}" "a\z
+              b\
+c"
+    "#;
+    let mut scanner = Lexer::new(source);
+    let tokens = scanner.tokenize().unwrap();
+
     assert_eq!(tokens.len(), 5);
-    assert_eq!(tokens[0], Token::NumberLiteral(0.32));
-    assert_eq!(tokens[1], Token::NumberLiteral(-23.44));
-    assert_eq!(tokens[2], Token::NumberLiteral(0.0));
-    assert_eq!(tokens[3], Token::NumberLiteral(-0.3));
-    assert_eq!(tokens[4], Token::Eof);
+    assert_eq!(
+        tokens[0],
+        Token::StringLiteral(b"\x07\x08\x0c\n\r\t\x0b\\\"'".to_vec())
+    );
+    assert_eq!(tokens[1], Token::StringLiteral(b"ABC7Az\xff".to_vec()));
+    let mut expected = "A\u{E9}\u{20AC}\u{1F600}".as_bytes().to_vec();
+    expected.extend_from_slice(b"\xfd\xbf\xbf\xbf\xbf\xbf");
+    assert_eq!(tokens[2], Token::StringLiteral(expected));
+    assert_eq!(tokens[3], Token::StringLiteral(b"ab\nc".to_vec()));
+    assert_eq!(tokens[3].span.end.line, 5);
+}
+
+#[test]
+fn count_every_newline_sequence() {
+    let source = "a\rb\r\nc\nd\n\re\r\rf \"\\\r\ng\" [[\r\n]] h";
+    let mut scanner = Lexer::new(source);
+    let tokens = scanner.tokenize().unwrap();
+
+    let lines: Vec<_> = tokens.iter().map(|t| t.span.start.line).collect();
+    assert_eq!(lines[..6], [1, 2, 3, 4, 5, 7]);
+    assert_eq!(tokens[6], Token::StringLiteral(b"\ng".to_vec()));
+    assert_eq!(tokens[7], Token::StringLiteral(Vec::new()));
+    assert_eq!(tokens[8].span.start.line, 9);
+    assert_eq!(tokens[8].span.start.column, 4);
+}
+
+#[test]
+fn handle_malformed_escapes() {
+    let source =
+        r#"a = "\q" b = "\x4G" c = "\256" d = "\u{110000000}" e = "\u41" f = "\u{41" g = "\x""#;
+    let mut scanner = Lexer::new(source);
+    let errors = scanner.tokenize().unwrap_err();
+
+    let texts: Vec<_> = errors.iter().map(|e| e.kind.clone()).collect();
+    assert_eq!(
+        texts,
+        [
+            "\\q",
+            "\\x4G",
+            "\\256",
+            "\\u{110000000",
+            "\\u4",
+            "\\u{41\"",
+            "\\x\""
+        ]
+        .map(|t| LexErrorKind::InvalidEscape(t.to_string()))
+    );
+    assert_eq!(errors[0].to_string(), "1:5: invalid escape sequence '\\q'");
+    assert_eq!(errors[1].span.start.offset, 13);
+    assert_eq!(errors[1].span.end.offset, 19);
+}
+
+#[test]
+fn handle_char_literals() {
+    let source = r#"'f' 'fo' "f" '\n' '\u{E9}' '😀' '' '\xFF' '\65'"#;
+    let mut scanner = Lexer::new(source);
+    let tokens: Vec<_> = scanner
+        .tokenize()
+        .unwrap()
+        .into_iter()
+        .map(|t| t.token)
+        .collect();
+
+    assert_eq!(
+        tokens,
+        [
+            Token::CharLiteral('f'),
+            Token::StringLiteral("fo".into()),
+            Token::StringLiteral("f".into()),
+            Token::CharLiteral('\n'),
+            Token::CharLiteral('é'),
+            Token::CharLiteral('😀'),
+            Token::StringLiteral(Vec::new()),
+            Token::StringLiteral(vec![0xFF]),
+            Token::CharLiteral('A'),
+            Token::Eof,
+        ]
+    );
 }
 
 #[test]
 fn handle_int_value_decleration() {
     let source = "int a = 10;";
     let mut scanner = Lexer::new(source);
-    let tokens = scanner.tokenize();
+    let tokens = scanner.tokenize().unwrap();
     assert_eq!(tokens.len(), 6);
     assert_eq!(tokens[0], Token::Int);
     assert_eq!(tokens[1], Token::Ident("a".to_string()));
     assert_eq!(tokens[2], Token::Assign);
-    assert_eq!(tokens[3], Token::NumberLiteral(10.0));
+    assert_eq!(tokens[3], Token::IntegerLiteral(10));
     assert_eq!(tokens[4], Token::Semicolon);
     assert_eq!(tokens[5], Token::Eof);
 }
@@ -44,25 +223,26 @@ fn handle_int_value_decleration() {
 fn handle_neg_int_value_decleration() {
     let source = "int a = -10;";
     let mut scanner = Lexer::new(source);
-    let tokens = scanner.tokenize();
-    assert_eq!(tokens.len(), 6);
+    let tokens = scanner.tokenize().unwrap();
+    assert_eq!(tokens.len(), 7);
     assert_eq!(tokens[0], Token::Int);
     assert_eq!(tokens[1], Token::Ident("a".to_string()));
     assert_eq!(tokens[2], Token::Assign);
-    assert_eq!(tokens[3], Token::NumberLiteral(-10.0));
-    assert_eq!(tokens[4], Token::Semicolon);
-    assert_eq!(tokens[5], Token::Eof);
+    assert_eq!(tokens[3], Token::Minus);
+    assert_eq!(tokens[4], Token::IntegerLiteral(10));
+    assert_eq!(tokens[5], Token::Semicolon);
+    assert_eq!(tokens[6], Token::Eof);
 }
 #[test]
 fn handle_long_value_decleration() {
     let source = "long b = 100;";
     let mut scanner = Lexer::new(source);
-    let tokens = scanner.tokenize();
+    let tokens = scanner.tokenize().unwrap();
     assert_eq!(tokens.len(), 6);
     assert_eq!(tokens[0], Token::Long);
     assert_eq!(tokens[1], Token::Ident("b".to_string()));
     assert_eq!(tokens[2], Token::Assign);
-    assert_eq!(tokens[3], Token::NumberLiteral(100.0));
+    assert_eq!(tokens[3], Token::IntegerLiteral(100));
     assert_eq!(tokens[4], Token::Semicolon);
     assert_eq!(tokens[5], Token::Eof);
 }
@@ -71,12 +251,12 @@ fn handle_long_value_decleration() {
 fn handle_float_value_decleration() {
     let source = "float c = 1.2;";
     let mut scanner = Lexer::new(source);
-    let tokens = scanner.tokenize();
+    let tokens = scanner.tokenize().unwrap();
     assert_eq!(tokens.len(), 6);
     assert_eq!(tokens[0], Token::Float);
     assert_eq!(tokens[1], Token::Ident("c".to_string()));
     assert_eq!(tokens[2], Token::Assign);
-    assert_eq!(tokens[3], Token::NumberLiteral(1.2));
+    assert_eq!(tokens[3], Token::FloatLiteral(1.2));
     assert_eq!(tokens[4], Token::Semicolon);
     assert_eq!(tokens[5], Token::Eof);
 }
@@ -84,25 +264,27 @@ fn handle_float_value_decleration() {
 fn handle_neg_float_value_decleration() {
     let source = "float c = -1.2;";
     let mut scanner = Lexer::new(source);
-    let tokens = scanner.tokenize();
-    assert_eq!(tokens.len(), 6);
+    let tokens = scanner.tokenize().unwrap();
+    assert_eq!(tokens.len(), 7);
     assert_eq!(tokens[0], Token::Float);
     assert_eq!(tokens[1], Token::Ident("c".to_string()));
     assert_eq!(tokens[2], Token::Assign);
-    assert_eq!(tokens[3], Token::NumberLiteral(-1.2));
-    assert_eq!(tokens[4], Token::Semicolon);
-    assert_eq!(tokens[5], Token::Eof);
+    assert_eq!(tokens[3], Token::Minus);
+    assert_eq!(tokens[4], Token::FloatLiteral(1.2));
+    assert_eq!(tokens[5], Token::Semicolon);
+    assert_eq!(tokens[6], Token::Eof);
 }
 #[test]
+#[allow(clippy::approx_constant)]
 fn handle_double_value_decleration() {
     let source = "double d = 3.14;";
     let mut scanner = Lexer::new(source);
-    let tokens = scanner.tokenize();
+    let tokens = scanner.tokenize().unwrap();
     assert_eq!(tokens.len(), 6);
     assert_eq!(tokens[0], Token::Double);
     assert_eq!(tokens[1], Token::Ident("d".to_string()));
     assert_eq!(tokens[2], Token::Assign);
-    assert_eq!(tokens[3], Token::NumberLiteral(3.14));
+    assert_eq!(tokens[3], Token::FloatLiteral(3.14));
     assert_eq!(tokens[4], Token::Semicolon);
     assert_eq!(tokens[5], Token::Eof);
 }
@@ -111,15 +293,12 @@ fn handle_double_value_decleration() {
 fn handle_string_value_decleration() {
     let source = "string e = \"this is a test\";";
     let mut scanner = Lexer::new(source);
-    let tokens = scanner.tokenize();
+    let tokens = scanner.tokenize().unwrap();
     assert_eq!(tokens.len(), 6);
     assert_eq!(tokens[0], Token::String);
     assert_eq!(tokens[1], Token::Ident("e".to_string()));
     assert_eq!(tokens[2], Token::Assign);
-    assert_eq!(
-        tokens[3],
-        Token::StringLiteral("this is a test".to_string())
-    );
+    assert_eq!(tokens[3], Token::StringLiteral("this is a test".into()));
     assert_eq!(tokens[4], Token::Semicolon);
     assert_eq!(tokens[5], Token::Eof);
 }
@@ -128,12 +307,12 @@ fn handle_string_value_decleration() {
 fn handle_char_value_decleration() {
     let source = "char f = 'f';";
     let mut scanner = Lexer::new(source);
-    let tokens = scanner.tokenize();
+    let tokens = scanner.tokenize().unwrap();
     assert_eq!(tokens.len(), 6);
     assert_eq!(tokens[0], Token::Char);
     assert_eq!(tokens[1], Token::Ident("f".to_string()));
     assert_eq!(tokens[2], Token::Assign);
-    assert_eq!(tokens[3], Token::StringLiteral("f".to_string()));
+    assert_eq!(tokens[3], Token::CharLiteral('f'));
     assert_eq!(tokens[4], Token::Semicolon);
     assert_eq!(tokens[5], Token::Eof);
 }
@@ -142,7 +321,7 @@ fn handle_char_value_decleration() {
 fn handle_boolean_value_decleration() {
     let source = "bool k = true;";
     let mut scanner = Lexer::new(source);
-    let tokens = scanner.tokenize();
+    let tokens = scanner.tokenize().unwrap();
     assert_eq!(tokens.len(), 6);
     assert_eq!(tokens[0], Token::Bool);
     assert_eq!(tokens[1], Token::Ident("k".to_string()));
@@ -163,7 +342,7 @@ fn handle_table_value_decleration() {
     "#;
 
     let mut scanner = Lexer::new(source);
-    let tokens = scanner.tokenize();
+    let tokens = scanner.tokenize().unwrap();
 
     assert_eq!(tokens[0], Token::Table);
     assert_eq!(tokens[1], Token::Ident("j".to_string()));
@@ -174,48 +353,49 @@ fn handle_table_value_decleration() {
     assert_eq!(tokens[4], Token::Int);
     assert_eq!(tokens[5], Token::Ident("t1".to_string()));
     assert_eq!(tokens[6], Token::Assign);
-    assert_eq!(tokens[7], Token::NumberLiteral(1.0));
+    assert_eq!(tokens[7], Token::IntegerLiteral(1));
     assert_eq!(tokens[8], Token::Comma);
 
     // Second field
     assert_eq!(tokens[9], Token::Float);
     assert_eq!(tokens[10], Token::Ident("t2".to_string()));
     assert_eq!(tokens[11], Token::Assign);
-    assert_eq!(tokens[12], Token::NumberLiteral(-1.1));
-    assert_eq!(tokens[13], Token::Comma);
+    assert_eq!(tokens[12], Token::Minus);
+    assert_eq!(tokens[13], Token::FloatLiteral(1.1));
+    assert_eq!(tokens[14], Token::Comma);
 
     // Third field
-    assert_eq!(tokens[14], Token::Vec);
-    assert_eq!(tokens[15], Token::Ident("t3".to_string()));
-    assert_eq!(tokens[16], Token::Assign);
-    assert_eq!(tokens[17], Token::LBracket);
-    assert_eq!(tokens[18], Token::NumberLiteral(1.0));
-    assert_eq!(tokens[19], Token::Comma);
-    assert_eq!(tokens[20], Token::NumberLiteral(2.0));
-    assert_eq!(tokens[21], Token::Comma);
-    assert_eq!(tokens[22], Token::NumberLiteral(3.0));
-    assert_eq!(tokens[23], Token::RBracket);
+    assert_eq!(tokens[15], Token::Vec);
+    assert_eq!(tokens[16], Token::Ident("t3".to_string()));
+    assert_eq!(tokens[17], Token::Assign);
+    assert_eq!(tokens[18], Token::LBracket);
+    assert_eq!(tokens[19], Token::IntegerLiteral(1));
+    assert_eq!(tokens[20], Token::Comma);
+    assert_eq!(tokens[21], Token::IntegerLiteral(2));
+    assert_eq!(tokens[22], Token::Comma);
+    assert_eq!(tokens[23], Token::IntegerLiteral(3));
+    assert_eq!(tokens[24], Token::RBracket);
 
-    assert_eq!(tokens[24], Token::RBrace);
-    assert_eq!(tokens[25], Token::Semicolon);
-    assert_eq!(tokens[26], Token::Eof);
+    assert_eq!(tokens[25], Token::RBrace);
+    assert_eq!(tokens[26], Token::Semicolon);
+    assert_eq!(tokens[27], Token::Eof);
 }
 
 #[test]
 fn handle_vec_value_decleration() {
     let source = "vec x = [1,2,3];";
     let mut scanner = Lexer::new(source);
-    let tokens = scanner.tokenize();
+    let tokens = scanner.tokenize().unwrap();
     assert_eq!(tokens.len(), 12);
     assert_eq!(tokens[0], Token::Vec);
     assert_eq!(tokens[1], Token::Ident("x".to_string()));
     assert_eq!(tokens[2], Token::Assign);
     assert_eq!(tokens[3], Token::LBracket);
-    assert_eq!(tokens[4], Token::NumberLiteral(1.0));
+    assert_eq!(tokens[4], Token::IntegerLiteral(1));
     assert_eq!(tokens[5], Token::Comma);
-    assert_eq!(tokens[6], Token::NumberLiteral(2.0));
+    assert_eq!(tokens[6], Token::IntegerLiteral(2));
     assert_eq!(tokens[7], Token::Comma);
-    assert_eq!(tokens[8], Token::NumberLiteral(3.0));
+    assert_eq!(tokens[8], Token::IntegerLiteral(3));
     assert_eq!(tokens[9], Token::RBracket);
     assert_eq!(tokens[10], Token::Semicolon);
     assert_eq!(tokens[11], Token::Eof);
@@ -225,7 +405,7 @@ fn handle_vec_value_decleration() {
 fn handle_void_function() {
     let source = "void function test() end";
     let mut scanner = Lexer::new(source);
-    let tokens = scanner.tokenize();
+    let tokens = scanner.tokenize().unwrap();
     assert_eq!(tokens.len(), 7);
     assert_eq!(tokens[0], Token::Void);
     assert_eq!(tokens[1], Token::Function);
@@ -240,7 +420,7 @@ fn handle_void_function() {
 fn handle_global_void_function() {
     let source = "global void test1 = function() end";
     let mut scanner = Lexer::new(source);
-    let tokens = scanner.tokenize();
+    let tokens = scanner.tokenize().unwrap();
     assert_eq!(tokens.len(), 9);
     assert_eq!(tokens[0], Token::Global);
     assert_eq!(tokens[1], Token::Void);
@@ -257,7 +437,7 @@ fn handle_global_void_function() {
 fn handle_int_function() {
     let source = "int function test3() return (int)12 end";
     let mut scanner = Lexer::new(source);
-    let tokens = scanner.tokenize();
+    let tokens = scanner.tokenize().unwrap();
     assert_eq!(tokens.len(), 12);
     assert_eq!(tokens[0], Token::Int);
     assert_eq!(tokens[1], Token::Function);
@@ -268,7 +448,7 @@ fn handle_int_function() {
     assert_eq!(tokens[6], Token::LParen);
     assert_eq!(tokens[7], Token::Int);
     assert_eq!(tokens[8], Token::RParen);
-    assert_eq!(tokens[9], Token::NumberLiteral(12.0));
+    assert_eq!(tokens[9], Token::IntegerLiteral(12));
     assert_eq!(tokens[10], Token::End);
     assert_eq!(tokens[11], Token::Eof);
 }
@@ -277,7 +457,7 @@ fn handle_int_function() {
 fn handle_long_function() {
     let source = "long function test4() long a = 111 return a end";
     let mut scanner = Lexer::new(source);
-    let tokens = scanner.tokenize();
+    let tokens = scanner.tokenize().unwrap();
     assert_eq!(tokens.len(), 13);
     assert_eq!(tokens[0], Token::Long);
     assert_eq!(tokens[1], Token::Function);
@@ -287,7 +467,7 @@ fn handle_long_function() {
     assert_eq!(tokens[5], Token::Long);
     assert_eq!(tokens[6], Token::Ident("a".to_string()));
     assert_eq!(tokens[7], Token::Assign);
-    assert_eq!(tokens[8], Token::NumberLiteral(111.0));
+    assert_eq!(tokens[8], Token::IntegerLiteral(111));
     assert_eq!(tokens[9], Token::Return);
     assert_eq!(tokens[10], Token::Ident("a".to_string()));
     assert_eq!(tokens[11], Token::End);
@@ -298,7 +478,7 @@ fn handle_long_function() {
 fn handle_anonymous_void_function() {
     let source = "void function() end";
     let mut scanner = Lexer::new(source);
-    let tokens = scanner.tokenize();
+    let tokens = scanner.tokenize().unwrap();
     assert_eq!(tokens.len(), 6);
     assert_eq!(tokens[0], Token::Void);
     assert_eq!(tokens[1], Token::Function);
@@ -312,7 +492,7 @@ fn handle_anonymous_void_function() {
 fn handle_void_fastcall_function() {
     let source = "void () end";
     let mut scanner = Lexer::new(source);
-    let tokens = scanner.tokenize();
+    let tokens = scanner.tokenize().unwrap();
     assert_eq!(tokens.len(), 5);
     assert_eq!(tokens[0], Token::Void);
     assert_eq!(tokens[1], Token::LParen);
@@ -325,7 +505,7 @@ fn handle_void_fastcall_function() {
 fn handle_for_loop() {
     let source = "for k,v in pairs(j) do print(k,v) end";
     let mut scanner = Lexer::new(source);
-    let tokens = scanner.tokenize();
+    let tokens = scanner.tokenize().unwrap();
 
     assert_eq!(tokens[0], Token::For);
     assert_eq!(tokens[1], Token::Ident("k".to_string()));
@@ -351,7 +531,7 @@ fn handle_for_loop() {
 fn handle_operators() {
     let source = "+ - * / % ^ # == ~= <= >= < > = ( ) { } [ ] ; : :: , . .. ...";
     let mut scanner = Lexer::new(source);
-    let tokens = scanner.tokenize();
+    let tokens = scanner.tokenize().unwrap();
 
     assert_eq!(tokens[0], Token::Plus);
     assert_eq!(tokens[1], Token::Minus);
@@ -383,11 +563,43 @@ fn handle_operators() {
     assert_eq!(tokens[27], Token::Eof);
 }
 
+#[test]
+fn handle_bitwise_operators() {
+    let source = "a // b & c | d ~ e << f >> g ~= ~h <= >= / <";
+    let mut scanner = Lexer::new(source);
+    let tokens: Vec<_> = scanner
+        .tokenize()
+        .unwrap()
+        .into_iter()
+        .map(|t| t.token)
+        .filter(|t| !matches!(t, Token::Ident(_)))
+        .collect();
+
+    assert_eq!(
+        tokens,
+        [
+            Token::FloorDiv,
+            Token::BitAnd,
+            Token::BitOr,
+            Token::Tilde,
+            Token::Shl,
+            Token::Shr,
+            Token::Ne,
+            Token::Tilde,
+            Token::Le,
+            Token::Ge,
+            Token::Div,
+            Token::Lt,
+            Token::Eof
+        ]
+    );
+}
+
 #[test]
 fn handle_keywords() {
     let source = "and break do else elseif end false for function goto if in local global nil not or repeat return then true until while int long float double string table bool char void vec";
     let mut scanner = Lexer::new(source);
-    let tokens = scanner.tokenize();
+    let tokens = scanner.tokenize().unwrap();
 
     assert_eq!(tokens[0], Token::And);
     assert_eq!(tokens[1], Token::Break);
@@ -424,3 +636,2471 @@ fn handle_keywords() {
     assert_eq!(tokens[32], Token::Vec);
     assert_eq!(tokens[33], Token::Eof);
 }
+
+#[test]
+fn handle_token_spans() {
+    let source = "int a = 10;\nstring s = \"hé\"";
+    let mut scanner = Lexer::new(source);
+    let tokens = scanner.tokenize().unwrap();
+
+    assert_eq!(tokens[0].span.start, Position::new(0, 1, 1));
+    assert_eq!(tokens[0].span.end, Position::new(3, 1, 4));
+    assert_eq!(tokens[3].span.start.offset, 8);
+    assert_eq!(tokens[3].span.end.offset, 10);
+    assert_eq!(tokens[3].span.start.column, 9);
+
+    // Second line, string literal covers both quotes and the two-byte char
+    assert_eq!(tokens[5], Token::String);
+    assert_eq!(tokens[5].span.start, Position::new(12, 2, 1));
+    assert_eq!(tokens[8].span.start.offset, 23);
+    assert_eq!(tokens[8].span.end.offset, 28);
+    assert_eq!(tokens[8].span.start.column, 12);
+    assert_eq!(tokens[8].span.end.column, 16);
+
+    assert_eq!(tokens[9], Token::Eof);
+    assert_eq!(tokens[9].span.start.offset, source.len());
+}
+
+#[test]
+fn handle_spans_across_comments() {
+    let source = "--[[ long\ncomment ]] a -- tail\n  b";
+    let mut scanner = Lexer::with_file_id(source, 3);
+    let tokens = scanner.tokenize().unwrap();
+
+    assert_eq!(tokens.len(), 3);
+    assert_eq!(tokens[0], Token::Ident("a".to_string()));
+    assert_eq!(tokens[0].span.file_id, 3);
+    assert_eq!(tokens[0].span.start, Position::new(21, 2, 12));
+    assert_eq!(tokens[1].span.start, Position::new(33, 3, 3));
+}
+
+#[test]
+fn handle_unexpected_chars() {
+    let source = "a @ b $ ` c";
+    let mut scanner = Lexer::new(source);
+    let errors = scanner.tokenize().unwrap_err();
+
+    assert_eq!(errors.len(), 3);
+    assert_eq!(errors[0].kind, LexErrorKind::UnexpectedChar('@'));
+    assert_eq!(errors[0].span.start.column, 3);
+    assert_eq!(errors[1].kind, LexErrorKind::UnexpectedChar('$'));
+    assert_eq!(errors[2].kind, LexErrorKind::UnexpectedChar('`'));
+    assert_eq!(errors[2].to_string(), "1:9: unexpected character '`'");
+}
+
+#[test]
+fn handle_unterminated_string() {
+    let source = "string s = \"abc\nstring t = 'def";
+    let mut scanner = Lexer::new(source);
+    let errors = scanner.tokenize().unwrap_err();
+
+    assert_eq!(errors.len(), 2);
+    assert_eq!(errors[0].kind, LexErrorKind::UnterminatedString);
+    assert_eq!(errors[0].span.start.offset, 11);
+    assert_eq!(errors[0].span.end.offset, 15);
+    assert_eq!(errors[1].kind, LexErrorKind::UnterminatedString);
+    assert_eq!(errors[1].span.start.line, 2);
+}
+
+#[test]
+fn handle_long_strings() {
+    let source =
+        "a = [[\nline one\r\nline ]=] two]] b = [==[\r\n]] ]=] ]===]]==] c = [=[]=] d[ [1] ]";
+    let mut scanner = Lexer::new(source);
+    let tokens = scanner.tokenize().unwrap();
+
+    assert_eq!(
+        tokens[2],
+        Token::StringLiteral("line one\nline ]=] two".into())
+    );
+    assert_eq!(tokens[2].span.start.line, 1);
+    assert_eq!(tokens[3].span.start.line, 3);
+    assert_eq!(tokens[5], Token::StringLiteral("]] ]=] ]===]".into()));
+    assert_eq!(tokens[8], Token::StringLiteral(Vec::new()));
+    assert_eq!(tokens[10], Token::LBracket);
+    assert_eq!(tokens[11], Token::LBracket);
+}
+
+#[test]
+fn handle_unterminated_long_string() {
+    let source = "s = [=[ never ]] closed ]==]";
+    let mut scanner = Lexer::new(source);
+    let errors = scanner.tokenize().unwrap_err();
+
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].to_string(), "1:5: unterminated long string");
+    assert_eq!(errors[0].span.end.offset, source.len());
+}
+
+#[test]
+fn handle_lossless_tokens() {
+    let source =
+        "-- header\r\nlocal x = 1 -- one\n\n\t--[==[ long\n]] ]==] y = [[s]]--[ not long\n-- end";
+    let mut scanner = Lexer::new(source);
+    let tokens = scanner.tokenize_lossless().unwrap();
+    let text: String = tokens.iter().map(|t| t.to_string()).collect();
+    assert_eq!(text, source);
+
+    let kinds = |trivia: &[Trivia]| trivia.iter().map(|t| t.kind).collect::<Vec<_>>();
+    assert_eq!(tokens[0].token, Token::Local);
+    assert_eq!(
+        kinds(&tokens[0].leading),
+        [TriviaKind::LineComment, TriviaKind::Newline]
+    );
+    assert_eq!(tokens[0].leading[1].text, "\r\n");
+    // `1` keeps the comment on its line, the next token gets the rest
+    assert_eq!(tokens[3].text, "1");
+    assert_eq!(
+        kinds(&tokens[3].trailing),
+        [TriviaKind::Whitespace, TriviaKind::LineComment]
+    );
+    assert_eq!(tokens[3].trailing[1].text, "-- one");
+    assert_eq!(
+        kinds(&tokens[4].leading),
+        [
+            TriviaKind::Newline,
+            TriviaKind::Newline,
+            TriviaKind::Whitespace,
+            TriviaKind::LongComment { level: 2 },
+            TriviaKind::Whitespace,
+        ]
+    );
+    assert_eq!(tokens[4].leading[3].text, "--[==[ long\n]] ]==]");
+    assert_eq!(tokens[6].text, "[[s]]");
+    assert_eq!(kinds(&tokens[6].trailing), [TriviaKind::LineComment]);
+    assert_eq!(tokens[7].token, Token::Eof);
+    assert_eq!(
+        kinds(&tokens[7].leading),
+        [TriviaKind::Newline, TriviaKind::LineComment]
+    );
+
+    // The plain token stream is the same without trivia
+    let plain = Lexer::new(source).tokenize().unwrap();
+    let lossless: Vec<SpannedToken> = tokens.into_iter().map(|t| t.token).collect();
+    assert_eq!(plain, lossless);
+}
+
+#[test]
+fn handle_lossless_test_script() {
+    let source = include_str!("test.rlua");
+    let tokens = Lexer::new(source).tokenize_lossless().unwrap();
+    let text: String = tokens.iter().map(|t| t.to_string()).collect();
+    assert_eq!(text, source);
+    let plain = Lexer::new(source).tokenize().unwrap();
+    assert!(plain.into_iter().eq(tokens.into_iter().map(|t| t.token)));
+}
+
+#[test]
+fn handle_unterminated_long_comment() {
+    let source = "a --[==[ never closed ]] ]=] ]===]";
+    let mut scanner = Lexer::new(source);
+    let errors = scanner.tokenize().unwrap_err();
+
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].kind, LexErrorKind::UnterminatedLongComment);
+    assert_eq!(errors[0].span.start.offset, 2);
+    assert_eq!(errors[0].span.end.offset, source.len());
+    assert_eq!(Lexer::new(source).tokenize_lossless().unwrap_err(), errors);
+}
+
+#[test]
+fn handle_bracket_line_comment() {
+    let source = "a --[ not long\nb --[= neither\nc";
+    let mut scanner = Lexer::new(source);
+    let tokens = scanner.tokenize().unwrap();
+
+    assert_eq!(tokens.len(), 4);
+    assert_eq!(tokens[1], Token::Ident("b".to_string()));
+    assert_eq!(tokens[2], Token::Ident("c".to_string()));
+}
+
+#[cfg(test)]
+fn parse(source: &str) -> Result<Block, ParseError> {
+    let tokens = Lexer::new(source).tokenize().unwrap();
+    Parser::new(tokens).parse()
+}
+
+#[test]
+fn parse_test_script() {
+    let chunk = parse(include_str!("test.rlua")).unwrap();
+    assert_eq!(chunk.stmts.len(), 16);
+
+    let StmtKind::Local {
+        scope, ty, names, ..
+    } = &chunk.stmts[0].kind
+    else {
+        panic!("expected declaration, got {:?}", chunk.stmts[0].kind);
+    };
+    assert_eq!(*scope, Scope::Local);
+    assert_eq!(*ty, Some(TypeName::Int));
+    assert_eq!(names[0].name, "a");
+    assert_eq!(chunk.stmts[0].span.start.line, 5);
+
+    assert!(matches!(chunk.stmts[15].kind, StmtKind::GenericFor { .. }));
+}
+
+#[test]
+fn parse_typed_function() {
+    let chunk = parse("long function test4(int x, y, ...) long a = 111 return a end").unwrap();
+    let StmtKind::Function { scope, name, func } = &chunk.stmts[0].kind else {
+        panic!("expected function, got {:?}", chunk.stmts[0].kind);
+    };
+    assert_eq!(*scope, Scope::Local);
+    assert_eq!(name.path[0].name, "test4");
+    assert_eq!(func.ret, Some(TypeName::Long));
+    assert_eq!(func.params.len(), 2);
+    assert_eq!(func.params[0].ty, Some(TypeName::Int));
+    assert_eq!(func.params[1].ty, None);
+    assert!(func.is_vararg);
+    assert_eq!(func.body.stmts.len(), 2);
+    assert!(matches!(func.body.stmts[1].kind, StmtKind::Return(ref v) if v.len() == 1));
+}
+
+#[test]
+fn parse_global_bindings() {
+    let chunk = parse("global void test1 = function() end global function f() end").unwrap();
+    let StmtKind::Local {
+        scope, ty, values, ..
+    } = &chunk.stmts[0].kind
+    else {
+        panic!("expected declaration, got {:?}", chunk.stmts[0].kind);
+    };
+    assert_eq!(*scope, Scope::Global);
+    assert_eq!(*ty, Some(TypeName::Void));
+    assert!(matches!(values[0].kind, ExprKind::Function(_)));
+    assert!(matches!(
+        chunk.stmts[1].kind,
+        StmtKind::Function {
+            scope: Scope::Global,
+            ..
+        }
+    ));
+}
+
+#[test]
+fn parse_anonymous_and_fastcall_functions() {
+    let chunk = parse("void function() end void () end").unwrap();
+    assert_eq!(chunk.stmts.len(), 2);
+    for (stmt, fastcall) in chunk.stmts.iter().zip([false, true]) {
+        let StmtKind::Expr(Expr {
+            kind: ExprKind::Function(func),
+            ..
+        }) = &stmt.kind
+        else {
+            panic!("expected function literal, got {:?}", stmt.kind);
+        };
+        assert_eq!(func.ret, Some(TypeName::Void));
+        assert_eq!(func.fastcall, fastcall);
+    }
+}
+
+#[test]
+fn parse_table_fields() {
+    let chunk = parse("t = { int t1 = 1, x = 2, [3] = 4, [5, 6]; 7 }").unwrap();
+    let StmtKind::Assign { values, .. } = &chunk.stmts[0].kind else {
+        panic!("expected assignment");
+    };
+    let ExprKind::Table(fields) = &values[0].kind else {
+        panic!("expected table");
+    };
+    assert_eq!(fields.len(), 5);
+    assert!(matches!(
+        &fields[0],
+        TableField::Named { name, ty: Some(TypeName::Int), .. } if name.name == "t1"
+    ));
+    assert!(matches!(&fields[1], TableField::Named { ty: None, .. }));
+    assert!(matches!(&fields[2], TableField::Keyed { .. }));
+    assert!(matches!(
+        &fields[3],
+        TableField::Positional(Expr { kind: ExprKind::Vec(items), .. }) if items.len() == 2
+    ));
+    assert!(matches!(&fields[4], TableField::Positional(_)));
+}
+
+#[test]
+fn parse_cast_and_paren() {
+    let chunk = parse("return (int)12, (x)").unwrap();
+    let StmtKind::Return(values) = &chunk.stmts[0].kind else {
+        panic!("expected return");
+    };
+    assert!(matches!(
+        &values[0].kind,
+        ExprKind::Cast { ty: TypeName::Int, expr } if expr.kind == ExprKind::Integer(12)
+    ));
+    assert!(matches!(&values[1].kind, ExprKind::Paren(_)));
+    assert_eq!(values[0].span.start.column, 8);
+    assert_eq!(values[0].span.end.column, 15);
+}
+
+#[test]
+fn parse_vec_types() {
+    let source = r#"
+        vec<int> a = [1, 2]
+        vec<vec<double>> b = [ [1.5] ]
+        local t = { vec<string> names = ["x"] }
+        vec<int> function f(vec<char> cs) return (vec<int>)cs end
+    "#;
+    let chunk = parse(source).unwrap();
+    let vec_of = |ty| TypeName::Vec(Some(Box::new(ty)));
+    let StmtKind::Local { ty, .. } = &chunk.stmts[0].kind else {
+        panic!("expected declaration");
+    };
+    assert_eq!(ty, &Some(vec_of(TypeName::Int)));
+    let StmtKind::Local { ty, .. } = &chunk.stmts[1].kind else {
+        panic!("expected declaration");
+    };
+    assert_eq!(ty, &Some(vec_of(vec_of(TypeName::Double))));
+    assert_eq!(ty.as_ref().unwrap().to_string(), "vec<vec<double>>");
+    let StmtKind::Function { func, .. } = &chunk.stmts[3].kind else {
+        panic!("expected function");
+    };
+    assert_eq!(func.ret, Some(vec_of(TypeName::Int)));
+    assert_eq!(func.params[0].ty, Some(vec_of(TypeName::Char)));
+
+    let err = parse("vec<> a").unwrap_err();
+    assert_eq!(err.to_string(), "1:5: expected an element type, found Gt");
+}
+
+#[test]
+fn parse_control_flow() {
+    let source = r#"
+        for i = 1, 10, 2 do
+            if i then
+                obj:method("a")
+            elseif x then
+                t.a.b, t[1] = 1, 2
+            else
+                break
+            end
+        end
+        while x do x = f{} end
+        repeat local y until y
+    "#;
+    let chunk = parse(source).unwrap();
+    assert_eq!(chunk.stmts.len(), 3);
+    let StmtKind::NumericFor {
+        var, step, body, ..
+    } = &chunk.stmts[0].kind
+    else {
+        panic!("expected numeric for");
+    };
+    assert_eq!(var.name, "i");
+    assert!(step.is_some());
+    let StmtKind::If {
+        branches,
+        else_block,
+    } = &body.stmts[0].kind
+    else {
+        panic!("expected if");
+    };
+    assert_eq!(branches.len(), 2);
+    assert!(else_block.is_some());
+    assert!(matches!(
+        branches[0].1.stmts[0].kind,
+        StmtKind::Call(Expr {
+            kind: ExprKind::MethodCall { .. },
+            ..
+        })
+    ));
+    assert!(matches!(
+        &branches[1].1.stmts[0].kind,
+        StmtKind::Assign { targets, .. } if targets.len() == 2
+    ));
+    assert!(matches!(chunk.stmts[1].kind, StmtKind::While { .. }));
+    assert!(matches!(chunk.stmts[2].kind, StmtKind::Repeat { .. }));
+}
+
+#[test]
+fn parse_errors() {
+    let err = parse("int function f()\n  return 1").unwrap_err();
+    assert_eq!(
+        err.kind,
+        ParseErrorKind::Expected {
+            expected: "'end'",
+            found: Token::Eof
+        }
+    );
+    assert_eq!(err.span.start.line, 2);
+
+    let err = parse("x.y").unwrap_err();
+    assert_eq!(err.kind, ParseErrorKind::NotAStatement);
+
+    let err = parse("f() = 1").unwrap_err();
+    assert_eq!(err.kind, ParseErrorKind::InvalidAssignTarget);
+    assert_eq!(err.to_string(), "1:1: cannot assign to this expression");
+}
+
+#[test]
+fn parse_nesting_limit() {
+    // Debug builds need more stack for 200 levels than a test thread has,
+    // so parse on a thread with the stack `main` gives scripts
+    let parse_deep = |source: String| {
+        std::thread::Builder::new()
+            .stack_size(crate::SCRIPT_STACK_SIZE)
+            .spawn(move || parse(&source).map(|_| ()))
+            .unwrap()
+            .join()
+            .unwrap()
+    };
+    let deep = [
+        format!("x = {}1{}", "(".repeat(20000), ")".repeat(20000)),
+        format!("x = {}{}", "{".repeat(20000), "}".repeat(20000)),
+        format!("{}{}", "do ".repeat(20000), "end ".repeat(20000)),
+        format!("x = 1{}", " .. 1".repeat(20000)),
+        format!("vec<{}int{} v", "vec<".repeat(300), ">".repeat(301)),
+    ];
+    for source in deep {
+        let err = parse_deep(source).unwrap_err();
+        assert_eq!(err.kind, ParseErrorKind::TooDeeplyNested);
+    }
+    let err = parse_deep(format!("x = {}1{}", "(".repeat(250), ")".repeat(250))).unwrap_err();
+    assert_eq!(err.to_string(), "1:204: more than 200 nested levels");
+    assert!(parse_deep(format!("x = {}1{}", "(".repeat(190), ")".repeat(190))).is_ok());
+}
+
+/// Renders an expression fully parenthesized so precedence is visible.
+#[cfg(test)]
+fn sexp(expr: &Expr) -> String {
+    match &expr.kind {
+        ExprKind::Integer(n) => n.to_string(),
+        ExprKind::Float(n) => n.to_string(),
+        ExprKind::Name(name) => name.clone(),
+        ExprKind::True => "true".to_string(),
+        ExprKind::Unary { op, expr } => format!("({:?} {})", op, sexp(expr)),
+        ExprKind::Binary { op, lhs, rhs } => {
+            format!("({:?} {} {})", op, sexp(lhs), sexp(rhs))
+        }
+        ExprKind::Cast { ty, expr } => format!("({:?} {})", ty, sexp(expr)),
+        ExprKind::Paren(inner) => sexp(inner),
+        ExprKind::Call { func, args } => {
+            let args: Vec<String> = args.iter().map(sexp).collect();
+            format!("{}({})", sexp(func), args.join(", "))
+        }
+        other => format!("{:?}", other),
+    }
+}
+
+#[cfg(test)]
+fn parse_expr(source: &str) -> String {
+    let tokens = Lexer::new(source).tokenize().unwrap();
+    let mut parser = Parser::new(tokens);
+    let expr = parser.parse_expr().unwrap();
+    sexp(&expr)
+}
+
+#[test]
+fn parse_arithmetic_precedence() {
+    assert_eq!(parse_expr("1 + 2 * 3"), "(Add 1 (Mul 2 3))");
+    assert_eq!(parse_expr("(1 + 2) * 3"), "(Mul (Add 1 2) 3)");
+    assert_eq!(parse_expr("1 - 2 - 3"), "(Sub (Sub 1 2) 3)");
+    assert_eq!(parse_expr("a / b % c"), "(Mod (Div a b) c)");
+    assert_eq!(parse_expr("x-1"), "(Sub x 1)");
+}
+
+#[test]
+fn parse_bitwise_precedence() {
+    // Lua 5.4: or < and < comparison < | < ~ < & < shift < .. < + < * //
+    assert_eq!(
+        parse_expr("a | b ~ c & d"),
+        "(BitOr a (BitXor b (BitAnd c d)))"
+    );
+    assert_eq!(parse_expr("a & b << c"), "(BitAnd a (Shl b c))");
+    assert_eq!(parse_expr("1 << 2 + 3"), "(Shl 1 (Add 2 3))");
+    assert_eq!(parse_expr("a << b .. c"), "(Shl a (Concat b c))");
+    assert_eq!(parse_expr("a < b | c"), "(Lt a (BitOr b c))");
+    assert_eq!(parse_expr("a >> b >> c"), "(Shr (Shr a b) c)");
+    assert_eq!(parse_expr("a // b * c"), "(Mul (FloorDiv a b) c)");
+    assert_eq!(parse_expr("~a ~ ~b"), "(BitXor (BitNot a) (BitNot b))");
+    assert_eq!(parse_expr("~x^2"), "(BitNot (Pow x 2))");
+}
+
+#[test]
+fn parse_right_associative_operators() {
+    assert_eq!(parse_expr("2^3^2"), "(Pow 2 (Pow 3 2))");
+    assert_eq!(parse_expr("a..b..c"), "(Concat a (Concat b c))");
+    assert_eq!(parse_expr("a..b+c"), "(Concat a (Add b c))");
+}
+
+#[test]
+fn parse_unary_precedence() {
+    // From the reference manual: -x^2 is -(x^2), 2^-x is 2^(-x)
+    assert_eq!(parse_expr("-x^2"), "(Neg (Pow x 2))");
+    assert_eq!(parse_expr("-2^2"), "(Neg (Pow 2 2))");
+    assert_eq!(parse_expr("2^-3"), "(Pow 2 (Neg 3))");
+    assert_eq!(parse_expr("not a == b"), "(Eq (Not a) b)");
+    assert_eq!(parse_expr("#t + 1"), "(Add (Len t) 1)");
+    assert_eq!(parse_expr("- - x"), "(Neg (Neg x))");
+    assert_eq!(parse_expr("(int)x ^ 2 + 1"), "(Add (Int (Pow x 2)) 1)");
+}
+
+#[test]
+fn parse_logical_precedence() {
+    assert_eq!(parse_expr("a or b and c"), "(Or a (And b c))");
+    assert_eq!(parse_expr("a and b or c"), "(Or (And a b) c)");
+    assert_eq!(
+        parse_expr("1 < 2 == true and x"),
+        "(And (Eq (Lt 1 2) true) x)"
+    );
+    assert_eq!(
+        parse_expr("a + 1 <= f(b) .. c"),
+        "(Le (Add a 1) (Concat f(b) c))"
+    );
+}
+
+#[test]
+fn parse_binary_spans() {
+    let tokens = Lexer::new("a + b * -2").tokenize().unwrap();
+    let expr = Parser::new(tokens).parse_expr().unwrap();
+    assert_eq!(expr.span.start.offset, 0);
+    assert_eq!(expr.span.end.offset, 10);
+    let ExprKind::Binary { rhs, .. } = expr.kind else {
+        panic!("expected binary");
+    };
+    let ExprKind::Binary { rhs: neg, .. } = rhs.kind else {
+        panic!("expected binary");
+    };
+    assert_eq!(neg.span.start.offset, 8);
+}
+
+#[cfg(test)]
+fn check(source: &str) -> Vec<TypeError> {
+    match Checker::new().check(&parse(source).unwrap()) {
+        Ok(()) => Vec::new(),
+        Err(errors) => errors,
+    }
+}
+
+#[test]
+fn check_test_script() {
+    assert_eq!(check(include_str!("test.rlua")), Vec::new());
+}
+
+#[test]
+fn check_declaration_mismatch() {
+    let errors = check("int a = \"x\"\nlong b = 1\nint c = b\ndouble d = a");
+    assert_eq!(errors.len(), 2);
+    assert_eq!(
+        errors[0].kind,
+        TypeErrorKind::Mismatch {
+            expected: Type::Int,
+            found: Type::String
+        }
+    );
+    assert_eq!(errors[0].span.start.column, 9);
+    assert_eq!(errors[1].to_string(), "3:9: expected int, found long");
+}
+
+#[test]
+fn check_integer_literals() {
+    let errors = check("int a = 1.5\nlong b = 9007199254740993\nint c = 1e2\ndouble d = 10");
+    assert_eq!(errors.len(), 2);
+    assert_eq!(errors[0].to_string(), "1:9: expected int, found double");
+    assert_eq!(errors[1].to_string(), "3:9: expected int, found double");
+
+    // Literals outside 32 bits are `long`, wherever an `int` is expected
+    let source = "int a = 3000000000\nint b = -2147483648\nint c = -2147483649\n\
+                  long d = 3000000000\nvoid function f(int x) end\nf(2147483648)\n\
+                  int function g() return 4294967296 end";
+    let errors = check(source);
+    assert_eq!(errors.len(), 4);
+    assert_eq!(errors[0].to_string(), "1:9: expected int, found long");
+    assert_eq!(errors[1].to_string(), "3:9: expected int, found long");
+    assert_eq!(errors[2].span.start.line, 6);
+    assert_eq!(errors[3].span.start.line, 7);
+}
+
+#[test]
+fn check_bitwise_operators() {
+    let errors =
+        check("int a = 1 | 2\nlong b = 1.0 & 2\nint c = b << 1\nint d = 7 // 2\nint e = true ~ 1");
+    assert_eq!(errors.len(), 2);
+    assert_eq!(errors[0].to_string(), "3:9: expected int, found long");
+    assert_eq!(errors[1].to_string(), "5:9: cannot apply '~' to bool");
+}
+
+#[test]
+fn check_char_values() {
+    let source = r#"
+        char a = 'ab'
+        char b = "b"
+        string s = 'c'
+        int n = (int)'a'
+        char c = (char)65
+        char d = s
+        char e = 'e' .. 'f'
+    "#;
+    let errors = check(source);
+    assert_eq!(errors.len(), 4);
+    assert_eq!(errors[0].to_string(), "2:18: expected char, found string");
+    assert_eq!(errors[1].span.start.line, 3);
+    assert_eq!(errors[2].span.start.line, 7);
+    assert_eq!(errors[3].span.start.line, 8);
+}
+
+#[test]
+fn check_casts() {
+    let source = r#"
+        local x = 1
+        int a = (int)"12"
+        string b = (string)1.5 .. (string)true
+        long c = (long)'c'
+        int d = (int)true
+        table e = (table)1
+        bool f = (bool)"true"
+        vec<int> g = (vec<int>)["1"]
+        string h = (string){}
+        double i = (double)x
+    "#;
+    let errors = check(source);
+    assert_eq!(errors.len(), 4);
+    assert_eq!(errors[0].to_string(), "6:17: cannot cast bool to int");
+    assert_eq!(errors[1].to_string(), "7:19: cannot cast int to table");
+    assert_eq!(errors[2].to_string(), "8:18: cannot cast string to bool");
+    assert_eq!(errors[3].to_string(), "10:20: cannot cast table to string");
+}
+
+#[test]
+fn check_vec_elements() {
+    let source = r#"
+        vec<int> a = [1, 2]
+        vec<int> b = ["x"]
+        vec<double> c = a
+        a[1] = "s"
+        string s = a[2]
+        vec d = [1, "x"]
+        d[1] = true
+        table t = [1]
+    "#;
+    let errors = check(source);
+    assert_eq!(errors.len(), 4);
+    assert_eq!(
+        errors[0].to_string(),
+        "3:22: expected vec<int>, found vec<string>"
+    );
+    assert_eq!(errors[1].to_string(), "5:16: expected int, found string");
+    assert_eq!(errors[2].to_string(), "6:20: expected string, found int");
+    assert_eq!(
+        errors[3].to_string(),
+        "9:19: expected table, found vec<int>"
+    );
+}
+
+#[test]
+fn check_table_schema() {
+    let source = r#"
+        table j = { int t1 = 1, float t2 = 1.1, vec t3 = [1, 2], t4 = "x" }
+        j.t1 = 2
+        j.t1 = "x"
+        j["t2"] = true
+        j.t4 = 1
+        j.t5 = 1
+        string s = j.t3[1]
+        table k = { table inner = { bool b = true } }
+        k.inner.b = 1
+        table l = { int n = "x" }
+        j = {}
+    "#;
+    let errors = check(source);
+    assert_eq!(errors.len(), 4);
+    assert_eq!(errors[0].to_string(), "4:16: expected int, found string");
+    assert_eq!(errors[1].to_string(), "5:19: expected float, found bool");
+    assert_eq!(errors[2].to_string(), "10:21: expected bool, found int");
+    assert_eq!(errors[3].to_string(), "11:29: expected int, found string");
+}
+
+#[test]
+fn check_assignment_to_typed_local() {
+    let errors = check("bool k = true k = 1 local u = 1 u = \"s\"");
+    assert_eq!(errors.len(), 1);
+    assert_eq!(
+        errors[0].kind,
+        TypeErrorKind::Mismatch {
+            expected: Type::Bool,
+            found: Type::Int
+        }
+    );
+}
+
+#[test]
+fn check_assignment_to_typed_global_declared_later() {
+    let source = "function h() g = \"s\" f = 2 end\nglobal int g = 1\nglobal int f = function() return 1 end";
+    let errors = check(source);
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].to_string(), "1:18: expected int, found string");
+}
+
+#[test]
+fn check_return_types() {
+    let source = r#"
+        int function f() return "s" end
+        void function g() return 1 end
+        long function h() return end
+        double function ok() return 1 end
+        int function nested()
+            local inner = function() return "fine" end
+            return 1
+        end
+        int function maybe(bool b) if b then return 1 end end
+        int function branches(bool b) if b then return 1 else return 2 end end
+        int function fails() error("never returns") end
+        int function forever(int n)
+            while true do
+                if n > 3 then return n end
+                n = n + 1
+            end
+        end
+        int function leaves(int n) while true do break end end
+    "#;
+    let errors = check(source);
+    assert_eq!(errors.len(), 5);
+    assert_eq!(
+        errors[0].kind,
+        TypeErrorKind::Mismatch {
+            expected: Type::Int,
+            found: Type::String
+        }
+    );
+    assert_eq!(errors[0].span.start.line, 2);
+    assert_eq!(errors[1].kind, TypeErrorKind::ReturnFromVoid);
+    assert_eq!(
+        errors[2].kind,
+        TypeErrorKind::MissingReturnValue(Type::Long)
+    );
+    assert_eq!(
+        errors[3].to_string(),
+        "10:59: missing return value, function returns int"
+    );
+    assert_eq!(
+        errors[4].to_string(),
+        "19:60: missing return value, function returns int"
+    );
+}
+
+#[test]
+fn check_function_bindings_and_calls() {
+    let source = r#"
+        global void test1 = function() return 1 end
+        int function add(int a, int b) return a + b end
+        string s = add(1, 2)
+        int t = add("x", 2)
+        add(1)
+        void bad = 5
+    "#;
+    let errors = check(source);
+    let kinds: Vec<_> = errors.iter().map(|e| e.kind.clone()).collect();
+    assert_eq!(
+        kinds,
+        vec![
+            TypeErrorKind::ReturnFromVoid,
+            TypeErrorKind::Mismatch {
+                expected: Type::String,
+                found: Type::Int
+            },
+            TypeErrorKind::Mismatch {
+                expected: Type::Int,
+                found: Type::String
+            },
+            TypeErrorKind::ArgumentCount {
+                expected: 2,
+                found: 1
+            },
+            TypeErrorKind::VoidVariable,
+        ]
+    );
+}
+
+#[test]
+fn check_missing_arguments() {
+    let source = r#"
+        function f(x, y) return x end
+        print(f(1))
+        void function g(string s, int n, x) end
+        g("a", 1)
+        g("a")
+        local t = {}
+        function t:m() return 1, 2 end
+        g("a", t:m())
+        void function h(...) g(...) g("a", ...) end
+    "#;
+    let errors = check(source);
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].to_string(), "6:9: expected 3 arguments, found 1");
+}
+
+#[test]
+fn check_operators() {
+    let errors = check("local x = true + 1 int n = #\"abc\" string s = 1 .. 2 bool b = 1 < 2");
+    assert_eq!(errors.len(), 1);
+    assert_eq!(
+        errors[0].kind,
+        TypeErrorKind::InvalidOperand {
+            op: "+",
+            found: Type::Bool
+        }
+    );
+}
+
+/// Output sink shared between a test and the interpreter it runs.
+#[cfg(test)]
+#[derive(Clone, Default)]
+struct SharedBuf(Rc<RefCell<Vec<u8>>>);
+
+#[cfg(test)]
+impl std::io::Write for SharedBuf {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+fn run_interp(source: &str) -> Result<String, RuntimeError> {
+    let chunk = parse(source).unwrap();
+    let out = SharedBuf::default();
+    Interpreter::with_output(Box::new(out.clone())).run(&chunk)?;
+    let bytes = out.0.borrow().clone();
+    Ok(String::from_utf8(bytes).unwrap())
+}
+
+#[cfg(test)]
+fn run_vm(source: &str) -> Result<String, RuntimeError> {
+    let chunk = parse(source).unwrap();
+    let main = Compiler::new().compile(&chunk).unwrap();
+    let out = SharedBuf::default();
+    Vm::with_output(Box::new(out.clone())).run(main)?;
+    let bytes = out.0.borrow().clone();
+    Ok(String::from_utf8(bytes).unwrap())
+}
+
+/// Output with heap addresses blanked, which differ between runs.
+#[cfg(test)]
+fn mask_addresses(out: &str) -> String {
+    let mut masked = String::new();
+    let mut rest = out;
+    while let Some(i) = rest.find("0x") {
+        masked.push_str(&rest[..i + 2]);
+        rest = rest[i + 2..].trim_start_matches(|c: char| c.is_ascii_hexdigit());
+    }
+    masked.push_str(rest);
+    masked
+}
+
+/// Runs a script on the VM, checking the interpreter agrees with it.
+#[cfg(test)]
+fn run(source: &str) -> Result<String, RuntimeError> {
+    let vm = run_vm(source);
+    let interp = run_interp(source);
+    match (&vm, &interp) {
+        (Ok(a), Ok(b)) => assert_eq!(mask_addresses(a), mask_addresses(b)),
+        (Err(a), Err(b)) => assert_eq!(a.to_string(), b.to_string()),
+        _ => panic!("engines disagree: vm {:?}, interpreter {:?}", vm, interp),
+    }
+    vm
+}
+
+#[test]
+fn run_test_script() {
+    let out = run(include_str!("test.rlua")).unwrap();
+    let lines: Vec<&str> = out.lines().collect();
+    assert_eq!(lines.len(), 3);
+    assert_eq!(lines[0], "t1\t1");
+    // `float` is single precision
+    assert_eq!(lines[1], "t2\t1.1000000238419");
+    assert!(lines[2].starts_with("t3\tvec: "));
+}
+
+#[test]
+fn run_locals_and_globals() {
+    let source = r#"
+        int a = 1
+        global int g = 2
+        void function show() print(a, g) end
+        function bump() g = g + 1 a = a + 10 end
+        bump()
+        show()
+        count = 5
+        print(count)
+    "#;
+    assert_eq!(run(source).unwrap(), "11\t3\n5\n");
+}
+
+#[test]
+fn run_multiple_returns() {
+    let source = r##"
+        function three() return 1, 2, 3 end
+        local a, b, c, d = three()
+        print(a, b, c, d)
+        print((three()))
+        print(three(), 10)
+        print(select("#", three()))
+        local t = {three(), three()}
+        print(#t)
+        function pass(...) return ... end
+        print(pass(4, 5))
+    "##;
+    assert_eq!(run(source).unwrap(), "1\t2\t3\tnil\n1\n1\t10\n3\n4\n4\t5\n");
+}
+
+#[test]
+fn run_lexical_scoping() {
+    let source = r#"
+        local x = 1
+        local get = function() return x end
+        local x = 2
+        print(get(), x)
+        do local x = 3 print(x) end
+        print(x)
+        local fns = {}
+        for i = 1, 3 do fns[i] = function() return i end end
+        print(fns[1](), fns[3]())
+    "#;
+    assert_eq!(run(source).unwrap(), "1\t2\n3\n2\n1\t3\n");
+}
+
+#[test]
+fn run_control_flow() {
+    let source = r#"
+        local sum = 0
+        for i = 10, 1, -3 do sum = sum + i end
+        print(sum)
+        local n = 0
+        while true do n = n + 1 if n >= 4 then break end end
+        repeat local m = n n = n - 1 until m <= 2
+        print(n)
+        for i = 1, 5 do
+            if i % 2 == 0 then goto continue end
+            print(i)
+            ::continue::
+        end
+        for x = 1.0, 2 , 0.5 do print(x) end
+    "#;
+    assert_eq!(run(source).unwrap(), "22\n1\n1\n3\n5\n1.0\n1.5\n2.0\n");
+}
+
+#[test]
+fn run_backward_goto_redeclares_locals() {
+    let source = r#"
+        local k = "outer"
+        local fs = {}
+        local i = 1
+        ::top::
+        print(k)
+        local k = i
+        fs[i] = function() return k end
+        i = i + 1
+        if i <= 3 then goto top end
+        print(fs[1](), fs[2](), fs[3]())
+        local n = 0
+        goto count
+        ::again::
+        if n == 1 then print(m) end
+        ::count::
+        local m = n
+        n = n + 1
+        if n < 100000 then goto again end
+        print(n)
+    "#;
+    assert_eq!(
+        run(source).unwrap(),
+        "outer\nouter\nouter\n1\t2\t3\nnil\n100000\n"
+    );
+}
+
+#[test]
+fn run_typed_values() {
+    let source = r#"
+        double d = 1
+        int function test3() return (int)12 end
+        double function half(int n) return n / 2 end
+        print(d, test3(), half(3))
+        local function two() return 2.0 end
+        int x = two()
+        vec<long> v = (vec<long>)[1.0, 2.0]
+        print(x, v[1], v[2])
+    "#;
+    assert_eq!(run(source).unwrap(), "1.0\t12\t1.5\n2\t1\t2\n");
+
+    let err = run("int a = 1 a = \"x\"").unwrap_err();
+    assert_eq!(err.to_string(), "1:11: cannot convert string to int");
+    let err = run("local function f() return 2.5 end int x = f()").unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "1:43: number 2.5 has no integer representation"
+    );
+}
+
+#[test]
+fn run_integer_and_float_literals() {
+    let source = "long b = 9007199254740993 print(b, b + 1, 1e2, 10, 10.0)";
+    assert_eq!(
+        run(source).unwrap(),
+        "9007199254740993\t9007199254740994\t100.0\t10\t10.0\n"
+    );
+}
+
+#[test]
+fn run_byte_strings() {
+    let source = r#"
+        local s = "\xFF\0\u{7FFFFFFF}"
+        print(#s, #"\u{E9}", "caf\u{E9}" == "café", "\65\x42\u{43}")
+    "#;
+    assert_eq!(run(source).unwrap(), "8\t2\ttrue\tABC\n");
+}
+
+#[test]
+fn run_floor_division() {
+    let source = r#"
+        print(7 // 2, -7 // 2, 7 // -2, 7.0 // 2, -7.5 // 2, 1 // 0.0)
+        print((-0x7fffffffffffffff - 1) // -1, 7 % -3, "9" // 2)
+    "#;
+    assert_eq!(
+        run(source).unwrap(),
+        "3\t-4\t-4\t3.0\t-4.0\tinf\n-9223372036854775808\t-2\t4\n"
+    );
+    assert_eq!(
+        run("local z = 0 print(1 // z)").unwrap_err().to_string(),
+        "1:19: attempt to perform 'n//0'"
+    );
+}
+
+#[test]
+fn run_negative_literals() {
+    // The sign is the unary operator, so it applies after the literal's type
+    // is decided and binds looser than `^`
+    let source = r#"
+        print(-9223372036854775808, -9223372036854775807 - 1, -0x10, 3-1)
+        print(-2^2, -.5, - -1)
+    "#;
+    assert_eq!(
+        run(source).unwrap(),
+        "-9.2233720368548e+18\t-9223372036854775808\t-16\t2\n-4.0\t-0.5\t1\n"
+    );
+}
+
+#[test]
+fn run_bitwise_operators() {
+    let source = r#"
+        int flags = 0xF0 | 0x0F
+        long h = 0x7fffffffffffffff
+        print(flags, 0xFF & 0x0F, 5 ~ 3, ~0, 1 << 4, 256 >> 4)
+        print(1 << 63, 1 << 64, -1 >> 1 == h, 1 << -1, 2 >> -1, 3.0 & 1, "6" | 1)
+        local x = 0x12345678
+        print((x >> 16) & 0xFFFF, x ~ x, ~x & 0xFF)
+    "#;
+    assert_eq!(
+        run(source).unwrap(),
+        "255\t15\t6\t-1\t16\t16\n\
+         -9223372036854775808\t0\ttrue\t0\t4\t1\t7\n\
+         4660\t0\t135\n"
+    );
+
+    let cases = [
+        (
+            "print(1.5 & 1)",
+            "1:7: number has no integer representation",
+        ),
+        (
+            "local t = {} print(1 | t)",
+            "1:20: attempt to perform bitwise operation on a table value",
+        ),
+        (
+            "local s = 'x' print(~s)",
+            "1:21: attempt to perform bitwise operation on a string value",
+        ),
+    ];
+    for (source, message) in cases {
+        assert_eq!(run(source).unwrap_err().to_string(), message, "{}", source);
+    }
+}
+
+#[test]
+fn run_char_conversions() {
+    let source = r#"
+        char c = 'A'
+        int n = (int)c
+        char d = (char)(n + 1)
+        string s = c .. d
+        print(c, n, d, s, (int)'é', (char)0x1F600, #c, c == "A", (string)c)
+        void function upper(char ch) print((char)((int)ch - 32)) end
+        upper('q')
+        char seven = '7'
+        local copy = seven
+        local a = 'a'
+        char function x() return 'x' end
+        print((int)seven, (int)copy, (int)a, (int)x(), (double)"b")
+    "#;
+    assert_eq!(
+        run(source).unwrap(),
+        "A\t65\tB\tAB\t233\t😀\t1\ttrue\tA\nQ\n7\t7\t97\t120\t98.0\n"
+    );
+
+    let cases = [
+        ("char c = (char)-1", "1:10: number -1 is not a valid char"),
+        ("print((char)1.5)", "1:7: number 1.5 is not a valid char"),
+        (
+            "local s = \"ab\" char c = s",
+            "1:25: cannot convert string to char",
+        ),
+        (
+            "local s = \"ab\" int n = (int)s",
+            "1:24: cannot convert string to int",
+        ),
+    ];
+    for (source, message) in cases {
+        assert_eq!(run(source).unwrap_err().to_string(), message, "{}", source);
+    }
+}
+
+#[test]
+fn run_vecs() {
+    let source = r#"
+        vec<int> v = [10, 20, 30]
+        v[2] = v[2] + 1
+        v:push(40)
+        print(#v, v[1], v[2], v:pop(), #v, type(v))
+        for i, x in ipairs(v) do print(i, x) end
+        vec<double> d = [1, 2]
+        d:push(3)
+        print(d[1], d[3])
+        vec w = [nil, 2, nil]
+        print(#w, w[1], w[2])
+        for i, x in pairs(w) do print(i, x) end
+        local e = []
+        print(#e, e:pop())
+    "#;
+    assert_eq!(
+        run(source).unwrap(),
+        "4\t10\t21\t40\t3\tvec\n\
+         1\t10\n2\t21\n3\t30\n\
+         1.0\t3.0\n\
+         3\tnil\t2\n\
+         1\tnil\n2\t2\n3\tnil\n\
+         0\tnil\n"
+    );
+}
+
+#[test]
+fn run_casts() {
+    let source = r#"
+        print((int)3.0, (long)-2.0, (int)2147483647, (long)3000000000)
+        print((int)" 42 ", (double)"0x10", (float)"1e2", (long)"7.0", (int)"5")
+        print((string)12, (string)1.5, (string)true, (string)(1 // 1.0))
+        print((double)7, (char)"x", (vec<double>)[1, 2] ~= nil, (table)nil)
+    "#;
+    assert_eq!(
+        run(source).unwrap(),
+        "3\t-2\t2147483647\t3000000000\n\
+         42\t16.0\t100.0\t7\t5\n\
+         12\t1.5\ttrue\t1.0\n\
+         7.0\tx\ttrue\tnil\n"
+    );
+
+    let cases = [
+        (
+            "print((int)1.5)",
+            "1:7: number 1.5 has no integer representation",
+        ),
+        (
+            "local x = 1 / 0 print((long)x)",
+            "1:23: number inf has no integer representation",
+        ),
+        (
+            "print((int)2147483648)",
+            "1:7: number 2147483648 is out of range for int",
+        ),
+        (
+            "local n = -3000000000 print((int)n)",
+            "1:29: number -3000000000 is out of range for int",
+        ),
+        (
+            "print((int)\"1.5\")",
+            "1:7: number 1.5 has no integer representation",
+        ),
+        (
+            "print((double)\"abc\")",
+            "1:7: cannot convert string to double",
+        ),
+        (
+            "local b = true print((string)b, (int)b)",
+            "1:33: cannot convert boolean to int",
+        ),
+    ];
+    for (source, message) in cases {
+        assert_eq!(run(source).unwrap_err().to_string(), message, "{}", source);
+    }
+}
+
+#[test]
+fn run_fixed_width_numbers() {
+    let source = r#"
+        int i = 2147483647
+        i = i + 1
+        long l = 2147483647
+        l = l + 1
+        print(i, l, i - 1, (long)i * 2)
+        int w = 0xFFFFFFFF
+        long m = 0x7FFFFFFFFFFFFFFF
+        m = m + 1
+        print(w, m < 0)
+        float f = 0.1
+        double d = 0.1
+        print(f, d, f == d, (double)f == f)
+        f = 16777217
+        print(f, f + 1)
+        vec<int> v = [4294967297]
+        v:push(2147483648)
+        print(v[1], v[2])
+        int function narrow(int n) return n end
+        print(narrow(4294967298))
+    "#;
+    assert_eq!(
+        run(source).unwrap(),
+        "-2147483648\t2147483648\t-2147483649\t-4294967296\n\
+         -1\ttrue\n\
+         0.10000000149012\t0.1\tfalse\ttrue\n\
+         16777216.0\t16777217.0\n\
+         1\t-2147483648\n\
+         2\n"
+    );
+}
+
+#[test]
+fn run_typed_table_fields() {
+    let source = r#"
+        table j = { int t1 = 1, float t2 = 2, vec t3 = [1, 2, 3] }
+        print(j.t1, j.t2, #j.t3, j[1], #j)
+        table k = { float t2 = 1.1, x = 1 }
+        print(k.t2)
+        k.t2 = 0.1
+        k.x = 0.1
+        print(k.t2, k.x)
+        local alias = k
+        alias["t2"] = 2
+        rawset(alias, "t2", 0.2)
+        print(k.t2)
+        local w = { float f = 1, f = 0.1, int n = 3 }
+        print(w.f, w.n)
+    "#;
+    assert_eq!(
+        run(source).unwrap(),
+        "1\t2.0\t3\tnil\t0\n1.1000000238419\n0.10000000149012\t0.1\n\
+         0.20000000298023\n0.10000000149012\t3\n"
+    );
+
+    let cases = [
+        (
+            "local t = { int n = 1 } t.n = \"a\"",
+            "1:25: cannot convert string to int",
+        ),
+        (
+            "local t = { int n = 1 } t.n = nil",
+            "1:25: cannot convert nil to int",
+        ),
+        (
+            "local t = { int n = \"a\" }",
+            "1:21: cannot convert string to int",
+        ),
+    ];
+    for (source, message) in cases {
+        assert_eq!(run(source).unwrap_err().to_string(), message, "{}", source);
+    }
+}
+
+#[test]
+fn run_vec_errors() {
+    let cases = [
+        (
+            "local v = [1, 2] print(v[3])",
+            "1:24: index 3 out of bounds for vec of length 2",
+        ),
+        (
+            "local v = [1] v[0] = 5",
+            "1:15: index 0 out of bounds for vec of length 1",
+        ),
+        ("local v = [1] print(v.x)", "1:21: vec has no method 'x'"),
+        (
+            "local v = [1] print(v[true])",
+            "1:21: vec index must be an integer, got boolean",
+        ),
+        (
+            "vec<int> v = [1] local w = v w[1] = 1.5",
+            "1:30: number 1.5 has no integer representation",
+        ),
+        (
+            "local v = [1, \"x\"] vec<int> w = v",
+            "1:33: cannot convert string to int",
+        ),
+        (
+            "vec<int> v = [1] vec<double> w = v",
+            "1:34: cannot convert vec<int> to vec<double>",
+        ),
+        (
+            "vec<int> v = [] v:push(\"s\")",
+            "1:17: cannot convert string to int",
+        ),
+    ];
+    for (source, message) in cases {
+        assert_eq!(run(source).unwrap_err().to_string(), message, "{}", source);
+    }
+}
+
+#[test]
+fn run_tables_and_methods() {
+    let source = r#"
+        local account = { balance = 10 }
+        function account:deposit(n) self.balance = self.balance + n end
+        account:deposit(5)
+        local t = { 1, 2, [10] = "ten", name = "t" }
+        print(account.balance, #t, t[10], t.name)
+        for k, v in ipairs({ "a", "b" }) do print(k, v) end
+    "#;
+    assert_eq!(run(source).unwrap(), "15\t2\tten\tt\n1\ta\n2\tb\n");
+}
+
+#[test]
+fn run_fastcall_runs_immediately() {
+    let source = r#"
+        void () print("fast") end
+        void function() print("never") end
+    "#;
+    assert_eq!(run(source).unwrap(), "fast\n");
+}
+
+#[test]
+fn run_runtime_errors() {
+    let err = run("local t = nil\nprint(t.x)").unwrap_err();
+    assert_eq!(err.to_string(), "2:7: attempt to index a nil value");
+
+    let err = run("local s = 1 .. {}").unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "1:11: attempt to concatenate a table value"
+    );
+
+    let source = r#"
+        local ok, msg = pcall(function() error("boom") end)
+        print(ok, msg)
+        local ok, e = pcall(error, { code = 7 })
+        print(ok, e.code)
+    "#;
+    assert_eq!(run(source).unwrap(), "false\t2:42: boom\nfalse\t7\n");
+
+    let err = run("function f() return f() + 1 end f()").unwrap_err();
+    assert!(err.to_string().ends_with("stack overflow"));
+}
+
+#[test]
+fn run_extreme_builtin_arguments() {
+    let source = r#"
+        local f, t = ipairs({ 1 })
+        print(pcall(f, t, 9223372036854775807))
+        print(pcall(select, -9223372036854775807 - 1, 1))
+        local g, v = ipairs([1, 2])
+        print(pcall(g, v, -1))
+        print(pcall(g, v, 9223372036854775807))
+    "#;
+    assert_eq!(
+        run(source).unwrap(),
+        "true\tnil\nfalse\tbad argument #1 to 'select' (index out of range)\ntrue\tnil\ntrue\tnil\n"
+    );
+}
+
+#[cfg(test)]
+fn compile(source: &str) -> Rc<crate::compiler::Proto> {
+    Compiler::new().compile(&parse(source).unwrap()).unwrap()
+}
+
+#[test]
+fn compile_specialized_arithmetic() {
+    use crate::compiler::Instr;
+    let proto =
+        compile("int a = 1 long b = a + 2 * a double x = 1.5 double y = x / x local z = a + x");
+    let code = &proto.code;
+    assert!(code.iter().any(|i| matches!(i, Instr::AddInt { .. })));
+    assert!(code.iter().any(|i| matches!(i, Instr::MulInt { .. })));
+    assert!(code.iter().any(|i| matches!(i, Instr::DivFloat { .. })));
+    // Mixed operands keep the generic instruction
+    assert!(code
+        .iter()
+        .any(|i| matches!(i, Instr::Binary { op: BinOp::Add, .. })));
+    // Statically typed values need no conversion
+    assert!(!code.iter().any(|i| matches!(i, Instr::Conform { .. })));
+
+    let proto = compile("local function f() return 1 end int n = f()");
+    assert!(proto
+        .code
+        .iter()
+        .any(|i| matches!(i, Instr::Conform { .. })));
+
+    // `int` and `float` narrow what arithmetic produces
+    for source in [
+        "int a = 1 int b = a + a",
+        "float x = 1.5",
+        "int n = 3000000000",
+    ] {
+        assert!(
+            compile(source)
+                .code
+                .iter()
+                .any(|i| matches!(i, Instr::Conform { .. })),
+            "{}",
+            source
+        );
+    }
+}
+
+#[test]
+fn compile_errors() {
+    use crate::compiler::{CompileError, CompileErrorKind};
+    let error = |source: &str| -> CompileError {
+        Compiler::new()
+            .compile(&parse(source).unwrap())
+            .unwrap_err()
+    };
+    assert_eq!(error("break").kind, CompileErrorKind::BreakOutsideLoop);
+    assert_eq!(
+        error("goto nowhere").kind,
+        CompileErrorKind::UndefinedLabel("nowhere".to_string())
+    );
+    assert_eq!(
+        error("goto skip local x = 1 ::skip:: print(x)").to_string(),
+        "1:6: goto skip jumps into the scope of local 'x'"
+    );
+}
+
+#[test]
+fn check_jumps() {
+    let message =
+        |source: &str| -> Vec<String> { check(source).iter().map(|e| e.to_string()).collect() };
+    assert_eq!(message("print(1) break"), ["1:10: break outside a loop"]);
+    assert_eq!(
+        message("while true do local f = function() break end end"),
+        ["1:36: break outside a loop"]
+    );
+    assert_eq!(
+        message("if false then goto nowhere end"),
+        ["1:20: no visible label 'nowhere' for goto"]
+    );
+    assert_eq!(
+        message("goto l int x = 1 ::l:: print(x + x)"),
+        ["1:6: goto l jumps into the scope of local 'x'"]
+    );
+    assert_eq!(
+        message("do goto l end local x ::l:: print(x)"),
+        ["1:9: goto l jumps into the scope of local 'x'"]
+    );
+    assert_eq!(
+        message("::a:: ::a:: do ::a:: end"),
+        [
+            "1:9: label 'a' already defined",
+            "1:18: label 'a' already defined"
+        ]
+    );
+    assert!(check("do ::a:: end ::a:: do goto e end local y ::e::").is_empty());
+    assert!(check("::top:: local x = 1 while x do goto top end").is_empty());
+}
+
+#[test]
+fn run_typed_numbers_are_never_nil() {
+    assert_eq!(run("int a double b print(a, b)").unwrap(), "0\t0.0\n");
+    let err = run("local function f() end\nint n = f()").unwrap_err();
+    assert_eq!(err.to_string(), "2:9: cannot convert nil to int");
+    let errors = check("int n = nil");
+    assert_eq!(
+        errors[0].kind,
+        TypeErrorKind::Mismatch {
+            expected: Type::Int,
+            found: Type::Nil
+        }
+    );
+}
+
+#[test]
+fn run_assignment_to_undeclared_name_sets_global() {
+    let source = r#"
+        function set() x = 1 end
+        set()
+        print(x)
+    "#;
+    assert_eq!(run(source).unwrap(), "1\n");
+}
+
+#[test]
+fn run_shared_upvalues() {
+    let source = r#"
+        local function counter()
+            local n = 0
+            return function() n = n + 1 return n end, function() return n end
+        end
+        local inc, get = counter()
+        inc() inc()
+        print(get())
+        local fns = {}
+        local i = 1
+        while i <= 3 do
+            local j = i
+            fns[i] = function() return j end
+            i = i + 1
+        end
+        print(fns[1](), fns[2](), fns[3]())
+    "#;
+    assert_eq!(run(source).unwrap(), "2\n1\t2\t3\n");
+}
+
+#[test]
+fn run_loop_closures() {
+    let source = r#"
+        local fns = {}
+        for i = 1, 3 do fns[#fns + 1] = function() return i end end
+        for i = 10, 11 do fns[#fns + 1] = function() i = i + 100 return i end end
+        for k, v in ipairs({ "a", "b" }) do fns[#fns + 1] = function() return k .. v end end
+        local out = ""
+        for _, f in ipairs(fns) do out = out .. f() .. " " end
+        print(out, fns[4](), fns[4]())
+        for i = 1, 3 do i = i * 10 out = out .. i end
+        print(out)
+        local r, n = {}, 0
+        repeat
+            local m = n
+            r[#r + 1] = function() return m end
+            n = n + 1
+        until m >= 2
+        print(r[1](), r[2](), r[3]())
+        local g = {}
+        for i = 1, 4 do
+            if i % 2 == 0 then goto continue end
+            local x = i * 2
+            g[#g + 1] = function() x = x + 1 return x end
+            ::continue::
+        end
+        print(g[1](), g[1](), g[2]())
+        local w, j = {}, 0
+        while true do
+            j = j + 1
+            local captured = j
+            w[j] = function() return captured end
+            if j == 3 then break end
+        end
+        print(w[1](), w[2](), w[3]())
+    "#;
+    assert_eq!(
+        run(source).unwrap(),
+        "1 2 3 110 111 1a 2b \t210\t310\n\
+         1 2 3 110 111 1a 2b 102030\n0\t1\t2\n3\t4\t7\n1\t2\t3\n"
+    );
+}
+
+#[test]
+fn run_nested_upvalues() {
+    let source = r#"
+        local function outer()
+            local a = 1
+            local function mid()
+                return function() a = a + 1 return a end
+            end
+            return mid(), function() return a end
+        end
+        local inc, get = outer()
+        inc() inc()
+        print(get())
+        local function make()
+            local v = 0
+            local set = function(x) v = x end
+            set(5)
+            local seen = v
+            v = 7
+            return seen, function() return v end, set
+        end
+        local seen, getv, setv = make()
+        setv(9)
+        print(seen, getv())
+        local function adder(p) return function(d) p = p + d return p end end
+        local add = adder(5)
+        add(1)
+        print(add(2))
+        int count = 2147483646
+        local bump = function() count = count + 1 end
+        bump() bump()
+        print(count)
+    "#;
+    assert_eq!(run(source).unwrap(), "3\n5\t9\n8\n-2147483648\n");
+}
+
+#[test]
+fn compile_loop_closes_upvalues() {
+    use crate::compiler::Instr;
+    let closes = |source: &str| {
+        compile(source)
+            .code
+            .iter()
+            .filter(|i| matches!(i, Instr::Close { .. }))
+            .count()
+    };
+    // Only loops whose body is captured close it every iteration
+    assert_eq!(
+        closes("for i = 1, 3 do local f = function() return i end end"),
+        1
+    );
+    assert_eq!(
+        closes("for k in pairs({}) do local f = function() return k end end"),
+        1
+    );
+    assert_eq!(closes("for i = 1, 3 do print(i) end"), 0);
+    assert_eq!(closes("while true do local x = 1 print(x) end"), 0);
+}
+
+#[test]
+fn run_vm_deep_recursion() {
+    // Lua calls do not recurse on the native stack
+    let source = r#"
+        int function depth(int n) if n == 0 then return 0 end return 1 + depth(n - 1) end
+        print(depth(100000))
+    "#;
+    assert_eq!(run_vm(source).unwrap(), "100000\n");
+}
+
+#[cfg(test)]
+fn format_twice(source: &str) -> String {
+    let formatted = format(source).unwrap();
+    assert_eq!(format(&formatted).unwrap(), formatted, "not idempotent");
+    formatted
+}
+
+#[test]
+fn format_layout() {
+    let source = r#"local x,y=1,2
+global int g=0x1F   ;
+if x<y then print(  x+-y , - -x, not x,#{},~x) elseif x==y then else print'sugar' end
+while x<10 do x=x+1 end
+repeat local z=x until z>0
+for i=1,10,2 do if i>5 then break end end
+for k,v in pairs({}) do ::top:: end
+local f=function(a,int b,...) return a,b,... end
+function t.m:method(s) return self end
+local function g() end
+int function h(vec<int> v) return #v end
+print(t["name"], t.name, t[ [[k]] ], [ [1], [2]], (int)"1", 2^-3 .. 1 // 2)
+local a = f
+;(print)("x")
+"#;
+    assert_eq!(
+        format_twice(source),
+        r#"local x, y = 1, 2
+global int g = 0x1F
+if x < y then
+    print(x + -y, - -x, not x, #{}, ~x)
+elseif x == y then
+else
+    print('sugar')
+end
+while x < 10 do
+    x = x + 1
+end
+repeat
+    local z = x
+until z > 0
+for i = 1, 10, 2 do
+    if i > 5 then
+        break
+    end
+end
+for k, v in pairs({}) do
+    ::top::
+end
+local f = function(a, int b, ...)
+    return a, b, ...
+end
+function t.m:method(s)
+    return self
+end
+function g() end
+int function h(vec<int> v)
+    return #v
+end
+print(t["name"], t.name, t[ [[k]] ], [ [1], [2] ], (int)"1", 2 ^ -3 .. 1 // 2)
+local a = f
+;(print)("x")
+"#
+    );
+}
+
+#[test]
+fn format_tables() {
+    let source = r#"local a = {1,2;  x=3,[4]=5}
+local b = {
+  1, 2,
+
+  float f = 1.5,   [ "k" ] = function() return 1 end
+}
+local c = {
+}
+local d = { f = function() return 1 end }
+local e = [1,
+  2]
+"#;
+    assert_eq!(
+        format_twice(source),
+        r#"local a = { 1, 2, x = 3, [4] = 5 }
+local b = {
+    1,
+    2,
+
+    float f = 1.5,
+    ["k"] = function()
+        return 1
+    end,
+}
+local c = {}
+local d = {
+    f = function()
+        return 1
+    end,
+}
+local e = [
+    1,
+    2,
+]
+"#
+    );
+}
+
+#[test]
+fn format_keeps_comments() {
+    let source = r#"-- header
+
+
+local x = 1 -- trailing
+--[==[ long
+  comment ]==]
+if x then -- after then
+  -- inside
+
+
+  print(x) --[[ inline ]]
+  -- before end
+end
+local t = {
+  -- first
+  a = 1, -- one
+  b = f(1, -- in call
+    2),
+  -- last
+}
+local u = { -- only comments
+}
+function f() -- header
+end
+-- trailing comment   
+"#;
+    assert_eq!(
+        format_twice(source),
+        r#"-- header
+
+local x = 1 -- trailing
+--[==[ long
+  comment ]==]
+if x then -- after then
+    -- inside
+
+    print(x) --[[ inline ]]
+    -- before end
+end
+local t = {
+    -- first
+    a = 1, -- one
+    b = f(1, 2), -- in call
+    -- last
+}
+local u = { -- only comments
+}
+function f() -- header
+end
+-- trailing comment
+"#
+    );
+}
+
+#[test]
+fn format_preserves_behavior() {
+    let source = include_str!("test.rlua");
+    let formatted = format_twice(source);
+    assert!(formatted.contains("table j = {\n    -- typed field j.t1\n    int t1 = 1,\n"));
+    assert!(formatted.contains("long function test4()\n    long a = 111\n    return a\nend\n"));
+    let lines = |out: String| out.lines().take(2).collect::<Vec<_>>().join("\n");
+    assert_eq!(lines(run(&formatted).unwrap()), lines(run(source).unwrap()));
+    assert_eq!(format("").unwrap(), "");
+    assert!(format("x = = 1").is_err());
+}
+
+/// Runs a language server session of `requests`, returning every message
+/// the server sent.
+#[cfg(test)]
+fn lsp_session(requests: &[String]) -> Vec<crate::json::Json> {
+    let mut input = String::new();
+    for body in requests {
+        input.push_str(&format!("Content-Length: {}\r\n\r\n{}", body.len(), body));
+    }
+    let mut output = Vec::new();
+    crate::lsp::serve(input.as_bytes(), &mut output).unwrap();
+    let output = String::from_utf8(output).unwrap();
+    let mut messages = Vec::new();
+    let mut rest = output.as_str();
+    while let Some((header, body)) = rest.split_once("\r\n\r\n") {
+        let length: usize = header["Content-Length: ".len()..].parse().unwrap();
+        messages.push(crate::json::parse(&body[..length]).unwrap());
+        rest = &body[length..];
+    }
+    messages
+}
+
+#[cfg(test)]
+fn lsp_open(text: &str) -> String {
+    format!(
+        r#"{{"jsonrpc":"2.0","method":"textDocument/didOpen","params":{{"textDocument":{{"uri":"file:///a.rlua","languageId":"rlua","version":1,"text":{}}}}}}}"#,
+        crate::json::Json::from(text)
+    )
+}
+
+#[cfg(test)]
+fn lsp_request(id: usize, method: &str, line: usize, character: usize) -> String {
+    format!(
+        r#"{{"jsonrpc":"2.0","id":{},"method":"{}","params":{{"textDocument":{{"uri":"file:///a.rlua"}},"position":{{"line":{},"character":{}}}}}}}"#,
+        id, method, line, character
+    )
+}
+
+#[test]
+fn lsp_lifecycle_and_diagnostics() {
+    let messages = lsp_session(&[
+        r#"{"jsonrpc":"2.0","id":1,"method":"initialize","params":{}}"#.to_string(),
+        r#"{"jsonrpc":"2.0","method":"initialized","params":{}}"#.to_string(),
+        lsp_open("int a = \"x\"\nb = = 1"),
+        lsp_open("int a = \"x\"\n"),
+        r#"{"jsonrpc":"2.0","id":2,"method":"workspace/symbol","params":{}}"#.to_string(),
+        "{oops".to_string(),
+        r#"{"jsonrpc":"2.0","id":3,"method":"shutdown"}"#.to_string(),
+        r#"{"jsonrpc":"2.0","method":"exit"}"#.to_string(),
+        r#"{"jsonrpc":"2.0","id":4,"method":"shutdown"}"#.to_string(),
+    ]);
+    assert_eq!(messages.len(), 6);
+    let capabilities = messages[0].get("result").get("capabilities");
+    assert_eq!(capabilities.get("textDocumentSync").as_usize(), Some(1));
+    assert_eq!(capabilities.get("hoverProvider"), &true.into());
+
+    // A parse error hides the type errors
+    let diagnostics = messages[1].get("params").get("diagnostics").as_array();
+    assert_eq!(diagnostics.len(), 1);
+    let range = diagnostics[0].get("range");
+    assert_eq!(range.get("start").get("line").as_usize(), Some(1));
+    assert_eq!(diagnostics[0].get("source").as_str(), Some("rlua"));
+
+    let diagnostics = messages[2].get("params").get("diagnostics").as_array();
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(
+        diagnostics[0].get("message").as_str(),
+        Some("expected int, found string")
+    );
+    assert_eq!(
+        diagnostics[0].get("range").get("start"),
+        &crate::json::Json::object([("line", 0.into()), ("character", 8.into())])
+    );
+
+    assert_eq!(
+        messages[3].get("error").get("code"),
+        &crate::json::Json::Number(-32601.0)
+    );
+    assert_eq!(
+        messages[4].get("error").get("code"),
+        &crate::json::Json::Number(-32700.0)
+    );
+    assert_eq!(messages[5].get("id").as_usize(), Some(3));
+}
+
+#[test]
+fn lsp_hover_and_definition() {
+    let source = "\
+int count = 0
+global table t = {}
+int function add(int n, m)
+    local total = count + n
+    return total
+end
+function t:get() return self end
+for i = 1, 3 do add(i, 2) end
+print(t:get(), g)
+g = 1
+";
+    let messages = lsp_session(&[
+        lsp_open(source),
+        lsp_request(1, "textDocument/hover", 3, 19),
+        lsp_request(2, "textDocument/hover", 3, 27),
+        lsp_request(3, "textDocument/hover", 7, 17),
+        lsp_request(4, "textDocument/hover", 8, 0),
+        lsp_request(5, "textDocument/hover", 8, 15),
+        lsp_request(6, "textDocument/hover", 6, 26),
+        lsp_request(7, "textDocument/definition", 4, 12),
+        lsp_request(8, "textDocument/definition", 8, 6),
+        lsp_request(9, "textDocument/hover", 5, 2),
+    ]);
+    assert!(messages[0]
+        .get("params")
+        .get("diagnostics")
+        .as_array()
+        .is_empty());
+    let hover = |i: usize| {
+        let value = messages[i].get("result").get("contents").get("value");
+        value
+            .as_str()
+            .map(|v| v.lines().nth(1).unwrap().to_string())
+    };
+    assert_eq!(hover(1).as_deref(), Some("int count"));
+    assert_eq!(hover(2).as_deref(), Some("(parameter) int n"));
+    assert_eq!(hover(3).as_deref(), Some("int function add(int n, m)"));
+    assert_eq!(hover(4).as_deref(), Some("builtin function print"));
+    assert_eq!(hover(5).as_deref(), Some("global g"));
+    assert_eq!(hover(6).as_deref(), Some("(parameter) self"));
+    assert_eq!(hover(9), None);
+
+    let range = |i: usize| {
+        let start = messages[i].get("result").get("range").get("start");
+        (
+            start.get("line").as_usize(),
+            start.get("character").as_usize(),
+        )
+    };
+    assert_eq!(range(7), (Some(3), Some(10)));
+    assert_eq!(range(8), (Some(1), Some(13)));
+    assert_eq!(
+        messages[8].get("result").get("uri").as_str(),
+        Some("file:///a.rlua")
+    );
+}
+
+#[test]
+fn lsp_completion_and_symbols() {
+    let source = "\
+int top = 1
+function f(a)
+    string inner = \"x\"
+    local function g() end
+    
+end
+double d = function() return 1 end
+";
+    let labels = |message: &crate::json::Json| {
+        message
+            .get("result")
+            .as_array()
+            .iter()
+            .map(|item| item.get("label").as_str().unwrap().to_string())
+            .collect::<Vec<_>>()
+    };
+    let messages = lsp_session(&[
+        lsp_open(source),
+        lsp_request(1, "textDocument/completion", 4, 4),
+        lsp_request(2, "textDocument/completion", 0, 0),
+        lsp_request(3, "textDocument/documentSymbol", 0, 0),
+        // Completion still knows the names while the text does not parse
+        lsp_open(&source.replace("    \n", "    local x = \n")),
+        lsp_request(4, "textDocument/completion", 4, 4),
+    ]);
+    let inside = labels(&messages[1]);
+    for name in ["while", "int", "top", "f", "a", "inner", "g", "print"] {
+        assert!(inside.contains(&name.to_string()), "missing {}", name);
+    }
+    assert!(!inside.contains(&"d".to_string()));
+    let outside = labels(&messages[2]);
+    assert!(!outside.contains(&"inner".to_string()));
+    assert!(!outside.contains(&"a".to_string()));
+    assert_eq!(labels(&messages[5]), inside);
+
+    let symbols = messages[3].get("result").as_array();
+    let names: Vec<_> = symbols
+        .iter()
+        .map(|s| s.get("name").as_str().unwrap())
+        .collect();
+    assert_eq!(names, ["top", "f", "d"]);
+    assert_eq!(symbols[1].get("kind").as_usize(), Some(12));
+    assert_eq!(
+        symbols[2].get("detail").as_str(),
+        Some("double function d()")
+    );
+    let children = symbols[1].get("children").as_array();
+    let names: Vec<_> = children
+        .iter()
+        .map(|s| s.get("name").as_str().unwrap())
+        .collect();
+    assert_eq!(names, ["inner", "g"]);
+}
+
+#[test]
+fn gc_frees_unreachable_cycles() {
+    let a = Value::new_table();
+    let b = Value::new_table();
+    let (Value::Table(ta), Value::Table(tb)) = (&a, &b) else {
+        unreachable!()
+    };
+    ta.borrow_mut().set(Value::str("b"), b.clone()).unwrap();
+    tb.borrow_mut().set(Value::str("a"), a.clone()).unwrap();
+    let v = Value::new_vec(Vec::new());
+    let Value::Vec(vv) = &v else { unreachable!() };
+    vv.borrow_mut().items.push(v.clone());
+    let weak_a = Rc::downgrade(ta);
+    let weak_v = Rc::downgrade(vv);
+
+    // Still referenced from Rust
+    crate::gc::collect();
+    assert!(weak_a.upgrade().is_some());
+    drop(b);
+    crate::gc::collect();
+    assert!(weak_a.upgrade().is_some());
+
+    drop((a, v));
+    assert!(weak_a.upgrade().is_some());
+    assert!(weak_v.upgrade().is_some());
+    crate::gc::collect();
+    assert!(weak_a.upgrade().is_none());
+    assert!(weak_v.upgrade().is_none());
+}
+
+#[test]
+fn run_collectgarbage() {
+    let source = r#"
+        collectgarbage("stop")
+        print(collectgarbage("isrunning"))
+        local before = collectgarbage("count")
+        local kept = {}
+        kept.self = kept
+        for i = 1, 2000 do
+            local a = {}
+            a.b = { a = a }
+            local function f() return f, a end
+            a.f = f
+            local v = [a]
+            v:push(v)
+        end
+        local grown = collectgarbage("count")
+        print(collectgarbage())
+        local after = collectgarbage("count")
+        print(grown > before + 100, after < before + 10, kept.self == kept)
+        print(collectgarbage("step"), collectgarbage("restart"), collectgarbage("isrunning"))
+        print(pcall(collectgarbage, "bogus"))
+    "#;
+    assert_eq!(
+        run(source).unwrap(),
+        "false\n0\ntrue\ttrue\ttrue\ntrue\t0\ttrue\n\
+         false\tbad argument #1 to 'collectgarbage' (invalid option 'bogus')\n"
+    );
+}
+
+#[test]
+fn run_collects_automatically() {
+    // Each iteration leaves a cycle behind, collections keep the heap small
+    let source = r#"
+        for i = 1, 20000 do
+            local a = { i }
+            a.a = a
+            local function f() return f end
+        end
+        print(collectgarbage("count") < 1024)
+    "#;
+    assert_eq!(run(source).unwrap(), "true\n");
+}
+
+#[test]
+fn gc_incremental_cycle_survives_mutation() {
+    use crate::gc::{self, Mode, Params};
+    // One object per step, so the program runs between every step
+    let params = Params {
+        stepmul: 1,
+        stepsize: 10,
+        ..Params::default()
+    };
+    gc::set_mode(Mode::Incremental, params);
+    gc::set_running(false);
+    let table = |v: &Value| match v {
+        Value::Table(t) => t.clone(),
+        _ => unreachable!(),
+    };
+    let a = Value::new_table();
+    let b = Value::new_table();
+    table(&b)
+        .borrow_mut()
+        .set(Value::Int(1), b.clone())
+        .unwrap();
+    table(&a)
+        .borrow_mut()
+        .set(Value::Int(1), b.clone())
+        .unwrap();
+    let weak_b = Rc::downgrade(&table(&b));
+    drop(b);
+    let garbage = Value::new_table();
+    table(&garbage)
+        .borrow_mut()
+        .set(Value::Int(1), garbage.clone())
+        .unwrap();
+    let weak_garbage = Rc::downgrade(&table(&garbage));
+    drop(garbage);
+
+    let mut moved = false;
+    let mut steps = 0;
+    while !gc::step(0) {
+        steps += 1;
+        if steps == 2 {
+            // Move `b` to a table created during the cycle
+            let c = Value::new_table();
+            let b = table(&a).borrow().get(&Value::Int(1));
+            table(&c).borrow_mut().set(Value::Int(1), b).unwrap();
+            table(&a).borrow_mut().set(Value::Int(1), c).unwrap();
+            moved = true;
+        }
+    }
+    assert!(moved && steps > 2);
+    assert!(weak_garbage.upgrade().is_none());
+    let b = weak_b.upgrade().expect("reachable table was collected");
+    assert!(b
+        .borrow()
+        .get(&Value::Int(1))
+        .raw_equals(&Value::Table(b.clone())));
+}
+
+#[test]
+fn run_gc_modes() {
+    let source = r#"
+        print(collectgarbage("generational"), collectgarbage("generational"))
+        local kept = { 1, 2, 3 }
+        for i = 1, 20000 do
+            local a = { i }
+            a.a = a
+        end
+        print(collectgarbage("count") < 1024, collectgarbage("step"), #kept)
+        print(collectgarbage("incremental", 150, 400, 12))
+        print(collectgarbage("setpause", 180), collectgarbage("setpause", 200))
+        print(collectgarbage("setstepmul", 100))
+        for i = 1, 20000 do
+            local a = { kept }
+            a.a = a
+            kept[#kept + 1] = i
+            kept[#kept] = nil
+        end
+        print(collectgarbage("count") < 1024, #kept)
+        collectgarbage("stop")
+        local a = {}
+        a.a = a
+        a = nil
+        local done = collectgarbage("step")
+        while not done do done = collectgarbage("step") end
+        print(done, pcall(collectgarbage, "step", "x"))
+    "#;
+    assert_eq!(
+        run(source).unwrap(),
+        "incremental\tgenerational\ntrue\ttrue\t3\ngenerational\n150\t180\n400\n\
+         true\t3\ntrue\tfalse\tbad argument #2 to 'collectgarbage' (integer expected, got string)\n"
+    );
+}
+
+#[test]
+fn run_under_gc_stress() {
+    crate::gc::set_stress(true);
+    let out = run(include_str!("test.rlua")).unwrap();
+    assert!(out.starts_with("t1\t1\nt2\t1.1000000238419\nt3\tvec: "));
+    let source = r#"
+        local counters = {}
+        for i = 1, 5 do
+            local n = i * 10
+            counters[i] = function() n = n + 1 return n end
+        end
+        local t = { name = "x" }
+        t.self = t
+        local v = [t, [1, 2], "s" .. 1]
+        print(counters[2](), counters[2](), counters[5](), t.self.name, #v[2], v[3])
+    "#;
+    assert_eq!(run(source).unwrap(), "21\t22\t51\tx\t2\ts1\n");
+    crate::gc::set_stress(false);
+}
+
+#[test]
+fn run_table_parts() {
+    let source = r#"
+        local t = {}
+        for i = 1, 1000 do t[i] = i * 2 end
+        print(#t, t[500], t[1001])
+        for i = 1000, 901, -1 do t[i] = nil end
+        print(#t, t[900])
+        local h = {}
+        h[3] = "c" h[2] = "b" h[1] = "a"
+        print(#h, h[1] .. h[2] .. h[3])
+        h[4] = "d"
+        print(#h, #{ n = 1 }, #{ nil })
+        local f = {}
+        f[1.0] = "one" f[2] = "two" f[2^53] = "big"
+        print(f[1], f[2.0], #f, f[2^53 // 1], next({ [3.0] = true }))
+        print(pcall(function() f[0/0] = 1 end))
+        print(pcall(function() f[nil] = 1 end))
+        print(f[0/0], f[nil], rawlen({ 1, 2, x = 3 }))
+    "#;
+    assert_eq!(
+        run(source).unwrap(),
+        "1000\t1000\tnil\n900\t1800\n3\tabc\n4\t0\t0\n\
+         one\ttwo\t2\tbig\t3\ttrue\n\
+         false\t15:32: table index is NaN\n\
+         false\t16:32: table index is nil\nnil\tnil\t2\n"
+    );
+}
+
+#[test]
+fn run_table_traversal() {
+    let source = r#"
+        local t = { x = 1, 10, 20, y = 2, 30 }
+        t.z = 3
+        local keys = ""
+        for k, v in pairs(t) do keys = keys .. k .. "=" .. v .. " " end
+        print(keys)
+        for k, v in pairs(t) do
+            t[k] = nil
+            if type(k) == "string" then t.x = nil end
+        end
+        print(next(t), #t)
+        local n = {}
+        for i = 1, 100 do n[i] = i n["k" .. i] = i end
+        local count, sum = 0, 0
+        for k, v in pairs(n) do
+            count = count + 1
+            sum = sum + v
+            n[k] = v * 2
+        end
+        print(count, sum, n[50], n.k50)
+        print(pcall(next, {}, "missing"))
+    "#;
+    assert_eq!(
+        run(source).unwrap(),
+        "1=10 2=20 3=30 x=1 y=2 z=3 \nnil\t0\n200\t10100\t100\t100\n\
+         false\tinvalid key to 'next'\n"
+    );
+}
+
+#[test]
+fn run_metatable_index() {
+    let source = r#"
+        local defaults = { color = "red" }
+        local t = setmetatable({}, { __index = defaults })
+        print(t.color, rawget(t, "color"))
+        local calls = 0
+        local p = setmetatable({}, {
+            __index = function(t, k) calls = calls + 1 return k .. "!" end,
+        })
+        print(p.x, p[1], calls)
+        local w = setmetatable({}, { __newindex = function(t, k, v) rawset(t, k, v * 2) end })
+        w.a = 1
+        w.a = 5
+        print(w.a)
+        local store = {}
+        local proxy = setmetatable({}, { __newindex = store, __index = store })
+        proxy.k = "v"
+        print(rawget(proxy, "k"), store.k, proxy.k)
+        local loop = {}
+        setmetatable(loop, { __index = loop })
+        print(pcall(function() return loop.missing end))
+        print(getmetatable("x"), getmetatable(t).__index == defaults)
+        local locked = setmetatable({}, { __metatable = "locked" })
+        print(getmetatable(locked), pcall(setmetatable, locked, {}))
+        print(pcall(setmetatable, {}, 1))
+    "#;
+    assert_eq!(
+        run(source).unwrap(),
+        "red\tnil\nx!\t1!\t2\n5\nnil\tv\tv\n\
+         false\t20:39: '__index' chain too long; possibly a loop\n\
+         nil\ttrue\nlocked\tfalse\tcannot change a protected metatable\n\
+         false\tbad argument #2 to 'setmetatable' (nil or table expected, got number)\n"
+    );
+}
+
+#[test]
+fn run_metamethod_operators() {
+    let source = r#"
+        local V = {}
+        V.__index = V
+        local function vec2(x, y) return setmetatable({ x = x, y = y }, V) end
+        V.__add = function(a, b) return vec2(a.x + b.x, a.y + b.y) end
+        V.__unm = function(a) return vec2(-a.x, -a.y) end
+        V.__mul = function(a, b)
+            if type(b) == "number" then return vec2(a.x * b, a.y * b) end
+            return vec2(a * b.x, a * b.y)
+        end
+        V.__eq = function(a, b) return a.x == b.x and a.y == b.y end
+        V.__lt = function(a, b) return a.x < b.x end
+        V.__le = function(a, b) return a.x <= b.x end
+        V.__len = function() return 2 end
+        V.__concat = function(a, b)
+            if type(a) == "table" then a = "(" .. a.x .. "," .. a.y .. ")" end
+            if type(b) == "table" then b = "(" .. b.x .. "," .. b.y .. ")" end
+            return a .. b
+        end
+        V.__tostring = function(a) return "vec" .. a end
+        V.__call = function(self, k) return self[k] end
+        V.__band = function() return "band" end
+        V.__shl = function() return "shl" end
+        V.__bnot = function() return "bnot" end
+        V.__idiv = function() return "idiv" end
+        V.__mod = function() return "mod" end
+        V.__pow = function() return "pow" end
+        V.__div = function() return "div" end
+        V.__sub = function() return "sub" end
+        local a, b = vec2(1, 2), vec2(3, 4)
+        print(a + b, -a, #a)
+        print(a == vec2(1, 2), a ~= b, a == b, a < b, a <= b, a > b, b >= a)
+        print(a .. "!", "<" .. b, a .. b)
+        print(a("y"), a * 2, 3 * a)
+        print(a & 1, 1 << a, ~a, a // 1, a % 2, a ^ 2, a / 2, a - 1)
+        print(pcall(function() return {} + 1 end))
+        print(pcall(function() return {} < {} end))
+        print(pcall(tostring, setmetatable({}, { __tostring = function() return {} end })))
+        print(setmetatable({}, { __name = "Point" }))
+    "#;
+    assert_eq!(
+        mask_addresses(&run(source).unwrap()),
+        "vec(4,6)\tvec(-1,-2)\t2\n\
+         true\ttrue\tfalse\ttrue\ttrue\tfalse\ttrue\n\
+         (1,2)!\t<(3,4)\t(1,2)(3,4)\n2\tvec(2,4)\tvec(3,6)\n\
+         band\tshl\tbnot\tidiv\tmod\tpow\tdiv\tsub\n\
+         false\t36:39: attempt to perform arithmetic on a table value\n\
+         false\t37:39: attempt to compare table with table\n\
+         false\t'__tostring' must return a string\n\
+         Point: 0x\n"
+    );
+}
+
+#[test]
+fn run_close_variables() {
+    let source = r#"
+        local log = {}
+        local function closer(name)
+            return setmetatable({}, {
+                __close = function(self, err) log[#log + 1] = name .. ":" .. tostring(err) end,
+            })
+        end
+        do
+            local a <close> = closer("a")
+            local b <close>, c <const> = closer("b"), 1
+            local d <close> = nil
+        end
+        for i = 1, 3 do
+            local x <close> = closer("loop" .. i)
+            if i == 2 then break end
+        end
+        local function f()
+            local r <close> = closer("return")
+            return "value"
+        end
+        print(f())
+        do
+            local n = 0
+            ::top::
+            local g <close> = closer("goto" .. n)
+            n = n + 1
+            if n < 3 then goto top end
+        end
+        print(pcall(function()
+            local e <close> = closer("error")
+            error("boom")
+        end))
+        print(pcall(function()
+            local z <close> = setmetatable({}, { __close = function() error("in close") end })
+            error("first")
+        end))
+        print(pcall(function() local bad <close> = {} end))
+        for _, entry in ipairs(log) do print(entry) end
+    "#;
+    assert_eq!(
+        run(source).unwrap(),
+        "value\nfalse\t31:13: boom\nfalse\t34:71: in close\n\
+         false\t37:38: variable 'bad' got a non-closable value\n\
+         b:nil\na:nil\nloop1:nil\nloop2:nil\nreturn:nil\n\
+         goto0:nil\ngoto1:nil\ngoto2:nil\nerror:31:13: boom\n"
+    );
+}
+
+#[test]
+fn run_finalizers_and_weak_tables() {
+    let source = r#"
+        local mt = { __gc = function(o) print("gc", o.name) end }
+        local keep = setmetatable({ name = "kept" }, mt)
+        local function drop() setmetatable({ name = "dropped" }, mt) end
+        drop()
+        collectgarbage()
+        print("collected")
+        local weakv = setmetatable({}, { __mode = "v" })
+        local weakk = setmetatable({}, { __mode = "k" })
+        local held = {}
+        local function fill()
+            weakv[1] = {}
+            weakv[2] = held
+            weakk[{}] = 1
+            weakk[held] = 2
+        end
+        fill()
+        collectgarbage()
+        local count = 0
+        for k, v in pairs(weakk) do count = count + 1 end
+        print(weakv[1], weakv[2] == held, count, weakk[held])
+    "#;
+    assert_eq!(
+        run(source).unwrap(),
+        "gc\tdropped\ncollected\nnil\ttrue\t1\t2\ngc\tkept\n"
+    );
+}
+
+#[test]
+fn parse_attributes() {
+    let chunk = parse("local a <const>, b <close> = 1, nil").unwrap();
+    let StmtKind::Local { attribs, .. } = &chunk.stmts[0].kind else {
+        panic!("expected a local declaration");
+    };
+    assert_eq!(attribs, &vec![Some(Attrib::Const), Some(Attrib::Close)]);
+
+    let err = parse("local a <static> = 1").unwrap_err();
+    assert_eq!(
+        err.kind,
+        ParseErrorKind::UnknownAttribute("static".to_string())
+    );
+    let err = parse("local a <close>, b <close> = nil, nil").unwrap_err();
+    assert_eq!(err.kind, ParseErrorKind::MultipleToBeClosed);
+}
+
+#[test]
+fn check_const_assignment() {
+    let errors =
+        check("local a <const> = 1\na = 2\nlocal b <close> = nil\nb = nil\nlocal c = 1\nc = 3");
+    assert_eq!(errors.len(), 2);
+    assert_eq!(
+        errors[0].to_string(),
+        "2:1: attempt to assign to const variable 'a'"
+    );
+    assert_eq!(errors[1].span.start.line, 4);
+}
+
+#[test]
+fn run_metatable_names_in_errors() {
+    let source = r#"
+        local t = setmetatable({}, { __name = "MyType" })
+        print(pcall(function() return t + 1 end))
+        print(pcall(function() return t < 1 end))
+        print(pcall(function() return t .. "x" end))
+        print(pcall(function() return t & 1 end))
+        print(pcall(function() return t() end))
+        print(pcall(function() return setmetatable({}, { __name = 1 }) + 1 end))
+    "#;
+    assert_eq!(
+        run(source).unwrap(),
+        "false\t3:39: attempt to perform arithmetic on a MyType value\n\
+         false\t4:39: attempt to compare MyType with number\n\
+         false\t5:39: attempt to concatenate a MyType value\n\
+         false\t6:39: attempt to perform bitwise operation on a MyType value\n\
+         false\t7:39: attempt to call a MyType value\n\
+         false\t8:39: attempt to perform arithmetic on a table value\n"
+    );
+}
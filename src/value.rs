@@ -0,0 +1,1612 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::rc::Rc;
+
+use crate::ast::{BinOp, TypeName, UnOp};
+use crate::gc::{self, Trace};
+use crate::interp::Closure;
+use crate::span::Span;
+use crate::stdlib::{vec_method, Host};
+use crate::vm::LuaClosure;
+
+pub type TableRef = Rc<RefCell<Table>>;
+pub type VecRef = Rc<RefCell<VecValue>>;
+
+pub type NativeFn = fn(&mut dyn Host, Vec<Value>) -> Result<Vec<Value>, RuntimeError>;
+
+/// A builtin implemented in Rust.
+pub struct Native {
+    pub name: &'static str,
+    pub func: NativeFn,
+}
+
+/// Anything a `Value::Function` can hold.
+pub enum Callable {
+    Native(Native),
+    /// Function of the tree-walking interpreter
+    Closure(Closure),
+    /// Function compiled to bytecode for the VM
+    Bytecode(LuaClosure),
+}
+
+/// A runtime value. Strings are byte strings like in Lua, heap values are
+/// shared and compared by identity.
+#[derive(Clone, Default)]
+pub enum Value {
+    #[default]
+    Nil,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    Str(Rc<[u8]>),
+    Table(TableRef),
+    Vec(VecRef),
+    Function(Rc<Callable>),
+}
+
+impl Value {
+    pub fn str(s: &str) -> Value {
+        Value::bytes(s.as_bytes())
+    }
+
+    pub fn bytes(bytes: impl Into<Rc<[u8]>>) -> Value {
+        Value::Str(gc::track_str(bytes.into()))
+    }
+
+    pub fn new_table() -> Value {
+        Value::Table(new_table_ref(Table::default()))
+    }
+
+    pub fn new_vec(items: Vec<Value>) -> Value {
+        Value::Vec(gc::track(Rc::new(RefCell::new(VecValue {
+            items,
+            elem: None,
+        }))))
+    }
+
+    pub fn native(name: &'static str, func: NativeFn) -> Value {
+        Value::Function(Rc::new(Callable::Native(Native { name, func })))
+    }
+
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Nil => "nil",
+            Value::Bool(_) => "boolean",
+            Value::Int(_) | Value::Float(_) => "number",
+            Value::Str(_) => "string",
+            Value::Table(_) => "table",
+            Value::Vec(_) => "vec",
+            Value::Function(_) => "function",
+        }
+    }
+
+    pub fn is_truthy(&self) -> bool {
+        !matches!(self, Value::Nil | Value::Bool(false))
+    }
+
+    /// Numeric view of the value, converting numeric strings as Lua does
+    /// for arithmetic.
+    pub fn to_number(&self) -> Option<Value> {
+        match self {
+            Value::Int(_) | Value::Float(_) => Some(self.clone()),
+            Value::Str(s) => str_to_number(s),
+            _ => None,
+        }
+    }
+
+    pub fn to_float(&self) -> Option<f64> {
+        match self.to_number()? {
+            Value::Int(i) => Some(i as f64),
+            Value::Float(f) => Some(f),
+            _ => None,
+        }
+    }
+
+    /// Integer view, accepting floats with an exact integer value.
+    pub fn to_int(&self) -> Option<i64> {
+        match self.to_number()? {
+            Value::Int(i) => Some(i),
+            Value::Float(f) => float_to_int(f),
+            _ => None,
+        }
+    }
+
+    /// `tostring` without metamethods.
+    pub fn to_display(&self) -> Vec<u8> {
+        match self {
+            Value::Nil => b"nil".to_vec(),
+            Value::Bool(b) => b.to_string().into_bytes(),
+            Value::Int(i) => i.to_string().into_bytes(),
+            Value::Float(f) => fmt_float(*f).into_bytes(),
+            Value::Str(s) => s.to_vec(),
+            Value::Table(t) => format!("table: {:p}", Rc::as_ptr(t)).into_bytes(),
+            Value::Vec(v) => format!("vec: {:p}", Rc::as_ptr(v)).into_bytes(),
+            Value::Function(f) => match &**f {
+                Callable::Native(n) => format!("builtin: {}", n.name).into_bytes(),
+                Callable::Closure(_) | Callable::Bytecode(_) => {
+                    format!("function: {:p}", Rc::as_ptr(f)).into_bytes()
+                }
+            },
+        }
+    }
+
+    /// Reports the heap object the value refers to to the collector.
+    pub fn trace(&self, visit: &mut dyn FnMut(usize)) {
+        match self {
+            Value::Table(t) => visit(gc::address(t)),
+            Value::Vec(v) => visit(gc::address(v)),
+            Value::Function(f) => visit(gc::address(f)),
+            _ => {}
+        }
+    }
+
+    /// Identity of the heap object the value refers to, if tracked.
+    pub fn address(&self) -> Option<usize> {
+        let mut address = None;
+        self.trace(&mut |a| address = Some(a));
+        address
+    }
+
+    /// Tells the collector the value was stored into a heap object.
+    pub fn write_barrier(&self) {
+        self.trace(&mut gc::barrier);
+    }
+
+    /// Primitive equality: numbers by value, strings by contents, everything
+    /// else by identity.
+    pub fn raw_equals(&self, other: &Value) -> bool {
+        match (self, other) {
+            (Value::Nil, Value::Nil) => true,
+            (Value::Bool(a), Value::Bool(b)) => a == b,
+            (Value::Int(a), Value::Int(b)) => a == b,
+            (Value::Float(a), Value::Float(b)) => a == b,
+            (Value::Int(i), Value::Float(f)) | (Value::Float(f), Value::Int(i)) => {
+                float_to_int(*f) == Some(*i)
+            }
+            (Value::Str(a), Value::Str(b)) => a == b,
+            (Value::Table(a), Value::Table(b)) => Rc::ptr_eq(a, b),
+            (Value::Vec(a), Value::Vec(b)) => Rc::ptr_eq(a, b),
+            (Value::Function(a), Value::Function(b)) => Rc::ptr_eq(a, b),
+            _ => false,
+        }
+    }
+}
+
+/// Puts a table on the collected heap.
+pub fn new_table_ref(table: Table) -> TableRef {
+    gc::track(Rc::new(RefCell::new(table)))
+}
+
+impl Trace for Callable {
+    fn trace(&self, visit: &mut dyn FnMut(usize)) -> bool {
+        match self {
+            Callable::Native(_) => {}
+            Callable::Closure(closure) => visit(gc::address(&closure.scope)),
+            Callable::Bytecode(closure) => {
+                for upvalue in &closure.upvalues {
+                    visit(gc::address(upvalue));
+                }
+            }
+        }
+        true
+    }
+
+    fn clear(&self) -> Box<dyn std::any::Any> {
+        // Closures are immutable, the cycles through them are broken at the
+        // scopes and upvalues they capture
+        Box::new(())
+    }
+
+    fn size(&self) -> usize {
+        std::mem::size_of::<Callable>()
+            + match self {
+                Callable::Bytecode(closure) => {
+                    closure.upvalues.len() * std::mem::size_of::<crate::vm::UpvalRef>()
+                }
+                _ => 0,
+            }
+    }
+}
+
+impl fmt::Debug for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Str(s) => write!(f, "{:?}", String::from_utf8_lossy(s)),
+            _ => write!(f, "{}", String::from_utf8_lossy(&self.to_display())),
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", String::from_utf8_lossy(&self.to_display()))
+    }
+}
+
+pub fn float_to_int(f: f64) -> Option<i64> {
+    // `i64::MAX as f64` rounds up to 2^63, which is out of range
+    if f.fract() == 0.0 && f >= -(2f64.powi(63)) && f < 2f64.powi(63) {
+        Some(f as i64)
+    } else {
+        None
+    }
+}
+
+/// Formats a float like Lua's `%.14g`, keeping a `.0` on integral values so
+/// floats stay distinguishable from integers.
+pub fn fmt_float(f: f64) -> String {
+    if f.is_nan() {
+        return if f.is_sign_negative() { "-nan" } else { "nan" }.to_string();
+    }
+    if f.is_infinite() {
+        return if f < 0.0 { "-inf" } else { "inf" }.to_string();
+    }
+    let sci = format!("{:.13e}", f);
+    let (mantissa, exp) = sci.split_once('e').unwrap();
+    let exp: i32 = exp.parse().unwrap();
+    let out = if !(-4..14).contains(&exp) {
+        let mantissa = trim_fraction(mantissa);
+        format!(
+            "{}e{}{:02}",
+            mantissa,
+            if exp < 0 { '-' } else { '+' },
+            exp.abs()
+        )
+    } else {
+        let precision = (13 - exp).max(0) as usize;
+        trim_fraction(&format!("{:.*}", precision, f)).to_string()
+    };
+    if out.bytes().all(|b| b.is_ascii_digit() || b == b'-') {
+        format!("{}.0", out)
+    } else {
+        out
+    }
+}
+
+fn trim_fraction(s: &str) -> &str {
+    if s.contains('.') {
+        s.trim_end_matches('0').trim_end_matches('.')
+    } else {
+        s
+    }
+}
+
+/// Converts a string the way `tonumber` and arithmetic coercion do:
+/// surrounding whitespace is ignored, integers stay integers.
+pub fn str_to_number(s: &[u8]) -> Option<Value> {
+    let s = std::str::from_utf8(s).ok()?.trim();
+    let (negative, digits) = match s.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, s.strip_prefix('+').unwrap_or(s)),
+    };
+    if let Some(hex) = digits
+        .strip_prefix("0x")
+        .or_else(|| digits.strip_prefix("0X"))
+    {
+        if hex.is_empty() || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return None;
+        }
+        // Hex integers wrap around on overflow
+        let n = hex.bytes().fold(0i64, |acc, b| {
+            acc.wrapping_mul(16)
+                .wrapping_add((b as char).to_digit(16).unwrap() as i64)
+        });
+        return Some(Value::Int(if negative { n.wrapping_neg() } else { n }));
+    }
+    if digits.is_empty()
+        || !digits
+            .bytes()
+            .all(|b| b.is_ascii_digit() || matches!(b, b'.' | b'e' | b'E' | b'+' | b'-'))
+    {
+        return None;
+    }
+    if let Ok(i) = s.parse::<i64>() {
+        return Some(Value::Int(i));
+    }
+    s.parse::<f64>().ok().map(Value::Float)
+}
+
+/// Error raised while running a program. The payload is an arbitrary value
+/// so `error` can throw tables as well as messages.
+#[derive(Debug, Clone)]
+pub struct RuntimeError {
+    pub value: Value,
+    pub span: Option<Span>,
+}
+
+impl RuntimeError {
+    pub fn new(message: impl Into<String>) -> Self {
+        RuntimeError {
+            value: Value::str(&message.into()),
+            span: None,
+        }
+    }
+
+    /// Attaches a location unless a more precise one was already recorded.
+    pub fn at(mut self, span: Span) -> Self {
+        self.span.get_or_insert(span);
+        self
+    }
+
+    /// The error as seen by `pcall`, messages prefixed with their location.
+    pub fn into_value(self) -> Value {
+        match (&self.value, self.span) {
+            (Value::Str(_), Some(_)) => Value::str(&self.to_string()),
+            _ => self.value,
+        }
+    }
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.span {
+            Some(span) => write!(f, "{}: {}", span, self.value),
+            None => write!(f, "{}", self.value),
+        }
+    }
+}
+
+impl std::error::Error for RuntimeError {}
+
+/// Hashable identity of a value used as a table key. Floats with an integral
+/// value are normalized so `t[1]` and `t[1.0]` are the same slot.
+#[derive(Clone)]
+pub struct TableKey(Value);
+
+impl TableKey {
+    pub fn new(key: Value) -> Result<TableKey, RuntimeError> {
+        match key {
+            Value::Nil => Err(RuntimeError::new("table index is nil")),
+            Value::Float(f) if f.is_nan() => Err(RuntimeError::new("table index is NaN")),
+            Value::Float(f) => Ok(TableKey(float_to_int(f).map_or(key, Value::Int))),
+            _ => Ok(TableKey(key)),
+        }
+    }
+
+    pub fn value(&self) -> &Value {
+        &self.0
+    }
+}
+
+impl PartialEq for TableKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.raw_equals(&other.0)
+    }
+}
+
+impl Eq for TableKey {}
+
+impl Hash for TableKey {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        match &self.0 {
+            Value::Nil => 0.hash(state),
+            Value::Bool(b) => b.hash(state),
+            Value::Int(i) => i.hash(state),
+            Value::Float(f) => f.to_bits().hash(state),
+            Value::Str(s) => s.hash(state),
+            Value::Table(t) => Rc::as_ptr(t).hash(state),
+            Value::Vec(v) => Rc::as_ptr(v).hash(state),
+            Value::Function(f) => Rc::as_ptr(f).hash(state),
+        }
+    }
+}
+
+/// A Lua table. The values of the keys `1..=n` for some `n` live in a
+/// dense array part, every other key in a hash part keeping insertion
+/// order, and `next` walks the array part then the hash part.
+///
+/// Assigning to an existing key, even `nil`, never moves anything, so a
+/// traversal may change or clear fields as it goes. Only adding a key to a
+/// full hash part rehashes: as in Lua, the array part is resized to the
+/// largest power of two that is more than half used and the other keys
+/// move to the hash part, dropping the cleared entries.
+#[derive(Default)]
+pub struct Table {
+    array: Vec<Value>,
+    /// Entries in insertion order, cleared ones left as `nil` until the
+    /// next rehash
+    hash: Vec<(TableKey, Value)>,
+    index: HashMap<TableKey, usize>,
+    /// Entries the hash part takes before adding one rehashes
+    hash_size: usize,
+    meta: Option<TableRef>,
+    /// Types of the fields declared as `T name = value` in the constructor
+    schema: Vec<(Rc<[u8]>, TypeName)>,
+}
+
+impl Table {
+    pub fn metatable(&self) -> Option<&TableRef> {
+        self.meta.as_ref()
+    }
+
+    /// Field `event` of the metatable, nil without one.
+    pub fn metamethod(&self, event: Event) -> Value {
+        match &self.meta {
+            Some(meta) => meta.borrow().get(&event.key()),
+            None => Value::Nil,
+        }
+    }
+
+    /// Declares field `name` as a `ty`: every later store to it converts
+    /// the value, as stores into a typed vec do.
+    pub fn type_field(&mut self, name: Rc<[u8]>, ty: TypeName) {
+        self.schema.retain(|(field, _)| *field != name);
+        self.schema.push((name, ty));
+    }
+
+    /// Converts `value` to the declared type of field `key`, if it has one.
+    /// Done before borrowing the table mutably, like `conform_element`.
+    pub fn conform_field(&self, key: &Value, value: Value) -> Result<Value, RuntimeError> {
+        let Value::Str(key) = key else {
+            return Ok(value);
+        };
+        match self.schema.iter().find(|(field, _)| field == key) {
+            Some((_, ty)) => conform(ty, value),
+            None => Ok(value),
+        }
+    }
+
+    /// Whether keys and values are weak, from the `__mode` of the metatable.
+    fn weakness(&self) -> (bool, bool) {
+        let Some(Ok(meta)) = self.meta.as_ref().map(|m| m.try_borrow()) else {
+            return (false, false);
+        };
+        match meta.get(&Event::Mode.key()) {
+            Value::Str(mode) => (mode.contains(&b'k'), mode.contains(&b'v')),
+            _ => (false, false),
+        }
+    }
+
+    /// Position in the array part of `key`, normalized by `TableKey`.
+    fn array_slot(&self, key: &Value) -> Option<usize> {
+        match key {
+            Value::Int(i) if *i >= 1 && *i <= self.array.len() as i64 => Some(*i as usize - 1),
+            _ => None,
+        }
+    }
+
+    pub fn get(&self, key: &Value) -> Value {
+        match key {
+            Value::Int(i) => return self.get_int(*i),
+            Value::Float(f) => {
+                if let Some(i) = float_to_int(*f) {
+                    return self.get_int(i);
+                }
+            }
+            _ => {}
+        }
+        let Ok(key) = TableKey::new(key.clone()) else {
+            return Value::Nil;
+        };
+        self.get_hash(&key)
+    }
+
+    fn get_int(&self, i: i64) -> Value {
+        match self.array_slot(&Value::Int(i)) {
+            Some(slot) => self.array[slot].clone(),
+            None => self.get_hash(&TableKey(Value::Int(i))),
+        }
+    }
+
+    fn get_hash(&self, key: &TableKey) -> Value {
+        match self.index.get(key) {
+            Some(&i) => self.hash[i].1.clone(),
+            None => Value::Nil,
+        }
+    }
+
+    pub fn set(&mut self, key: Value, value: Value) -> Result<(), RuntimeError> {
+        let key = TableKey::new(key)?;
+        key.value().write_barrier();
+        value.write_barrier();
+        if let Some(slot) = self.array_slot(key.value()) {
+            self.array[slot] = value;
+            return Ok(());
+        }
+        if let Some(&i) = self.index.get(&key) {
+            self.hash[i].1 = value;
+            return Ok(());
+        }
+        if matches!(value, Value::Nil) {
+            return Ok(());
+        }
+        if self.hash.len() == self.hash_size {
+            self.rehash(&key);
+            if let Some(slot) = self.array_slot(key.value()) {
+                self.array[slot] = value;
+                return Ok(());
+            }
+        }
+        self.index.insert(key.clone(), self.hash.len());
+        self.hash.push((key, value));
+        Ok(())
+    }
+
+    /// Resizes both parts to fit the entries plus `extra`, the key about to
+    /// be added.
+    fn rehash(&mut self, extra: &TableKey) {
+        // nums[b] counts the positive integer keys in (2^(b-1), 2^b]
+        let mut nums = [0usize; 64];
+        let mut count = |key: &Value| {
+            if let Value::Int(k) = key {
+                if *k >= 1 {
+                    let bin = 64 - (*k as u64 - 1).leading_zeros() as usize;
+                    nums[bin] += 1;
+                }
+            }
+        };
+        let mut total = 1;
+        count(extra.value());
+        for (i, value) in self.array.iter().enumerate() {
+            if !matches!(value, Value::Nil) {
+                count(&Value::Int(i as i64 + 1));
+                total += 1;
+            }
+        }
+        for (key, value) in &self.hash {
+            if !matches!(value, Value::Nil) {
+                count(key.value());
+                total += 1;
+            }
+        }
+        let (array_size, in_array) = array_size(&nums);
+        self.resize(array_size, total - in_array);
+    }
+
+    fn resize(&mut self, array_size: usize, hash_entries: usize) {
+        let hash_size = hash_entries.next_power_of_two();
+        gc::grow(
+            array_size.saturating_sub(self.array.len()) * std::mem::size_of::<Value>()
+                + hash_size.saturating_sub(self.hash_size)
+                    * std::mem::size_of::<(TableKey, Value, usize)>(),
+        );
+        let old_array = std::mem::take(&mut self.array);
+        let old_hash = std::mem::take(&mut self.hash);
+        self.index.clear();
+        self.hash_size = hash_size;
+        self.array = vec![Value::Nil; array_size];
+        let moved = old_array
+            .into_iter()
+            .enumerate()
+            .map(|(i, value)| (TableKey(Value::Int(i as i64 + 1)), value));
+        for (key, value) in old_hash.into_iter().chain(moved) {
+            if matches!(value, Value::Nil) {
+                continue;
+            }
+            match self.array_slot(key.value()) {
+                Some(slot) => self.array[slot] = value,
+                None => {
+                    self.index.insert(key.clone(), self.hash.len());
+                    self.hash.push((key, value));
+                }
+            }
+        }
+        self.index.shrink_to_fit();
+    }
+
+    /// A border of the table, as Lua's `#`: an `n` with `t[n] ~= nil` and
+    /// `t[n + 1] == nil`, or 0 when `t[1]` is nil. When the table has holes
+    /// any of its borders may be returned.
+    pub fn len(&self) -> i64 {
+        let n = self.array.len();
+        if n > 0 && matches!(self.array[n - 1], Value::Nil) {
+            // Binary search for a border inside the array part
+            let (mut lo, mut hi) = (0, n);
+            while hi - lo > 1 {
+                let mid = (lo + hi) / 2;
+                if matches!(self.array[mid - 1], Value::Nil) {
+                    hi = mid;
+                } else {
+                    lo = mid;
+                }
+            }
+            return lo as i64;
+        }
+        let mut i = n as i64;
+        if self.hash.is_empty() || matches!(self.get_int(i + 1), Value::Nil) {
+            return i;
+        }
+        // Unbound search for a nil in the hash part
+        let mut j = i + 1;
+        while !matches!(self.get_int(j), Value::Nil) {
+            i = j;
+            if j > i64::MAX / 2 {
+                // Pathological table, fall back to a linear search
+                let mut k = 1;
+                while !matches!(self.get_int(k), Value::Nil) {
+                    k += 1;
+                }
+                return k - 1;
+            }
+            j *= 2;
+        }
+        while j - i > 1 {
+            let mid = i + (j - i) / 2;
+            if matches!(self.get_int(mid), Value::Nil) {
+                j = mid;
+            } else {
+                i = mid;
+            }
+        }
+        i
+    }
+
+    /// Entry following `key` in iteration order, `nil` key starting over.
+    pub fn next(&self, key: &Value) -> Result<Option<(Value, Value)>, RuntimeError> {
+        let start = match key {
+            Value::Nil => 0,
+            _ => {
+                let key = TableKey::new(key.clone())?;
+                match (self.array_slot(key.value()), self.index.get(&key)) {
+                    (Some(slot), _) => slot + 1,
+                    (None, Some(&i)) => self.array.len() + i + 1,
+                    (None, None) => return Err(RuntimeError::new("invalid key to 'next'")),
+                }
+            }
+        };
+        let array = self.array.iter().enumerate().skip(start);
+        if let Some((i, value)) = array.into_iter().find(|(_, v)| !matches!(v, Value::Nil)) {
+            return Ok(Some((Value::Int(i as i64 + 1), value.clone())));
+        }
+        Ok(self.hash[start.saturating_sub(self.array.len())..]
+            .iter()
+            .find(|(_, v)| !matches!(v, Value::Nil))
+            .map(|(k, v)| (k.value().clone(), v.clone())))
+    }
+}
+
+/// Size of the array part for the positive integer keys counted in `nums`:
+/// the largest power of two `n` with more than `n / 2` of the keys `1..=n`
+/// present, and how many keys that puts in the array part.
+fn array_size(nums: &[usize; 64]) -> (usize, usize) {
+    let total: usize = nums.iter().sum();
+    let (mut size, mut in_array) = (0, 0);
+    let mut below = 0;
+    let mut power = 1usize;
+    for &n in nums.iter() {
+        if total <= power / 2 {
+            break;
+        }
+        below += n;
+        if below > power / 2 {
+            size = power;
+            in_array = below;
+        }
+        power *= 2;
+    }
+    (size, in_array)
+}
+
+impl Trace for RefCell<Table> {
+    fn trace(&self, visit: &mut dyn FnMut(usize)) -> bool {
+        let Ok(table) = self.try_borrow() else {
+            return false;
+        };
+        let (weak_keys, weak_values) = table.weakness();
+        if let Some(meta) = &table.meta {
+            visit(gc::address(meta));
+        }
+        if !weak_values {
+            for value in &table.array {
+                value.trace(visit);
+            }
+        }
+        for (key, value) in &table.hash {
+            // Keys are held twice, by `hash` and by `index`
+            if !weak_keys {
+                key.value().trace(visit);
+                key.value().trace(visit);
+            }
+            // With weak keys the value only lives as long as its key
+            let weak_value = weak_values || (weak_keys && key.value().address().is_some());
+            if !weak_value {
+                value.trace(visit);
+            }
+        }
+        true
+    }
+
+    fn clear(&self) -> Box<dyn std::any::Any> {
+        Box::new(std::mem::take(&mut *self.borrow_mut()))
+    }
+
+    fn size(&self) -> usize {
+        let parts = self.try_borrow().map_or(0, |t| {
+            t.array.capacity() * std::mem::size_of::<Value>()
+                + t.hash.capacity() * std::mem::size_of::<(TableKey, Value)>()
+                + t.index.capacity() * std::mem::size_of::<(TableKey, usize)>()
+        });
+        std::mem::size_of::<Self>() + parts
+    }
+
+    fn trace_weak(&self, visit: &mut dyn FnMut(usize)) {
+        let Ok(table) = self.try_borrow() else {
+            return;
+        };
+        let (weak_keys, weak_values) = table.weakness();
+        if weak_values {
+            for value in &table.array {
+                value.trace(visit);
+            }
+        }
+        for (key, value) in &table.hash {
+            if weak_keys {
+                key.value().trace(visit);
+                key.value().trace(visit);
+            }
+            if weak_values || (weak_keys && key.value().address().is_some()) {
+                value.trace(visit);
+            }
+        }
+    }
+
+    fn trace_ephemerons(&self, visit: &mut dyn FnMut(usize, usize)) {
+        let Ok(table) = self.try_borrow() else {
+            return;
+        };
+        if table.weakness() != (true, false) {
+            return;
+        }
+        for (key, value) in &table.hash {
+            if let (Some(key), Some(value)) = (key.value().address(), value.address()) {
+                visit(key, value);
+            }
+        }
+    }
+
+    fn clear_weak(&self, dead: &dyn Fn(usize) -> bool) -> Box<dyn std::any::Any> {
+        let Ok(mut table) = self.try_borrow_mut() else {
+            return Box::new(());
+        };
+        let (weak_keys, weak_values) = table.weakness();
+        let is_dead = |value: &Value| value.address().is_some_and(dead);
+        let mut removed = Vec::new();
+        if weak_values {
+            for value in table.array.iter_mut().filter(|v| is_dead(v)) {
+                removed.push(std::mem::take(value));
+            }
+        }
+        // Cleared entries stay as `nil` so a traversal can go on
+        for (key, value) in &mut table.hash {
+            if (weak_keys && is_dead(key.value())) || (weak_values && is_dead(value)) {
+                removed.push(std::mem::take(value));
+            }
+        }
+        Box::new(removed)
+    }
+}
+
+/// Dense array behind a `vec`, indexed from 1 like Lua sequences. Once a
+/// vec is stored in a `vec<T>` variable its elements are typed, and every
+/// later store into it is converted to `T`.
+#[derive(Default)]
+pub struct VecValue {
+    pub items: Vec<Value>,
+    pub elem: Option<TypeName>,
+}
+
+impl VecValue {
+    /// Position in `items` of the 1-based index `key`.
+    fn slot(&self, key: &Value) -> Result<usize, RuntimeError> {
+        let i = match key {
+            Value::Int(i) => Some(*i),
+            Value::Float(f) => float_to_int(*f),
+            _ => None,
+        };
+        let Some(i) = i else {
+            return Err(RuntimeError::new(format!(
+                "vec index must be an integer, got {}",
+                key.type_name()
+            )));
+        };
+        if i < 1 || i > self.items.len() as i64 {
+            return Err(RuntimeError::new(format!(
+                "index {} out of bounds for vec of length {}",
+                i,
+                self.items.len()
+            )));
+        }
+        Ok(i as usize - 1)
+    }
+
+    pub fn get(&self, key: &Value) -> Result<Value, RuntimeError> {
+        Ok(self.items[self.slot(key)?].clone())
+    }
+
+    /// Replaces an existing element, `value` already converted to the
+    /// element type.
+    pub fn set(&mut self, key: &Value, value: Value) -> Result<(), RuntimeError> {
+        let i = self.slot(key)?;
+        value.write_barrier();
+        self.items[i] = value;
+        Ok(())
+    }
+}
+
+impl Trace for RefCell<VecValue> {
+    fn trace(&self, visit: &mut dyn FnMut(usize)) -> bool {
+        let Ok(v) = self.try_borrow() else {
+            return false;
+        };
+        for item in &v.items {
+            item.trace(visit);
+        }
+        true
+    }
+
+    fn clear(&self) -> Box<dyn std::any::Any> {
+        Box::new(std::mem::take(&mut self.borrow_mut().items))
+    }
+
+    fn size(&self) -> usize {
+        let items = self
+            .try_borrow()
+            .map_or(0, |v| v.items.capacity() * std::mem::size_of::<Value>());
+        std::mem::size_of::<Self>() + items
+    }
+}
+
+/// Converts `value` to the element type of `v`, if it has one. Done before
+/// borrowing `v` mutably since `value` may be `v` itself.
+pub fn conform_element(v: &VecRef, value: Value) -> Result<Value, RuntimeError> {
+    let elem = v.borrow().elem.clone();
+    match elem {
+        Some(ty) => conform(&ty, value),
+        None => Ok(value),
+    }
+}
+
+/// Gives a vec the element type `elem`, converting the elements of a vec
+/// that was untyped so far.
+fn type_vec(elem: &TypeName, v: &VecRef) -> Result<(), RuntimeError> {
+    let items = match &v.borrow().elem {
+        Some(ty) if ty == elem => return Ok(()),
+        Some(ty) => {
+            return Err(RuntimeError::new(format!(
+                "cannot convert vec<{}> to vec<{}>",
+                ty, elem
+            )))
+        }
+        None => v.borrow().items.clone(),
+    };
+    let items = items
+        .into_iter()
+        .map(|item| conform(elem, item))
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut v = v.borrow_mut();
+    v.items = items;
+    v.elem = Some(elem.clone());
+    Ok(())
+}
+
+/// Lua arithmetic on two operands. Integers stay integers for `+ - * // %`
+/// and wrap around on overflow, `/` and `^` always produce floats. Returns
+/// `None` when an operand is not a number, leaving the error to the caller.
+pub fn arith(op: BinOp, a: &Value, b: &Value) -> Option<Result<Value, RuntimeError>> {
+    let (a, b) = (a.to_number()?, b.to_number()?);
+    if let (Value::Int(x), Value::Int(y)) = (&a, &b) {
+        let (x, y) = (*x, *y);
+        let v = match op {
+            BinOp::Add => x.wrapping_add(y),
+            BinOp::Sub => x.wrapping_sub(y),
+            BinOp::Mul => x.wrapping_mul(y),
+            BinOp::FloorDiv => {
+                if y == 0 {
+                    return Some(Err(RuntimeError::new("attempt to perform 'n//0'")));
+                }
+                let q = x.wrapping_div(y);
+                if x.wrapping_rem(y) != 0 && (x ^ y) < 0 {
+                    q - 1
+                } else {
+                    q
+                }
+            }
+            BinOp::Mod => {
+                if y == 0 {
+                    return Some(Err(RuntimeError::new("attempt to perform 'n%0'")));
+                }
+                let r = x.wrapping_rem(y);
+                if r != 0 && (r ^ y) < 0 {
+                    r + y
+                } else {
+                    r
+                }
+            }
+            _ => return Some(Ok(float_arith(op, x as f64, y as f64))),
+        };
+        return Some(Ok(Value::Int(v)));
+    }
+    let x = a.to_float()?;
+    let y = b.to_float()?;
+    Some(Ok(float_arith(op, x, y)))
+}
+
+fn float_arith(op: BinOp, x: f64, y: f64) -> Value {
+    Value::Float(match op {
+        BinOp::Add => x + y,
+        BinOp::Sub => x - y,
+        BinOp::Mul => x * y,
+        BinOp::Div => x / y,
+        BinOp::FloorDiv => (x / y).floor(),
+        BinOp::Pow => x.powf(y),
+        BinOp::Mod => {
+            let r = x % y;
+            if r != 0.0 && (r < 0.0) != (y < 0.0) {
+                r + y
+            } else {
+                r
+            }
+        }
+        _ => unreachable!("not an arithmetic operator: {:?}", op),
+    })
+}
+
+/// `<` and `<=` on numbers and strings, `None` for other operand types.
+pub fn compare(op: BinOp, a: &Value, b: &Value) -> Option<bool> {
+    let ordering = match (a, b) {
+        (Value::Int(x), Value::Int(y)) => x.partial_cmp(y),
+        (Value::Str(x), Value::Str(y)) => x.partial_cmp(y),
+        (Value::Int(_) | Value::Float(_), Value::Int(_) | Value::Float(_)) => {
+            // Exact comparison, an i64 does not always fit in an f64
+            match (a, b) {
+                (Value::Int(i), Value::Float(f)) => cmp_int_float(*i, *f),
+                (Value::Float(f), Value::Int(i)) => cmp_int_float(*i, *f).map(|o| o.reverse()),
+                (Value::Float(x), Value::Float(y)) => x.partial_cmp(y),
+                _ => unreachable!(),
+            }
+        }
+        _ => return None,
+    };
+    Some(match op {
+        BinOp::Lt => ordering == Some(std::cmp::Ordering::Less),
+        BinOp::Le => matches!(
+            ordering,
+            Some(std::cmp::Ordering::Less | std::cmp::Ordering::Equal)
+        ),
+        _ => unreachable!("not an ordering operator: {:?}", op),
+    })
+}
+
+fn cmp_int_float(i: i64, f: f64) -> Option<std::cmp::Ordering> {
+    use std::cmp::Ordering;
+    if f.is_nan() {
+        return None;
+    }
+    let Some(floor) = float_to_int(f.floor()) else {
+        return Some(if f > 0.0 {
+            Ordering::Less
+        } else {
+            Ordering::Greater
+        });
+    };
+    match i.cmp(&floor) {
+        Ordering::Equal if f.fract() != 0.0 => Some(Ordering::Less),
+        ord => Some(ord),
+    }
+}
+
+/// Converts a value to fit a declared type, widening integers to floats and
+/// turning floats with an integral value into integers. Numbers are never
+/// nil, so code working on a typed number can rely on its representation.
+pub fn conform(ty: &TypeName, value: Value) -> Result<Value, RuntimeError> {
+    let ok = match (ty, &value) {
+        // `int` is 32 bits and wraps around like Lua's 64-bit integers
+        (TypeName::Int, Value::Int(i)) => return Ok(Value::Int(*i as i32 as i64)),
+        (TypeName::Long, Value::Int(_)) => true,
+        (TypeName::Int | TypeName::Long, Value::Float(f)) => {
+            return match float_to_int(*f) {
+                Some(i) => conform(ty, Value::Int(i)),
+                None => Err(no_integer_representation(*f)),
+            }
+        }
+        // `float` is single precision, stored rounded
+        (TypeName::Float, Value::Int(i)) => return Ok(Value::Float(*i as f32 as f64)),
+        (TypeName::Float, Value::Float(f)) => return Ok(Value::Float(*f as f32 as f64)),
+        (TypeName::Double, Value::Int(i)) => return Ok(Value::Float(*i as f64)),
+        (TypeName::Double, Value::Float(_)) => true,
+        (TypeName::Int | TypeName::Long | TypeName::Float | TypeName::Double, _) => false,
+        (_, Value::Nil) => true,
+        (TypeName::String, Value::Str(_)) => true,
+        (TypeName::Char, Value::Str(s)) => is_char(s),
+        (TypeName::Bool, Value::Bool(_)) => true,
+        (TypeName::Table, Value::Table(_)) => true,
+        (TypeName::Vec(None), Value::Vec(_)) => true,
+        (TypeName::Vec(Some(elem)), Value::Vec(v)) => return type_vec(elem, v).map(|()| value),
+        _ => false,
+    };
+    if ok {
+        Ok(value)
+    } else {
+        Err(RuntimeError::new(format!(
+            "cannot convert {} to {}",
+            value.type_name(),
+            ty
+        )))
+    }
+}
+
+/// Converts a value for an explicit cast `(ty)value`. Beyond what `conform`
+/// does, numeric strings parse as numbers, numbers and bools format as
+/// strings, and `int` takes integers in the 32-bit range only. A number casts to
+/// the `char` with that code point, and a char that is not a numeral, like
+/// `'a'` but not `'7'`, casts to its code point.
+pub fn cast(ty: &TypeName, value: Value) -> Result<Value, RuntimeError> {
+    let numeric = matches!(
+        ty,
+        TypeName::Int | TypeName::Long | TypeName::Float | TypeName::Double
+    );
+    match (ty, &value) {
+        (TypeName::Char, Value::Int(_) | Value::Float(_)) => {
+            let c = value
+                .to_int()
+                .and_then(|n| u32::try_from(n).ok())
+                .and_then(char::from_u32);
+            match c {
+                Some(c) => Ok(Value::str(c.encode_utf8(&mut [0; 4]))),
+                None => Err(RuntimeError::new(format!(
+                    "number {} is not a valid char",
+                    String::from_utf8_lossy(&value.to_display())
+                ))),
+            }
+        }
+        (TypeName::Int | TypeName::Long, Value::Float(f)) => match float_to_int(*f) {
+            Some(i) => cast(ty, Value::Int(i)),
+            None => Err(no_integer_representation(*f)),
+        },
+        (TypeName::Int, Value::Int(i)) if i32::try_from(*i).is_err() => Err(RuntimeError::new(
+            format!("number {} is out of range for int", i),
+        )),
+        (_, Value::Str(s)) if numeric => match str_to_number(s) {
+            Some(n) => cast(ty, n),
+            None if is_char(s) => cast(ty, char_code(s)),
+            None => conform(ty, value),
+        },
+        (TypeName::String, Value::Int(_) | Value::Float(_) | Value::Bool(_)) => {
+            Ok(Value::bytes(value.to_display()))
+        }
+        _ => conform(ty, value),
+    }
+}
+
+fn no_integer_representation(f: f64) -> RuntimeError {
+    RuntimeError::new(format!(
+        "number {} has no integer representation",
+        fmt_float(f)
+    ))
+}
+
+/// Code point of a `char`.
+fn char_code(bytes: &[u8]) -> Value {
+    let c = std::str::from_utf8(bytes).unwrap().chars().next().unwrap();
+    Value::Int(c as i64)
+}
+
+/// Whether a byte string holds exactly one UTF-8 encoded character, which
+/// is how a `char` is represented at runtime.
+pub fn is_char(bytes: &[u8]) -> bool {
+    std::str::from_utf8(bytes).is_ok_and(|s| s.chars().count() == 1)
+}
+
+/// Initial value of a declaration without an initializer: zero for numbers,
+/// nil for everything else.
+pub fn default_value(ty: Option<&TypeName>) -> Value {
+    match ty {
+        Some(TypeName::Int | TypeName::Long) => Value::Int(0),
+        Some(TypeName::Float | TypeName::Double) => Value::Float(0.0),
+        _ => Value::Nil,
+    }
+}
+
+/// Metamethod events, the fields of a metatable the engines look at.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    Index,
+    NewIndex,
+    Call,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    Pow,
+    Unm,
+    IDiv,
+    BAnd,
+    BOr,
+    BXor,
+    Shl,
+    Shr,
+    BNot,
+    Concat,
+    Len,
+    Eq,
+    Lt,
+    Le,
+    Close,
+    Gc,
+    Mode,
+    Name,
+    ToString,
+    Metatable,
+}
+
+const EVENT_NAMES: [&str; 28] = [
+    "__index",
+    "__newindex",
+    "__call",
+    "__add",
+    "__sub",
+    "__mul",
+    "__div",
+    "__mod",
+    "__pow",
+    "__unm",
+    "__idiv",
+    "__band",
+    "__bor",
+    "__bxor",
+    "__shl",
+    "__shr",
+    "__bnot",
+    "__concat",
+    "__len",
+    "__eq",
+    "__lt",
+    "__le",
+    "__close",
+    "__gc",
+    "__mode",
+    "__name",
+    "__tostring",
+    "__metatable",
+];
+
+thread_local! {
+    /// Event names as table keys. They are left out of the collector's
+    /// count, which looks them up while it runs.
+    static EVENT_KEYS: Vec<Value> = EVENT_NAMES
+        .iter()
+        .map(|name| Value::Str(Rc::from(name.as_bytes())))
+        .collect();
+}
+
+impl Event {
+    pub fn key(self) -> Value {
+        EVENT_KEYS.with(|keys| keys[self as usize].clone())
+    }
+
+    fn of_binary(op: BinOp) -> Event {
+        match op {
+            BinOp::Add => Event::Add,
+            BinOp::Sub => Event::Sub,
+            BinOp::Mul => Event::Mul,
+            BinOp::Div => Event::Div,
+            BinOp::FloorDiv => Event::IDiv,
+            BinOp::Mod => Event::Mod,
+            BinOp::Pow => Event::Pow,
+            BinOp::BitAnd => Event::BAnd,
+            BinOp::BitOr => Event::BOr,
+            BinOp::BitXor => Event::BXor,
+            BinOp::Shl => Event::Shl,
+            BinOp::Shr => Event::Shr,
+            BinOp::Concat => Event::Concat,
+            BinOp::Lt => Event::Lt,
+            BinOp::Le => Event::Le,
+            _ => unreachable!("no event for {:?}", op),
+        }
+    }
+}
+
+/// Longest `__index` or `__newindex` chain followed before giving up.
+const MAX_META_CHAIN: usize = 2000;
+
+/// Field `event` of the metatable of `value`, nil when it has none. Only
+/// tables have metatables.
+pub fn metamethod(value: &Value, event: Event) -> Value {
+    match value {
+        Value::Table(t) => t.try_borrow().map_or(Value::Nil, |t| t.metamethod(event)),
+        _ => Value::Nil,
+    }
+}
+
+/// Type of `value` as errors name it: the `__name` of its metatable when
+/// that is a string, as Lua's `luaT_objtypename` does.
+pub fn object_type_name(value: &Value) -> String {
+    if let Value::Str(name) = metamethod(value, Event::Name) {
+        return String::from_utf8_lossy(&name).into_owned();
+    }
+    value.type_name().to_string()
+}
+
+/// Sets the metatable of `table`. A `__mode` field makes it weak, and a
+/// `__gc` field present now marks it for finalization, as in Lua.
+pub fn set_metatable(table: &TableRef, meta: Option<TableRef>) {
+    if let Some(meta) = &meta {
+        let fields = meta.borrow();
+        if !matches!(fields.get(&Event::Mode.key()), Value::Nil) {
+            gc::register_weak(table);
+        }
+        if !matches!(fields.get(&Event::Gc.key()), Value::Nil) {
+            gc::register_finalizer(table, Box::new(Value::Table(table.clone())));
+        }
+        gc::barrier(gc::address(meta));
+    }
+    table.borrow_mut().meta = meta;
+}
+
+/// Runs the `__gc` metamethods of the objects the collector queued.
+/// Errors in finalizers are ignored.
+pub fn run_finalizers(host: &mut dyn Host) {
+    for holder in gc::pending_finalizers() {
+        let Ok(value) = holder.downcast::<Value>() else {
+            continue;
+        };
+        let handler = metamethod(&value, Event::Gc);
+        if let Value::Function(_) = handler {
+            let _ = host.call(&handler, vec![*value]);
+        }
+    }
+}
+
+/// Checks the value of a `<close>` variable when it is declared. Only nil
+/// and false may lack a `__close` metamethod.
+pub fn check_closable(value: &Value, name: &str) -> Result<(), RuntimeError> {
+    if !value.is_truthy() || !matches!(metamethod(value, Event::Close), Value::Nil) {
+        return Ok(());
+    }
+    Err(RuntimeError::new(format!(
+        "variable '{}' got a non-closable value",
+        name
+    )))
+}
+
+/// Calls the `__close` metamethod of a variable going out of scope, with the
+/// error that ended the scope, if any.
+pub fn close_value(
+    host: &mut dyn Host,
+    value: &Value,
+    error: Option<RuntimeError>,
+) -> Result<(), RuntimeError> {
+    if !value.is_truthy() {
+        return Ok(());
+    }
+    let handler = metamethod(value, Event::Close);
+    let error = error.map_or(Value::Nil, RuntimeError::into_value);
+    host.call(&handler, vec![value.clone(), error]).map(|_| ())
+}
+
+/// What calling `value` calls, with `value` as the first argument, when it
+/// is not a function: its `__call` metamethod.
+pub fn call_handler(value: &Value) -> Option<Value> {
+    match metamethod(value, Event::Call) {
+        handler @ Value::Function(_) => Some(handler),
+        _ => None,
+    }
+}
+
+/// Calls a metamethod, keeping its first result.
+fn call_event(
+    host: &mut dyn Host,
+    handler: &Value,
+    args: Vec<Value>,
+) -> Result<Value, RuntimeError> {
+    Ok(host
+        .call(handler, args)?
+        .into_iter()
+        .next()
+        .unwrap_or_default())
+}
+
+/// Calls the metamethod for `event` of the first operand having one, or
+/// returns `None`.
+fn binary_event(
+    host: &mut dyn Host,
+    event: Event,
+    left: &Value,
+    right: &Value,
+) -> Option<Result<Value, RuntimeError>> {
+    let handler = match metamethod(left, event) {
+        Value::Nil => metamethod(right, event),
+        handler => handler,
+    };
+    if let Value::Nil = handler {
+        return None;
+    }
+    Some(call_event(
+        host,
+        &handler,
+        vec![left.clone(), right.clone()],
+    ))
+}
+
+/// `tostring`: the result of `__tostring`, or the value's default text
+/// using `__name` for the type of a table.
+pub fn tostring(host: &mut dyn Host, value: &Value) -> Result<Vec<u8>, RuntimeError> {
+    let Value::Table(t) = value else {
+        return Ok(value.to_display());
+    };
+    let handler = metamethod(value, Event::ToString);
+    if !matches!(handler, Value::Nil) {
+        return match call_event(host, &handler, vec![value.clone()])? {
+            result @ (Value::Str(_) | Value::Int(_) | Value::Float(_)) => Ok(result.to_display()),
+            _ => Err(RuntimeError::new("'__tostring' must return a string")),
+        };
+    }
+    match metamethod(value, Event::Name) {
+        Value::Str(name) => {
+            let mut text = name.to_vec();
+            text.extend(format!(": {:p}", Rc::as_ptr(t)).bytes());
+            Ok(text)
+        }
+        _ => Ok(value.to_display()),
+    }
+}
+
+/// `==`, calling `__eq` for two different tables.
+pub fn equals(host: &mut dyn Host, left: &Value, right: &Value) -> Result<bool, RuntimeError> {
+    if left.raw_equals(right) {
+        return Ok(true);
+    }
+    if !matches!((left, right), (Value::Table(_), Value::Table(_))) {
+        return Ok(false);
+    }
+    match binary_event(host, Event::Eq, left, right) {
+        Some(result) => Ok(result?.is_truthy()),
+        None => Ok(false),
+    }
+}
+
+/// Evaluates a binary operator other than `and`/`or`, which short-circuit
+/// and are left to the engines. Operands the operator does not apply to
+/// go through the metamethod of the operator, if either has one.
+pub fn binary(
+    host: &mut dyn Host,
+    op: BinOp,
+    left: &Value,
+    right: &Value,
+) -> Result<Value, RuntimeError> {
+    match op {
+        BinOp::Eq => equals(host, left, right).map(Value::Bool),
+        BinOp::Ne => equals(host, left, right).map(|eq| Value::Bool(!eq)),
+        BinOp::Lt | BinOp::Le => match compare(op, left, right) {
+            Some(result) => Ok(Value::Bool(result)),
+            None => match binary_event(host, Event::of_binary(op), left, right) {
+                Some(result) => Ok(Value::Bool(result?.is_truthy())),
+                None => Err(compare_error(left, right)),
+            },
+        },
+        BinOp::Gt => binary(host, BinOp::Lt, right, left),
+        BinOp::Ge => binary(host, BinOp::Le, right, left),
+        BinOp::Concat => match (left, right) {
+            (
+                Value::Str(_) | Value::Int(_) | Value::Float(_),
+                Value::Str(_) | Value::Int(_) | Value::Float(_),
+            ) => {
+                let mut bytes = left.to_display();
+                bytes.extend(right.to_display());
+                Ok(Value::bytes(bytes))
+            }
+            _ => {
+                if let Some(result) = binary_event(host, Event::Concat, left, right) {
+                    return result;
+                }
+                let bad = if matches!(left, Value::Str(_) | Value::Int(_) | Value::Float(_)) {
+                    right
+                } else {
+                    left
+                };
+                Err(RuntimeError::new(format!(
+                    "attempt to concatenate a {} value",
+                    object_type_name(bad)
+                )))
+            }
+        },
+        BinOp::BitAnd | BinOp::BitOr | BinOp::BitXor | BinOp::Shl | BinOp::Shr => {
+            let operands = bitwise_operand(left, right)
+                .and_then(|x| bitwise_operand(right, left).map(|y| (x, y)));
+            let (x, y) = match operands {
+                Ok(operands) => operands,
+                Err(e) => {
+                    return binary_event(host, Event::of_binary(op), left, right).unwrap_or(Err(e))
+                }
+            };
+            Ok(Value::Int(match op {
+                BinOp::BitAnd => x & y,
+                BinOp::BitOr => x | y,
+                BinOp::BitXor => x ^ y,
+                BinOp::Shl => shift_left(x, y),
+                _ => shift_left(x, y.wrapping_neg()),
+            }))
+        }
+        BinOp::And | BinOp::Or => unreachable!("short-circuit operators are evaluated lazily"),
+        _ => match arith(op, left, right) {
+            Some(result) => result,
+            None => binary_event(host, Event::of_binary(op), left, right).unwrap_or_else(|| {
+                Err(arith_error(if left.to_number().is_none() {
+                    left
+                } else {
+                    right
+                }))
+            }),
+        },
+    }
+}
+
+pub fn unary(host: &mut dyn Host, op: UnOp, value: &Value) -> Result<Value, RuntimeError> {
+    Ok(match op {
+        UnOp::Not => Value::Bool(!value.is_truthy()),
+        UnOp::Neg => match value.to_number() {
+            Some(Value::Int(i)) => Value::Int(i.wrapping_neg()),
+            Some(Value::Float(f)) => Value::Float(-f),
+            // Lua passes the operand twice to unary metamethods
+            _ => match binary_event(host, Event::Unm, value, value) {
+                Some(result) => result?,
+                None => return Err(arith_error(value)),
+            },
+        },
+        UnOp::BitNot => match bitwise_operand(value, value) {
+            Ok(i) => Value::Int(!i),
+            Err(e) => binary_event(host, Event::BNot, value, value).unwrap_or(Err(e))?,
+        },
+        UnOp::Len => match value {
+            Value::Str(s) => Value::Int(s.len() as i64),
+            Value::Table(t) => {
+                let handler = t.borrow().metamethod(Event::Len);
+                match handler {
+                    Value::Nil => Value::Int(t.borrow().len()),
+                    handler => call_event(host, &handler, vec![value.clone()])?,
+                }
+            }
+            Value::Vec(v) => Value::Int(v.borrow().items.len() as i64),
+            _ => {
+                return Err(RuntimeError::new(format!(
+                    "attempt to get length of a {} value",
+                    object_type_name(value)
+                )))
+            }
+        },
+    })
+}
+
+/// `object[key]`, following `__index` when a table has no such key.
+pub fn index(host: &mut dyn Host, object: &Value, key: &Value) -> Result<Value, RuntimeError> {
+    let mut object = object.clone();
+    for _ in 0..MAX_META_CHAIN {
+        let handler = match &object {
+            Value::Table(t) => {
+                let t = t.borrow();
+                let value = t.get(key);
+                if !matches!(value, Value::Nil) || t.meta.is_none() {
+                    return Ok(value);
+                }
+                t.metamethod(Event::Index)
+            }
+            Value::Vec(v) => {
+                return match key {
+                    Value::Str(name) => vec_method(name).ok_or_else(|| {
+                        RuntimeError::new(format!(
+                            "vec has no method '{}'",
+                            String::from_utf8_lossy(name)
+                        ))
+                    }),
+                    _ => v.borrow().get(key),
+                }
+            }
+            _ => return Err(index_error(&object)),
+        };
+        match handler {
+            Value::Nil => return Ok(Value::Nil),
+            Value::Function(_) => return call_event(host, &handler, vec![object, key.clone()]),
+            handler => object = handler,
+        }
+    }
+    Err(RuntimeError::new(
+        "'__index' chain too long; possibly a loop",
+    ))
+}
+
+/// `object[key] = value`, going through `__newindex` when a table has no
+/// such key.
+pub fn set_index(
+    host: &mut dyn Host,
+    object: &Value,
+    key: Value,
+    value: Value,
+) -> Result<(), RuntimeError> {
+    let mut object = object.clone();
+    for _ in 0..MAX_META_CHAIN {
+        let handler = match &object {
+            Value::Table(t) => {
+                let handler = {
+                    let t = t.borrow();
+                    match t.meta {
+                        Some(_) if matches!(t.get(&key), Value::Nil) => {
+                            t.metamethod(Event::NewIndex)
+                        }
+                        _ => Value::Nil,
+                    }
+                };
+                if let Value::Nil = handler {
+                    let value = t.borrow().conform_field(&key, value)?;
+                    return t.borrow_mut().set(key, value);
+                }
+                handler
+            }
+            Value::Vec(v) => {
+                let value = conform_element(v, value)?;
+                return v.borrow_mut().set(&key, value);
+            }
+            _ => return Err(index_error(&object)),
+        };
+        match handler {
+            Value::Function(_) => {
+                return host.call(&handler, vec![object, key, value]).map(|_| ());
+            }
+            handler => object = handler,
+        }
+    }
+    Err(RuntimeError::new(
+        "'__newindex' chain too long; possibly a loop",
+    ))
+}
+
+pub fn call_error(value: &Value) -> RuntimeError {
+    RuntimeError::new(format!(
+        "attempt to call a {} value",
+        object_type_name(value)
+    ))
+}
+
+fn index_error(object: &Value) -> RuntimeError {
+    RuntimeError::new(format!(
+        "attempt to index a {} value",
+        object_type_name(object)
+    ))
+}
+
+/// Integer view of a bitwise operand, converting floats and numeric strings
+/// with an exact integer value like Lua does. `other` is the other operand,
+/// blamed instead when this one is a number but `other` is not.
+fn bitwise_operand(value: &Value, other: &Value) -> Result<i64, RuntimeError> {
+    if let Some(i) = value.to_int() {
+        return Ok(i);
+    }
+    let bad = if value.to_number().is_none() {
+        value
+    } else if other.to_number().is_none() {
+        other
+    } else {
+        return Err(RuntimeError::new("number has no integer representation"));
+    };
+    Err(RuntimeError::new(format!(
+        "attempt to perform bitwise operation on a {} value",
+        object_type_name(bad)
+    )))
+}
+
+/// Logical shift, negative amounts shifting right. Shifting by 64 or more
+/// bits in either direction clears every bit.
+fn shift_left(x: i64, y: i64) -> i64 {
+    match y {
+        64.. | ..=-64 => 0,
+        0.. => ((x as u64) << y) as i64,
+        _ => ((x as u64) >> -y) as i64,
+    }
+}
+
+fn arith_error(value: &Value) -> RuntimeError {
+    RuntimeError::new(format!(
+        "attempt to perform arithmetic on a {} value",
+        object_type_name(value)
+    ))
+}
+
+fn compare_error(left: &Value, right: &Value) -> RuntimeError {
+    RuntimeError::new(format!(
+        "attempt to compare {} with {}",
+        object_type_name(left),
+        object_type_name(right)
+    ))
+}
@@ -0,0 +1,746 @@
+use std::cell::RefCell;
+use std::io::{self, Write};
+use std::rc::Rc;
+
+use crate::ast::TypeName;
+use crate::compiler::{Instr, Proto, MULTI};
+use crate::gc::{self, Trace};
+use crate::stdlib::{self, Host};
+use crate::value::*;
+
+/// Lua frames one script may stack up before "stack overflow".
+const MAX_FRAMES: usize = 200_000;
+
+/// Nesting of calls re-entering the VM from Rust, e.g. through `pcall`,
+/// each of which takes native stack.
+const MAX_REENTRY: usize = 200;
+
+/// A variable captured by a closure. It stays open, pointing into the
+/// stack, while the function declaring it runs, and is closed over a copy
+/// of the value when its block ends.
+pub enum Upvalue {
+    Open(usize),
+    Closed(Value),
+}
+
+pub type UpvalRef = Rc<RefCell<Upvalue>>;
+
+impl Trace for RefCell<Upvalue> {
+    fn trace(&self, visit: &mut dyn FnMut(usize)) -> bool {
+        let Ok(upvalue) = self.try_borrow() else {
+            return false;
+        };
+        // An open upvalue's value is on the stack, which the VM holds
+        if let Upvalue::Closed(value) = &*upvalue {
+            value.trace(visit);
+        }
+        true
+    }
+
+    fn clear(&self) -> Box<dyn std::any::Any> {
+        Box::new(self.replace(Upvalue::Closed(Value::Nil)))
+    }
+
+    fn size(&self) -> usize {
+        std::mem::size_of::<Self>()
+    }
+}
+
+pub struct LuaClosure {
+    pub proto: Rc<Proto>,
+    pub upvalues: Vec<UpvalRef>,
+}
+
+struct CallFrame {
+    func: Rc<Callable>,
+    proto: Rc<Proto>,
+    /// Next instruction, only kept up to date while the frame is not running
+    pc: usize,
+    /// Stack index of register 0
+    base: usize,
+    /// Stack slot of the called function, where results go
+    ret: usize,
+    nresults: u8,
+    varargs: Vec<Value>,
+}
+
+/// Register-based virtual machine running bytecode from the compiler. Lua
+/// calls push frames on a heap stack instead of recursing.
+pub struct Vm {
+    stack: Vec<Value>,
+    frames: Vec<CallFrame>,
+    /// Open upvalues, sorted by the stack slot they point to
+    open_upvalues: Vec<UpvalRef>,
+    /// Stack slots of the live `<close>` variables, in declaration order
+    tbc: Vec<usize>,
+    globals: TableRef,
+    out: Box<dyn Write>,
+    /// End of the values left by the last call or `...` asking for all of them
+    top: usize,
+    reentry: usize,
+}
+
+impl Default for Vm {
+    fn default() -> Self {
+        Vm::new()
+    }
+}
+
+impl Vm {
+    pub fn new() -> Self {
+        Vm::with_output(Box::new(io::stdout()))
+    }
+
+    pub fn with_output(out: Box<dyn Write>) -> Self {
+        gc::reset();
+        let mut globals = Table::default();
+        stdlib::open_base(&mut globals);
+        Vm {
+            stack: Vec::new(),
+            frames: Vec::new(),
+            open_upvalues: Vec::new(),
+            tbc: Vec::new(),
+            globals: new_table_ref(globals),
+            out,
+            top: 0,
+            reentry: 0,
+        }
+    }
+
+    pub fn run(&mut self, main: Rc<Proto>) -> Result<Vec<Value>, RuntimeError> {
+        let closure = Value::Function(gc::track(Rc::new(Callable::Bytecode(LuaClosure {
+            proto: main,
+            upvalues: Vec::new(),
+        }))));
+        let result = self.call_value(&closure, Vec::new());
+        // Objects still alive when the program ends are finalized too
+        gc::finalize_all();
+        run_finalizers(self);
+        result
+    }
+
+    pub fn call_value(
+        &mut self,
+        func: &Value,
+        args: Vec<Value>,
+    ) -> Result<Vec<Value>, RuntimeError> {
+        let Value::Function(f) = func else {
+            if let Some(handler) = call_handler(func) {
+                let mut args = args;
+                args.insert(0, func.clone());
+                return self.call_value(&handler, args);
+            }
+            return Err(call_error(func));
+        };
+        match &**f {
+            Callable::Native(native) => (native.func)(self, args),
+            Callable::Bytecode(_) => {
+                if self.reentry >= MAX_REENTRY {
+                    return Err(RuntimeError::new("stack overflow"));
+                }
+                let slot = self.stack.len();
+                let nargs = args.len();
+                self.stack.push(func.clone());
+                self.stack.extend(args);
+                let entry = self.frames.len();
+                let result = match self.push_frame(f.clone(), slot, nargs, MULTI) {
+                    Ok(()) => {
+                        self.reentry += 1;
+                        let result = self.execute(entry);
+                        self.reentry -= 1;
+                        result
+                    }
+                    Err(e) => Err(e),
+                };
+                self.stack.truncate(slot);
+                result
+            }
+            Callable::Closure(_) => Err(RuntimeError::new(
+                "cannot call a function of the tree-walking interpreter",
+            )),
+        }
+    }
+
+    /// Sets up a frame for a bytecode function whose arguments are already
+    /// on the stack after `slot`.
+    fn push_frame(
+        &mut self,
+        func: Rc<Callable>,
+        slot: usize,
+        nargs: usize,
+        nresults: u8,
+    ) -> Result<(), RuntimeError> {
+        let Callable::Bytecode(closure) = &*func else {
+            unreachable!("frames only run bytecode functions");
+        };
+        if self.frames.len() >= MAX_FRAMES {
+            return Err(RuntimeError::new("stack overflow"));
+        }
+        let proto = closure.proto.clone();
+        let base = slot + 1;
+        let nparams = proto.params.len();
+        let varargs = if proto.is_vararg && nargs > nparams {
+            self.stack[base + nparams..base + nargs].to_vec()
+        } else {
+            Vec::new()
+        };
+        self.stack.truncate(base + nargs.min(nparams));
+        self.stack.resize(base + proto.max_stack, Value::Nil);
+        for (i, (ty, span)) in proto.params.iter().enumerate() {
+            if let Some(ty) = ty {
+                let value = std::mem::take(&mut self.stack[base + i]);
+                self.stack[base + i] =
+                    conform(&proto.types[*ty as usize], value).map_err(|e| e.at(*span))?;
+            }
+        }
+        self.frames.push(CallFrame {
+            func,
+            proto,
+            pc: 0,
+            base,
+            ret: slot,
+            nresults,
+            varargs,
+        });
+        Ok(())
+    }
+
+    /// Runs until the frame at depth `entry` returns, unwinding the frames
+    /// above it on error.
+    fn execute(&mut self, entry: usize) -> Result<Vec<Value>, RuntimeError> {
+        let result = self.dispatch(entry);
+        match result {
+            Err(e) if self.frames.len() > entry => {
+                let base = self.frames[entry].base;
+                self.frames.truncate(entry);
+                self.close_upvalues(base);
+                Err(self.close_tbc(base, Some(e)).unwrap_err())
+            }
+            result => result,
+        }
+    }
+
+    /// Calls `__close` on the to-be-closed variables at `level` and above,
+    /// newest first, passing the error unwinding the stack if there is one.
+    /// An error raised by a `__close` replaces it.
+    fn close_tbc(&mut self, level: usize, error: Option<RuntimeError>) -> Result<(), RuntimeError> {
+        let mut error = error;
+        while let Some(&slot) = self.tbc.last().filter(|&&slot| slot >= level) {
+            self.tbc.pop();
+            let value = self.stack[slot].clone();
+            if let Err(e) = close_value(self, &value, error.clone()) {
+                error = Some(e);
+            }
+        }
+        error.map_or(Ok(()), Err)
+    }
+
+    fn dispatch(&mut self, entry: usize) -> Result<Vec<Value>, RuntimeError> {
+        'frames: loop {
+            let frame = self.frames.last().unwrap();
+            let func = frame.func.clone();
+            let proto = frame.proto.clone();
+            let base = frame.base;
+            let mut pc = frame.pc;
+            let Callable::Bytecode(closure) = &*func else {
+                unreachable!("frames only run bytecode functions");
+            };
+
+            macro_rules! reg {
+                ($r:expr) => {
+                    self.stack[base + $r as usize]
+                };
+            }
+            // Attaches the location of the current instruction to errors
+            macro_rules! at {
+                ($e:expr) => {
+                    match $e {
+                        Ok(v) => v,
+                        Err(e) => return Err(RuntimeError::at(e, proto.spans[pc - 1])),
+                    }
+                };
+            }
+
+            loop {
+                let instr = proto.code[pc];
+                pc += 1;
+                match instr {
+                    Instr::Move { a, b } => reg!(a) = reg!(b).clone(),
+                    Instr::LoadK { a, k } => reg!(a) = proto.constants[k as usize].clone(),
+                    Instr::LoadBool { a, value } => reg!(a) = Value::Bool(value),
+                    Instr::LoadNil { a, n } => {
+                        for i in 0..n {
+                            reg!(a + i) = Value::Nil;
+                        }
+                    }
+                    Instr::GetUpval { a, up } => {
+                        let value = match &*closure.upvalues[up as usize].borrow() {
+                            Upvalue::Open(slot) => self.stack[*slot].clone(),
+                            Upvalue::Closed(value) => value.clone(),
+                        };
+                        reg!(a) = value;
+                    }
+                    Instr::SetUpval { up, b } => {
+                        let value = reg!(b).clone();
+                        match &mut *closure.upvalues[up as usize].borrow_mut() {
+                            Upvalue::Open(slot) => self.stack[*slot] = value,
+                            Upvalue::Closed(closed) => {
+                                value.write_barrier();
+                                *closed = value;
+                            }
+                        }
+                    }
+                    Instr::GetGlobal { a, k } => {
+                        reg!(a) = self.globals.borrow().get(&proto.constants[k as usize]);
+                    }
+                    Instr::SetGlobal { k, b } => {
+                        let value = reg!(b).clone();
+                        let key = proto.constants[k as usize].clone();
+                        at!(self.globals.borrow_mut().set(key, value));
+                    }
+                    Instr::GetIndex { a, b, c } => {
+                        let (object, key) = (reg!(b).clone(), reg!(c).clone());
+                        reg!(a) = at!(index(self, &object, &key));
+                    }
+                    Instr::GetField { a, b, k } => {
+                        let object = reg!(b).clone();
+                        reg!(a) = at!(index(self, &object, &proto.constants[k as usize]));
+                    }
+                    Instr::SetIndex { a, b, c } => {
+                        let (object, key, value) =
+                            (reg!(a).clone(), reg!(b).clone(), reg!(c).clone());
+                        at!(set_index(self, &object, key, value));
+                    }
+                    Instr::SetField { a, k, c } => {
+                        let (object, value) = (reg!(a).clone(), reg!(c).clone());
+                        let key = proto.constants[k as usize].clone();
+                        at!(set_index(self, &object, key, value));
+                    }
+                    Instr::NewTable { a } => reg!(a) = Value::new_table(),
+                    Instr::TypeField { a, k, ty } => {
+                        let name = &proto.constants[k as usize];
+                        if let (Value::Table(t), Value::Str(name)) = (&reg!(a), name) {
+                            let ty = proto.types[ty as usize].clone();
+                            t.borrow_mut().type_field(name.clone(), ty);
+                        }
+                    }
+                    Instr::NewVec { a } => reg!(a) = Value::new_vec(Vec::new()),
+                    Instr::SetList { a, n, start } => {
+                        let first = base + a as usize + 1;
+                        let count = if n == MULTI {
+                            self.top - first
+                        } else {
+                            n as usize
+                        };
+                        let values = &self.stack[first..first + count];
+                        match &reg!(a) {
+                            Value::Table(table) => {
+                                let mut table = table.borrow_mut();
+                                for (i, value) in values.iter().enumerate() {
+                                    let key = Value::Int(start as i64 + i as i64);
+                                    at!(table.set(key, value.clone()));
+                                }
+                            }
+                            Value::Vec(v) => v.borrow_mut().items.extend_from_slice(values),
+                            _ => unreachable!("SetList on a register without a table"),
+                        }
+                    }
+                    Instr::Method { a, b, k } => {
+                        let object = reg!(b).clone();
+                        let method = at!(index(self, &object, &proto.constants[k as usize]));
+                        reg!(a + 1) = object;
+                        reg!(a) = method;
+                    }
+                    Instr::Unary { op, a, b } => {
+                        let value = reg!(b).clone();
+                        reg!(a) = at!(unary(self, op, &value));
+                    }
+                    Instr::Binary { op, a, b, c } => {
+                        let (left, right) = (reg!(b).clone(), reg!(c).clone());
+                        reg!(a) = at!(binary(self, op, &left, &right));
+                    }
+                    Instr::AddInt { a, b, c } => {
+                        reg!(a) = Value::Int(int(&reg!(b)).wrapping_add(int(&reg!(c))));
+                    }
+                    Instr::SubInt { a, b, c } => {
+                        reg!(a) = Value::Int(int(&reg!(b)).wrapping_sub(int(&reg!(c))));
+                    }
+                    Instr::MulInt { a, b, c } => {
+                        reg!(a) = Value::Int(int(&reg!(b)).wrapping_mul(int(&reg!(c))));
+                    }
+                    Instr::LtInt { a, b, c } => {
+                        reg!(a) = Value::Bool(int(&reg!(b)) < int(&reg!(c)))
+                    }
+                    Instr::LeInt { a, b, c } => {
+                        reg!(a) = Value::Bool(int(&reg!(b)) <= int(&reg!(c)));
+                    }
+                    Instr::AddFloat { a, b, c } => {
+                        reg!(a) = Value::Float(float(&reg!(b)) + float(&reg!(c)));
+                    }
+                    Instr::SubFloat { a, b, c } => {
+                        reg!(a) = Value::Float(float(&reg!(b)) - float(&reg!(c)));
+                    }
+                    Instr::MulFloat { a, b, c } => {
+                        reg!(a) = Value::Float(float(&reg!(b)) * float(&reg!(c)));
+                    }
+                    Instr::DivFloat { a, b, c } => {
+                        reg!(a) = Value::Float(float(&reg!(b)) / float(&reg!(c)));
+                    }
+                    Instr::LtFloat { a, b, c } => {
+                        reg!(a) = Value::Bool(float(&reg!(b)) < float(&reg!(c)));
+                    }
+                    Instr::LeFloat { a, b, c } => {
+                        reg!(a) = Value::Bool(float(&reg!(b)) <= float(&reg!(c)));
+                    }
+                    Instr::Jump { target } => pc = target as usize,
+                    Instr::JumpIfFalse { a, target } => {
+                        if !reg!(a).is_truthy() {
+                            pc = target as usize;
+                        }
+                    }
+                    Instr::JumpIfTrue { a, target } => {
+                        if reg!(a).is_truthy() {
+                            pc = target as usize;
+                        }
+                    }
+                    Instr::Call { a, nargs, nresults } => {
+                        let slot = base + a as usize;
+                        let mut nargs = if nargs == MULTI {
+                            self.top - slot - 1
+                        } else {
+                            nargs as usize
+                        };
+                        if gc::has_pending() {
+                            self.frames.last_mut().unwrap().pc = pc;
+                            run_finalizers(self);
+                        }
+                        let f = match self.stack[slot].clone() {
+                            Value::Function(f) => f,
+                            // Calling a value with `__call` calls the handler
+                            // with the value as its first argument
+                            object => match call_handler(&object) {
+                                Some(Value::Function(f)) => {
+                                    self.stack.insert(slot, Value::Function(f.clone()));
+                                    nargs += 1;
+                                    f
+                                }
+                                _ => return Err(call_error(&object).at(proto.spans[pc - 1])),
+                            },
+                        };
+                        match &*f {
+                            Callable::Bytecode(_) => {
+                                self.frames.last_mut().unwrap().pc = pc;
+                                at!(self.push_frame(f.clone(), slot, nargs, nresults));
+                                continue 'frames;
+                            }
+                            Callable::Native(native) => {
+                                let args = self.stack[slot + 1..slot + 1 + nargs].to_vec();
+                                let results = at!((native.func)(self, args));
+                                self.place_results(slot, results, nresults);
+                            }
+                            Callable::Closure(_) => {
+                                let e = RuntimeError::new(
+                                    "cannot call a function of the tree-walking interpreter",
+                                );
+                                return Err(e.at(proto.spans[pc - 1]));
+                            }
+                        }
+                    }
+                    Instr::Return { a, n } => {
+                        let start = base + a as usize;
+                        let end = if n == MULTI {
+                            self.top
+                        } else {
+                            start + n as usize
+                        };
+                        let mut values = self.stack[start..end].to_vec();
+                        self.close_upvalues(base);
+                        at!(self.close_tbc(base, None));
+                        let frame = self.frames.pop().unwrap();
+                        // Return types are checked like the interpreter does,
+                        // blaming the call rather than the `return`
+                        let converted = match &proto.ret {
+                            Some(TypeName::Void) => {
+                                values.clear();
+                                Ok(())
+                            }
+                            Some(ty) => match values.first_mut() {
+                                Some(first) => {
+                                    conform(ty, std::mem::take(first)).map(|value| *first = value)
+                                }
+                                None => Ok(()),
+                            },
+                            None => Ok(()),
+                        };
+                        if self.frames.len() == entry {
+                            converted?;
+                            return Ok(values);
+                        }
+                        let caller = self.frames.last().unwrap();
+                        if let Err(e) = converted {
+                            return Err(e.at(caller.proto.spans[caller.pc - 1]));
+                        }
+                        let needed = caller.base + caller.proto.max_stack;
+                        // Registers of the callee are cleared so they do not
+                        // keep garbage alive
+                        self.stack.truncate(frame.ret);
+                        self.place_results(frame.ret, values, frame.nresults);
+                        if self.stack.len() < needed {
+                            self.stack.resize(needed, Value::Nil);
+                        }
+                        continue 'frames;
+                    }
+                    Instr::Closure { a, proto: index } => {
+                        let child = proto.protos[index as usize].clone();
+                        let upvalues = child
+                            .upvalues
+                            .iter()
+                            .map(|desc| {
+                                if desc.in_parent_local {
+                                    self.find_upvalue(base + desc.index as usize)
+                                } else {
+                                    closure.upvalues[desc.index as usize].clone()
+                                }
+                            })
+                            .collect();
+                        reg!(a) =
+                            Value::Function(gc::track(Rc::new(Callable::Bytecode(LuaClosure {
+                                proto: child,
+                                upvalues,
+                            }))));
+                    }
+                    Instr::Vararg { a, n } => {
+                        let varargs = &self.frames.last().unwrap().varargs;
+                        let start = base + a as usize;
+                        if n == MULTI {
+                            let values = varargs.clone();
+                            self.top = start + values.len();
+                            if self.stack.len() < self.top {
+                                self.stack.resize(self.top, Value::Nil);
+                            }
+                            for (i, value) in values.into_iter().enumerate() {
+                                self.stack[start + i] = value;
+                            }
+                        } else {
+                            let values: Vec<Value> = (0..n as usize)
+                                .map(|i| varargs.get(i).cloned().unwrap_or_default())
+                                .collect();
+                            for (i, value) in values.into_iter().enumerate() {
+                                self.stack[start + i] = value;
+                            }
+                        }
+                    }
+                    Instr::ForPrep { a, exit } => {
+                        if !at!(self.for_prep(base + a as usize)) {
+                            pc = exit as usize;
+                        }
+                    }
+                    Instr::ForLoop { a, body } => {
+                        let a = base + a as usize;
+                        match (&self.stack[a], &self.stack[a + 2]) {
+                            (Value::Int(i), Value::Int(step)) => {
+                                let count = int(&self.stack[a + 1]) as u64;
+                                if count > 0 {
+                                    let i = i.wrapping_add(*step);
+                                    self.stack[a] = Value::Int(i);
+                                    self.stack[a + 1] = Value::Int((count - 1) as i64);
+                                    self.stack[a + 3] = Value::Int(i);
+                                    pc = body as usize;
+                                }
+                            }
+                            (i, step) => {
+                                let (step, limit) = (float(step), float(&self.stack[a + 1]));
+                                let i = float(i) + step;
+                                if (step > 0.0 && i <= limit) || (step < 0.0 && i >= limit) {
+                                    self.stack[a] = Value::Float(i);
+                                    self.stack[a + 3] = Value::Float(i);
+                                    pc = body as usize;
+                                }
+                            }
+                        }
+                    }
+                    Instr::TForCall { a, n } => {
+                        let func = reg!(a).clone();
+                        let args = vec![reg!(a + 1).clone(), reg!(a + 2).clone()];
+                        let mut results = at!(self.call_value(&func, args)).into_iter();
+                        for i in 0..n {
+                            reg!(a + 3 + i) = results.next().unwrap_or_default();
+                        }
+                    }
+                    Instr::TForLoop { a, body } => {
+                        if !matches!(reg!(a + 3), Value::Nil) {
+                            reg!(a + 2) = reg!(a + 3).clone();
+                            pc = body as usize;
+                        }
+                    }
+                    Instr::Close { a } => {
+                        self.close_upvalues(base + a as usize);
+                        at!(self.close_tbc(base + a as usize, None));
+                    }
+                    Instr::Tbc { a, k } => {
+                        let value = reg!(a).clone();
+                        let name = proto.constants[k as usize].to_display();
+                        at!(check_closable(&value, &String::from_utf8_lossy(&name)));
+                        if value.is_truthy() {
+                            self.tbc.push(base + a as usize);
+                        }
+                    }
+                    Instr::Conform { a, ty } => {
+                        let value = std::mem::take(&mut reg!(a));
+                        reg!(a) = at!(conform(&proto.types[ty as usize], value));
+                    }
+                    Instr::Cast { a, ty } => {
+                        let value = std::mem::take(&mut reg!(a));
+                        reg!(a) = at!(cast(&proto.types[ty as usize], value));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Checks and converts the control values of a numeric `for` starting at
+    /// stack slot `a`, returning whether the loop runs at all.
+    fn for_prep(&mut self, a: usize) -> Result<bool, RuntimeError> {
+        let mut numbers = Vec::with_capacity(3);
+        for (i, what) in ["initial", "limit", "step"].into_iter().enumerate() {
+            match self.stack[a + i].to_number() {
+                Some(n) => numbers.push(n),
+                None => {
+                    return Err(RuntimeError::new(format!(
+                        "'for' {} value must be a number",
+                        what
+                    )))
+                }
+            }
+        }
+        match (&numbers[0], &numbers[1], &numbers[2]) {
+            (Value::Int(start), limit, Value::Int(step)) => {
+                let (start, step) = (*start, *step);
+                if step == 0 {
+                    return Err(RuntimeError::new("'for' step is zero"));
+                }
+                // Clip a float limit to the integer range, as Lua does
+                let limit = match limit {
+                    Value::Int(l) => *l,
+                    Value::Float(f) if step > 0 => {
+                        f.floor().clamp(i64::MIN as f64, i64::MAX as f64) as i64
+                    }
+                    Value::Float(f) => f.ceil().clamp(i64::MIN as f64, i64::MAX as f64) as i64,
+                    _ => unreachable!(),
+                };
+                if (step > 0 && start > limit) || (step < 0 && start < limit) {
+                    return Ok(false);
+                }
+                // The remaining iteration count replaces the limit, so the
+                // loop cannot overflow
+                let count = if step > 0 {
+                    (limit as u64).wrapping_sub(start as u64) / step as u64
+                } else {
+                    (start as u64).wrapping_sub(limit as u64) / (step as u64).wrapping_neg()
+                };
+                self.stack[a] = Value::Int(start);
+                self.stack[a + 1] = Value::Int(count as i64);
+                self.stack[a + 3] = Value::Int(start);
+                Ok(true)
+            }
+            (start, limit, step) => {
+                let (start, limit, step) = (
+                    start.to_float().unwrap(),
+                    limit.to_float().unwrap(),
+                    step.to_float().unwrap(),
+                );
+                if step == 0.0 {
+                    return Err(RuntimeError::new("'for' step is zero"));
+                }
+                self.stack[a] = Value::Float(start);
+                self.stack[a + 1] = Value::Float(limit);
+                self.stack[a + 2] = Value::Float(step);
+                self.stack[a + 3] = Value::Float(start);
+                Ok((step > 0.0 && start <= limit) || (step < 0.0 && start >= limit))
+            }
+        }
+    }
+
+    /// Stores call results from `slot`, padding or truncating them to
+    /// `nresults`, or keeping all of them up to `top` for `MULTI`.
+    fn place_results(&mut self, slot: usize, values: Vec<Value>, nresults: u8) {
+        let count = if nresults == MULTI {
+            values.len()
+        } else {
+            nresults as usize
+        };
+        if self.stack.len() < slot + count {
+            self.stack.resize(slot + count, Value::Nil);
+        }
+        let mut values = values.into_iter();
+        for i in 0..count {
+            self.stack[slot + i] = values.next().unwrap_or_default();
+        }
+        if nresults == MULTI {
+            self.top = slot + count;
+        }
+    }
+
+    fn find_upvalue(&mut self, slot: usize) -> UpvalRef {
+        let mut at = 0;
+        for (i, upvalue) in self.open_upvalues.iter().enumerate().rev() {
+            let Upvalue::Open(open) = *upvalue.borrow() else {
+                unreachable!("closed upvalue in the open list");
+            };
+            if open == slot {
+                return upvalue.clone();
+            }
+            if open < slot {
+                at = i + 1;
+                break;
+            }
+        }
+        let upvalue = gc::track(Rc::new(RefCell::new(Upvalue::Open(slot))));
+        self.open_upvalues.insert(at, upvalue.clone());
+        upvalue
+    }
+
+    /// Closes every open upvalue pointing at `level` or above.
+    fn close_upvalues(&mut self, level: usize) {
+        while let Some(upvalue) = self.open_upvalues.last() {
+            let Upvalue::Open(slot) = *upvalue.borrow() else {
+                unreachable!("closed upvalue in the open list");
+            };
+            if slot < level {
+                break;
+            }
+            let value = self.stack[slot].clone();
+            value.write_barrier();
+            *upvalue.borrow_mut() = Upvalue::Closed(value);
+            self.open_upvalues.pop();
+        }
+    }
+}
+
+/// Payload of a register the compiler proved to hold an integer.
+fn int(value: &Value) -> i64 {
+    match value {
+        Value::Int(i) => *i,
+        _ => unreachable!("register expected to hold an integer: {:?}", value),
+    }
+}
+
+/// Payload of a register the compiler proved to hold a float.
+fn float(value: &Value) -> f64 {
+    match value {
+        Value::Float(f) => *f,
+        _ => unreachable!("register expected to hold a float: {:?}", value),
+    }
+}
+
+impl Host for Vm {
+    fn call(&mut self, func: &Value, args: Vec<Value>) -> Result<Vec<Value>, RuntimeError> {
+        self.call_value(func, args)
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> Result<(), RuntimeError> {
+        self.out
+            .write_all(bytes)
+            .map_err(|e| RuntimeError::new(e.to_string()))
+    }
+}